@@ -0,0 +1,122 @@
+//! Accounting of vCPU execution.
+
+use super::{Result, VirtualCpu, VirtualCpuExitReason};
+use crate::ffi::system::mach_absolute_time;
+
+/// Keep track of the time consumed by the guest of a vCPU across runs.
+///
+/// Ticks are in mach_absolute_time() units, as reported by [VirtualCpu::get_exec_time].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SchedulingAccount {
+    /// Ticks consumed by the guest during accounted runs.
+    busy_ticks: u64,
+
+    /// Number of accounted runs.
+    run_count: u64,
+}
+
+impl SchedulingAccount {
+    /// Create a new empty account.
+    pub fn new() -> Self {
+        SchedulingAccount::default()
+    }
+
+    /// Runs the vCPU, accounting the time consumed by the guest during this run.
+    ///
+    /// Only successful runs are accounted. If the execution time cannot be queried after the run, the exit is still returned
+    /// but the run isn't accounted at all, so [SchedulingAccount::average_ticks_per_run] stays accurate.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run(&mut self, vcpu: &mut VirtualCpu) -> Result<VirtualCpuExitReason> {
+        let start = vcpu.get_exec_time()?;
+        let reason = vcpu.run()?;
+
+        if let Ok(end) = vcpu.get_exec_time() {
+            self.busy_ticks += end.saturating_sub(start);
+            self.run_count += 1;
+        }
+
+        Ok(reason)
+    }
+
+    /// Gets the ticks consumed by the guest during accounted runs.
+    pub fn busy_ticks(&self) -> u64 {
+        self.busy_ticks
+    }
+
+    /// Gets the number of accounted runs.
+    pub fn run_count(&self) -> u64 {
+        self.run_count
+    }
+
+    /// Gets the average ticks consumed by the guest per run.
+    pub fn average_ticks_per_run(&self) -> u64 {
+        self.busy_ticks.checked_div(self.run_count).unwrap_or(0)
+    }
+}
+
+/// Keep track of the time spent inside runs of a vCPU compared to the time spent in the host between them.
+///
+/// Ticks are in mach_absolute_time() units. Time inside a run includes the Hypervisor entry and exit costs.
+/// Host time is only accounted between two runs done through the same accumulator.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HostOverhead {
+    /// Ticks spent inside runs.
+    guest_ticks: u64,
+
+    /// Ticks spent in the host between runs.
+    host_ticks: u64,
+
+    /// Time at which the last run returned.
+    last_exit: Option<u64>,
+}
+
+impl HostOverhead {
+    /// Create a new empty accumulator.
+    pub fn new() -> Self {
+        HostOverhead::default()
+    }
+
+    /// Runs the vCPU, accounting the time spent inside the run and in the host since the previous one.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run(&mut self, vcpu: &mut VirtualCpu) -> Result<VirtualCpuExitReason> {
+        let entry = unsafe { mach_absolute_time() };
+
+        if let Some(last_exit) = self.last_exit {
+            self.host_ticks += entry.saturating_sub(last_exit);
+        }
+
+        let result = vcpu.run();
+
+        let exit = unsafe { mach_absolute_time() };
+
+        self.guest_ticks += exit.saturating_sub(entry);
+        self.last_exit = Some(exit);
+
+        result
+    }
+
+    /// Gets the ticks spent inside runs.
+    pub fn guest_ticks(&self) -> u64 {
+        self.guest_ticks
+    }
+
+    /// Gets the ticks spent in the host between runs.
+    pub fn host_ticks(&self) -> u64 {
+        self.host_ticks
+    }
+
+    /// Gets the ratio of the time spent inside runs over the total accounted time, between 0.0 and 1.0.
+    ///
+    /// Returns 0.0 if nothing was accounted yet.
+    pub fn guest_ratio(&self) -> f64 {
+        let total = self.guest_ticks + self.host_ticks;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.guest_ticks as f64 / total as f64
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Atomic operations on guest memory, used to emulate guest atomic instructions.
+
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use super::{hv_ipa_t, HypervisorError, Result, VirtualMachine};
+
+/// Width of an atomic access.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtomicSize {
+    /// 8 bits.
+    Byte,
+
+    /// 16 bits.
+    HalfWord,
+
+    /// 32 bits.
+    Word,
+
+    /// 64 bits.
+    DoubleWord,
+}
+
+impl AtomicSize {
+    /// Gets the size of the access in bytes.
+    pub const fn bytes(&self) -> usize {
+        match self {
+            AtomicSize::Byte => 1,
+            AtomicSize::HalfWord => 2,
+            AtomicSize::Word => 4,
+            AtomicSize::DoubleWord => 8,
+        }
+    }
+}
+
+/// Atomically compare and exchange a value in host memory, returning the previous value.
+///
+/// # Safety
+///
+/// ``pointer`` must be valid for reads and writes of ``size`` bytes and aligned on ``size``.
+unsafe fn compare_exchange_raw(pointer: *mut u8, expected: u64, new: u64, size: AtomicSize) -> u64 {
+    match size {
+        AtomicSize::Byte => {
+            let atomic = &*(pointer as *const AtomicU8);
+
+            atomic
+                .compare_exchange(
+                    expected as u8,
+                    new as u8,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .map_or_else(u64::from, u64::from)
+        }
+        AtomicSize::HalfWord => {
+            let atomic = &*(pointer as *const AtomicU16);
+
+            atomic
+                .compare_exchange(
+                    expected as u16,
+                    new as u16,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .map_or_else(u64::from, u64::from)
+        }
+        AtomicSize::Word => {
+            let atomic = &*(pointer as *const AtomicU32);
+
+            atomic
+                .compare_exchange(
+                    expected as u32,
+                    new as u32,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .map_or_else(u64::from, u64::from)
+        }
+        AtomicSize::DoubleWord => {
+            let atomic = &*(pointer as *const AtomicU64);
+
+            atomic
+                .compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst)
+                .unwrap_or_else(|value| value)
+        }
+    }
+}
+
+impl VirtualMachine {
+    /// Atomically compare and exchange a value in guest memory, returning the previous value.
+    ///
+    /// The value is only replaced by ``new`` if it's equal to ``expected``. Both are truncated to the access size.
+    /// The operation uses [Ordering::SeqCst] ordering on the host memory backing the guest address,
+    /// making it atomic with respect to guest accesses from running vCPUs.
+    ///
+    /// Returns [HypervisorError::MisalignedAddress] if the address isn't aligned on the access size
+    /// and [HypervisorError::UnmappedAddress] if the address isn't mapped.
+    pub fn guest_compare_exchange(
+        &mut self,
+        ipa: hv_ipa_t,
+        expected: u64,
+        new: u64,
+        size: AtomicSize,
+    ) -> Result<u64> {
+        if ipa % size.bytes() as u64 != 0 {
+            return Err(HypervisorError::MisalignedAddress);
+        }
+
+        let mapping = self
+            .find_mapping_by_address(ipa)
+            .ok_or(HypervisorError::UnmappedAddress)?;
+
+        // As mappings are page aligned, an aligned access cannot cross the end of a mapping.
+        let offset = (ipa - mapping.address) as usize;
+        let (_, allocation) = self.find_allocation_by_handle(mapping.allocation_handle)?;
+
+        let result = unsafe {
+            compare_exchange_raw(allocation.base_address.add(offset), expected, new, size)
+        };
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the atomic operations.
+
+    use super::*;
+
+    /// The value is only replaced when it matches, truncated to the access size.
+    #[test]
+    fn compare_exchange_truncates_to_size() {
+        let mut value = 0x1122_3344_5566_7788u64.to_le();
+        let pointer = &mut value as *mut u64 as *mut u8;
+
+        unsafe {
+            assert_eq!(
+                compare_exchange_raw(pointer, 0, 1, AtomicSize::Word),
+                0x5566_7788
+            );
+            assert_eq!(
+                compare_exchange_raw(pointer, 0xffff_7788, 0xaa, AtomicSize::HalfWord),
+                0x7788
+            );
+        }
+
+        assert_eq!(u64::from_le(value), 0x1122_3344_5566_00aa);
+    }
+}
@@ -0,0 +1,101 @@
+//! Audit log of the mapping permission changes of a Virtual Machine.
+
+use alloc::vec::Vec;
+
+use super::{MappingHandle, MemoryPermission, VirtualMachine};
+use crate::ffi::system::mach_absolute_time;
+
+/// Default number of entries kept in the audit log, older entries are discarded first.
+pub const DEFAULT_AUDIT_LOG_CAPACITY: usize = 1024;
+
+/// Operation recorded in the audit log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AuditOperation {
+    /// A mapping was created.
+    Map,
+
+    /// A mapping was removed.
+    Unmap,
+
+    /// The permission of a mapping was changed.
+    Reprotect,
+}
+
+/// Entry of the audit log.
+#[derive(Copy, Clone, Debug)]
+pub struct AuditEntry {
+    /// Time of the operation, in ``mach_absolute_time`` ticks.
+    pub timestamp: u64,
+
+    /// The handle of the mapping.
+    pub mapping_handle: MappingHandle,
+
+    /// The permission before the operation, None for [AuditOperation::Map].
+    pub old_permission: Option<MemoryPermission>,
+
+    /// The permission after the operation, None for [AuditOperation::Unmap].
+    pub new_permission: Option<MemoryPermission>,
+
+    /// The operation.
+    pub operation: AuditOperation,
+}
+
+impl VirtualMachine {
+    /// Enable or disable the audit log of mapping permission changes.
+    ///
+    /// Disabling the audit log discards all its entries.
+    pub fn set_audit_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.audit_log = None;
+        } else if self.audit_log.is_none() {
+            self.audit_log = Some(Vec::new());
+        }
+    }
+
+    /// Sets the maximum number of entries kept in the audit log (by default [DEFAULT_AUDIT_LOG_CAPACITY]).
+    ///
+    /// The oldest entries above the new capacity are discarded.
+    pub fn set_audit_capacity(&mut self, capacity: usize) {
+        self.audit_log_capacity = capacity;
+
+        if let Some(audit_log) = &mut self.audit_log {
+            let excess = audit_log.len().saturating_sub(capacity);
+
+            audit_log.drain(..excess);
+        }
+    }
+
+    /// Gets the entries of the audit log, from the oldest to the newest.
+    ///
+    /// Empty when the audit log is disabled.
+    pub fn permission_audit(&self) -> &[AuditEntry] {
+        self.audit_log.as_deref().unwrap_or_default()
+    }
+
+    /// Record an operation in the audit log if enabled.
+    pub(super) fn record_audit(
+        &mut self,
+        operation: AuditOperation,
+        mapping_handle: MappingHandle,
+        old_permission: Option<MemoryPermission>,
+        new_permission: Option<MemoryPermission>,
+    ) {
+        if self.audit_log_capacity == 0 {
+            return;
+        }
+
+        if let Some(audit_log) = &mut self.audit_log {
+            if audit_log.len() >= self.audit_log_capacity {
+                audit_log.remove(0);
+            }
+
+            audit_log.push(AuditEntry {
+                timestamp: unsafe { mach_absolute_time() },
+                mapping_handle,
+                old_permission,
+                new_permission,
+                operation,
+            });
+        }
+    }
+}
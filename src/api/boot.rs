@@ -0,0 +1,24 @@
+//! Helpers to set up the initial state of well known guest boot protocols.
+
+use super::{hv_ipa_t, Register, Result, VirtualCpu};
+
+impl VirtualCpu {
+    /// Set up the registers for the arm64 Linux boot protocol.
+    ///
+    /// As described in the kernel documentation (``Documentation/arch/arm64/booting.rst``), the kernel is entered at EL1h
+    /// with all exceptions masked, ``x0`` containing the physical address of the device tree blob and ``x1`` to ``x3`` set to zero.
+    ///
+    /// The MMU and data cache must be off, which is the case for a freshly created vCPU.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn setup_linux_boot(&mut self, kernel_entry: u64, dtb_addr: hv_ipa_t) -> Result<()> {
+        self.set_register(Register::X0, dtb_addr)?;
+        self.set_register(Register::X1, 0)?;
+        self.set_register(Register::X2, 0)?;
+        self.set_register(Register::X3, 0)?;
+
+        // EL1h with D, A, I and F masked.
+        self.set_register(Register::CPSR, 0x3c5)?;
+        self.set_register(Register::PC, kernel_entry)
+    }
+}
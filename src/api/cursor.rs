@@ -0,0 +1,111 @@
+//! Standard I/O view over guest memory.
+
+use super::{hv_ipa_t, VirtualMachine};
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Cursor over guest memory implementing [Read], [Write] and [Seek].
+///
+/// Accesses advance across contiguous mappings. Reaching an unmapped guest address is reported as the end of the stream,
+/// making [Read::read_exact] and [Write::write_all] fail at gaps.
+#[derive(Debug)]
+pub struct GuestMemoryCursor<'a> {
+    /// The Virtual Machine owning the guest memory.
+    virtual_machine: &'a mut VirtualMachine,
+
+    /// The current guest address.
+    position: hv_ipa_t,
+}
+
+impl<'a> GuestMemoryCursor<'a> {
+    /// Create a new cursor positioned at a given guest address.
+    pub fn new(virtual_machine: &'a mut VirtualMachine, position: hv_ipa_t) -> Self {
+        GuestMemoryCursor {
+            virtual_machine,
+            position,
+        }
+    }
+
+    /// Gets the current guest address of the cursor.
+    pub fn position(&self) -> hv_ipa_t {
+        self.position
+    }
+
+    /// Gets the number of bytes accessible from the current position without crossing the end of a mapping, up to ``limit``.
+    fn available(&self, limit: usize) -> usize {
+        self.virtual_machine
+            .find_mapping_by_address(self.position)
+            .map_or(0, |mapping| {
+                let offset = (self.position - mapping.address) as usize;
+
+                core::cmp::min(mapping.size - offset, limit)
+            })
+    }
+
+    /// Advance the cursor by a given amount of bytes.
+    fn advance(&mut self, size: usize) {
+        self.position = self.position.wrapping_add(size as u64);
+    }
+}
+
+/// Convert a guest memory access error to an I/O error.
+fn to_io_error(error: super::HypervisorError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, std::format!("{:?}", error))
+}
+
+impl<'a> Read for GuestMemoryCursor<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.available(buf.len());
+
+        self.virtual_machine
+            .read_guest(self.position, &mut buf[..size])
+            .map_err(to_io_error)?;
+
+        self.advance(size);
+
+        Ok(size)
+    }
+}
+
+impl<'a> Write for GuestMemoryCursor<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.available(buf.len());
+
+        self.virtual_machine
+            .write_guest(self.position, &buf[..size])
+            .map_err(to_io_error)?;
+
+        self.advance(size);
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for GuestMemoryCursor<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) if offset >= 0 => self.position.checked_add(offset as u64),
+            SeekFrom::Current(offset) => self.position.checked_sub(offset.unsigned_abs()),
+            // Guest memory has no end.
+            SeekFrom::End(_) => None,
+        };
+
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid guest memory seek")
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+impl VirtualMachine {
+    /// Create a cursor over the guest memory positioned at a given guest address.
+    pub fn guest_memory_cursor(&mut self, guest_address: hv_ipa_t) -> GuestMemoryCursor<'_> {
+        GuestMemoryCursor::new(self, guest_address)
+    }
+}
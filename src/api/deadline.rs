@@ -0,0 +1,112 @@
+//! Deadline on vCPU runs using Grand Central Dispatch timers.
+
+use super::{HypervisorError, Result, VcpuExitHandle, VirtualCpu, VirtualCpuExitReason};
+use crate::ffi::dispatch::*;
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// State shared between a run and its deadline timer.
+struct DeadlineState {
+    /// The handle of the vCPU to exit.
+    exit_handle: VcpuExitHandle,
+
+    /// Set once the run returned, the timer must then not exit the vCPU.
+    is_done: AtomicBool,
+
+    /// Signaled once the timer is cancelled and its handler can't run anymore.
+    cancelled: dispatch_semaphore_t,
+}
+
+/// Event handler of the dispatch timer, forcing the vCPU to exit if the run didn't return yet.
+extern "C" fn deadline_reached(context: *mut c_void) {
+    let state = unsafe { &*(context as *const DeadlineState) };
+
+    if !state.is_done.load(Ordering::Acquire) {
+        let _ = state.exit_handle.exit();
+    }
+}
+
+/// Cancellation handler of the dispatch timer, waking up the run waiting for the cancellation.
+extern "C" fn deadline_cancelled(context: *mut c_void) {
+    let state = unsafe { &*(context as *const DeadlineState) };
+
+    unsafe {
+        dispatch_semaphore_signal(state.cancelled);
+    }
+}
+
+impl VirtualCpu {
+    /// Runs the vCPU until a given deadline, in which case [VirtualCpuExitReason::Cancelled] is returned.
+    ///
+    /// The deadline is a one-shot dispatch timer source on the high priority global dispatch queue, which then forces the vCPU to exit.
+    /// Unlike [VirtualCpu::run_bounded], no thread is spawned: the handler runs on a thread of the dispatch pool.
+    /// The timer is cancelled before returning, waiting for a running handler to complete, so it never outlives the run.
+    ///
+    /// If the guest exits at the same time the deadline is reached, the next run may exit immediately with [VirtualCpuExitReason::Cancelled].
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_until_deadline(&mut self, deadline: Instant) -> Result<VirtualCpuExitReason> {
+        let delta = deadline
+            .saturating_duration_since(Instant::now())
+            .as_nanos();
+        let delta = i64::try_from(delta).unwrap_or(i64::MAX);
+
+        let cancelled = unsafe { dispatch_semaphore_create(0) };
+
+        if cancelled.is_null() {
+            return Err(HypervisorError::Error);
+        }
+
+        let source = unsafe {
+            dispatch_source_create(
+                dispatch_source_type_timer(),
+                0,
+                0,
+                dispatch_get_global_queue(DISPATCH_QUEUE_PRIORITY_HIGH, 0),
+            )
+        };
+
+        if source.is_null() {
+            unsafe {
+                dispatch_release(cancelled);
+            }
+
+            return Err(HypervisorError::Error);
+        }
+
+        let state = DeadlineState {
+            exit_handle: self.exit_handle(),
+            is_done: AtomicBool::new(false),
+            cancelled,
+        };
+
+        // The state outlives the timer as the cancellation is waited for before returning.
+        unsafe {
+            dispatch_set_context(source, &state as *const DeadlineState as *mut c_void);
+            dispatch_source_set_event_handler_f(source, deadline_reached);
+            dispatch_source_set_cancel_handler_f(source, deadline_cancelled);
+            dispatch_source_set_timer(
+                source,
+                dispatch_time(DISPATCH_TIME_NOW, delta),
+                DISPATCH_TIME_FOREVER,
+                0,
+            );
+            dispatch_resume(source);
+        }
+
+        let result = self.run();
+
+        state.is_done.store(true, Ordering::Release);
+
+        unsafe {
+            dispatch_source_cancel(source);
+            dispatch_semaphore_wait(cancelled, DISPATCH_TIME_FOREVER);
+            dispatch_release(source);
+            dispatch_release(cancelled);
+        }
+
+        result
+    }
+}
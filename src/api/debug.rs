@@ -0,0 +1,252 @@
+//! Debugging helpers for vCPUs.
+
+use super::{
+    HypervisorError, Register, Result, SystemRegister, VirtualCpu, VirtualCpuExitReason,
+    VirtualMachine,
+};
+
+/// Breakpoint value registers, by breakpoint index.
+const BREAKPOINT_VALUE_REGISTERS: [SystemRegister; 16] = [
+    SystemRegister::DBGBVR0_EL1,
+    SystemRegister::DBGBVR1_EL1,
+    SystemRegister::DBGBVR2_EL1,
+    SystemRegister::DBGBVR3_EL1,
+    SystemRegister::DBGBVR4_EL1,
+    SystemRegister::DBGBVR5_EL1,
+    SystemRegister::DBGBVR6_EL1,
+    SystemRegister::DBGBVR7_EL1,
+    SystemRegister::DBGBVR8_EL1,
+    SystemRegister::DBGBVR9_EL1,
+    SystemRegister::DBGBVR10_EL1,
+    SystemRegister::DBGBVR11_EL1,
+    SystemRegister::DBGBVR12_EL1,
+    SystemRegister::DBGBVR13_EL1,
+    SystemRegister::DBGBVR14_EL1,
+    SystemRegister::DBGBVR15_EL1,
+];
+
+/// Breakpoint control registers, by breakpoint index.
+const BREAKPOINT_CONTROL_REGISTERS: [SystemRegister; 16] = [
+    SystemRegister::DBGBCR0_EL1,
+    SystemRegister::DBGBCR1_EL1,
+    SystemRegister::DBGBCR2_EL1,
+    SystemRegister::DBGBCR3_EL1,
+    SystemRegister::DBGBCR4_EL1,
+    SystemRegister::DBGBCR5_EL1,
+    SystemRegister::DBGBCR6_EL1,
+    SystemRegister::DBGBCR7_EL1,
+    SystemRegister::DBGBCR8_EL1,
+    SystemRegister::DBGBCR9_EL1,
+    SystemRegister::DBGBCR10_EL1,
+    SystemRegister::DBGBCR11_EL1,
+    SystemRegister::DBGBCR12_EL1,
+    SystemRegister::DBGBCR13_EL1,
+    SystemRegister::DBGBCR14_EL1,
+    SystemRegister::DBGBCR15_EL1,
+];
+
+/// Breakpoint control enabling an address match on any instruction at EL1 and EL0 (E, PMC = 0b11, BAS = 0b1111).
+const BREAKPOINT_CONTROL_ENABLED: u64 = (0xF << 5) | (0x3 << 1) | 1;
+
+/// Software step enable bit of MDSCR_EL1.
+const MDSCR_SS: u64 = 1 << 0;
+
+/// Monitor debug events enable bit of MDSCR_EL1.
+const MDSCR_MDE: u64 = 1 << 15;
+
+/// Software step bit of PSTATE.
+const CPSR_SS: u64 = 1 << 21;
+
+/// Instruction used by [VirtualCpu::run_to_pc] to stop the guest (``BRK #0xF001``).
+const RUN_TO_PC_BRK_INSTRUCTION: u32 = 0xD420_0000 | (0xF001 << 5);
+
+/// Gets the value and control registers of a breakpoint.
+fn breakpoint_registers(index: u8) -> Result<(SystemRegister, SystemRegister)> {
+    let index = index as usize;
+
+    match (
+        BREAKPOINT_VALUE_REGISTERS.get(index),
+        BREAKPOINT_CONTROL_REGISTERS.get(index),
+    ) {
+        (Some(value), Some(control)) => Ok((*value, *control)),
+        _ => Err(HypervisorError::BadArgument),
+    }
+}
+
+/// Debug traps configuration of a vCPU.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DebugConfig {
+    /// Whether debug exceptions exit the vCPU.
+    pub trap_exceptions: bool,
+
+    /// Whether debug-register accesses exit the vCPU.
+    pub trap_reg_accesses: bool,
+}
+
+/// Errors reported by the toggles that failed while applying a debug configuration.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DebugConfigError {
+    /// The error of the debug exceptions trap toggle, if it failed.
+    pub trap_exceptions: Option<HypervisorError>,
+
+    /// The error of the debug-register accesses trap toggle, if it failed.
+    pub trap_reg_accesses: Option<HypervisorError>,
+}
+
+impl VirtualCpu {
+    /// Gets the debug traps configuration.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_debug_config(&mut self) -> Result<DebugConfig> {
+        Ok(DebugConfig {
+            trap_exceptions: self.get_trap_debug_exceptions()?,
+            trap_reg_accesses: self.get_trap_debug_reg_accesses()?,
+        })
+    }
+
+    /// Sets the debug traps configuration.
+    ///
+    /// Both toggles are always applied, the error reports which ones failed.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_debug_config(
+        &mut self,
+        config: DebugConfig,
+    ) -> core::result::Result<(), DebugConfigError> {
+        self.configure_debug(config.trap_exceptions, config.trap_reg_accesses)
+    }
+
+    /// Sets whether debug exceptions and debug-register accesses exit the vCPU.
+    ///
+    /// Both toggles are always applied, the error reports which ones failed.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn configure_debug(
+        &mut self,
+        trap_exceptions: bool,
+        trap_reg_accesses: bool,
+    ) -> core::result::Result<(), DebugConfigError> {
+        let error = DebugConfigError {
+            trap_exceptions: self.set_trap_debug_exceptions(trap_exceptions).err(),
+            trap_reg_accesses: self.set_trap_debug_reg_accesses(trap_reg_accesses).err(),
+        };
+
+        if error.trap_exceptions.is_none() && error.trap_reg_accesses.is_none() {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    /// Sets an hardware breakpoint on the instruction at a given guest virtual address, for EL1 and EL0.
+    ///
+    /// Debug exceptions must be trapped (see [VirtualCpu::set_trap_debug_exceptions]) for the breakpoint to exit the vCPU.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is above 15.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_hardware_breakpoint(&mut self, index: u8, address: u64) -> Result<()> {
+        let (value_register, control_register) = breakpoint_registers(index)?;
+
+        let mdscr = self.get_system_register(SystemRegister::MDSCR_EL1)?;
+        self.set_system_register(SystemRegister::MDSCR_EL1, mdscr | MDSCR_MDE)?;
+
+        self.set_system_register(value_register, address)?;
+        self.set_system_register(control_register, BREAKPOINT_CONTROL_ENABLED)
+    }
+
+    /// Clears an hardware breakpoint.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is above 15.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn clear_hardware_breakpoint(&mut self, index: u8) -> Result<()> {
+        let (_, control_register) = breakpoint_registers(index)?;
+
+        self.set_system_register(control_register, 0)
+    }
+
+    /// Sets whether the guest is single-stepped, exiting after each instruction.
+    ///
+    /// Debug exceptions must be trapped (see [VirtualCpu::set_trap_debug_exceptions]) for the steps to exit the vCPU.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_single_step(&mut self, enabled: bool) -> Result<()> {
+        let mdscr = self.get_system_register(SystemRegister::MDSCR_EL1)?;
+        let cpsr = self.get_register(Register::CPSR)?;
+
+        if enabled {
+            self.set_system_register(SystemRegister::MDSCR_EL1, mdscr | MDSCR_SS | MDSCR_MDE)?;
+            self.set_register(Register::CPSR, cpsr | CPSR_SS)
+        } else {
+            self.set_system_register(SystemRegister::MDSCR_EL1, mdscr & !MDSCR_SS)?;
+            self.set_register(Register::CPSR, cpsr & !CPSR_SS)
+        }
+    }
+
+    /// Resume the guest stopped on an hardware breakpoint, executing the instruction under it.
+    ///
+    /// The breakpoint is disabled, the guest is single-stepped over one instruction and the breakpoint is enabled again.
+    /// The exit of the step is returned, which is a software step exception if the instruction was executed.
+    /// Any other exit (for example a pending interrupt taken before the instruction) means the guest is still on the breakpoint.
+    ///
+    /// Debug exceptions must be trapped (see [VirtualCpu::set_trap_debug_exceptions]).
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is above 15.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn step_over_breakpoint(&mut self, index: u8) -> Result<VirtualCpuExitReason> {
+        let (_, control_register) = breakpoint_registers(index)?;
+
+        let control = self.get_system_register(control_register)?;
+
+        self.set_system_register(control_register, control & !1)?;
+
+        let result = self.set_single_step(true).and_then(|_| self.run());
+
+        // Always restore the state, even if stepping failed.
+        let disable_result = self.set_single_step(false);
+        let restore_result = self.set_system_register(control_register, control);
+
+        let reason = result?;
+
+        disable_result?;
+        restore_result?;
+
+        Ok(reason)
+    }
+
+    /// Runs the vCPU until the guest reaches a given address, using a software breakpoint.
+    ///
+    /// A BRK instruction temporarily replaces the instruction at ``target`` in guest memory and the original instruction is always restored before returning.
+    /// If the guest stopped on ``target``, the returned exit is a [ExceptionClass::Brk64](super::ExceptionClass::Brk64) exception with PC equal to ``target``.
+    /// Any other exit is returned as is and the breakpoint is removed: the call must then be repeated to keep running to ``target``.
+    ///
+    /// ``target`` is used as a guest physical address, the guest must thus have the MMU disabled or identity map it.
+    /// Debug exceptions must be trapped (see [VirtualCpu::set_trap_debug_exceptions]).
+    ///
+    /// As the guest code is modified, the guest reading its own code while running or caching the instructions
+    /// across calls (without invalidating its instruction cache) may observe the breakpoint.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_to_pc(
+        &mut self,
+        virtual_machine: &mut VirtualMachine,
+        target: u64,
+    ) -> Result<VirtualCpuExitReason> {
+        let original = virtual_machine.read_u32(target)?;
+
+        virtual_machine.write_u32(target, RUN_TO_PC_BRK_INSTRUCTION)?;
+
+        let result = self.run();
+
+        // Always restore the original instruction, even if running failed.
+        let restore_result = virtual_machine.write_u32(target, original);
+
+        let reason = result?;
+
+        restore_result?;
+
+        Ok(reason)
+    }
+}
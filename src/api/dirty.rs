@@ -0,0 +1,146 @@
+//! Dirty page tracking based on write protection of the guest memory.
+
+use alloc::vec::Vec;
+
+use super::{
+    convert_hv_return, hv_ipa_t, hv_memory_flags_t, hv_vm_protect, MemoryPermission, Result,
+    VirtualMachine, VirtualMachineMapping, PAGE_SIZE,
+};
+
+/// Gets the permission to apply on a tracked page that isn't dirty.
+fn write_protected(permission: MemoryPermission) -> MemoryPermission {
+    MemoryPermission::new(permission.read, false, permission.execute)
+}
+
+/// Change the memory permission of a range of guest memory without altering the recorded mapping permission.
+fn protect(address: hv_ipa_t, size: usize, permission: MemoryPermission) -> Result<()> {
+    let ret = unsafe { hv_vm_protect(address, size, hv_memory_flags_t::from(permission)) };
+
+    convert_hv_return(ret)
+}
+
+impl VirtualMachine {
+    /// Find the writable mapping containing a given guest address.
+    fn find_writable_mapping_by_address(&self, ipa: hv_ipa_t) -> Option<&VirtualMachineMapping> {
+        self.mapping_list
+            .iter()
+            .find(|mapping| mapping.permission.write && mapping.contains_address(ipa))
+    }
+
+    /// Apply a new permission to a mapped range, keeping it write protected while dirty page tracking is enabled.
+    ///
+    /// The pages of the range that are already dirty stay writable, as their writes are already reported.
+    pub(super) fn protect_mapping_range(
+        &self,
+        address: hv_ipa_t,
+        size: usize,
+        permission: MemoryPermission,
+    ) -> Result<()> {
+        if !self.is_dirty_tracking_enabled || !permission.write {
+            return protect(address, size, permission);
+        }
+
+        protect(address, size, write_protected(permission))?;
+
+        let end = address + size as hv_ipa_t;
+
+        for page in &self.dirty_pages {
+            if (address..end).contains(page) {
+                protect(*page, PAGE_SIZE, permission)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Enable dirty page tracking.
+    ///
+    /// Every writable mapping is write protected, the first write to each page then causes a permission fault data abort that must be forwarded to [VirtualMachine::handle_write_fault].
+    ///
+    /// Pages are tracked with a granularity of [PAGE_SIZE].
+    /// Mappings created after this call are not tracked until it's called again, while reprotected mappings stay tracked.
+    pub fn enable_dirty_tracking(&mut self) -> Result<()> {
+        for mapping in &self.mapping_list {
+            if mapping.permission.write {
+                protect(
+                    mapping.address,
+                    mapping.size,
+                    write_protected(mapping.permission),
+                )?;
+            }
+        }
+
+        self.dirty_pages.clear();
+        self.is_dirty_tracking_enabled = true;
+
+        Ok(())
+    }
+
+    /// Disable dirty page tracking, restoring the permission of every mapping and discarding the dirty pages.
+    pub fn disable_dirty_tracking(&mut self) -> Result<()> {
+        for mapping in &self.mapping_list {
+            if mapping.permission.write {
+                protect(mapping.address, mapping.size, mapping.permission)?;
+            }
+        }
+
+        self.dirty_pages.clear();
+        self.is_dirty_tracking_enabled = false;
+
+        Ok(())
+    }
+
+    /// Handle a write permission fault at a given guest address.
+    ///
+    /// If the fault was caused by dirty page tracking, the page is marked dirty, write access is restored and ``true`` is returned.
+    /// The faulting instruction should then be executed again.
+    ///
+    /// Returns ``false`` if the fault isn't related to dirty page tracking.
+    pub fn handle_write_fault(&mut self, ipa: hv_ipa_t) -> Result<bool> {
+        if !self.is_dirty_tracking_enabled {
+            return Ok(false);
+        }
+
+        let page = ipa - ipa % PAGE_SIZE as hv_ipa_t;
+
+        // A write fault on a page that is already dirty isn't ours.
+        if self.dirty_pages.contains(&page) {
+            return Ok(false);
+        }
+
+        let permission = match self.find_writable_mapping_by_address(ipa) {
+            Some(mapping) => mapping.permission,
+            None => return Ok(false),
+        };
+
+        protect(page, PAGE_SIZE, permission)?;
+
+        self.dirty_pages.push(page);
+
+        Ok(true)
+    }
+
+    /// Take the guest addresses of all the pages written since dirty page tracking was enabled or since the last call.
+    ///
+    /// The returned pages are sorted and write protected again.
+    pub fn take_dirty_pages(&mut self) -> Vec<hv_ipa_t> {
+        let mut pages = core::mem::take(&mut self.dirty_pages);
+
+        pages.sort_unstable();
+
+        for page in &pages {
+            // Pages whose mapping got unmapped in the meantime don't need to be protected.
+            let permission = match self.find_writable_mapping_by_address(*page) {
+                Some(mapping) => write_protected(mapping.permission),
+                None => continue,
+            };
+
+            // Keep the page dirty if it cannot be protected again as writes to it won't be reported.
+            if protect(*page, PAGE_SIZE, permission).is_err() {
+                self.dirty_pages.push(*page);
+            }
+        }
+
+        pages
+    }
+}
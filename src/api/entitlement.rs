@@ -0,0 +1,54 @@
+//! Detection of the hypervisor entitlement of the running process.
+//!
+//! This links the CoreFoundation and Security frameworks, which the rest of the crate doesn't need.
+//! It's thus only available with the ``std`` feature, ``no_std`` users have to check the entitlement by other means.
+
+use crate::ffi::security::*;
+
+use core::ffi::c_char;
+
+/// The hypervisor entitlement, null terminated.
+const HYPERVISOR_ENTITLEMENT: &[u8] = b"com.apple.security.hypervisor\0";
+
+/// Check if the running process is signed with the ``com.apple.security.hypervisor`` entitlement.
+///
+/// Without it, creating a Virtual Machine fails with [HypervisorError::Denied](crate::HypervisorError::Denied).
+///
+/// The entitlements are read from the code signature of the process with the Security framework,
+/// ``false`` is returned if they cannot be queried (for example with an unsigned binary).
+pub fn has_hypervisor_entitlement() -> bool {
+    unsafe {
+        let task = SecTaskCreateFromSelf(core::ptr::null());
+
+        if task.is_null() {
+            return false;
+        }
+
+        let entitlement = CFStringCreateWithCString(
+            core::ptr::null(),
+            HYPERVISOR_ENTITLEMENT.as_ptr() as *const c_char,
+            kCFStringEncodingUTF8,
+        );
+
+        if entitlement.is_null() {
+            CFRelease(task);
+
+            return false;
+        }
+
+        let value = SecTaskCopyValueForEntitlement(task, entitlement, core::ptr::null_mut());
+
+        let result = !value.is_null()
+            && CFGetTypeID(value) == CFBooleanGetTypeID()
+            && CFBooleanGetValue(value);
+
+        if !value.is_null() {
+            CFRelease(value);
+        }
+
+        CFRelease(entitlement);
+        CFRelease(task);
+
+        result
+    }
+}
@@ -0,0 +1,598 @@
+//! Decoding of guest exception syndromes (ESR_EL2).
+
+use crate::ffi::types::*;
+
+use super::{HypervisorError, Register, Result, SystemRegister, VirtualCpu};
+
+/// Exception class of a guest exception (ESR_EL2.EC).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExceptionClass {
+    /// Unknown reason.
+    Unknown,
+
+    /// Trapped WFI or WFE instruction.
+    WfiWfe,
+
+    /// Access to SIMD or floating-point registers trapped.
+    SimdFpAccess,
+
+    /// Illegal Execution state.
+    IllegalExecutionState,
+
+    /// SVC instruction execution in AArch64 state.
+    Svc64,
+
+    /// HVC instruction execution in AArch64 state.
+    Hvc64,
+
+    /// SMC instruction execution in AArch64 state.
+    Smc64,
+
+    /// Trapped MSR, MRS or System instruction execution in AArch64 state.
+    SystemRegister,
+
+    /// Instruction Abort from a lower Exception level.
+    InstructionAbortLowerEl,
+
+    /// Instruction Abort taken without a change in Exception level.
+    InstructionAbortSameEl,
+
+    /// PC alignment fault exception.
+    PcAlignment,
+
+    /// Data Abort from a lower Exception level.
+    DataAbortLowerEl,
+
+    /// Data Abort taken without a change in Exception level.
+    DataAbortSameEl,
+
+    /// SP alignment fault exception.
+    SpAlignment,
+
+    /// Trapped floating-point exception taken from AArch64 state.
+    FpException64,
+
+    /// SError interrupt.
+    SError,
+
+    /// Breakpoint exception from a lower Exception level.
+    BreakpointLowerEl,
+
+    /// Breakpoint exception taken without a change in Exception level.
+    BreakpointSameEl,
+
+    /// Software Step exception from a lower Exception level.
+    SoftwareStepLowerEl,
+
+    /// Software Step exception taken without a change in Exception level.
+    SoftwareStepSameEl,
+
+    /// Watchpoint exception from a lower Exception level.
+    WatchpointLowerEl,
+
+    /// Watchpoint exception taken without a change in Exception level.
+    WatchpointSameEl,
+
+    /// BRK instruction execution in AArch64 state.
+    Brk64,
+
+    /// Any other exception class.
+    Other(u8),
+}
+
+impl ExceptionClass {
+    /// Gets the exception class of a syndrome.
+    pub fn from_syndrome(syndrome: hv_exception_syndrome_t) -> Self {
+        ExceptionClass::from(((syndrome >> 26) & 0x3f) as u8)
+    }
+}
+
+impl From<u8> for ExceptionClass {
+    fn from(value: u8) -> ExceptionClass {
+        match value {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::WfiWfe,
+            0x07 => ExceptionClass::SimdFpAccess,
+            0x0e => ExceptionClass::IllegalExecutionState,
+            0x15 => ExceptionClass::Svc64,
+            0x16 => ExceptionClass::Hvc64,
+            0x17 => ExceptionClass::Smc64,
+            0x18 => ExceptionClass::SystemRegister,
+            0x20 => ExceptionClass::InstructionAbortLowerEl,
+            0x21 => ExceptionClass::InstructionAbortSameEl,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLowerEl,
+            0x25 => ExceptionClass::DataAbortSameEl,
+            0x26 => ExceptionClass::SpAlignment,
+            0x2c => ExceptionClass::FpException64,
+            0x2f => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLowerEl,
+            0x31 => ExceptionClass::BreakpointSameEl,
+            0x32 => ExceptionClass::SoftwareStepLowerEl,
+            0x33 => ExceptionClass::SoftwareStepSameEl,
+            0x34 => ExceptionClass::WatchpointLowerEl,
+            0x35 => ExceptionClass::WatchpointSameEl,
+            0x3c => ExceptionClass::Brk64,
+            value => ExceptionClass::Other(value),
+        }
+    }
+}
+
+/// Fault status of an abort (DFSC or IFSC).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultStatus {
+    /// Address size fault at the given translation level.
+    AddressSize {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Translation fault at the given translation level.
+    Translation {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Access flag fault at the given translation level.
+    AccessFlag {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Permission fault at the given translation level.
+    Permission {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Synchronous External abort, not on translation table walk.
+    SyncExternal,
+
+    /// Synchronous Tag Check fault.
+    SyncTagCheck,
+
+    /// Synchronous External abort on translation table walk at the given translation level.
+    SyncExternalOnWalk {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Synchronous parity or ECC error on memory access, not on translation table walk.
+    SyncParity,
+
+    /// Synchronous parity or ECC error on translation table walk at the given translation level.
+    SyncParityOnWalk {
+        /// The translation level.
+        level: u8,
+    },
+
+    /// Alignment fault.
+    Alignment,
+
+    /// TLB conflict abort.
+    TlbConflict,
+
+    /// Unsupported atomic hardware update fault.
+    UnsupportedAtomicUpdate,
+
+    /// Any other fault status code.
+    Other(u8),
+}
+
+impl From<u8> for FaultStatus {
+    fn from(value: u8) -> FaultStatus {
+        let level = value & 0x3;
+
+        match value & 0x3f {
+            0x00..=0x03 => FaultStatus::AddressSize { level },
+            0x04..=0x07 => FaultStatus::Translation { level },
+            0x08..=0x0b => FaultStatus::AccessFlag { level },
+            0x0c..=0x0f => FaultStatus::Permission { level },
+            0x10 => FaultStatus::SyncExternal,
+            0x11 => FaultStatus::SyncTagCheck,
+            0x14..=0x17 => FaultStatus::SyncExternalOnWalk { level },
+            0x18 => FaultStatus::SyncParity,
+            0x1c..=0x1f => FaultStatus::SyncParityOnWalk { level },
+            0x21 => FaultStatus::Alignment,
+            0x30 => FaultStatus::TlbConflict,
+            0x31 => FaultStatus::UnsupportedAtomicUpdate,
+            value => FaultStatus::Other(value),
+        }
+    }
+}
+
+/// Decoded informations of a data abort syndrome.
+///
+/// Aborts reported to the host are stage-2 faults (the guest address isn't mapped or lacks the permission in the Virtual Machine).
+/// When [DataAbortInfo::stage1_walk] is set, the stage-2 fault happened while the guest MMU was walking its own (stage-1) translation tables.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DataAbortInfo {
+    /// Whether the instruction syndrome fields (access size, sign extension, register...) are valid (ISV).
+    pub is_valid: bool,
+
+    /// The size of the access in bytes (SAS).
+    pub access_size: usize,
+
+    /// Whether the loaded value must be sign-extended (SSE).
+    pub sign_extend: bool,
+
+    /// The register transferred by the access (SRT), 31 stands for XZR.
+    pub register: u8,
+
+    /// Whether the transferred register is a 64-bit register (SF).
+    pub is_64bit_register: bool,
+
+    /// Whether the access has acquire/release semantics (AR).
+    pub acquire_release: bool,
+
+    /// Whether the abort is an external abort (EA).
+    pub is_external: bool,
+
+    /// Whether the abort comes from a cache maintenance or address translation instruction (CM).
+    pub is_cache_maintenance: bool,
+
+    /// Whether the fault happened on a stage-2 translation of a stage-1 translation table walk (S1PTW).
+    pub stage1_walk: bool,
+
+    /// Whether the access was a write (WnR).
+    pub is_write: bool,
+
+    /// The raw data fault status code (DFSC).
+    pub fault_status_code: u8,
+}
+
+impl DataAbortInfo {
+    /// Decode a data abort syndrome, returns None if the syndrome isn't a data abort.
+    pub fn from_syndrome(syndrome: hv_exception_syndrome_t) -> Option<Self> {
+        match ExceptionClass::from_syndrome(syndrome) {
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl => {
+                Some(DataAbortInfo {
+                    is_valid: syndrome & (1 << 24) != 0,
+                    access_size: 1 << ((syndrome >> 22) & 0x3),
+                    sign_extend: syndrome & (1 << 21) != 0,
+                    register: ((syndrome >> 16) & 0x1f) as u8,
+                    is_64bit_register: syndrome & (1 << 15) != 0,
+                    acquire_release: syndrome & (1 << 14) != 0,
+                    is_external: syndrome & (1 << 9) != 0,
+                    is_cache_maintenance: syndrome & (1 << 8) != 0,
+                    stage1_walk: syndrome & (1 << 7) != 0,
+                    is_write: syndrome & (1 << 6) != 0,
+                    fault_status_code: (syndrome & 0x3f) as u8,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets the decoded fault status.
+    pub fn fault_status(&self) -> FaultStatus {
+        FaultStatus::from(self.fault_status_code)
+    }
+}
+
+/// Decoded informations of a trapped MSR or MRS instruction syndrome.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SysRegTrapInfo {
+    /// The op0 field of the system register encoding.
+    pub op0: u8,
+
+    /// The op1 field of the system register encoding.
+    pub op1: u8,
+
+    /// The CRn field of the system register encoding.
+    pub crn: u8,
+
+    /// The CRm field of the system register encoding.
+    pub crm: u8,
+
+    /// The op2 field of the system register encoding.
+    pub op2: u8,
+
+    /// The general purpose register transferred by the instruction (Rt), 31 stands for XZR.
+    pub register: u8,
+
+    /// Whether the instruction is a read (MRS) rather than a write (MSR).
+    pub is_read: bool,
+}
+
+impl SysRegTrapInfo {
+    /// Decode a trapped MSR or MRS syndrome, returns None if the syndrome isn't a system register trap.
+    pub fn from_syndrome(syndrome: hv_exception_syndrome_t) -> Option<Self> {
+        match ExceptionClass::from_syndrome(syndrome) {
+            ExceptionClass::SystemRegister => Some(SysRegTrapInfo {
+                op0: ((syndrome >> 20) & 0x3) as u8,
+                op2: ((syndrome >> 17) & 0x7) as u8,
+                op1: ((syndrome >> 14) & 0x7) as u8,
+                crn: ((syndrome >> 10) & 0xf) as u8,
+                register: ((syndrome >> 5) & 0x1f) as u8,
+                crm: ((syndrome >> 1) & 0xf) as u8,
+                is_read: syndrome & 1 != 0,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Gets the encoding of the system register (``op0 << 14 | op1 << 11 | CRn << 7 | CRm << 3 | op2``).
+    pub fn encoding(&self) -> hv_sys_reg_t {
+        (hv_sys_reg_t::from(self.op0) << 14)
+            | (hv_sys_reg_t::from(self.op1) << 11)
+            | (hv_sys_reg_t::from(self.crn) << 7)
+            | (hv_sys_reg_t::from(self.crm) << 3)
+            | hv_sys_reg_t::from(self.op2)
+    }
+
+    /// Gets the accessed system register, returns None if it isn't supported by the Hypervisor.
+    pub fn system_register(&self) -> Option<SystemRegister> {
+        SystemRegister::from_encoding(self.encoding())
+    }
+}
+
+impl hv_vcpu_exit_exception_t {
+    /// Gets the exception class of the exception.
+    pub fn exception_class(&self) -> ExceptionClass {
+        ExceptionClass::from_syndrome(self.syndrome)
+    }
+
+    /// Decode the exception as a data abort, returns None if the exception isn't a data abort.
+    pub fn data_abort_info(&self) -> Option<DataAbortInfo> {
+        DataAbortInfo::from_syndrome(self.syndrome)
+    }
+
+    /// Decode the exception as a trapped MSR or MRS instruction, returns None if the exception isn't a system register trap.
+    pub fn decode_sysreg_trap(&self) -> Option<SysRegTrapInfo> {
+        SysRegTrapInfo::from_syndrome(self.syndrome)
+    }
+}
+
+/// Default syndrome of an injected SError (EC 0x2F, IL set, no implementation defined syndrome).
+pub const DEFAULT_SERROR_SYNDROME: u64 = (0x2F << 26) | (1 << 25);
+
+impl VirtualCpu {
+    /// Sets whether an SError (asynchronous abort) is pending for the guest, with an optional syndrome.
+    ///
+    /// The Hypervisor doesn't allow injecting SErrors, the exception entry is thus emulated on the next [VirtualCpu::run]:
+    /// ESR_EL1, ELR_EL1 and SPSR_EL1 are set, the guest is moved to EL1h with all exceptions masked and PC is set to the SError vector of VBAR_EL1.
+    ///
+    /// The SError stays pending as long as the guest masks asynchronous aborts (PSTATE.A), which is only checked before entering the guest.
+    /// When no syndrome is given, [DEFAULT_SERROR_SYNDROME] is used.
+    ///
+    /// If the SError vector overflows the address space, the next run fails with [HypervisorError::IllegalGuestState] and the SError stays pending.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_pending_serror(&mut self, value: bool, esr: Option<u64>) -> Result<()> {
+        self.pending_serror = if value {
+            Some(esr.unwrap_or(DEFAULT_SERROR_SYNDROME))
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    /// Gets whether an SError is pending delivery to the guest.
+    pub fn get_pending_serror(&self) -> bool {
+        self.pending_serror.is_some()
+    }
+
+    /// Deliver the pending SError to the guest if it doesn't mask asynchronous aborts.
+    pub(super) fn deliver_pending_serror(&mut self) -> Result<()> {
+        let esr = match self.pending_serror {
+            Some(esr) => esr,
+            None => return Ok(()),
+        };
+
+        let cpsr = self.get_register(Register::CPSR)?;
+
+        // PSTATE.A set, keep it pending.
+        if cpsr & (1 << 8) != 0 {
+            return Ok(());
+        }
+
+        // Select the vector group from the mode the exception is taken from.
+        let vector_offset = match cpsr & 0x1f {
+            // EL1t
+            0b00100 => 0x000,
+            // EL1h
+            0b00101 => 0x200,
+            // AArch32
+            mode if mode & 0x10 != 0 => 0x600,
+            // EL0 (AArch64)
+            _ => 0x400,
+        };
+
+        let pc = self.get_register(Register::PC)?;
+        let vbar = self.vbar_el1()?;
+
+        // A vector table at the very end of the address space cannot be entered.
+        let vector = vbar
+            .checked_add(vector_offset + 0x180)
+            .ok_or(HypervisorError::IllegalGuestState)?;
+
+        self.set_system_register(SystemRegister::ESR_EL1, esr)?;
+        self.set_elr_el1(pc)?;
+        self.set_spsr_el1(cpsr)?;
+
+        // EL1h with D, A, I and F masked.
+        self.set_register(Register::CPSR, 0x3c5)?;
+        self.set_register(Register::PC, vector)?;
+
+        self.pending_serror = None;
+
+        Ok(())
+    }
+
+    /// Gets the ESR_EL1 register, the syndrome of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_esr_el1(&mut self) -> Result<u64> {
+        self.get_system_register(SystemRegister::ESR_EL1)
+    }
+
+    /// Gets the ELR_EL1 register, the return address of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn elr_el1(&mut self) -> Result<u64> {
+        self.get_system_register(SystemRegister::ELR_EL1)
+    }
+
+    /// Sets the ELR_EL1 register, the return address of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_elr_el1(&mut self, value: u64) -> Result<()> {
+        self.set_system_register(SystemRegister::ELR_EL1, value)
+    }
+
+    /// Gets the SPSR_EL1 register, the saved process state of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn spsr_el1(&mut self) -> Result<u64> {
+        self.get_system_register(SystemRegister::SPSR_EL1)
+    }
+
+    /// Sets the SPSR_EL1 register, the saved process state of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_spsr_el1(&mut self, value: u64) -> Result<()> {
+        self.set_system_register(SystemRegister::SPSR_EL1, value)
+    }
+
+    /// Gets the VBAR_EL1 register, the base address of the EL1 exception vectors of the guest.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn vbar_el1(&mut self) -> Result<u64> {
+        self.get_system_register(SystemRegister::VBAR_EL1)
+    }
+
+    /// Sets the VBAR_EL1 register, the base address of the EL1 exception vectors of the guest.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_vbar_el1(&mut self, value: u64) -> Result<()> {
+        self.set_system_register(SystemRegister::VBAR_EL1, value)
+    }
+
+    /// Gets the exception class of the last exception taken by the guest at EL1.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_esr_el1_exception_class(&mut self) -> Result<ExceptionClass> {
+        self.get_esr_el1().map(ExceptionClass::from_syndrome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the syndrome decoding.
+
+    use super::*;
+
+    /// Syndrome of ``str w1, [x2]`` faulting on a missing stage-2 level 3 translation.
+    const STR_W1_TRANSLATION_FAULT: hv_exception_syndrome_t = 0x9381_0047;
+
+    /// Syndrome of ``hvc #0``.
+    const HVC_0: hv_exception_syndrome_t = 0x5a00_0000;
+
+    /// The exception class is decoded from the top bits of the syndrome.
+    #[test]
+    fn exception_class_from_syndrome() {
+        assert_eq!(
+            ExceptionClass::from_syndrome(STR_W1_TRANSLATION_FAULT),
+            ExceptionClass::DataAbortLowerEl
+        );
+        assert_eq!(ExceptionClass::from_syndrome(HVC_0), ExceptionClass::Hvc64);
+        assert_eq!(ExceptionClass::from(0x3f), ExceptionClass::Other(0x3f));
+    }
+
+    /// Fault status codes carry their translation level.
+    #[test]
+    fn fault_status_from_code() {
+        assert_eq!(
+            FaultStatus::from(0x07),
+            FaultStatus::Translation { level: 3 }
+        );
+        assert_eq!(
+            FaultStatus::from(0x0d),
+            FaultStatus::Permission { level: 1 }
+        );
+        assert_eq!(
+            FaultStatus::from(0x16),
+            FaultStatus::SyncExternalOnWalk { level: 2 }
+        );
+        assert_eq!(FaultStatus::from(0x21), FaultStatus::Alignment);
+        assert_eq!(FaultStatus::from(0x22), FaultStatus::Other(0x22));
+    }
+
+    /// A data abort syndrome decodes to the access that faulted.
+    #[test]
+    fn data_abort_info_from_syndrome() {
+        let info = DataAbortInfo::from_syndrome(STR_W1_TRANSLATION_FAULT).unwrap();
+
+        assert!(info.is_valid);
+        assert_eq!(info.access_size, 4);
+        assert!(!info.sign_extend);
+        assert_eq!(info.register, 1);
+        assert!(!info.is_64bit_register);
+        assert!(!info.stage1_walk);
+        assert!(info.is_write);
+        assert_eq!(info.fault_status(), FaultStatus::Translation { level: 3 });
+    }
+
+    /// A sign-extending 64-bit load decodes its size and destination register.
+    #[test]
+    fn data_abort_info_sign_extended_load() {
+        // ldrsh x3, [x4] with a permission fault at level 2.
+        let syndrome = (0x24 << 26)
+            | (1 << 25)
+            | (1 << 24)
+            | (1 << 22)
+            | (1 << 21)
+            | (3 << 16)
+            | (1 << 15)
+            | 0x0e;
+
+        let info = DataAbortInfo::from_syndrome(syndrome).unwrap();
+
+        assert_eq!(info.access_size, 2);
+        assert!(info.sign_extend);
+        assert_eq!(info.register, 3);
+        assert!(info.is_64bit_register);
+        assert!(!info.is_write);
+        assert_eq!(info.fault_status(), FaultStatus::Permission { level: 2 });
+    }
+
+    /// Other exception classes aren't decoded as data aborts.
+    #[test]
+    fn data_abort_info_rejects_other_classes() {
+        assert_eq!(DataAbortInfo::from_syndrome(HVC_0), None);
+    }
+
+    /// A trapped MSR decodes to the written system register.
+    #[test]
+    fn sysreg_trap_info_from_syndrome() {
+        // msr sctlr_el1, x5
+        let info = SysRegTrapInfo::from_syndrome(0x6230_04a0).unwrap();
+
+        assert_eq!(
+            (info.op0, info.op1, info.crn, info.crm, info.op2),
+            (3, 0, 1, 0, 0)
+        );
+        assert_eq!(info.register, 5);
+        assert!(!info.is_read);
+        assert_eq!(info.encoding(), HV_SYS_REG_SCTLR_EL1);
+        assert!(matches!(
+            info.system_register(),
+            Some(SystemRegister::SCTLR_EL1)
+        ));
+    }
+
+    /// A trapped MRS of a register unknown to the Hypervisor keeps its encoding.
+    #[test]
+    fn sysreg_trap_info_unsupported_register() {
+        // mrs x0, cntvct_el0
+        let info = SysRegTrapInfo::from_syndrome(0x6234_f801).unwrap();
+
+        assert!(info.is_read);
+        assert_eq!(info.register, 0);
+        assert_eq!(info.encoding(), 0xdf02);
+        assert!(info.system_register().is_none());
+        assert_eq!(SysRegTrapInfo::from_syndrome(HVC_0), None);
+    }
+}
@@ -0,0 +1,372 @@
+//! Decoding of the floating-point control and status registers and access to the SIMD & FP register file.
+
+use super::{convert_hv_return, HypervisorError, Register, Result, VirtualCpu};
+use crate::ffi::types::{hv_simd_fp_reg_t, SimdFpRegisterBuffer};
+use crate::ffi::{hv_vcpu_get_simd_fp_reg, hv_vcpu_set_simd_fp_reg};
+
+/// Number of SIMD & FP registers (Q0-Q31).
+pub const SIMD_FP_REGISTER_COUNT: usize = 32;
+
+/// Rounding mode of floating-point operations (FPCR.RMode).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RoundingMode {
+    /// Round to Nearest (RN).
+    ToNearest,
+
+    /// Round towards Plus Infinity (RP).
+    TowardsPlusInfinity,
+
+    /// Round towards Minus Infinity (RM).
+    TowardsMinusInfinity,
+
+    /// Round towards Zero (RZ).
+    TowardsZero,
+}
+
+/// Decoded floating-point control register (FPCR).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fpcr {
+    /// Alternative half-precision control (AHP).
+    pub alternative_half_precision: bool,
+
+    /// Default NaN mode control (DN).
+    pub default_nan: bool,
+
+    /// Flush-to-zero mode control (FZ).
+    pub flush_to_zero: bool,
+
+    /// Rounding mode control (RMode).
+    pub rounding_mode: RoundingMode,
+
+    /// Flush-to-zero mode control for half-precision (FZ16).
+    pub flush_to_zero_half_precision: bool,
+
+    /// Input Denormal floating-point exception trap enable (IDE).
+    pub input_denormal_trap: bool,
+
+    /// Inexact floating-point exception trap enable (IXE).
+    pub inexact_trap: bool,
+
+    /// Underflow floating-point exception trap enable (UFE).
+    pub underflow_trap: bool,
+
+    /// Overflow floating-point exception trap enable (OFE).
+    pub overflow_trap: bool,
+
+    /// Divide by Zero floating-point exception trap enable (DZE).
+    pub divide_by_zero_trap: bool,
+
+    /// Invalid Operation floating-point exception trap enable (IOE).
+    pub invalid_operation_trap: bool,
+}
+
+impl Fpcr {
+    /// Mask of all the bits decoded by [Fpcr].
+    const MASK: u64 = (1 << 26)
+        | (1 << 25)
+        | (1 << 24)
+        | (0x3 << 22)
+        | (1 << 19)
+        | (1 << 15)
+        | (1 << 12)
+        | (1 << 11)
+        | (1 << 10)
+        | (1 << 9)
+        | (1 << 8);
+}
+
+impl From<u64> for Fpcr {
+    fn from(value: u64) -> Fpcr {
+        Fpcr {
+            alternative_half_precision: value & (1 << 26) != 0,
+            default_nan: value & (1 << 25) != 0,
+            flush_to_zero: value & (1 << 24) != 0,
+            rounding_mode: match (value >> 22) & 0x3 {
+                0 => RoundingMode::ToNearest,
+                1 => RoundingMode::TowardsPlusInfinity,
+                2 => RoundingMode::TowardsMinusInfinity,
+                _ => RoundingMode::TowardsZero,
+            },
+            flush_to_zero_half_precision: value & (1 << 19) != 0,
+            input_denormal_trap: value & (1 << 15) != 0,
+            inexact_trap: value & (1 << 12) != 0,
+            underflow_trap: value & (1 << 11) != 0,
+            overflow_trap: value & (1 << 10) != 0,
+            divide_by_zero_trap: value & (1 << 9) != 0,
+            invalid_operation_trap: value & (1 << 8) != 0,
+        }
+    }
+}
+
+impl From<Fpcr> for u64 {
+    fn from(value: Fpcr) -> u64 {
+        let rounding_mode = match value.rounding_mode {
+            RoundingMode::ToNearest => 0,
+            RoundingMode::TowardsPlusInfinity => 1,
+            RoundingMode::TowardsMinusInfinity => 2,
+            RoundingMode::TowardsZero => 3,
+        };
+
+        (u64::from(value.alternative_half_precision) << 26)
+            | (u64::from(value.default_nan) << 25)
+            | (u64::from(value.flush_to_zero) << 24)
+            | (rounding_mode << 22)
+            | (u64::from(value.flush_to_zero_half_precision) << 19)
+            | (u64::from(value.input_denormal_trap) << 15)
+            | (u64::from(value.inexact_trap) << 12)
+            | (u64::from(value.underflow_trap) << 11)
+            | (u64::from(value.overflow_trap) << 10)
+            | (u64::from(value.divide_by_zero_trap) << 9)
+            | (u64::from(value.invalid_operation_trap) << 8)
+    }
+}
+
+/// Decoded floating-point status register (FPSR).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fpsr {
+    /// Cumulative saturation bit (QC).
+    pub saturation: bool,
+
+    /// Input Denormal cumulative floating-point exception bit (IDC).
+    pub input_denormal: bool,
+
+    /// Inexact cumulative floating-point exception bit (IXC).
+    pub inexact: bool,
+
+    /// Underflow cumulative floating-point exception bit (UFC).
+    pub underflow: bool,
+
+    /// Overflow cumulative floating-point exception bit (OFC).
+    pub overflow: bool,
+
+    /// Divide by Zero cumulative floating-point exception bit (DZC).
+    pub divide_by_zero: bool,
+
+    /// Invalid Operation cumulative floating-point exception bit (IOC).
+    pub invalid_operation: bool,
+}
+
+impl Fpsr {
+    /// Mask of all the bits decoded by [Fpsr].
+    const MASK: u64 = (1 << 27) | (1 << 7) | (1 << 4) | (1 << 3) | (1 << 2) | (1 << 1) | 1;
+}
+
+impl From<u64> for Fpsr {
+    fn from(value: u64) -> Fpsr {
+        Fpsr {
+            saturation: value & (1 << 27) != 0,
+            input_denormal: value & (1 << 7) != 0,
+            inexact: value & (1 << 4) != 0,
+            underflow: value & (1 << 3) != 0,
+            overflow: value & (1 << 2) != 0,
+            divide_by_zero: value & (1 << 1) != 0,
+            invalid_operation: value & 1 != 0,
+        }
+    }
+}
+
+impl From<Fpsr> for u64 {
+    fn from(value: Fpsr) -> u64 {
+        (u64::from(value.saturation) << 27)
+            | (u64::from(value.input_denormal) << 7)
+            | (u64::from(value.inexact) << 4)
+            | (u64::from(value.underflow) << 3)
+            | (u64::from(value.overflow) << 2)
+            | (u64::from(value.divide_by_zero) << 1)
+            | u64::from(value.invalid_operation)
+    }
+}
+
+/// Floating-point and NEON state of a vCPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SimdFpState {
+    /// Q0-Q31 registers.
+    pub registers: [u128; SIMD_FP_REGISTER_COUNT],
+
+    /// FPCR register.
+    pub fpcr: u64,
+
+    /// FPSR register.
+    pub fpsr: u64,
+}
+
+impl VirtualCpu {
+    /// Gets the value of a SIMD & FP register (Q0-Q31).
+    ///
+    /// The register bytes are interpreted in little endian, the least significant byte being lane 0.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is above 31.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_simd_register(&mut self, index: u8) -> Result<u128> {
+        if usize::from(index) >= SIMD_FP_REGISTER_COUNT {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let mut value = SimdFpRegisterBuffer::default();
+
+        let ret = unsafe {
+            hv_vcpu_get_simd_fp_reg(self.handle, hv_simd_fp_reg_t::from(index), &mut value)
+        };
+
+        convert_hv_return(ret).map(|_| u128::from_le_bytes(value.0))
+    }
+
+    /// Sets the value of a SIMD & FP register (Q0-Q31).
+    ///
+    /// The register bytes are interpreted in little endian, the least significant byte being lane 0.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is above 31.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_simd_register(&mut self, index: u8, value: u128) -> Result<()> {
+        if usize::from(index) >= SIMD_FP_REGISTER_COUNT {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let value = SimdFpRegisterBuffer(value.to_le_bytes());
+
+        let ret =
+            unsafe { hv_vcpu_set_simd_fp_reg(self.handle, hv_simd_fp_reg_t::from(index), &value) };
+
+        convert_hv_return(ret)
+    }
+
+    /// Gets the values of all the SIMD & FP registers (Q0-Q31).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_all_simd_registers(&mut self) -> Result<[u128; SIMD_FP_REGISTER_COUNT]> {
+        let mut result = [0; SIMD_FP_REGISTER_COUNT];
+
+        for (index, value) in result.iter_mut().enumerate() {
+            *value = self.get_simd_register(index as u8)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Sets the values of all the SIMD & FP registers (Q0-Q31).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_all_simd_registers(
+        &mut self,
+        values: &[u128; SIMD_FP_REGISTER_COUNT],
+    ) -> Result<()> {
+        for (index, value) in values.iter().enumerate() {
+            self.set_simd_register(index as u8, *value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the whole floating-point and NEON state (Q0-Q31, FPCR and FPSR).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_simd_fp_state(&mut self) -> Result<SimdFpState> {
+        Ok(SimdFpState {
+            registers: self.get_all_simd_registers()?,
+            fpcr: self.get_register(Register::FPCR)?,
+            fpsr: self.get_register(Register::FPSR)?,
+        })
+    }
+
+    /// Sets the whole floating-point and NEON state (Q0-Q31, FPCR and FPSR).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_simd_fp_state(&mut self, state: &SimdFpState) -> Result<()> {
+        self.set_all_simd_registers(&state.registers)?;
+        self.set_register(Register::FPCR, state.fpcr)?;
+        self.set_register(Register::FPSR, state.fpsr)
+    }
+
+    /// Gets the decoded FPCR register.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn fpcr_decoded(&mut self) -> Result<Fpcr> {
+        self.get_register(Register::FPCR).map(Fpcr::from)
+    }
+
+    /// Sets the FPCR register from its decoded form, bits not covered by [Fpcr] are preserved.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_fpcr_decoded(&mut self, value: Fpcr) -> Result<()> {
+        let current = self.get_register(Register::FPCR)?;
+
+        self.set_register(Register::FPCR, (current & !Fpcr::MASK) | u64::from(value))
+    }
+
+    /// Gets the decoded FPSR register.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn fpsr_decoded(&mut self) -> Result<Fpsr> {
+        self.get_register(Register::FPSR).map(Fpsr::from)
+    }
+
+    /// Sets the FPSR register from its decoded form, bits not covered by [Fpsr] are preserved.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_fpsr_decoded(&mut self, value: Fpsr) -> Result<()> {
+        let current = self.get_register(Register::FPSR)?;
+
+        self.set_register(Register::FPSR, (current & !Fpsr::MASK) | u64::from(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the FPCR and FPSR decoding.
+
+    use super::*;
+
+    /// FPCR decodes the rounding mode and control bits.
+    #[test]
+    fn fpcr_decode() {
+        // DN, FZ, RMode = RZ, IOE
+        let fpcr = Fpcr::from((1 << 25) | (1 << 24) | (0x3 << 22) | (1 << 8));
+
+        assert!(fpcr.default_nan);
+        assert!(fpcr.flush_to_zero);
+        assert!(!fpcr.alternative_half_precision);
+        assert_eq!(fpcr.rounding_mode, RoundingMode::TowardsZero);
+        assert!(fpcr.invalid_operation_trap);
+        assert!(!fpcr.inexact_trap);
+
+        assert_eq!(
+            Fpcr::from(0x1 << 22).rounding_mode,
+            RoundingMode::TowardsPlusInfinity
+        );
+    }
+
+    /// FPCR encoding is the inverse of the decoding for the decoded bits, other bits are dropped.
+    #[test]
+    fn fpcr_round_trip() {
+        for value in [0, Fpcr::MASK, (0x2 << 22) | (1 << 19) | (1 << 10)] {
+            assert_eq!(u64::from(Fpcr::from(value)), value);
+        }
+
+        assert_eq!(u64::from(Fpcr::from(u64::MAX)), Fpcr::MASK);
+    }
+
+    /// FPSR decodes the cumulative exception bits.
+    #[test]
+    fn fpsr_decode() {
+        // QC, IXC, DZC
+        let fpsr = Fpsr::from((1 << 27) | (1 << 4) | (1 << 1));
+
+        assert!(fpsr.saturation);
+        assert!(fpsr.inexact);
+        assert!(fpsr.divide_by_zero);
+        assert!(!fpsr.overflow);
+        assert!(!fpsr.invalid_operation);
+    }
+
+    /// FPSR encoding is the inverse of the decoding for the decoded bits, other bits are dropped.
+    #[test]
+    fn fpsr_round_trip() {
+        for value in [0, Fpsr::MASK, (1 << 7) | (1 << 3) | 1] {
+            assert_eq!(u64::from(Fpsr::from(value)), value);
+        }
+
+        assert_eq!(u64::from(Fpsr::from(u64::MAX)), Fpsr::MASK);
+    }
+}
@@ -0,0 +1,355 @@
+//! Description of the GIC layout exposed to a guest and software interrupt state.
+
+use super::{HypervisorError, InterruptType, Result, VirtualCpu};
+use crate::ffi::types::hv_ipa_t;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Conventional guest address of the GIC distributor (same as QEMU's virt machine).
+pub const GIC_DISTRIBUTOR_BASE: hv_ipa_t = 0x0800_0000;
+
+/// Size of the GIC distributor region.
+pub const GIC_DISTRIBUTOR_SIZE: usize = 0x1_0000;
+
+/// Conventional guest address of the GIC redistributors (same as QEMU's virt machine).
+pub const GIC_REDISTRIBUTOR_BASE: hv_ipa_t = 0x080A_0000;
+
+/// Size of the GIC redistributor region of one vCPU.
+pub const GIC_REDISTRIBUTOR_SIZE_PER_VCPU: usize = 0x2_0000;
+
+/// Represent the guest layout of the MMIO regions a guest treats as a GICv3.
+///
+/// **The Hypervisor doesn't emulate a GIC: this only describes where the host handles those regions.
+/// Interrupts are still delivered with [crate::VirtualCpu::set_pending_interrupt].**
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GicLayout {
+    /// The guest address of the distributor.
+    pub distributor_base: hv_ipa_t,
+
+    /// The size of the distributor region.
+    pub distributor_size: usize,
+
+    /// The guest address of the redistributors.
+    pub redistributor_base: hv_ipa_t,
+
+    /// The size of the redistributors region (covering all vCPUs).
+    pub redistributor_size: usize,
+}
+
+impl GicLayout {
+    /// Create the conventional GIC layout for a given amount of vCPUs.
+    pub const fn new(vcpu_count: usize) -> Self {
+        GicLayout {
+            distributor_base: GIC_DISTRIBUTOR_BASE,
+            distributor_size: GIC_DISTRIBUTOR_SIZE,
+            redistributor_base: GIC_REDISTRIBUTOR_BASE,
+            redistributor_size: GIC_REDISTRIBUTOR_SIZE_PER_VCPU * vcpu_count,
+        }
+    }
+
+    /// Generate the device tree source of the matching ``interrupt-controller`` node.
+    ///
+    /// The node is labeled ``intc`` and expects its parent to use two cells for addresses and sizes.
+    pub fn device_tree_node(&self) -> String {
+        let mut result = String::new();
+
+        writeln!(result, "intc: intc@{:x} {{", self.distributor_base).unwrap();
+        writeln!(result, "\tcompatible = \"arm,gic-v3\";").unwrap();
+        writeln!(result, "\t#interrupt-cells = <3>;").unwrap();
+        writeln!(result, "\tinterrupt-controller;").unwrap();
+        writeln!(
+            result,
+            "\treg = <0x{:x} 0x{:x} 0x{:x} 0x{:x}>, <0x{:x} 0x{:x} 0x{:x} 0x{:x}>;",
+            self.distributor_base >> 32,
+            self.distributor_base as u32,
+            (self.distributor_size as u64) >> 32,
+            self.distributor_size as u32,
+            self.redistributor_base >> 32,
+            self.redistributor_base as u32,
+            (self.redistributor_size as u64) >> 32,
+            self.redistributor_size as u32,
+        )
+        .unwrap();
+        writeln!(result, "}};").unwrap();
+
+        result
+    }
+}
+
+/// Number of interrupt ids per bitmap word.
+const BITS_PER_WORD: u32 = u64::BITS;
+
+/// Software state of a set of interrupt ids, aggregated into the IRQ and FIQ lines of a vCPU.
+///
+/// Every interrupt id has an enabled, pending and active state and is routed either to IRQ or FIQ (IRQ by default).
+/// A line is asserted when at least one enabled, pending and not active interrupt is routed to it.
+/// There is no priority: [VirtualGic::acknowledge] returns the lowest pending interrupt id.
+///
+/// As pending interrupts are cleared after every run, [VirtualGic::update] must be called before every call to [VirtualCpu::run].
+#[derive(Clone, Debug)]
+pub struct VirtualGic {
+    /// The number of interrupt ids.
+    interrupt_count: u32,
+
+    /// Enabled interrupts bitmap.
+    enabled: Vec<u64>,
+
+    /// Pending interrupts bitmap.
+    pending: Vec<u64>,
+
+    /// Active interrupts bitmap.
+    active: Vec<u64>,
+
+    /// Interrupts routed to FIQ bitmap.
+    fiq: Vec<u64>,
+}
+
+impl VirtualGic {
+    /// Create a new state for interrupt ids from 0 to ``interrupt_count`` (excluded), all disabled, not pending and routed to IRQ.
+    pub fn new(interrupt_count: u32) -> Self {
+        let word_count = (interrupt_count / BITS_PER_WORD
+            + u32::from(interrupt_count % BITS_PER_WORD != 0)) as usize;
+
+        VirtualGic {
+            interrupt_count,
+            enabled: vec![0; word_count],
+            pending: vec![0; word_count],
+            active: vec![0; word_count],
+            fiq: vec![0; word_count],
+        }
+    }
+
+    /// Gets the number of interrupt ids.
+    pub fn interrupt_count(&self) -> u32 {
+        self.interrupt_count
+    }
+
+    /// Gets the word index and the mask of an interrupt id in the bitmaps.
+    fn position(&self, id: u32) -> Result<(usize, u64)> {
+        if id >= self.interrupt_count {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        Ok(((id / BITS_PER_WORD) as usize, 1 << (id % BITS_PER_WORD)))
+    }
+
+    /// Sets or clears an interrupt id in a bitmap.
+    fn update_bit(bitmap: &mut [u64], (word, mask): (usize, u64), value: bool) {
+        if value {
+            bitmap[word] |= mask;
+        } else {
+            bitmap[word] &= !mask;
+        }
+    }
+
+    /// Sets whether an interrupt is enabled.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> Result<()> {
+        let position = self.position(id)?;
+
+        Self::update_bit(&mut self.enabled, position, enabled);
+
+        Ok(())
+    }
+
+    /// Sets the line an interrupt is routed to.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn set_interrupt_type(&mut self, id: u32, interrupt_type: InterruptType) -> Result<()> {
+        let position = self.position(id)?;
+
+        Self::update_bit(
+            &mut self.fiq,
+            position,
+            matches!(interrupt_type, InterruptType::FIQ),
+        );
+
+        Ok(())
+    }
+
+    /// Mark an interrupt as pending.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn raise(&mut self, id: u32) -> Result<()> {
+        let position = self.position(id)?;
+
+        Self::update_bit(&mut self.pending, position, true);
+
+        Ok(())
+    }
+
+    /// Clear the pending state of an interrupt.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn lower(&mut self, id: u32) -> Result<()> {
+        let position = self.position(id)?;
+
+        Self::update_bit(&mut self.pending, position, false);
+
+        Ok(())
+    }
+
+    /// Gets whether an interrupt is pending.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn is_pending(&self, id: u32) -> Result<bool> {
+        let (word, mask) = self.position(id)?;
+
+        Ok(self.pending[word] & mask != 0)
+    }
+
+    /// Gets whether an interrupt is active.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn is_active(&self, id: u32) -> Result<bool> {
+        let (word, mask) = self.position(id)?;
+
+        Ok(self.active[word] & mask != 0)
+    }
+
+    /// Gets the bitmap word of interrupts that are signaled on a given line.
+    fn signaled_word(&self, word: usize, interrupt_type: InterruptType) -> u64 {
+        let routing = match interrupt_type {
+            InterruptType::IRQ => !self.fiq[word],
+            InterruptType::FIQ => self.fiq[word],
+        };
+
+        self.enabled[word] & self.pending[word] & !self.active[word] & routing
+    }
+
+    /// Gets whether a given line should be asserted.
+    pub fn is_line_asserted(&self, interrupt_type: InterruptType) -> bool {
+        (0..self.pending.len()).any(|word| self.signaled_word(word, interrupt_type) != 0)
+    }
+
+    /// Acknowledge the lowest interrupt id signaled on a given line, moving it from pending to active.
+    ///
+    /// Returns [None] if no interrupt is signaled on the line.
+    pub fn acknowledge(&mut self, interrupt_type: InterruptType) -> Option<u32> {
+        let (word, bits) = (0..self.pending.len())
+            .map(|word| (word, self.signaled_word(word, interrupt_type)))
+            .find(|(_, bits)| *bits != 0)?;
+
+        let mask = bits & bits.wrapping_neg();
+
+        self.pending[word] &= !mask;
+        self.active[word] |= mask;
+
+        Some(word as u32 * BITS_PER_WORD + mask.trailing_zeros())
+    }
+
+    /// Signal the end of an interrupt, clearing its active state.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the id is out of range.
+    pub fn end_of_interrupt(&mut self, id: u32) -> Result<()> {
+        let position = self.position(id)?;
+
+        Self::update_bit(&mut self.active, position, false);
+
+        Ok(())
+    }
+
+    /// Drives the IRQ and FIQ lines of a vCPU from the current state.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn update(&self, vcpu: &mut VirtualCpu) -> Result<()> {
+        vcpu.set_pending_interrupt(
+            InterruptType::IRQ,
+            self.is_line_asserted(InterruptType::IRQ),
+        )?;
+        vcpu.set_pending_interrupt(
+            InterruptType::FIQ,
+            self.is_line_asserted(InterruptType::FIQ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the GIC layout and software interrupt state.
+
+    use super::*;
+
+    /// The device tree node of the conventional layout matches QEMU's virt machine.
+    #[test]
+    fn device_tree_node_matches_conventional_layout() {
+        let expected = "intc: intc@8000000 {\n\
+                        \tcompatible = \"arm,gic-v3\";\n\
+                        \t#interrupt-cells = <3>;\n\
+                        \tinterrupt-controller;\n\
+                        \treg = <0x0 0x8000000 0x0 0x10000>, <0x0 0x80a0000 0x0 0x40000>;\n\
+                        };\n";
+
+        assert_eq!(GicLayout::new(2).device_tree_node(), expected);
+    }
+
+    /// An interrupt asserts its line until acknowledged, and is only signaled when enabled.
+    #[test]
+    fn raise_acknowledge_end_of_interrupt() {
+        let mut gic = VirtualGic::new(96);
+
+        gic.raise(70).unwrap();
+
+        // Disabled interrupts stay pending without asserting the line.
+        assert!(gic.is_pending(70).unwrap());
+        assert!(!gic.is_line_asserted(InterruptType::IRQ));
+        assert_eq!(gic.acknowledge(InterruptType::IRQ), None);
+
+        gic.set_enabled(70, true).unwrap();
+        gic.set_enabled(3, true).unwrap();
+        gic.raise(3).unwrap();
+
+        assert!(gic.is_line_asserted(InterruptType::IRQ));
+        assert!(!gic.is_line_asserted(InterruptType::FIQ));
+
+        // The lowest id is acknowledged first.
+        assert_eq!(gic.acknowledge(InterruptType::IRQ), Some(3));
+        assert!(!gic.is_pending(3).unwrap());
+        assert!(gic.is_active(3).unwrap());
+
+        assert_eq!(gic.acknowledge(InterruptType::IRQ), Some(70));
+        assert!(!gic.is_line_asserted(InterruptType::IRQ));
+
+        // Raised again while active, it's only signaled after the end of interrupt.
+        gic.raise(70).unwrap();
+        assert!(!gic.is_line_asserted(InterruptType::IRQ));
+
+        gic.end_of_interrupt(70).unwrap();
+        assert!(!gic.is_active(70).unwrap());
+        assert!(gic.is_line_asserted(InterruptType::IRQ));
+
+        gic.lower(70).unwrap();
+        assert!(!gic.is_line_asserted(InterruptType::IRQ));
+    }
+
+    /// Interrupts routed to FIQ only assert the FIQ line.
+    #[test]
+    fn fiq_routing() {
+        let mut gic = VirtualGic::new(32);
+
+        gic.set_interrupt_type(5, InterruptType::FIQ).unwrap();
+        gic.set_enabled(5, true).unwrap();
+        gic.raise(5).unwrap();
+
+        assert!(gic.is_line_asserted(InterruptType::FIQ));
+        assert!(!gic.is_line_asserted(InterruptType::IRQ));
+        assert_eq!(gic.acknowledge(InterruptType::IRQ), None);
+        assert_eq!(gic.acknowledge(InterruptType::FIQ), Some(5));
+    }
+
+    /// Ids out of range are rejected.
+    #[test]
+    fn out_of_range_ids() {
+        let mut gic = VirtualGic::new(32);
+
+        assert!(matches!(gic.raise(32), Err(HypervisorError::BadArgument)));
+        assert!(matches!(
+            gic.set_enabled(u32::MAX, true),
+            Err(HypervisorError::BadArgument)
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+//! Guard regions left unmapped around mappings to catch guest overruns.
+
+use core::ops::Range;
+
+use super::{
+    hv_ipa_t, AllocationHandle, HypervisorError, MappingHandle, MemoryPermission, Result,
+    VirtualMachine, PAGE_SIZE,
+};
+
+/// A guest region reserved as a guard of a mapping.
+#[derive(Clone, Debug)]
+pub(super) struct GuardRegion {
+    /// The mapping guarded by the region.
+    mapping_handle: MappingHandle,
+
+    /// The guest address range of the region.
+    range: Range<u128>,
+}
+
+/// Check if two guest address ranges share at least one address.
+pub(super) fn ranges_overlap(a: &Range<u128>, b: &Range<u128>) -> bool {
+    !a.is_empty() && !b.is_empty() && a.start < b.end && b.start < a.end
+}
+
+impl VirtualMachine {
+    /// Map an allocation in the Virtual Machine, reserving ``guard_pages`` unmapped pages of [PAGE_SIZE] before and after it.
+    ///
+    /// Guest accesses to the guard regions exit with a data abort, which can be identified with [VirtualMachine::is_guard_address].
+    /// Guard regions cannot be mapped until the guarded mapping is unmapped.
+    ///
+    /// Returns [HypervisorError::BadArgument] if a guard region doesn't fit in the address space or overlaps with an existing mapping, guard region or MMIO region.
+    pub fn map_with_guard(
+        &mut self,
+        allocation_handle: AllocationHandle,
+        guest_address: hv_ipa_t,
+        permission: MemoryPermission,
+        guard_pages: usize,
+    ) -> Result<MappingHandle> {
+        let size = self.allocation_mapped_size(allocation_handle)?;
+
+        let guard_size = guard_pages
+            .checked_mul(PAGE_SIZE)
+            .ok_or(HypervisorError::BadArgument)? as u128;
+
+        let start = u128::from(guest_address);
+        let end = start + size as u128;
+
+        if guard_size > start || end + guard_size > u128::from(hv_ipa_t::MAX) + 1 {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let guards = [start - guard_size..start, end..end + guard_size];
+
+        for guard in &guards {
+            if self.overlaps_guard_region(guard)
+                || self.overlaps_slice_region(guard)
+                || self.overlaps_mmio_region(guard)
+                || self
+                    .mapping_list
+                    .iter()
+                    .any(|mapping| ranges_overlap(&mapping.guest_range(), guard))
+            {
+                return Err(HypervisorError::BadArgument);
+            }
+        }
+
+        let mapping_handle = self.map(allocation_handle, guest_address, permission)?;
+
+        for range in guards {
+            if !range.is_empty() {
+                self.guard_regions.push(GuardRegion {
+                    mapping_handle,
+                    range,
+                });
+            }
+        }
+
+        Ok(mapping_handle)
+    }
+
+    /// Check if a guest address belongs to the guard region of a mapping.
+    pub fn is_guard_address(&self, ipa: hv_ipa_t) -> bool {
+        self.guard_regions
+            .iter()
+            .any(|guard| guard.range.contains(&u128::from(ipa)))
+    }
+
+    /// Check if a guest address range overlaps with a guard region.
+    pub(super) fn overlaps_guard_region(&self, range: &Range<u128>) -> bool {
+        self.guard_regions
+            .iter()
+            .any(|guard| ranges_overlap(&guard.range, range))
+    }
+
+    /// Release the guard regions of a mapping.
+    pub(super) fn release_guard_regions(&mut self, mapping_handle: MappingHandle) {
+        self.guard_regions
+            .retain(|guard| guard.mapping_handle != mapping_handle);
+    }
+}
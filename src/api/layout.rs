@@ -0,0 +1,273 @@
+//! Mapping layout of a Virtual Machine: comparison of the layout over time and building of a contiguous guest layout made of named regions.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{
+    hv_ipa_t, HypervisorError, MappingHandle, MemoryPermission, Result, VirtualMachine,
+    VirtualMachineMapping, PAGE_SIZE,
+};
+
+/// Differences between two mapping layouts.
+///
+/// Mappings are matched by guest address and size, as handles depend on the order of operations.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LayoutDiff {
+    /// Mappings only present in the current layout.
+    pub added: Vec<VirtualMachineMapping>,
+
+    /// Mappings only present in the previous layout.
+    pub removed: Vec<VirtualMachineMapping>,
+
+    /// Mappings present in both layouts with a different permission, as ``(previous, current)``.
+    pub permission_changed: Vec<(VirtualMachineMapping, VirtualMachineMapping)>,
+}
+
+impl LayoutDiff {
+    /// Compare two mapping layouts obtained with [VirtualMachine::get_all_mapping_infos].
+    pub fn between(previous: &[VirtualMachineMapping], current: &[VirtualMachineMapping]) -> Self {
+        let mut result = LayoutDiff::default();
+
+        for mapping in current {
+            match previous.iter().find(|other| same_region(mapping, other)) {
+                Some(other) if other.permission != mapping.permission => {
+                    result.permission_changed.push((*other, *mapping));
+                }
+                Some(_) => {}
+                None => result.added.push(*mapping),
+            }
+        }
+
+        for other in previous {
+            if !current.iter().any(|mapping| same_region(mapping, other)) {
+                result.removed.push(*other);
+            }
+        }
+
+        result
+    }
+
+    /// Check if both layouts are identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.permission_changed.is_empty()
+    }
+}
+
+/// Check if two mappings cover the same guest region.
+fn same_region(a: &VirtualMachineMapping, b: &VirtualMachineMapping) -> bool {
+    a.address == b.address && a.size == b.size
+}
+
+impl VirtualMachine {
+    /// Compare the current mapping layout with a previous one obtained with [VirtualMachine::get_all_mapping_infos].
+    ///
+    /// As only one Virtual Machine can exist in a process, layouts are compared over time rather than between instances.
+    #[must_use]
+    pub fn diff_layout(&self, previous: &[VirtualMachineMapping]) -> LayoutDiff {
+        LayoutDiff::between(previous, &self.mapping_list)
+    }
+}
+
+/// Region declared in a [GuestLayout].
+#[derive(Clone, Debug)]
+struct GuestLayoutRegion {
+    /// The name of the region.
+    name: String,
+
+    /// The size of the region.
+    size: usize,
+
+    /// The memory permission of the region.
+    permission: MemoryPermission,
+}
+
+/// Builder of a contiguous guest address space made of named regions.
+///
+/// Regions are laid out in declaration order, each one starting right after the previous one (sizes are padded to [PAGE_SIZE]).
+#[derive(Clone, Debug, Default)]
+pub struct GuestLayout {
+    /// The declared regions.
+    regions: Vec<GuestLayoutRegion>,
+}
+
+impl GuestLayout {
+    /// Create a new empty layout.
+    pub fn new() -> Self {
+        GuestLayout::default()
+    }
+
+    /// Declare a region mapped as [MemoryPermission::READ_WRITE].
+    #[must_use]
+    pub fn region(self, name: &str, size: usize) -> Self {
+        self.region_with_permission(name, size, MemoryPermission::READ_WRITE)
+    }
+
+    /// Declare a region mapped with a given permission.
+    #[must_use]
+    pub fn region_with_permission(
+        mut self,
+        name: &str,
+        size: usize,
+        permission: MemoryPermission,
+    ) -> Self {
+        self.regions.push(GuestLayoutRegion {
+            name: String::from(name),
+            size,
+            permission,
+        });
+
+        self
+    }
+
+    /// Allocate and map every region contiguously from ``base``.
+    ///
+    /// Returns the mapping handle of every region by name.
+    /// Returns [HypervisorError::BadArgument] if a region is empty, if two regions share a name or if the layout overlaps an existing mapping.
+    /// If an allocation or a mapping fails, the regions already created are removed and the error is returned.
+    pub fn build(
+        &self,
+        virtual_machine: &mut VirtualMachine,
+        base: hv_ipa_t,
+    ) -> Result<BTreeMap<String, MappingHandle>> {
+        let mut addresses = Vec::with_capacity(self.regions.len());
+        let mut address = u128::from(base);
+
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.size == 0
+                || self.regions[..index]
+                    .iter()
+                    .any(|other| other.name == region.name)
+            {
+                return Err(HypervisorError::BadArgument);
+            }
+
+            let size = region
+                .size
+                .checked_add(PAGE_SIZE - 1)
+                .ok_or(HypervisorError::BadArgument)?
+                / PAGE_SIZE
+                * PAGE_SIZE;
+
+            let range = address..address + size as u128;
+
+            let overlaps = virtual_machine.mapping_list.iter().any(|mapping| {
+                let mapping_range = mapping.guest_range();
+
+                range.start < mapping_range.end && mapping_range.start < range.end
+            });
+
+            if overlaps || range.end > u128::from(u64::MAX) + 1 {
+                return Err(HypervisorError::BadArgument);
+            }
+
+            addresses.push(address as hv_ipa_t);
+            address = range.end;
+        }
+
+        let mut result = BTreeMap::new();
+
+        for (region, address) in self.regions.iter().zip(addresses) {
+            match Self::create_region(virtual_machine, region, address) {
+                Ok(mapping_handle) => {
+                    result.insert(region.name.clone(), mapping_handle);
+                }
+                Err(error) => {
+                    for mapping_handle in result.values() {
+                        Self::remove_region(virtual_machine, *mapping_handle);
+                    }
+
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Allocate and map a region at a given guest address.
+    fn create_region(
+        virtual_machine: &mut VirtualMachine,
+        region: &GuestLayoutRegion,
+        address: hv_ipa_t,
+    ) -> Result<MappingHandle> {
+        let allocation_handle = virtual_machine.allocate(region.size)?;
+
+        virtual_machine
+            .map(allocation_handle, address, region.permission)
+            .map_err(|error| {
+                let _ = virtual_machine.deallocate(allocation_handle);
+
+                error
+            })
+    }
+
+    /// Unmap and deallocate a region created by [GuestLayout::build], ignoring errors.
+    fn remove_region(virtual_machine: &mut VirtualMachine, mapping_handle: MappingHandle) {
+        if let Ok(mapping) = virtual_machine.get_mapping_info(mapping_handle) {
+            let _ = virtual_machine.unmap(mapping_handle);
+            let _ = virtual_machine.deallocate(mapping.allocation_handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the layout comparison.
+
+    use super::*;
+    use crate::AllocationHandle;
+
+    /// Create a mapping description.
+    fn mapping(
+        handle: u64,
+        address: hv_ipa_t,
+        size: usize,
+        permission: MemoryPermission,
+    ) -> VirtualMachineMapping {
+        VirtualMachineMapping {
+            allocation_handle: AllocationHandle(handle),
+            mapping_handle: MappingHandle(handle),
+            address,
+            size,
+            permission,
+        }
+    }
+
+    /// Identical layouts have no differences, regardless of handles and order.
+    #[test]
+    fn identical_layouts() {
+        let previous = [
+            mapping(0, 0x10000, PAGE_SIZE, MemoryPermission::READ),
+            mapping(1, 0x20000, PAGE_SIZE, MemoryPermission::READ_WRITE),
+        ];
+        let current = [
+            mapping(3, 0x20000, PAGE_SIZE, MemoryPermission::READ_WRITE),
+            mapping(2, 0x10000, PAGE_SIZE, MemoryPermission::READ),
+        ];
+
+        assert!(LayoutDiff::between(&previous, &current).is_empty());
+    }
+
+    /// Added, removed and reprotected mappings are reported.
+    #[test]
+    fn changed_layouts() {
+        let kept = mapping(0, 0x10000, PAGE_SIZE, MemoryPermission::READ);
+        let removed = mapping(1, 0x20000, PAGE_SIZE, MemoryPermission::READ);
+        let resized = mapping(2, 0x30000, PAGE_SIZE, MemoryPermission::READ);
+        let reprotected = mapping(3, 0x50000, PAGE_SIZE, MemoryPermission::READ);
+
+        let grown = mapping(4, 0x30000, 2 * PAGE_SIZE, MemoryPermission::READ);
+        let writable = mapping(5, 0x50000, PAGE_SIZE, MemoryPermission::READ_WRITE);
+
+        let diff = LayoutDiff::between(
+            &[kept, removed, resized, reprotected],
+            &[kept, grown, writable],
+        );
+
+        assert_eq!(diff.added, [grown]);
+        assert_eq!(diff.removed, [removed, resized]);
+        assert_eq!(diff.permission_changed, [(reprotected, writable)]);
+        assert!(!diff.is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+//! Registry of emulated MMIO regions, dispatching guest accesses to device models.
+
+use alloc::boxed::Box;
+
+use super::{
+    hv_ipa_t, hv_vcpu_exit_exception_t, HypervisorError, Result, VirtualCpu, VirtualMachine,
+};
+
+/// Device model handling the accesses to an MMIO region.
+pub trait MmioHandler {
+    /// Handle a read of ``size`` bytes at ``offset`` from the start of the region, returning the value read.
+    fn read(&mut self, offset: u64, size: usize) -> u64;
+
+    /// Handle a write of ``size`` bytes at ``offset`` from the start of the region.
+    fn write(&mut self, offset: u64, size: usize, value: u64);
+}
+
+/// An MMIO region registered in a Virtual Machine.
+pub(super) struct MmioRegion {
+    /// The guest address of the region.
+    start: hv_ipa_t,
+
+    /// The size of the region.
+    size: usize,
+
+    /// The device model of the region.
+    handler: Box<dyn MmioHandler>,
+}
+
+impl MmioRegion {
+    /// Gets the guest address range covered by the region.
+    ///
+    /// The range is computed on 128 bits to not wrap around at the end of the address space.
+    fn guest_range(&self) -> core::ops::Range<u128> {
+        let start = u128::from(self.start);
+
+        start..start + self.size as u128
+    }
+}
+
+impl core::fmt::Debug for MmioRegion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MmioRegion")
+            .field("start", &self.start)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Gets the mask of an access of a given size.
+fn access_mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+impl VirtualMachine {
+    /// Register an emulated MMIO region.
+    ///
+    /// The region must not be mapped so that guest accesses to it exit with a data abort, to be forwarded to [VirtualMachine::dispatch_mmio].
+    ///
+    /// Returns [HypervisorError::BadArgument] if the region is empty or overlaps with another MMIO region, a mapping or a guard region.
+    pub fn register_mmio(
+        &mut self,
+        start: hv_ipa_t,
+        size: usize,
+        handler: Box<dyn MmioHandler>,
+    ) -> Result<()> {
+        let region = MmioRegion {
+            start,
+            size,
+            handler,
+        };
+
+        let range = region.guest_range();
+
+        if range.is_empty()
+            || self.overlaps_guard_region(&range)
+            || self.overlaps_slice_region(&range)
+            || self.overlaps_mmio_region(&range)
+            || self
+                .mapping_list
+                .iter()
+                .any(|mapping| super::guard::ranges_overlap(&mapping.guest_range(), &range))
+        {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        self.mmio_regions.push(region);
+
+        Ok(())
+    }
+
+    /// Handle a data abort on a registered MMIO region.
+    ///
+    /// The access is forwarded to the device model of the region, loads are completed by writing the destination register
+    /// (with sign extension if requested) and PC is advanced past the faulting instruction.
+    ///
+    /// Returns ``false`` if the exception isn't a data abort on a registered MMIO region,
+    /// and [HypervisorError::Unsupported] if the syndrome doesn't describe the access (for example with load/store pair instructions).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn dispatch_mmio(
+        &mut self,
+        vcpu: &mut VirtualCpu,
+        exception: &hv_vcpu_exit_exception_t,
+    ) -> Result<bool> {
+        let info = match exception.data_abort_info() {
+            Some(info) => info,
+            None => return Ok(false),
+        };
+
+        let address = exception.physical_address;
+
+        let region = match self
+            .mmio_regions
+            .iter_mut()
+            .find(|region| region.guest_range().contains(&u128::from(address)))
+        {
+            Some(region) => region,
+            None => return Ok(false),
+        };
+
+        if !info.is_valid {
+            return Err(HypervisorError::Unsupported);
+        }
+
+        let offset = address - region.start;
+        let mask = access_mask(info.access_size);
+
+        if info.is_write {
+            let value = vcpu.gpr(info.register)? & mask;
+
+            region.handler.write(offset, info.access_size, value);
+        } else {
+            let mut value = region.handler.read(offset, info.access_size) & mask;
+
+            if info.sign_extend {
+                let sign_bit = 1 << (info.access_size * 8 - 1);
+
+                if value & sign_bit != 0 {
+                    value |= !mask;
+                }
+            }
+
+            if !info.is_64bit_register {
+                value &= u64::from(u32::MAX);
+            }
+
+            vcpu.set_gpr(info.register, value)?;
+        }
+
+        vcpu.advance_pc(4)?;
+
+        Ok(true)
+    }
+
+    /// Check if a guest address range overlaps with a registered MMIO region.
+    pub(super) fn overlaps_mmio_region(&self, range: &core::ops::Range<u128>) -> bool {
+        self.mmio_regions
+            .iter()
+            .any(|region| super::guard::ranges_overlap(&region.guest_range(), range))
+    }
+}
@@ -0,0 +1,201 @@
+//! Decoding of the guest EL1&0 translation regime configuration.
+
+use super::{Result, SystemRegister, VirtualCpu};
+
+/// Translation granule size.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TranslationGranule {
+    /// 4KB granule.
+    Size4K,
+
+    /// 16KB granule.
+    Size16K,
+
+    /// 64KB granule.
+    Size64K,
+}
+
+impl TranslationGranule {
+    /// Gets the granule size in bytes.
+    pub const fn size(&self) -> usize {
+        match self {
+            TranslationGranule::Size4K => 0x1000,
+            TranslationGranule::Size16K => 0x4000,
+            TranslationGranule::Size64K => 0x10000,
+        }
+    }
+}
+
+/// Snapshot of the guest EL1&0 translation regime configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MmuState {
+    /// Raw SCTLR_EL1 value.
+    pub sctlr: u64,
+
+    /// Raw TCR_EL1 value.
+    pub tcr: u64,
+
+    /// Raw TTBR0_EL1 value.
+    pub ttbr0: u64,
+
+    /// Raw TTBR1_EL1 value.
+    pub ttbr1: u64,
+
+    /// Raw MAIR_EL1 value.
+    pub mair: u64,
+
+    /// Whether the stage 1 MMU is enabled (SCTLR_EL1.M).
+    pub mmu_enabled: bool,
+
+    /// Whether alignment fault checking is enabled (SCTLR_EL1.A).
+    pub alignment_check: bool,
+
+    /// Whether data accesses are cacheable (SCTLR_EL1.C).
+    pub data_cache_enabled: bool,
+
+    /// Whether instruction accesses are cacheable (SCTLR_EL1.I).
+    pub instruction_cache_enabled: bool,
+
+    /// Size offset of the region translated by TTBR0_EL1 (TCR_EL1.T0SZ).
+    pub t0sz: u8,
+
+    /// Size offset of the region translated by TTBR1_EL1 (TCR_EL1.T1SZ).
+    pub t1sz: u8,
+
+    /// Whether translation table walks using TTBR0_EL1 are disabled (TCR_EL1.EPD0).
+    pub ttbr0_walk_disabled: bool,
+
+    /// Whether translation table walks using TTBR1_EL1 are disabled (TCR_EL1.EPD1).
+    pub ttbr1_walk_disabled: bool,
+
+    /// Granule of TTBR0_EL1 (TCR_EL1.TG0), None for a reserved value.
+    pub ttbr0_granule: Option<TranslationGranule>,
+
+    /// Granule of TTBR1_EL1 (TCR_EL1.TG1), None for a reserved value.
+    pub ttbr1_granule: Option<TranslationGranule>,
+
+    /// Intermediate physical address size (TCR_EL1.IPS), in bits. None for a reserved value.
+    pub ipa_size: Option<u8>,
+}
+
+impl MmuState {
+    /// Decode the translation regime configuration from raw register values.
+    pub fn from_registers(sctlr: u64, tcr: u64, ttbr0: u64, ttbr1: u64, mair: u64) -> Self {
+        MmuState {
+            sctlr,
+            tcr,
+            ttbr0,
+            ttbr1,
+            mair,
+            mmu_enabled: sctlr & (1 << 0) != 0,
+            alignment_check: sctlr & (1 << 1) != 0,
+            data_cache_enabled: sctlr & (1 << 2) != 0,
+            instruction_cache_enabled: sctlr & (1 << 12) != 0,
+            t0sz: (tcr & 0x3f) as u8,
+            t1sz: ((tcr >> 16) & 0x3f) as u8,
+            ttbr0_walk_disabled: tcr & (1 << 7) != 0,
+            ttbr1_walk_disabled: tcr & (1 << 23) != 0,
+            ttbr0_granule: match (tcr >> 14) & 0x3 {
+                0b00 => Some(TranslationGranule::Size4K),
+                0b01 => Some(TranslationGranule::Size64K),
+                0b10 => Some(TranslationGranule::Size16K),
+                _ => None,
+            },
+            ttbr1_granule: match (tcr >> 30) & 0x3 {
+                0b01 => Some(TranslationGranule::Size16K),
+                0b10 => Some(TranslationGranule::Size4K),
+                0b11 => Some(TranslationGranule::Size64K),
+                _ => None,
+            },
+            ipa_size: match (tcr >> 32) & 0x7 {
+                0b000 => Some(32),
+                0b001 => Some(36),
+                0b010 => Some(40),
+                0b011 => Some(42),
+                0b100 => Some(44),
+                0b101 => Some(48),
+                0b110 => Some(52),
+                _ => None,
+            },
+        }
+    }
+
+    /// Gets the size in bits of the virtual address region translated by TTBR0_EL1.
+    pub fn ttbr0_va_bits(&self) -> u8 {
+        64u8.saturating_sub(self.t0sz)
+    }
+
+    /// Gets the size in bits of the virtual address region translated by TTBR1_EL1.
+    pub fn ttbr1_va_bits(&self) -> u8 {
+        64u8.saturating_sub(self.t1sz)
+    }
+
+    /// Gets the memory attributes of a MAIR_EL1 index (0 to 7).
+    pub fn memory_attribute(&self, index: u8) -> u8 {
+        (self.mair >> ((u32::from(index) & 0x7) * 8)) as u8
+    }
+}
+
+impl VirtualCpu {
+    /// Gets the guest EL1&0 translation regime configuration.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn mmu_state(&mut self) -> Result<MmuState> {
+        Ok(MmuState::from_registers(
+            self.get_system_register(SystemRegister::SCTLR_EL1)?,
+            self.get_system_register(SystemRegister::TCR_EL1)?,
+            self.get_system_register(SystemRegister::TTBR0_EL1)?,
+            self.get_system_register(SystemRegister::TTBR1_EL1)?,
+            self.get_system_register(SystemRegister::MAIR_EL1)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the translation regime decoding.
+
+    use super::*;
+
+    /// SCTLR_EL1 reset value, with only RES1 bits set.
+    const SCTLR_RESET: u64 = 0x30d0_0800;
+
+    /// The MMU is only reported enabled when SCTLR_EL1.M is set.
+    #[test]
+    fn mmu_enabled_follows_sctlr_m() {
+        let disabled = MmuState::from_registers(SCTLR_RESET, 0, 0, 0, 0);
+
+        assert!(!disabled.mmu_enabled);
+        assert!(!disabled.data_cache_enabled);
+        assert!(!disabled.instruction_cache_enabled);
+
+        // M, C and I
+        let enabled = MmuState::from_registers(SCTLR_RESET | (1 << 12) | (1 << 2) | 1, 0, 0, 0, 0);
+
+        assert!(enabled.mmu_enabled);
+        assert!(!enabled.alignment_check);
+        assert!(enabled.data_cache_enabled);
+        assert!(enabled.instruction_cache_enabled);
+
+        assert!(!MmuState::from_registers(1 << 2, 0, 0, 0, 0).mmu_enabled);
+    }
+
+    /// A 48-bit, 4KB granule configuration decodes as such.
+    #[test]
+    fn tcr_decode() {
+        // T0SZ = T1SZ = 16, TG0 = 4KB, TG1 = 4KB, IPS = 48 bits
+        let tcr = 16 | (16 << 16) | (0b10 << 30) | (0b101 << 32);
+        let state = MmuState::from_registers(SCTLR_RESET | 1, tcr, 0x8000, 0x9000, 0x04ff);
+
+        assert_eq!(state.ttbr0_va_bits(), 48);
+        assert_eq!(state.ttbr1_va_bits(), 48);
+        assert_eq!(state.ttbr0_granule, Some(TranslationGranule::Size4K));
+        assert_eq!(state.ttbr1_granule, Some(TranslationGranule::Size4K));
+        assert_eq!(state.ipa_size, Some(48));
+        assert!(!state.ttbr0_walk_disabled);
+        assert_eq!((state.ttbr0, state.ttbr1), (0x8000, 0x9000));
+        assert_eq!(state.memory_attribute(0), 0xff);
+        assert_eq!(state.memory_attribute(1), 0x04);
+        assert_eq!(state.memory_attribute(2), 0x00);
+    }
+}
@@ -1,13 +1,84 @@
 //! Safe API for Apple Hypervisor
+use crate::ffi::system::{sysconf, _SC_PAGESIZE};
 use crate::ffi::types::*;
 use crate::ffi::*;
 
+use core::cell::RefCell;
 use core::ffi::c_void;
 use core::marker::PhantomData;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 
 use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
 
+mod accounting;
+mod atomic;
+mod audit;
+mod boot;
+#[cfg(feature = "std")]
+mod cursor;
+#[cfg(feature = "dispatch")]
+mod deadline;
+mod debug;
+mod dirty;
+#[cfg(feature = "std")]
+mod entitlement;
+mod exception;
+mod fpu;
+mod gic;
+mod guard;
+mod layout;
+mod mmio;
+mod mmu;
+#[cfg(feature = "std")]
+mod profiler;
+mod ring;
+#[cfg(feature = "std")]
+mod runner;
+#[cfg(feature = "std")]
+mod scheduler;
+mod semihosting;
+mod snapshot;
+mod state;
+mod trace;
+mod validation;
+#[cfg(feature = "std")]
+mod vcpu_thread;
+mod vtimer;
+
+pub use accounting::*;
+pub use atomic::*;
+pub use audit::*;
+#[cfg(feature = "std")]
+pub use cursor::*;
+pub use debug::*;
+#[cfg(feature = "std")]
+pub use entitlement::*;
+pub use exception::*;
+pub use fpu::*;
+pub use gic::*;
+pub use layout::*;
+pub use mmio::*;
+pub use mmu::*;
+#[cfg(feature = "std")]
+pub use profiler::*;
+pub use ring::*;
+#[cfg(feature = "std")]
+pub use runner::*;
+#[cfg(feature = "std")]
+pub use scheduler::*;
+pub use semihosting::*;
+pub use snapshot::*;
+pub use trace::*;
+pub use validation::*;
+pub use vtimer::*;
+
+use guard::GuardRegion;
+
 /// An Hypervisor Result.
 pub type Result<T> = core::result::Result<T, HypervisorError>;
 
@@ -47,6 +118,15 @@ pub enum HypervisorError {
     /// A memory address was misaligned
     MisalignedAddress,
 
+    /// The given guest address isn't mapped.
+    UnmappedAddress,
+
+    /// The given system register cannot be written.
+    ReadOnlyRegister,
+
+    /// The maximum number of vCPUs supported by the Hypervisor (given value) is already alive.
+    MaxVcpusReached(u32),
+
     /// An unknown error was returned.
     Unknown(u32),
 }
@@ -60,6 +140,117 @@ fn convert_hv_return(value: hv_return_t) -> Result<()> {
     }
 }
 
+/// Number of spin iterations used to back off between attempts when the standard library isn't available.
+#[cfg(not(feature = "std"))]
+const RETRY_SPIN_COUNT: usize = 128;
+
+/// Give the Hypervisor a short time to recover before retrying an operation.
+///
+/// Yields the host thread with the standard library, spins briefly otherwise.
+fn yield_briefly() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+
+    #[cfg(not(feature = "std"))]
+    for _ in 0..RETRY_SPIN_COUNT {
+        core::hint::spin_loop();
+    }
+}
+
+/// Call an operation up to ``max_attempts`` times while it reports a transient error, yielding between attempts.
+///
+/// If all attempts are exhausted, the last error is returned ([HypervisorError::BadArgument] if ``max_attempts`` is zero).
+fn retry_transient<T>(max_attempts: usize, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut last_error = HypervisorError::BadArgument;
+
+    for _ in 0..max_attempts {
+        match operation() {
+            Err(error) if error.is_transient() => {
+                last_error = error;
+
+                yield_briefly();
+            }
+            result => return result,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Set the values of multiple registers through ``get`` and ``set``, all or nothing (see [VirtualCpu::apply_registers_transactional]).
+fn apply_transactional<C, R: Copy + core::fmt::Debug>(
+    context: &mut C,
+    pairs: &[(R, u64)],
+    get: impl Fn(&mut C, R) -> Result<u64>,
+    set: impl Fn(&mut C, R, u64) -> Result<()>,
+) -> Result<()> {
+    let stash = pairs
+        .iter()
+        .map(|(register, _)| Ok((*register, get(context, *register)?)))
+        .collect::<Result<Vec<(R, u64)>>>()?;
+
+    for (index, (register, value)) in pairs.iter().enumerate() {
+        if let Err(error) = set(context, *register, *value) {
+            // Restore in reverse order so that aliased registers end up with their original value.
+            for (register, value) in stash[..index].iter().rev() {
+                let result = set(context, *register, *value);
+
+                // The register was already set successfully, restoring it is not expected to fail.
+                debug_assert!(
+                    result.is_ok(),
+                    "Restoring {:?} failed: {:?}",
+                    register,
+                    result
+                );
+            }
+
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Map a region with ``map`` then reserve its bookkeeping with ``reserve`` (see [VirtualMachine::map]).
+///
+/// If the reservation fails, the region is unmapped with ``unmap`` so that it isn't leaked in the Hypervisor, and [HypervisorError::NoResources] is returned.
+fn map_with_rollback(
+    map: impl FnOnce() -> Result<()>,
+    reserve: impl FnOnce() -> bool,
+    unmap: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    map()?;
+
+    if !reserve() {
+        let result = unmap();
+
+        // The region was just mapped, unmapping it is not expected to fail.
+        debug_assert!(result.is_ok(), "Unmapping failed: {:?}", result);
+
+        return Err(HypervisorError::NoResources);
+    }
+
+    Ok(())
+}
+
+/// Read the exit informations of a vCPU, checking the pointer in debug builds.
+///
+/// # Safety
+///
+/// ``vcpu_exit`` must point to the exit informations of a vCPU, as returned by ``hv_vcpu_create``.
+unsafe fn read_vcpu_exit(vcpu_exit: *const hv_vcpu_exit_t) -> hv_vcpu_exit_t {
+    debug_assert!(
+        !vcpu_exit.is_null(),
+        "vCPU exit informations pointer is null!"
+    );
+    debug_assert!(
+        vcpu_exit as usize % core::mem::align_of::<hv_vcpu_exit_t>() == 0,
+        "vCPU exit informations pointer is misaligned!"
+    );
+
+    *vcpu_exit
+}
+
 impl From<hv_return_t> for HypervisorError {
     fn from(value: hv_return_t) -> HypervisorError {
         match value {
@@ -77,6 +268,37 @@ impl From<hv_return_t> for HypervisorError {
     }
 }
 
+impl HypervisorError {
+    /// Check if the error is transient and the operation can be retried as is.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, HypervisorError::Busy)
+    }
+
+    /// Gets a stable process exit code for the error.
+    ///
+    /// Every variant maps to a distinct code starting from 1, in declaration order.
+    /// [HypervisorError::Unknown] always maps to 255.
+    pub fn as_exit_code(&self) -> i32 {
+        match self {
+            HypervisorError::Error => 1,
+            HypervisorError::Busy => 2,
+            HypervisorError::BadArgument => 3,
+            HypervisorError::IllegalGuestState => 4,
+            HypervisorError::NoResources => 5,
+            HypervisorError::NoDevice => 6,
+            HypervisorError::Denied => 7,
+            HypervisorError::Unsupported => 8,
+            HypervisorError::InvalidHandle => 9,
+            HypervisorError::AllocationStillMapped => 10,
+            HypervisorError::MisalignedAddress => 11,
+            HypervisorError::UnmappedAddress => 12,
+            HypervisorError::ReadOnlyRegister => 13,
+            HypervisorError::MaxVcpusReached(_) => 14,
+            HypervisorError::Unknown(_) => 255,
+        }
+    }
+}
+
 /// Represent the configuration of a Virtual Machine.
 #[derive(Debug)]
 pub struct VirtualMachineConfiguration {
@@ -95,7 +317,7 @@ impl VirtualMachineConfiguration {
 }
 
 /// Represent the permission of a memory region.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct MemoryPermission {
     /// Read.
     read: bool,
@@ -137,6 +359,42 @@ impl MemoryPermission {
 
     /// Read Write Execute.
     pub const READ_WRITE_EXECUTE: MemoryPermission = MemoryPermission::new(true, true, true);
+
+    /// Gets the compact representation of the permission (bit 0 is read, bit 1 is write and bit 2 is execute).
+    ///
+    /// Unlike the conversion to [hv_memory_flags_t], this representation is stable and suitable for serialization.
+    pub const fn bits(&self) -> u8 {
+        (self.read as u8) | ((self.write as u8) << 1) | ((self.execute as u8) << 2)
+    }
+
+    /// Create a memory permission from its compact representation (see [MemoryPermission::bits]).
+    ///
+    /// Returns [None] if any bit above bit 2 is set.
+    pub const fn from_bits(bits: u8) -> Option<MemoryPermission> {
+        if bits & !0b111 != 0 {
+            return None;
+        }
+
+        Some(MemoryPermission::new(
+            bits & 0b001 != 0,
+            bits & 0b010 != 0,
+            bits & 0b100 != 0,
+        ))
+    }
+}
+
+impl From<MemoryPermission> for u8 {
+    fn from(value: MemoryPermission) -> u8 {
+        value.bits()
+    }
+}
+
+impl TryFrom<u8> for MemoryPermission {
+    type Error = u8;
+
+    fn try_from(value: u8) -> core::result::Result<MemoryPermission, u8> {
+        MemoryPermission::from_bits(value).ok_or(value)
+    }
 }
 
 impl From<MemoryPermission> for hv_memory_flags_t {
@@ -159,8 +417,72 @@ impl From<MemoryPermission> for hv_memory_flags_t {
     }
 }
 
-/// Represent a memory mapping of a Virtual Machine.
+/// Error returned when parsing a [MemoryPermission] from a string fails.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseMemoryPermissionError;
+
+impl core::fmt::Display for ParseMemoryPermissionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "invalid memory permission (expected a string like \"rw-\")"
+        )
+    }
+}
+
+impl core::str::FromStr for MemoryPermission {
+    type Err = ParseMemoryPermissionError;
+
+    /// Parse a memory permission in the ``rwx`` format, using ``-`` for missing permissions (case insensitive).
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        /// Parse one permission character.
+        fn parse_flag(
+            value: u8,
+            expected: u8,
+        ) -> core::result::Result<bool, ParseMemoryPermissionError> {
+            match value.to_ascii_lowercase() {
+                b'-' => Ok(false),
+                value if value == expected => Ok(true),
+                _ => Err(ParseMemoryPermissionError),
+            }
+        }
+
+        match value.as_bytes() {
+            [read, write, execute] => Ok(MemoryPermission::new(
+                parse_flag(*read, b'r')?,
+                parse_flag(*write, b'w')?,
+                parse_flag(*execute, b'x')?,
+            )),
+            _ => Err(ParseMemoryPermissionError),
+        }
+    }
+}
+
+impl core::fmt::Display for MemoryPermission {
+    /// Format the memory permission in the ``rwx`` format, using ``-`` for missing permissions.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' }
+        )
+    }
+}
+
+/// Error reported by [VirtualMachine::reprotect_allocation].
 #[derive(Copy, Clone, Debug)]
+pub struct ReprotectAllocationError {
+    /// The mapping that failed to be reprotected, if the failure is related to a mapping.
+    pub mapping_handle: Option<MappingHandle>,
+
+    /// The error reported.
+    pub error: HypervisorError,
+}
+
+/// Represent a memory mapping of a Virtual Machine.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct VirtualMachineMapping {
     /// The allcation handle associated to this mapping.
     pub allocation_handle: AllocationHandle,
@@ -178,6 +500,138 @@ pub struct VirtualMachineMapping {
     pub permission: MemoryPermission,
 }
 
+impl VirtualMachineMapping {
+    /// Gets the guest address range covered by the mapping.
+    ///
+    /// The range is computed on 128 bits to not wrap around at the end of the address space.
+    fn guest_range(&self) -> core::ops::Range<u128> {
+        let start = u128::from(self.address);
+
+        start..start + self.size as u128
+    }
+
+    /// Check if the mapping covers a given guest address.
+    ///
+    /// An empty mapping never contains any address.
+    pub fn contains_address(&self, ipa: hv_ipa_t) -> bool {
+        self.guest_range().contains(&u128::from(ipa))
+    }
+
+    /// Check if the mapping shares at least one guest address with another mapping.
+    ///
+    /// An empty mapping never overlaps with any other mapping.
+    pub fn overlaps(&self, other: &VirtualMachineMapping) -> bool {
+        let range = self.guest_range();
+        let other_range = other.guest_range();
+
+        !range.is_empty()
+            && !other_range.is_empty()
+            && range.start < other_range.end
+            && other_range.start < range.end
+    }
+}
+
+/// Represent a compact record of a mapping, suitable for external storage.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MappingRecord {
+    /// The guest address of the region.
+    pub address: hv_ipa_t,
+
+    /// The size of the region.
+    pub size: u64,
+
+    /// The raw value of the allocation handle associated to this mapping.
+    pub allocation_handle: u64,
+
+    /// The memory permission of the region, as returned by [MemoryPermission::bits].
+    pub permission: u8,
+}
+
+impl MappingRecord {
+    /// Gets the memory permission of the region.
+    ///
+    /// Returns [None] if the permission bitmask is invalid (see [MemoryPermission::from_bits]).
+    pub fn memory_permission(&self) -> Option<MemoryPermission> {
+        MemoryPermission::from_bits(self.permission)
+    }
+}
+
+impl From<&VirtualMachineMapping> for MappingRecord {
+    fn from(value: &VirtualMachineMapping) -> MappingRecord {
+        MappingRecord {
+            address: value.address,
+            size: value.size as u64,
+            allocation_handle: value.allocation_handle.0,
+            permission: value.permission.bits(),
+        }
+    }
+}
+
+/// Guest range of a host buffer mapped with [VirtualMachine::map_slice].
+#[derive(Clone, Debug)]
+struct SliceRegion {
+    /// The guest range.
+    range: Range<u128>,
+
+    /// The permission of the mapping.
+    permission: MemoryPermission,
+}
+
+/// Represent a mapping of a host buffer in a Virtual Machine, unmapped on drop.
+///
+/// The mapping borrows the buffer for its whole lifetime, the Virtual Machine stays usable meanwhile.
+/// It must not be leaked, see [VirtualMachine::map_slice].
+/// If the Virtual Machine is destroyed first, the region is unmapped with it and dropping the mapping does nothing.
+#[derive(Debug)]
+pub struct SliceMapping<'a> {
+    /// The guest address of the region.
+    address: hv_ipa_t,
+
+    /// The size of the region.
+    size: usize,
+
+    /// The slice regions of the Virtual Machine, shared to release the region on drop.
+    slice_regions: Rc<RefCell<Vec<SliceRegion>>>,
+
+    /// Ties the mapping lifetime to the buffer.
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> SliceMapping<'a> {
+    /// Gets the guest address of the region.
+    pub fn address(&self) -> hv_ipa_t {
+        self.address
+    }
+
+    /// Gets the size of the region.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl<'a> Drop for SliceMapping<'a> {
+    fn drop(&mut self) {
+        let start = u128::from(self.address);
+        let range = start..start + self.size as u128;
+
+        let mut slice_regions = self.slice_regions.borrow_mut();
+
+        // The region is gone if the Virtual Machine was destroyed, there is nothing left to unmap then.
+        if let Some(index) = slice_regions
+            .iter()
+            .position(|region| region.range == range)
+        {
+            slice_regions.swap_remove(index);
+
+            // Errors are ignored as they cannot be reported here.
+            unsafe {
+                hv_vm_unmap(self.address, self.size);
+            }
+        }
+    }
+}
+
 /// Represent an handle to an allocation.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct AllocationHandle(pub u64);
@@ -197,6 +651,11 @@ impl Counter {
 
         self.0
     }
+
+    /// Ensure the next values of the counter are greater than a given value.
+    pub fn skip_past(&mut self, value: u64) {
+        self.0 = core::cmp::max(self.0, value);
+    }
 }
 
 /// Represent a Virtual Machine allocation.
@@ -221,22 +680,137 @@ impl Drop for VirtualMachineAllocation {
 }
 
 /// The size of a page.
-pub const PAGE_SIZE: usize = 0x10000;
+///
+/// Allocations are aligned and padded to this size, which is a multiple of the host page size (16K on Apple Silicon) required by the Hypervisor.
+pub const PAGE_SIZE: usize = PAGE_SIZE_64K;
+
+/// The size of a 4K page.
+pub const PAGE_SIZE_4K: usize = 0x1000;
+
+/// The size of a 16K page.
+pub const PAGE_SIZE_16K: usize = 0x4000;
+
+/// The size of a 64K page.
+pub const PAGE_SIZE_64K: usize = 0x10000;
+
+/// Guest address of the code mapped by [VirtualMachine::run_snippet].
+pub const SNIPPET_CODE_ADDRESS: hv_ipa_t = 0x20000;
+
+/// Guest address of the stack mapped by [VirtualMachine::run_snippet].
+pub const SNIPPET_STACK_ADDRESS: hv_ipa_t = 0x1000_0000;
+
+/// CPSR used by [VirtualMachine::run_snippet] (EL1h with all interrupts masked).
+const SNIPPET_CPSR: u64 = 0x3c5;
+
+/// Gets the page size of the host, as required for the host memory mapped in the Virtual Machine.
+///
+/// Falls back to [PAGE_SIZE_16K] if the page size cannot be queried.
+pub fn host_page_size() -> usize {
+    let result = unsafe { sysconf(_SC_PAGESIZE) };
+
+    if result > 0 {
+        result as usize
+    } else {
+        PAGE_SIZE_16K
+    }
+}
 
 impl VirtualMachineAllocation {
-    /// Create a new allocation to use by the VirtualMachine.
-    pub fn new(size: usize) -> Self {
-        unsafe {
-            let layout = Layout::from_size_align(size, PAGE_SIZE)
-                .unwrap()
-                .pad_to_align();
-
-            VirtualMachineAllocation {
-                base_address: alloc::alloc::alloc_zeroed(layout),
-                layout,
-                handle: AllocationHandle(0),
-            }
+    /// Create a new allocation with a given alignment to use by the VirtualMachine.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the size padded to the alignment overflows,
+    /// and [HypervisorError::NoResources] if the host memory cannot be allocated.
+    pub fn with_alignment(size: usize, align: usize) -> Result<Self> {
+        debug_assert!(
+            align % host_page_size() == 0,
+            "Allocation alignment doesn't satisfy the host page size!"
+        );
+        debug_assert!(size != 0, "Allocation size is zero!");
+
+        let layout = Layout::from_size_align(size, align)
+            .map_err(|_| HypervisorError::BadArgument)?
+            .pad_to_align();
+
+        let base_address = unsafe { alloc::alloc::alloc_zeroed(layout) };
+
+        if base_address.is_null() {
+            return Err(HypervisorError::NoResources);
         }
+
+        Ok(VirtualMachineAllocation {
+            base_address,
+            layout,
+            handle: AllocationHandle(0),
+        })
+    }
+}
+
+/// Set while a [HypervisorContext] is alive.
+static HYPERVISOR_CONTEXT_ACQUIRED: AtomicBool = AtomicBool::new(false);
+
+/// Maximum number of vCPUs supported by the Hypervisor, queried when the Virtual Machine is created (0 when unknown).
+static MAX_VCPU_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Number of vCPUs alive in the process.
+static ACTIVE_VCPU_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Reservation of a vCPU in [ACTIVE_VCPU_COUNT], released on drop.
+#[derive(Debug)]
+struct VcpuSlot;
+
+impl VcpuSlot {
+    /// Reserve a vCPU, returns [HypervisorError::MaxVcpusReached] if [MAX_VCPU_COUNT] vCPUs are already reserved.
+    fn reserve() -> Result<VcpuSlot> {
+        let max_vcpu_count = MAX_VCPU_COUNT.load(Ordering::SeqCst);
+
+        ACTIVE_VCPU_COUNT
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if max_vcpu_count == 0 || count < max_vcpu_count {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| HypervisorError::MaxVcpusReached(max_vcpu_count))?;
+
+        Ok(VcpuSlot)
+    }
+}
+
+impl Drop for VcpuSlot {
+    fn drop(&mut self) {
+        ACTIVE_VCPU_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Represent the exclusive right to create the Virtual Machine of the process.
+///
+/// The Hypervisor only supports one Virtual Machine per process, as such only one context can be alive at a time.
+///
+/// A Virtual Machine takes ownership of its context (see [VirtualMachine::from_context]) instead of borrowing it:
+/// this releases the context exactly when the Virtual Machine is destroyed, without adding a lifetime to [VirtualMachine].
+#[derive(Debug)]
+pub struct HypervisorContext {
+    /// Prevent creating a context without acquiring it.
+    _private: (),
+}
+
+impl HypervisorContext {
+    /// Acquire the context of the process.
+    ///
+    /// Returns [HypervisorError::Busy] if the context is already held (by the user or by a living [VirtualMachine]).
+    pub fn acquire() -> Result<Self> {
+        HYPERVISOR_CONTEXT_ACQUIRED
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| HypervisorError::Busy)?;
+
+        Ok(HypervisorContext { _private: () })
+    }
+}
+
+impl Drop for HypervisorContext {
+    fn drop(&mut self) {
+        HYPERVISOR_CONTEXT_ACQUIRED.store(false, Ordering::SeqCst);
     }
 }
 
@@ -254,30 +828,176 @@ pub struct VirtualMachine {
 
     /// List of all mappings.
     mapping_list: Vec<VirtualMachineMapping>,
+
+    /// List of all guard regions.
+    guard_regions: Vec<GuardRegion>,
+
+    /// List of all emulated MMIO regions.
+    mmio_regions: Vec<MmioRegion>,
+
+    /// Guest address ranges of the host buffers mapped with [VirtualMachine::map_slice].
+    slice_regions: Rc<RefCell<Vec<SliceRegion>>>,
+
+    /// Set when dirty page tracking is enabled.
+    is_dirty_tracking_enabled: bool,
+
+    /// Guest addresses of the pages written since dirty page tracking was enabled.
+    dirty_pages: Vec<hv_ipa_t>,
+
+    /// Audit log of the mapping permission changes, None when disabled.
+    audit_log: Option<Vec<AuditEntry>>,
+
+    /// Maximum number of entries kept in the audit log.
+    audit_log_capacity: usize,
+
+    /// Maximum amount of host memory committed by allocations, None when unlimited.
+    memory_limit: Option<usize>,
+
+    /// Set once the Virtual Machine has been torn down.
+    is_destroyed: bool,
+
+    /// The context of the process, released after the Virtual Machine is destroyed.
+    _context: HypervisorContext,
 }
 
 impl VirtualMachine {
     /// Create a new Virtual Machine instance
     ///
-    /// **There should be only one instance living in the same process.**
+    /// The [HypervisorContext] is acquired implicitly, use [VirtualMachine::from_context] to create the Virtual Machine from a context acquired beforehand.
+    ///
+    /// Returns [HypervisorError::Busy] if another instance is living in the same process.
     pub fn new(config: Option<VirtualMachineConfiguration>) -> Result<Self> {
+        VirtualMachine::from_context(HypervisorContext::acquire()?, config)
+    }
+
+    /// Create a new Virtual Machine instance with ``ram_size`` bytes of RAM mapped as [MemoryPermission::READ_WRITE_EXECUTE] at ``ram_base``.
+    ///
+    /// This is a convenience over [VirtualMachine::new], [VirtualMachine::allocate] and [VirtualMachine::map], the RAM size is thus padded to [PAGE_SIZE].
+    /// The Virtual Machine is destroyed if any of those steps fail.
+    ///
+    /// Returns the Virtual Machine and the handle of the RAM mapping.
+    pub fn with_flat_ram(ram_base: hv_ipa_t, ram_size: usize) -> Result<(Self, MappingHandle)> {
+        let mut virtual_machine = VirtualMachine::new(None)?;
+
+        let allocation_handle = virtual_machine.allocate(ram_size)?;
+        let mapping_handle = virtual_machine.map(
+            allocation_handle,
+            ram_base,
+            MemoryPermission::READ_WRITE_EXECUTE,
+        )?;
+
+        Ok((virtual_machine, mapping_handle))
+    }
+
+    /// Create a new Virtual Machine instance from an already acquired context.
+    ///
+    /// The context is released once the Virtual Machine is destroyed.
+    pub fn from_context(
+        context: HypervisorContext,
+        config: Option<VirtualMachineConfiguration>,
+    ) -> Result<Self> {
         let handle: hv_vm_config_t = config
             .map(|value| value.handle)
             .unwrap_or(core::ptr::null_mut());
 
         let ret = unsafe { hv_vm_create(handle) };
 
-        convert_hv_return(ret).map(|_| VirtualMachine {
+        convert_hv_return(ret)?;
+
+        let mut max_vcpu_count = 0;
+
+        let ret = unsafe { hv_vm_get_max_vcpu_count(&mut max_vcpu_count) };
+
+        // Don't enforce any limit if it cannot be queried.
+        if convert_hv_return(ret).is_err() {
+            max_vcpu_count = 0;
+        }
+
+        MAX_VCPU_COUNT.store(max_vcpu_count, Ordering::SeqCst);
+
+        Ok(VirtualMachine {
             allocation_counter: Counter::default(),
             mapping_counter: Counter::default(),
             allocation_list: Vec::new(),
             mapping_list: Vec::new(),
+            guard_regions: Vec::new(),
+            mmio_regions: Vec::new(),
+            slice_regions: Rc::default(),
+            is_dirty_tracking_enabled: false,
+            dirty_pages: Vec::new(),
+            audit_log: None,
+            audit_log_capacity: DEFAULT_AUDIT_LOG_CAPACITY,
+            memory_limit: None,
+            is_destroyed: false,
+            _context: context,
         })
     }
 
+    /// Gets the amount of host memory committed by the allocations, including their padding.
+    ///
+    /// This is distinct from the amount of guest memory mapped, as an allocation can be mapped multiple times or not at all.
+    pub fn committed_host_bytes(&self) -> usize {
+        self.allocation_list
+            .iter()
+            .map(|allocation| allocation.layout.size())
+            .sum()
+    }
+
+    /// Sets the maximum amount of host memory committed by allocations (see [VirtualMachine::committed_host_bytes]), None to remove the limit.
+    ///
+    /// Allocations exceeding the limit fail with [HypervisorError::NoResources]. Existing allocations are kept even if they exceed a new limit.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// Gets the maximum amount of host memory committed by allocations, None when unlimited.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Ensure an allocation of a given size and alignment stays within the memory limit.
+    fn check_memory_limit(&self, size: usize, align: usize) -> Result<()> {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let size = Layout::from_size_align(size, align)
+            .map_err(|_| HypervisorError::BadArgument)?
+            .pad_to_align()
+            .size();
+
+        match self.committed_host_bytes().checked_add(size) {
+            Some(total) if total <= limit => Ok(()),
+            _ => Err(HypervisorError::NoResources),
+        }
+    }
+
     /// Create a new allocation that can be used in the Virtual Machine.
+    ///
+    /// The size must be at least one byte (it is then padded to [PAGE_SIZE]) and must not overflow once padded, otherwise [HypervisorError::BadArgument] is returned.
+    /// Returns [HypervisorError::NoResources] if the allocation exceeds the memory limit (see [VirtualMachine::set_memory_limit]) or the host is out of memory.
     pub fn allocate(&mut self, size: usize) -> Result<AllocationHandle> {
-        let mut allocation = VirtualMachineAllocation::new(size);
+        self.allocate_aligned(size, PAGE_SIZE)
+    }
+
+    /// Create a new allocation with a given alignment that can be used in the Virtual Machine.
+    ///
+    /// The alignment must be a power of two and a multiple of [PAGE_SIZE], otherwise [HypervisorError::BadArgument] is returned.
+    /// The size must be at least one byte (it is then padded to the alignment), otherwise [HypervisorError::BadArgument] is returned.
+    /// Returns [HypervisorError::NoResources] if the allocation exceeds the memory limit (see [VirtualMachine::set_memory_limit]) or the host is out of memory.
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Result<AllocationHandle> {
+        if size == 0 || !align.is_power_of_two() || align % PAGE_SIZE != 0 {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        if Layout::from_size_align(size, align).is_err() {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        self.check_memory_limit(size, align)?;
+
+        let mut allocation = VirtualMachineAllocation::with_alignment(size, align)?;
 
         let handle = AllocationHandle(self.allocation_counter.get_next_value());
 
@@ -289,6 +1009,8 @@ impl VirtualMachine {
     }
 
     /// Create a new allocation from data that can be used in the Virtual Machine.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the data is empty.
     pub fn allocate_from(&mut self, source: &[u8]) -> Result<AllocationHandle> {
         let allocation_handle = self.allocate(source.len())?;
 
@@ -302,6 +1024,44 @@ impl VirtualMachine {
         }
     }
 
+    /// Create a new zeroed allocation of ``total_size`` bytes that can be used in the Virtual Machine, filled with data segments.
+    ///
+    /// Each segment is made of an offset in the allocation and its data.
+    /// Returns [HypervisorError::BadArgument] if a segment exceeds ``total_size`` or overlaps with another segment, or if ``total_size`` is zero.
+    pub fn allocate_from_segments(
+        &mut self,
+        total_size: usize,
+        segments: &[(usize, &[u8])],
+    ) -> Result<AllocationHandle> {
+        let mut ranges = Vec::with_capacity(segments.len());
+
+        for (offset, data) in segments {
+            let end = offset
+                .checked_add(data.len())
+                .filter(|end| *end <= total_size)
+                .ok_or(HypervisorError::BadArgument)?;
+
+            if !data.is_empty() {
+                ranges.push(*offset..end);
+            }
+        }
+
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let allocation_handle = self.allocate(total_size)?;
+        let destination = self.get_allocation_slice_mut(allocation_handle)?;
+
+        for (offset, data) in segments {
+            destination[*offset..*offset + data.len()].copy_from_slice(data);
+        }
+
+        Ok(allocation_handle)
+    }
+
     /// Find an allocation by handle.
     fn find_allocation_by_handle(
         &self,
@@ -332,7 +1092,7 @@ impl VirtualMachine {
 
     /// Check if the given allocation handle is mapped.
     fn is_allocation_mapped(&self, handle: AllocationHandle) -> bool {
-        for (_, entry) in self.mapping_list.iter().enumerate() {
+        for entry in &self.mapping_list {
             if entry.allocation_handle == handle {
                 return true;
             }
@@ -357,6 +1117,34 @@ impl VirtualMachine {
         Ok(())
     }
 
+    /// Unmap every mapping of an allocation and destroy it.
+    ///
+    /// If unmapping fails, the error is returned and the allocation isn't destroyed (mappings already unmapped stay unmapped).
+    pub fn force_deallocate(&mut self, allocation_handle: AllocationHandle) -> Result<()> {
+        // Ensure the allocation exists before unmapping anything.
+        self.find_allocation_by_handle(allocation_handle)?;
+
+        let mapping_handles: Vec<MappingHandle> = self
+            .mapping_list
+            .iter()
+            .filter(|mapping| mapping.allocation_handle == allocation_handle)
+            .map(|mapping| mapping.mapping_handle)
+            .collect();
+
+        for mapping_handle in mapping_handles {
+            self.unmap(mapping_handle)?;
+        }
+
+        self.deallocate(allocation_handle)
+    }
+
+    /// Gets the size of an allocation once mapped, which is the requested size padded to [PAGE_SIZE].
+    pub fn allocation_mapped_size(&self, allocation_handle: AllocationHandle) -> Result<usize> {
+        let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
+
+        Ok(allocation.layout.size())
+    }
+
     /// Gets a slice to an allocation with its handle.
     pub fn get_allocation_slice(&self, allocation_handle: AllocationHandle) -> Result<&[u8]> {
         let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
@@ -368,26 +1156,115 @@ impl VirtualMachine {
         Ok(slice)
     }
 
-    /// Gets a mutable slice to an allocation with its handle.
-    pub fn get_allocation_slice_mut(
-        &mut self,
-        allocation_handle: AllocationHandle,
-    ) -> Result<&mut [u8]> {
+    /// Touch every host page of an allocation so that it's resident before the guest accesses it.
+    ///
+    /// This avoids the latency of the first access to each page inside the guest, at the cost of committing the whole allocation in host memory right away.
+    /// The content of the allocation is left untouched, even if a running vCPU writes to it concurrently.
+    pub fn prefault_allocation(&mut self, allocation_handle: AllocationHandle) -> Result<()> {
         let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
 
-        let slice = unsafe {
-            core::slice::from_raw_parts_mut(allocation.base_address, allocation.layout.size())
-        };
+        let page_size = host_page_size();
 
-        Ok(slice)
+        for offset in (0..allocation.layout.size()).step_by(page_size) {
+            let byte = unsafe { &*(allocation.base_address.add(offset) as *const AtomicU8) };
+
+            // Atomically OR zero to fault the page as writable without changing it, a guest write can't be lost in between.
+            byte.fetch_or(0, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Gets a slice to the host memory backing a mapping with its handle.
+    ///
+    /// The slice covers exactly the mapped range: as mappings always start at the beginning of their allocation, it is bounded by the mapping size.
+    pub fn mapping_host_slice(&self, mapping_handle: MappingHandle) -> Result<&[u8]> {
+        let (_, mapping) = self.find_mapping_by_handle(mapping_handle)?;
+        let (_, allocation) = self.find_allocation_by_handle(mapping.allocation_handle)?;
+
+        let size = mapping.size.min(allocation.layout.size());
+
+        let slice = unsafe { core::slice::from_raw_parts(allocation.base_address, size) };
+
+        Ok(slice)
+    }
+
+    /// Gets a mutable slice to an allocation with its handle.
+    pub fn get_allocation_slice_mut(
+        &mut self,
+        allocation_handle: AllocationHandle,
+    ) -> Result<&mut [u8]> {
+        let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
+
+        let slice = unsafe {
+            core::slice::from_raw_parts_mut(allocation.base_address, allocation.layout.size())
+        };
+
+        Ok(slice)
+    }
+
+    /// Gets the host address backing an allocation with its handle.
+    ///
+    /// This is meant for zero-copy interoperability with other APIs.
+    ///
+    /// # Safety
+    ///
+    /// The allocation is owned by the Virtual Machine: the returned pointer is only valid until the allocation is deallocated
+    /// or the Virtual Machine is dropped, and it is only valid for ``get_allocation_slice(handle)?.len()`` bytes.
+    /// The caller must also ensure that accesses through it don't alias with slices obtained from [VirtualMachine::get_allocation_slice_mut].
+    pub unsafe fn allocation_host_ptr(
+        &self,
+        allocation_handle: AllocationHandle,
+    ) -> Result<*mut u8> {
+        let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
+
+        Ok(allocation.base_address)
     }
 
     /// Map an allocation in the Virtual Machine.
+    ///
+    /// The whole allocation is mapped, including the padding up to [PAGE_SIZE] (see [VirtualMachine::allocation_mapped_size]).
+    ///
+    /// Returns [HypervisorError::BadArgument] if the mapping overlaps with a guard region, an MMIO region or a mapped host buffer,
+    /// and [HypervisorError::NoResources] if the mapping cannot be tracked, in which case nothing is mapped.
     pub fn map(
         &mut self,
         allocation_handle: AllocationHandle,
         guest_address: hv_ipa_t,
         permission: MemoryPermission,
+    ) -> Result<MappingHandle> {
+        self.map_internal(allocation_handle, guest_address, permission, None)
+    }
+
+    /// Map an allocation in the Virtual Machine with a given mapping handle.
+    ///
+    /// Returns [HypervisorError::InvalidHandle] if the handle is already used by another mapping.
+    /// Handles returned by [VirtualMachine::map] afterward never collide with it.
+    pub fn map_with_id(
+        &mut self,
+        allocation_handle: AllocationHandle,
+        guest_address: hv_ipa_t,
+        permission: MemoryPermission,
+        desired: MappingHandle,
+    ) -> Result<()> {
+        if self.find_mapping_by_handle(desired).is_ok() {
+            return Err(HypervisorError::InvalidHandle);
+        }
+
+        self.map_internal(allocation_handle, guest_address, permission, Some(desired))?;
+
+        self.mapping_counter.skip_past(desired.0);
+
+        Ok(())
+    }
+
+    /// Map an allocation in the Virtual Machine, using the given mapping handle or a new one.
+    fn map_internal(
+        &mut self,
+        allocation_handle: AllocationHandle,
+        guest_address: hv_ipa_t,
+        permission: MemoryPermission,
+        mapping_handle: Option<MappingHandle>,
     ) -> Result<MappingHandle> {
         let (_, allocation) = self.find_allocation_by_handle(allocation_handle)?;
 
@@ -397,19 +1274,39 @@ impl VirtualMachine {
             return Err(HypervisorError::MisalignedAddress);
         }
 
-        let ret = unsafe {
-            hv_vm_map(
-                allocation.base_address as *mut c_void,
-                guest_address,
-                allocation_size,
-                hv_memory_flags_t::from(permission),
-            )
-        };
+        let guest_start = u128::from(guest_address);
 
-        // Ensure no error got reported
-        convert_hv_return(ret)?;
+        let guest_range = guest_start..guest_start + allocation_size as u128;
 
-        let mapping_handle = MappingHandle(self.mapping_counter.get_next_value());
+        if self.overlaps_guard_region(&guest_range)
+            || self.overlaps_mmio_region(&guest_range)
+            || self.overlaps_slice_region(&guest_range)
+        {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let base_address = allocation.base_address;
+        let mapping_list = &mut self.mapping_list;
+
+        map_with_rollback(
+            || {
+                let ret = unsafe {
+                    hv_vm_map(
+                        base_address as *mut c_void,
+                        guest_address,
+                        allocation_size,
+                        hv_memory_flags_t::from(permission),
+                    )
+                };
+
+                convert_hv_return(ret)
+            },
+            || mapping_list.try_reserve(1).is_ok(),
+            || convert_hv_return(unsafe { hv_vm_unmap(guest_address, allocation_size) }),
+        )?;
+
+        let mapping_handle =
+            mapping_handle.unwrap_or_else(|| MappingHandle(self.mapping_counter.get_next_value()));
 
         let virtual_mapping = VirtualMachineMapping {
             allocation_handle,
@@ -421,9 +1318,117 @@ impl VirtualMachine {
 
         self.mapping_list.push(virtual_mapping);
 
+        self.record_audit(AuditOperation::Map, mapping_handle, None, Some(permission));
+
         Ok(mapping_handle)
     }
 
+    /// Map a host buffer in the Virtual Machine.
+    ///
+    /// The buffer must be aligned on [PAGE_SIZE] and its size must be a non-zero multiple of [PAGE_SIZE], otherwise [HypervisorError::BadArgument] is returned.
+    /// [HypervisorError::BadArgument] is also returned if the region overlaps with a mapping, a guard region, an MMIO region or another mapped buffer.
+    ///
+    /// The region stays mapped until the returned [SliceMapping] is dropped or the Virtual Machine is destroyed.
+    /// As it isn't backed by an allocation of the Virtual Machine, it isn't reported by [VirtualMachine::get_all_mapping_infos].
+    ///
+    /// # Safety
+    ///
+    /// The borrow of the buffer ends when the [SliceMapping] is dropped, but also when it is leaked (with [core::mem::forget] or a reference cycle)
+    /// while the region stays mapped in the Hypervisor. The caller must ensure the returned [SliceMapping] is dropped,
+    /// or the Virtual Machine destroyed, before the buffer is freed or reused, otherwise the guest accesses freed host memory.
+    pub unsafe fn map_slice<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+        guest_address: hv_ipa_t,
+        permission: MemoryPermission,
+    ) -> Result<SliceMapping<'a>> {
+        if buffer.is_empty()
+            || buffer.len() % PAGE_SIZE != 0
+            || buffer.as_ptr() as usize % PAGE_SIZE != 0
+        {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        if guest_address % PAGE_SIZE as u64 != 0 {
+            return Err(HypervisorError::MisalignedAddress);
+        }
+
+        let guest_start = u128::from(guest_address);
+        let guest_range = guest_start..guest_start + buffer.len() as u128;
+
+        if self.overlaps_guard_region(&guest_range)
+            || self.overlaps_mmio_region(&guest_range)
+            || self.overlaps_slice_region(&guest_range)
+            || self
+                .mapping_list
+                .iter()
+                .any(|mapping| guard::ranges_overlap(&mapping.guest_range(), &guest_range))
+        {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        // Reserve the bookkeeping space first so the region cannot be leaked in the Hypervisor if the allocator fails.
+        if self.slice_regions.borrow_mut().try_reserve(1).is_err() {
+            return Err(HypervisorError::NoResources);
+        }
+
+        let ret = hv_vm_map(
+            buffer.as_mut_ptr() as *mut c_void,
+            guest_address,
+            buffer.len(),
+            hv_memory_flags_t::from(permission),
+        );
+
+        // Ensure no error got reported
+        convert_hv_return(ret)?;
+
+        self.slice_regions.borrow_mut().push(SliceRegion {
+            range: guest_range,
+            permission,
+        });
+
+        Ok(SliceMapping {
+            address: guest_address,
+            size: buffer.len(),
+            slice_regions: Rc::clone(&self.slice_regions),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Check if a guest address range overlaps with a host buffer mapped with [VirtualMachine::map_slice].
+    fn overlaps_slice_region(&self, range: &Range<u128>) -> bool {
+        self.slice_regions
+            .borrow()
+            .iter()
+            .any(|region| guard::ranges_overlap(&region.range, range))
+    }
+
+    /// Gets the permission of the host buffer mapped with [VirtualMachine::map_slice] containing a given guest address.
+    fn find_slice_permission(&self, ipa: hv_ipa_t) -> Option<MemoryPermission> {
+        self.slice_regions
+            .borrow()
+            .iter()
+            .find(|region| region.range.contains(&u128::from(ipa)))
+            .map(|region| region.permission)
+    }
+
+    /// Map an allocation in the Virtual Machine as described by a [MappingRecord].
+    ///
+    /// Returns [HypervisorError::BadArgument] if the permission bitmask of the record is invalid.
+    ///
+    /// **The allocation referenced by the record must exist in this Virtual Machine.**
+    pub fn map_record(&mut self, record: &MappingRecord) -> Result<MappingHandle> {
+        let permission = record
+            .memory_permission()
+            .ok_or(HypervisorError::BadArgument)?;
+
+        self.map(
+            AllocationHandle(record.allocation_handle),
+            record.address,
+            permission,
+        )
+    }
+
     /// Unmap a given mapping in the Virtual Machine.
     pub fn unmap(&mut self, mapping_handle: MappingHandle) -> Result<()> {
         let (index, mapping) = self.find_mapping_by_handle(mapping_handle)?;
@@ -433,12 +1438,37 @@ impl VirtualMachine {
         // Ensure no error got reported
         convert_hv_return(ret)?;
 
-        self.mapping_list.remove(index);
+        let mapping = self.mapping_list.remove(index);
+
+        self.release_guard_regions(mapping_handle);
+
+        self.record_audit(
+            AuditOperation::Unmap,
+            mapping_handle,
+            Some(mapping.permission),
+            None,
+        );
 
         Ok(())
     }
 
+    /// Unmap the mapping starting at a given guest address in the Virtual Machine.
+    ///
+    /// Returns [HypervisorError::InvalidHandle] if no mapping starts exactly at this address.
+    pub fn unmap_address(&mut self, guest_address: hv_ipa_t) -> Result<()> {
+        let mapping_handle = self
+            .mapping_list
+            .iter()
+            .find(|mapping| mapping.address == guest_address)
+            .map(|mapping| mapping.mapping_handle)
+            .ok_or(HypervisorError::InvalidHandle)?;
+
+        self.unmap(mapping_handle)
+    }
+
     /// Change memory permissions of a given mapping in the Virtual Machine.
+    ///
+    /// While dirty page tracking is enabled, a writable mapping stays tracked: its clean pages are kept write protected.
     pub fn reprotect(
         &mut self,
         mapping_handle: MappingHandle,
@@ -446,24 +1476,57 @@ impl VirtualMachine {
     ) -> Result<()> {
         let (index, mapping) = self.find_mapping_by_handle(mapping_handle)?;
 
-        let ret = unsafe {
-            hv_vm_protect(
-                mapping.address,
-                mapping.size,
-                hv_memory_flags_t::from(permission),
-            )
-        };
-
-        // Ensure no error got reported
-        convert_hv_return(ret)?;
+        self.protect_mapping_range(mapping.address, mapping.size, permission)?;
 
         let mapping = self
             .mapping_list
             .get_mut(index)
             .expect("Mapping disapeared in between! (TOUTOC????)");
 
+        let old_permission = mapping.permission;
+
         mapping.permission = permission;
 
+        self.record_audit(
+            AuditOperation::Reprotect,
+            mapping_handle,
+            Some(old_permission),
+            Some(permission),
+        );
+
+        Ok(())
+    }
+
+    /// Change memory permissions of every mapping of a given allocation in the Virtual Machine.
+    ///
+    /// Mappings are reprotected in mapping order and the first failure stops the operation, the mappings before it keep their new permission.
+    /// The error reports the mapping that failed, or no mapping if the allocation handle is invalid.
+    pub fn reprotect_allocation(
+        &mut self,
+        allocation_handle: AllocationHandle,
+        permission: MemoryPermission,
+    ) -> core::result::Result<(), ReprotectAllocationError> {
+        self.find_allocation_by_handle(allocation_handle)
+            .map_err(|error| ReprotectAllocationError {
+                mapping_handle: None,
+                error,
+            })?;
+
+        let mapping_handles: Vec<MappingHandle> = self
+            .mapping_list
+            .iter()
+            .filter(|mapping| mapping.allocation_handle == allocation_handle)
+            .map(|mapping| mapping.mapping_handle)
+            .collect();
+
+        for mapping_handle in mapping_handles {
+            self.reprotect(mapping_handle, permission)
+                .map_err(|error| ReprotectAllocationError {
+                    mapping_handle: Some(mapping_handle),
+                    error,
+                })?;
+        }
+
         Ok(())
     }
 
@@ -472,8 +1535,17 @@ impl VirtualMachine {
         VirtualCpuConfiguration::new()
     }
 
+    /// Gets the maximum number of vCPUs supported by the Hypervisor, queried when the Virtual Machine was created.
+    ///
+    /// Returns 0 if it couldn't be queried, in which case no limit is enforced by [VirtualMachine::create_vcpu].
+    pub fn max_vcpu_count(&self) -> u32 {
+        MAX_VCPU_COUNT.load(Ordering::SeqCst)
+    }
+
     /// Create a new vCPU.
     ///
+    /// Returns [HypervisorError::MaxVcpusReached] if [VirtualMachine::max_vcpu_count] vCPUs are already alive.
+    ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
     pub fn create_vcpu(
         &mut self,
@@ -483,16 +1555,94 @@ impl VirtualMachine {
             .map(|value| value.handle)
             .unwrap_or(core::ptr::null_mut());
 
-        let mut vcpu_handle: hv_vcpu_t = 0;
-        let mut vcpu_exit: *const hv_vcpu_exit_t = core::ptr::null_mut();
+        VirtualCpu::new(handle)
+    }
+
+    /// Run a piece of code once on a new vCPU.
+    ///
+    /// The code is mapped as [MemoryPermission::READ_EXECUTE] at [SNIPPET_CODE_ADDRESS] and a stack of ``stack_size`` bytes
+    /// is mapped as [MemoryPermission::READ_WRITE] at [SNIPPET_STACK_ADDRESS]. The vCPU starts at EL1h with all interrupts masked,
+    /// PC pointing to the code and SP_EL1 pointing to the end of the stack.
+    ///
+    /// The mappings are left in the Virtual Machine and the vCPU is returned alongside its exit so the state can be inspected.
+    /// As the mappings use fixed guest addresses, this can only be called once per Virtual Machine unless they are unmapped.
+    /// If any step fails, the mappings and allocations already created are removed.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the code or the stack is empty, if the code overlaps the stack
+    /// or if the mappings of a previous call are still present.
+    ///
+    /// **This creates the vCPU in the calling thread, it must thus be used from that thread.**
+    pub fn run_snippet(
+        &mut self,
+        code: &[u8],
+        stack_size: usize,
+    ) -> Result<(VirtualCpu, VirtualCpuExitReason)> {
+        let mut allocations = Vec::new();
+        let mut mappings = Vec::new();
+
+        let result = self.run_snippet_steps(code, stack_size, &mut allocations, &mut mappings);
+
+        if result.is_err() {
+            // Errors are ignored as the one that caused the rollback is reported.
+            for mapping_handle in mappings {
+                let _ = self.unmap(mapping_handle);
+            }
 
-        let ret = unsafe { hv_vcpu_create(&mut vcpu_handle, &mut vcpu_exit, &handle) };
+            for allocation_handle in allocations {
+                let _ = self.deallocate(allocation_handle);
+            }
+        }
 
-        convert_hv_return(ret).map(|_| VirtualCpu {
-            _not_send_marker: PhantomData,
-            handle: vcpu_handle,
-            vcpu_exit,
-        })
+        result
+    }
+
+    /// Do the steps of [VirtualMachine::run_snippet], recording the allocations and mappings created so they can be undone.
+    fn run_snippet_steps(
+        &mut self,
+        code: &[u8],
+        stack_size: usize,
+        allocations: &mut Vec<AllocationHandle>,
+        mappings: &mut Vec<MappingHandle>,
+    ) -> Result<(VirtualCpu, VirtualCpuExitReason)> {
+        if code.len() as u64 > SNIPPET_STACK_ADDRESS - SNIPPET_CODE_ADDRESS
+            || self.find_mapping_by_address(SNIPPET_CODE_ADDRESS).is_some()
+            || self
+                .find_mapping_by_address(SNIPPET_STACK_ADDRESS)
+                .is_some()
+        {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let code_allocation_handle = self.allocate_from(code)?;
+        allocations.push(code_allocation_handle);
+
+        mappings.push(self.map(
+            code_allocation_handle,
+            SNIPPET_CODE_ADDRESS,
+            MemoryPermission::READ_EXECUTE,
+        )?);
+
+        let stack_allocation_handle = self.allocate(stack_size)?;
+        allocations.push(stack_allocation_handle);
+
+        mappings.push(self.map(
+            stack_allocation_handle,
+            SNIPPET_STACK_ADDRESS,
+            MemoryPermission::READ_WRITE,
+        )?);
+
+        let stack_top =
+            SNIPPET_STACK_ADDRESS + self.allocation_mapped_size(stack_allocation_handle)? as u64;
+
+        let mut vcpu = self.create_vcpu(None)?;
+
+        vcpu.set_register(Register::CPSR, SNIPPET_CPSR)?;
+        vcpu.set_register(Register::PC, SNIPPET_CODE_ADDRESS)?;
+        vcpu.set_system_register(SystemRegister::SP_EL1, stack_top)?;
+
+        let reason = vcpu.run()?;
+
+        Ok((vcpu, reason))
     }
 
     /// Exits given vCPUs.
@@ -502,28 +1652,340 @@ impl VirtualMachine {
         convert_hv_return(ret)
     }
 
+    /// Exits given vCPUs one by one, reporting the result for each vCPU.
+    ///
+    /// Unlike [VirtualMachine::exit_vcpus], a failure on one vCPU (for example an already destroyed one) does not hide the outcome of the others.
+    /// As every vCPU is exited with a separate call, the vCPUs are not all requested to exit at the same time.
+    pub fn exit_vcpus_detailed(&mut self, vcpus: &[hv_vcpu_t]) -> Vec<(hv_vcpu_t, Result<()>)> {
+        vcpus
+            .iter()
+            .map(|vcpu| {
+                let ret = unsafe { hv_vcpus_exit(vcpu, 1) };
+
+                (*vcpu, convert_hv_return(ret))
+            })
+            .collect()
+    }
+
     /// Gets the information about a mapping from its handle.
     pub fn get_mapping_info(&self, mapping_handle: MappingHandle) -> Result<VirtualMachineMapping> {
         self.find_mapping_by_handle(mapping_handle)
             .map(|(_, value)| *value)
     }
 
+    /// Gets the information about the mapping containing a given guest address.
+    ///
+    /// Returns [HypervisorError::InvalidHandle] if the address isn't mapped.
+    pub fn get_mapping_info_at(&self, ipa: hv_ipa_t) -> Result<VirtualMachineMapping> {
+        self.find_mapping_by_address(ipa)
+            .copied()
+            .ok_or(HypervisorError::InvalidHandle)
+    }
+
     /// Get a list of all mapping informations.
     pub fn get_all_mapping_infos(&self) -> Vec<VirtualMachineMapping> {
         self.mapping_list.clone()
     }
-}
 
-impl Drop for VirtualMachine {
-    fn drop(&mut self) {
+    /// Copy the content of every mapped region of the guest.
+    ///
+    /// Each entry contains the guest address of a mapping and a copy of its content.
+    pub fn dump_guest_memory(&self) -> Vec<(hv_ipa_t, Vec<u8>)> {
+        let mut result = Vec::with_capacity(self.mapping_list.len());
+
+        for mapping in &self.mapping_list {
+            let slice = self
+                .get_allocation_slice(mapping.allocation_handle)
+                .expect("Mapping without allocation! (BUG)");
+
+            result.push((mapping.address, slice[..mapping.size].to_vec()));
+        }
+
+        result
+    }
+
+    /// Restore the content of mapped regions of the guest previously obtained with [VirtualMachine::dump_guest_memory].
+    ///
+    /// **Every region must start at the guest address of an existing mapping and cannot be bigger than it.**
+    /// Otherwise nothing is restored and [HypervisorError::UnmappedAddress] is returned if the mapping of a region no longer exists,
+    /// or [HypervisorError::BadArgument] if a region is bigger than its mapping.
+    pub fn restore_guest_memory(&mut self, regions: &[(hv_ipa_t, Vec<u8>)]) -> Result<()> {
+        // Ensure every region is valid before touching anything.
+        for (address, data) in regions {
+            let mapping = self
+                .mapping_list
+                .iter()
+                .find(|mapping| mapping.address == *address)
+                .ok_or(HypervisorError::UnmappedAddress)?;
+
+            if data.len() > mapping.size {
+                return Err(HypervisorError::BadArgument);
+            }
+        }
+
+        for (address, data) in regions {
+            let allocation_handle = self
+                .mapping_list
+                .iter()
+                .find(|mapping| mapping.address == *address)
+                .map(|mapping| mapping.allocation_handle)
+                .ok_or(HypervisorError::UnmappedAddress)?;
+
+            let destination = self.get_allocation_slice_mut(allocation_handle)?;
+            destination[..data.len()].copy_from_slice(data);
+        }
+
+        Ok(())
+    }
+
+    /// Find the mapping containing a given guest address.
+    fn find_mapping_by_address(&self, ipa: hv_ipa_t) -> Option<&VirtualMachineMapping> {
+        self.mapping_list
+            .iter()
+            .find(|mapping| mapping.contains_address(ipa))
+    }
+
+    /// Split a range of guest memory into chunks of contiguous host memory.
+    ///
+    /// Each chunk is made of the allocation handle, the offset inside the allocation and the size of the chunk.
+    fn guest_memory_chunks(
+        &self,
+        guest_address: hv_ipa_t,
+        size: usize,
+    ) -> Result<Vec<(AllocationHandle, usize, usize)>> {
+        let mut result = Vec::new();
+        let mut offset = 0;
+
+        while offset < size {
+            let address = guest_address
+                .checked_add(offset as u64)
+                .ok_or(HypervisorError::UnmappedAddress)?;
+
+            let mapping = self
+                .find_mapping_by_address(address)
+                .ok_or(HypervisorError::UnmappedAddress)?;
+
+            let mapping_offset = (address - mapping.address) as usize;
+            let chunk_size = core::cmp::min(mapping.size - mapping_offset, size - offset);
+
+            result.push((mapping.allocation_handle, mapping_offset, chunk_size));
+
+            offset += chunk_size;
+        }
+
+        Ok(result)
+    }
+
+    /// Check if every byte of a guest address range is mapped, possibly across multiple contiguous mappings.
+    ///
+    /// An empty range is always considered mapped.
+    pub fn is_range_mapped(&self, ipa: hv_ipa_t, size: usize) -> bool {
+        self.guest_memory_chunks(ipa, size).is_ok()
+    }
+
+    /// Read guest memory at a given guest address.
+    ///
+    /// The range can span multiple contiguous mappings, [HypervisorError::UnmappedAddress] is returned if any part of it isn't mapped.
+    pub fn read_guest(&self, guest_address: hv_ipa_t, buffer: &mut [u8]) -> Result<()> {
+        let mut offset = 0;
+
+        for (allocation_handle, chunk_offset, chunk_size) in
+            self.guest_memory_chunks(guest_address, buffer.len())?
+        {
+            let source = self.get_allocation_slice(allocation_handle)?;
+
+            buffer[offset..offset + chunk_size]
+                .copy_from_slice(&source[chunk_offset..chunk_offset + chunk_size]);
+
+            offset += chunk_size;
+        }
+
+        Ok(())
+    }
+
+    /// Write guest memory at a given guest address.
+    ///
+    /// The range can span multiple contiguous mappings, [HypervisorError::UnmappedAddress] is returned if any part of it isn't mapped.
+    /// Nothing is written in this case.
+    pub fn write_guest(&mut self, guest_address: hv_ipa_t, buffer: &[u8]) -> Result<()> {
+        let mut offset = 0;
+
+        for (allocation_handle, chunk_offset, chunk_size) in
+            self.guest_memory_chunks(guest_address, buffer.len())?
+        {
+            let destination = self.get_allocation_slice_mut(allocation_handle)?;
+
+            destination[chunk_offset..chunk_offset + chunk_size]
+                .copy_from_slice(&buffer[offset..offset + chunk_size]);
+
+            offset += chunk_size;
+        }
+
+        Ok(())
+    }
+
+    /// Read a little-endian ``u8`` at a given guest address.
+    pub fn read_u8(&self, ipa: hv_ipa_t) -> Result<u8> {
+        let mut buffer = [0; core::mem::size_of::<u8>()];
+
+        self.read_guest(ipa, &mut buffer)?;
+
+        Ok(u8::from_le_bytes(buffer))
+    }
+
+    /// Read a little-endian ``u16`` at a given guest address.
+    pub fn read_u16(&self, ipa: hv_ipa_t) -> Result<u16> {
+        let mut buffer = [0; core::mem::size_of::<u16>()];
+
+        self.read_guest(ipa, &mut buffer)?;
+
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    /// Read a little-endian ``u32`` at a given guest address.
+    pub fn read_u32(&self, ipa: hv_ipa_t) -> Result<u32> {
+        let mut buffer = [0; core::mem::size_of::<u32>()];
+
+        self.read_guest(ipa, &mut buffer)?;
+
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    /// Read a little-endian ``u64`` at a given guest address.
+    pub fn read_u64(&self, ipa: hv_ipa_t) -> Result<u64> {
+        let mut buffer = [0; core::mem::size_of::<u64>()];
+
+        self.read_guest(ipa, &mut buffer)?;
+
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    /// Write a little-endian ``u8`` at a given guest address.
+    pub fn write_u8(&mut self, ipa: hv_ipa_t, value: u8) -> Result<()> {
+        self.write_guest(ipa, &value.to_le_bytes())
+    }
+
+    /// Write a little-endian ``u16`` at a given guest address.
+    pub fn write_u16(&mut self, ipa: hv_ipa_t, value: u16) -> Result<()> {
+        self.write_guest(ipa, &value.to_le_bytes())
+    }
+
+    /// Write a little-endian ``u32`` at a given guest address.
+    pub fn write_u32(&mut self, ipa: hv_ipa_t, value: u32) -> Result<()> {
+        self.write_guest(ipa, &value.to_le_bytes())
+    }
+
+    /// Write a little-endian ``u64`` at a given guest address.
+    pub fn write_u64(&mut self, ipa: hv_ipa_t, value: u64) -> Result<()> {
+        self.write_guest(ipa, &value.to_le_bytes())
+    }
+
+    /// Fill guest memory at a given guest address with a byte value.
+    ///
+    /// The range can span multiple contiguous mappings, [HypervisorError::UnmappedAddress] is returned if any part of it isn't mapped.
+    /// Nothing is written in this case.
+    pub fn fill_guest(&mut self, ipa: hv_ipa_t, byte: u8, size: usize) -> Result<()> {
+        for (allocation_handle, chunk_offset, chunk_size) in self.guest_memory_chunks(ipa, size)? {
+            let destination = self.get_allocation_slice_mut(allocation_handle)?;
+
+            destination[chunk_offset..chunk_offset + chunk_size].fill(byte);
+        }
+
+        Ok(())
+    }
+
+    /// Compute a checksum of guest memory at a given guest address, meant to cheaply compare memory between runs.
+    ///
+    /// The checksum is a 64-bit FNV-1a hash and isn't suitable for cryptographic usages.
+    /// The range can span multiple contiguous mappings, [HypervisorError::UnmappedAddress] is returned if any part of it isn't mapped.
+    pub fn checksum_region(&self, ipa: hv_ipa_t, size: usize) -> Result<u64> {
+        /// FNV-1a 64-bit offset basis.
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+        /// FNV-1a 64-bit prime.
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let mut hash = FNV_OFFSET_BASIS;
+
+        for (allocation_handle, chunk_offset, chunk_size) in self.guest_memory_chunks(ipa, size)? {
+            let source = self.get_allocation_slice(allocation_handle)?;
+
+            for byte in &source[chunk_offset..chunk_offset + chunk_size] {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Unmap everything and destroy the Virtual Machine, returning every error encountered.
+    fn teardown(&mut self) -> Vec<HypervisorError> {
+        let mut errors = Vec::new();
+
         for mapping in self.get_all_mapping_infos() {
-            self.unmap(mapping.mapping_handle)
-                .expect("Cannot unmap memory on VM drop!");
+            if let Err(error) = self.unmap(mapping.mapping_handle) {
+                errors.push(error);
+            }
+        }
+
+        // Outliving slice mappings must not unmap anything once the Virtual Machine is gone.
+        for region in self.slice_regions.borrow_mut().drain(..) {
+            let ret = unsafe {
+                hv_vm_unmap(
+                    region.range.start as hv_ipa_t,
+                    (region.range.end - region.range.start) as usize,
+                )
+            };
+
+            if let Err(error) = convert_hv_return(ret) {
+                errors.push(error);
+            }
         }
 
         let ret = unsafe { hv_vm_destroy() };
 
-        convert_hv_return(ret).expect("Cannot destroy VM on drop!");
+        if let Err(error) = convert_hv_return(ret) {
+            errors.push(error);
+        }
+
+        // The limit is queried again by the next Virtual Machine.
+        MAX_VCPU_COUNT.store(0, Ordering::SeqCst);
+
+        self.is_destroyed = true;
+
+        errors
+    }
+
+    /// Destroy the Virtual Machine, reporting all errors that happened during the teardown.
+    ///
+    /// Dropping the Virtual Machine cannot report those errors, use this to observe them.
+    /// Allocations are released in all cases.
+    pub fn try_drop(mut self) -> core::result::Result<(), Vec<HypervisorError>> {
+        let errors = self.teardown();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Drop for VirtualMachine {
+    /// Destroy the Virtual Machine.
+    ///
+    /// Errors cannot be reported here and only fail a debug assertion, use [VirtualMachine::try_drop] to observe them.
+    fn drop(&mut self) {
+        if !self.is_destroyed {
+            let errors = self.teardown();
+
+            debug_assert!(
+                errors.is_empty(),
+                "Virtual Machine teardown failed: {:?}",
+                errors
+            );
+        }
     }
 }
 
@@ -621,6 +2083,22 @@ impl VirtualCpuConfiguration {
         }
     }
 
+    /// Create a new vCPU configuration identical to this one.
+    ///
+    /// The Hypervisor doesn't provide any way to alter a vCPU configuration after its creation:
+    /// feature registers and cache informations only depend on the host, so the clone reports the same values.
+    ///
+    /// Returns [HypervisorError::NoResources] if the Hypervisor cannot create a new configuration.
+    pub fn try_clone(&self) -> Result<VirtualCpuConfiguration> {
+        let handle = unsafe { hv_vcpu_config_create() };
+
+        if handle.is_null() {
+            return Err(HypervisorError::NoResources);
+        }
+
+        Ok(VirtualCpuConfiguration { handle })
+    }
+
     /// Return value of a feature register.
     pub fn get_feature_register(&self, feature_register: FeatureRegister) -> Result<u64> {
         let mut result = 0;
@@ -786,6 +2264,154 @@ pub enum Register {
     CPSR,
 }
 
+impl Register {
+    /// All the registers.
+    pub const ALL: [Register; 37] = [
+        Register::X0,
+        Register::X1,
+        Register::X2,
+        Register::X3,
+        Register::X4,
+        Register::X5,
+        Register::X6,
+        Register::X7,
+        Register::X8,
+        Register::X9,
+        Register::X10,
+        Register::X11,
+        Register::X12,
+        Register::X13,
+        Register::X14,
+        Register::X15,
+        Register::X16,
+        Register::X17,
+        Register::X18,
+        Register::X19,
+        Register::X20,
+        Register::X21,
+        Register::X22,
+        Register::X23,
+        Register::X24,
+        Register::X25,
+        Register::X26,
+        Register::X27,
+        Register::X28,
+        Register::X29,
+        Register::FP,
+        Register::X30,
+        Register::LR,
+        Register::PC,
+        Register::FPCR,
+        Register::FPSR,
+        Register::CPSR,
+    ];
+
+    /// Gets the general purpose register ``X<index>``, returns None if the index isn't in 0..=30.
+    pub fn from_x_index(index: u8) -> Option<Register> {
+        // Register::ALL lists X0 to X29 first, followed by the FP alias and X30.
+        match index {
+            0..=29 => Some(Register::ALL[index as usize]),
+            30 => Some(Register::X30),
+            _ => None,
+        }
+    }
+
+    /// Gets the name of the register.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::X0 => "X0",
+            Register::X1 => "X1",
+            Register::X2 => "X2",
+            Register::X3 => "X3",
+            Register::X4 => "X4",
+            Register::X5 => "X5",
+            Register::X6 => "X6",
+            Register::X7 => "X7",
+            Register::X8 => "X8",
+            Register::X9 => "X9",
+            Register::X10 => "X10",
+            Register::X11 => "X11",
+            Register::X12 => "X12",
+            Register::X13 => "X13",
+            Register::X14 => "X14",
+            Register::X15 => "X15",
+            Register::X16 => "X16",
+            Register::X17 => "X17",
+            Register::X18 => "X18",
+            Register::X19 => "X19",
+            Register::X20 => "X20",
+            Register::X21 => "X21",
+            Register::X22 => "X22",
+            Register::X23 => "X23",
+            Register::X24 => "X24",
+            Register::X25 => "X25",
+            Register::X26 => "X26",
+            Register::X27 => "X27",
+            Register::X28 => "X28",
+            Register::X29 => "X29",
+            Register::FP => "FP",
+            Register::X30 => "X30",
+            Register::LR => "LR",
+            Register::PC => "PC",
+            Register::FPCR => "FPCR",
+            Register::FPSR => "FPSR",
+            Register::CPSR => "CPSR",
+        }
+    }
+}
+
+/// Error returned when parsing a [Register] from a string fails.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseRegisterError;
+
+impl core::fmt::Display for ParseRegisterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unknown register name")
+    }
+}
+
+/// Error returned by [VirtualCpu::get_register_ctx] and [VirtualCpu::set_register_ctx], identifying the register that failed.
+#[derive(Copy, Clone, Debug)]
+pub struct RegisterError {
+    /// The register that failed to be accessed.
+    pub register: Register,
+
+    /// The error reported by the Hypervisor.
+    pub source: HypervisorError,
+}
+
+impl core::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "access to {} failed: {:?}",
+            self.register.name(),
+            self.source
+        )
+    }
+}
+
+impl core::str::FromStr for Register {
+    type Err = ParseRegisterError;
+
+    /// Parse a register from its name (case insensitive).
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        Register::ALL
+            .iter()
+            .copied()
+            .find(|register| register.name().eq_ignore_ascii_case(value))
+            .ok_or(ParseRegisterError)
+    }
+}
+
+impl TryFrom<&str> for Register {
+    type Error = ParseRegisterError;
+
+    fn try_from(value: &str) -> core::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 impl From<Register> for hv_reg_t {
     fn from(value: Register) -> hv_reg_t {
         match value {
@@ -831,6 +2457,12 @@ impl From<Register> for hv_reg_t {
 }
 
 /// ARM system register.
+///
+/// The Hypervisor doesn't expose CNTHCTL_EL2, as such guest accesses to the generic timer registers (``CNTV_*``/``CNTP_*``) cannot be configured to trap.
+/// The virtual timer can only be observed through [VirtualCpuExitReason::VTimerActivated] and the vtimer mask and offset.
+///
+/// PMU registers (``PMCR_EL0``, ``PMCCNTR_EL0``, ``PMEVCNTR<n>_EL0``) aren't exposed either and guest accesses to them trap.
+/// Use [VirtualCpu::get_exec_time] to measure the guest execution time instead.
 #[derive(Copy, Clone, Debug)]
 #[allow(non_camel_case_types)]
 pub enum SystemRegister {
@@ -1171,6 +2803,300 @@ pub enum SystemRegister {
     SP_EL1,
 }
 
+impl SystemRegister {
+    /// All the system registers.
+    pub const ALL: [SystemRegister; 112] = [
+        SystemRegister::DBGBVR0_EL1,
+        SystemRegister::DBGBCR0_EL1,
+        SystemRegister::DBGWVR0_EL1,
+        SystemRegister::DBGWCR0_EL1,
+        SystemRegister::DBGBVR1_EL1,
+        SystemRegister::DBGBCR1_EL1,
+        SystemRegister::DBGWVR1_EL1,
+        SystemRegister::DBGWCR1_EL1,
+        SystemRegister::MDCCINT_EL1,
+        SystemRegister::MDSCR_EL1,
+        SystemRegister::DBGBVR2_EL1,
+        SystemRegister::DBGBCR2_EL1,
+        SystemRegister::DBGWVR2_EL1,
+        SystemRegister::DBGWCR2_EL1,
+        SystemRegister::DBGBVR3_EL1,
+        SystemRegister::DBGBCR3_EL1,
+        SystemRegister::DBGWVR3_EL1,
+        SystemRegister::DBGWCR3_EL1,
+        SystemRegister::DBGBVR4_EL1,
+        SystemRegister::DBGBCR4_EL1,
+        SystemRegister::DBGWVR4_EL1,
+        SystemRegister::DBGWCR4_EL1,
+        SystemRegister::DBGBVR5_EL1,
+        SystemRegister::DBGBCR5_EL1,
+        SystemRegister::DBGWVR5_EL1,
+        SystemRegister::DBGWCR5_EL1,
+        SystemRegister::DBGBVR6_EL1,
+        SystemRegister::DBGBCR6_EL1,
+        SystemRegister::DBGWVR6_EL1,
+        SystemRegister::DBGWCR6_EL1,
+        SystemRegister::DBGBVR7_EL1,
+        SystemRegister::DBGBCR7_EL1,
+        SystemRegister::DBGWVR7_EL1,
+        SystemRegister::DBGWCR7_EL1,
+        SystemRegister::DBGBVR8_EL1,
+        SystemRegister::DBGBCR8_EL1,
+        SystemRegister::DBGWVR8_EL1,
+        SystemRegister::DBGWCR8_EL1,
+        SystemRegister::DBGBVR9_EL1,
+        SystemRegister::DBGBCR9_EL1,
+        SystemRegister::DBGWVR9_EL1,
+        SystemRegister::DBGWCR9_EL1,
+        SystemRegister::DBGBVR10_EL1,
+        SystemRegister::DBGBCR10_EL1,
+        SystemRegister::DBGWVR10_EL1,
+        SystemRegister::DBGWCR10_EL1,
+        SystemRegister::DBGBVR11_EL1,
+        SystemRegister::DBGBCR11_EL1,
+        SystemRegister::DBGWVR11_EL1,
+        SystemRegister::DBGWCR11_EL1,
+        SystemRegister::DBGBVR12_EL1,
+        SystemRegister::DBGBCR12_EL1,
+        SystemRegister::DBGWVR12_EL1,
+        SystemRegister::DBGWCR12_EL1,
+        SystemRegister::DBGBVR13_EL1,
+        SystemRegister::DBGBCR13_EL1,
+        SystemRegister::DBGWVR13_EL1,
+        SystemRegister::DBGWCR13_EL1,
+        SystemRegister::DBGBVR14_EL1,
+        SystemRegister::DBGBCR14_EL1,
+        SystemRegister::DBGWVR14_EL1,
+        SystemRegister::DBGWCR14_EL1,
+        SystemRegister::DBGBVR15_EL1,
+        SystemRegister::DBGBCR15_EL1,
+        SystemRegister::DBGWVR15_EL1,
+        SystemRegister::DBGWCR15_EL1,
+        SystemRegister::MIDR_EL1,
+        SystemRegister::MPIDR_EL1,
+        SystemRegister::ID_AA64PFR0_EL1,
+        SystemRegister::ID_AA64PFR1_EL1,
+        SystemRegister::ID_AA64DFR0_EL1,
+        SystemRegister::ID_AA64DFR1_EL1,
+        SystemRegister::ID_AA64ISAR0_EL1,
+        SystemRegister::ID_AA64ISAR1_EL1,
+        SystemRegister::ID_AA64MMFR0_EL1,
+        SystemRegister::ID_AA64MMFR1_EL1,
+        SystemRegister::ID_AA64MMFR2_EL1,
+        SystemRegister::SCTLR_EL1,
+        SystemRegister::CPACR_EL1,
+        SystemRegister::TTBR0_EL1,
+        SystemRegister::TTBR1_EL1,
+        SystemRegister::TCR_EL1,
+        SystemRegister::APIAKEYLO_EL1,
+        SystemRegister::APIAKEYHI_EL1,
+        SystemRegister::APIBKEYLO_EL1,
+        SystemRegister::APIBKEYHI_EL1,
+        SystemRegister::APDAKEYLO_EL1,
+        SystemRegister::APDAKEYHI_EL1,
+        SystemRegister::APDBKEYLO_EL1,
+        SystemRegister::APDBKEYHI_EL1,
+        SystemRegister::APGAKEYLO_EL1,
+        SystemRegister::APGAKEYHI_EL1,
+        SystemRegister::SPSR_EL1,
+        SystemRegister::ELR_EL1,
+        SystemRegister::SP_EL0,
+        SystemRegister::AFSR0_EL1,
+        SystemRegister::AFSR1_EL1,
+        SystemRegister::ESR_EL1,
+        SystemRegister::FAR_EL1,
+        SystemRegister::PAR_EL1,
+        SystemRegister::MAIR_EL1,
+        SystemRegister::AMAIR_EL1,
+        SystemRegister::VBAR_EL1,
+        SystemRegister::CONTEXTIDR_EL1,
+        SystemRegister::TPIDR_EL1,
+        SystemRegister::CNTKCTL_EL1,
+        SystemRegister::CSSELR_EL1,
+        SystemRegister::TPIDR_EL0,
+        SystemRegister::TPIDRRO_EL0,
+        SystemRegister::CNTV_CTL_EL0,
+        SystemRegister::CNTV_CVAL_EL0,
+        SystemRegister::SP_EL1,
+    ];
+
+    /// Check if the system register can be written by the host.
+    ///
+    /// Among the identification registers, the Hypervisor only allows overriding MIDR_EL1, MPIDR_EL1 and ID_AA64PFR0_EL1.
+    pub fn is_writable(&self) -> bool {
+        !matches!(
+            self,
+            SystemRegister::ID_AA64PFR1_EL1
+                | SystemRegister::ID_AA64DFR0_EL1
+                | SystemRegister::ID_AA64DFR1_EL1
+                | SystemRegister::ID_AA64ISAR0_EL1
+                | SystemRegister::ID_AA64ISAR1_EL1
+                | SystemRegister::ID_AA64MMFR0_EL1
+                | SystemRegister::ID_AA64MMFR1_EL1
+                | SystemRegister::ID_AA64MMFR2_EL1
+        )
+    }
+
+    /// Gets the system register matching an encoding (``op0 << 14 | op1 << 11 | CRn << 7 | CRm << 3 | op2``).
+    ///
+    /// Returns None if the system register isn't supported by the Hypervisor.
+    pub fn from_encoding(encoding: hv_sys_reg_t) -> Option<SystemRegister> {
+        SystemRegister::ALL
+            .iter()
+            .copied()
+            .find(|register| hv_sys_reg_t::from(*register) == encoding)
+    }
+
+    /// Gets the name of the system register.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SystemRegister::DBGBVR0_EL1 => "DBGBVR0_EL1",
+            SystemRegister::DBGBCR0_EL1 => "DBGBCR0_EL1",
+            SystemRegister::DBGWVR0_EL1 => "DBGWVR0_EL1",
+            SystemRegister::DBGWCR0_EL1 => "DBGWCR0_EL1",
+            SystemRegister::DBGBVR1_EL1 => "DBGBVR1_EL1",
+            SystemRegister::DBGBCR1_EL1 => "DBGBCR1_EL1",
+            SystemRegister::DBGWVR1_EL1 => "DBGWVR1_EL1",
+            SystemRegister::DBGWCR1_EL1 => "DBGWCR1_EL1",
+            SystemRegister::MDCCINT_EL1 => "MDCCINT_EL1",
+            SystemRegister::MDSCR_EL1 => "MDSCR_EL1",
+            SystemRegister::DBGBVR2_EL1 => "DBGBVR2_EL1",
+            SystemRegister::DBGBCR2_EL1 => "DBGBCR2_EL1",
+            SystemRegister::DBGWVR2_EL1 => "DBGWVR2_EL1",
+            SystemRegister::DBGWCR2_EL1 => "DBGWCR2_EL1",
+            SystemRegister::DBGBVR3_EL1 => "DBGBVR3_EL1",
+            SystemRegister::DBGBCR3_EL1 => "DBGBCR3_EL1",
+            SystemRegister::DBGWVR3_EL1 => "DBGWVR3_EL1",
+            SystemRegister::DBGWCR3_EL1 => "DBGWCR3_EL1",
+            SystemRegister::DBGBVR4_EL1 => "DBGBVR4_EL1",
+            SystemRegister::DBGBCR4_EL1 => "DBGBCR4_EL1",
+            SystemRegister::DBGWVR4_EL1 => "DBGWVR4_EL1",
+            SystemRegister::DBGWCR4_EL1 => "DBGWCR4_EL1",
+            SystemRegister::DBGBVR5_EL1 => "DBGBVR5_EL1",
+            SystemRegister::DBGBCR5_EL1 => "DBGBCR5_EL1",
+            SystemRegister::DBGWVR5_EL1 => "DBGWVR5_EL1",
+            SystemRegister::DBGWCR5_EL1 => "DBGWCR5_EL1",
+            SystemRegister::DBGBVR6_EL1 => "DBGBVR6_EL1",
+            SystemRegister::DBGBCR6_EL1 => "DBGBCR6_EL1",
+            SystemRegister::DBGWVR6_EL1 => "DBGWVR6_EL1",
+            SystemRegister::DBGWCR6_EL1 => "DBGWCR6_EL1",
+            SystemRegister::DBGBVR7_EL1 => "DBGBVR7_EL1",
+            SystemRegister::DBGBCR7_EL1 => "DBGBCR7_EL1",
+            SystemRegister::DBGWVR7_EL1 => "DBGWVR7_EL1",
+            SystemRegister::DBGWCR7_EL1 => "DBGWCR7_EL1",
+            SystemRegister::DBGBVR8_EL1 => "DBGBVR8_EL1",
+            SystemRegister::DBGBCR8_EL1 => "DBGBCR8_EL1",
+            SystemRegister::DBGWVR8_EL1 => "DBGWVR8_EL1",
+            SystemRegister::DBGWCR8_EL1 => "DBGWCR8_EL1",
+            SystemRegister::DBGBVR9_EL1 => "DBGBVR9_EL1",
+            SystemRegister::DBGBCR9_EL1 => "DBGBCR9_EL1",
+            SystemRegister::DBGWVR9_EL1 => "DBGWVR9_EL1",
+            SystemRegister::DBGWCR9_EL1 => "DBGWCR9_EL1",
+            SystemRegister::DBGBVR10_EL1 => "DBGBVR10_EL1",
+            SystemRegister::DBGBCR10_EL1 => "DBGBCR10_EL1",
+            SystemRegister::DBGWVR10_EL1 => "DBGWVR10_EL1",
+            SystemRegister::DBGWCR10_EL1 => "DBGWCR10_EL1",
+            SystemRegister::DBGBVR11_EL1 => "DBGBVR11_EL1",
+            SystemRegister::DBGBCR11_EL1 => "DBGBCR11_EL1",
+            SystemRegister::DBGWVR11_EL1 => "DBGWVR11_EL1",
+            SystemRegister::DBGWCR11_EL1 => "DBGWCR11_EL1",
+            SystemRegister::DBGBVR12_EL1 => "DBGBVR12_EL1",
+            SystemRegister::DBGBCR12_EL1 => "DBGBCR12_EL1",
+            SystemRegister::DBGWVR12_EL1 => "DBGWVR12_EL1",
+            SystemRegister::DBGWCR12_EL1 => "DBGWCR12_EL1",
+            SystemRegister::DBGBVR13_EL1 => "DBGBVR13_EL1",
+            SystemRegister::DBGBCR13_EL1 => "DBGBCR13_EL1",
+            SystemRegister::DBGWVR13_EL1 => "DBGWVR13_EL1",
+            SystemRegister::DBGWCR13_EL1 => "DBGWCR13_EL1",
+            SystemRegister::DBGBVR14_EL1 => "DBGBVR14_EL1",
+            SystemRegister::DBGBCR14_EL1 => "DBGBCR14_EL1",
+            SystemRegister::DBGWVR14_EL1 => "DBGWVR14_EL1",
+            SystemRegister::DBGWCR14_EL1 => "DBGWCR14_EL1",
+            SystemRegister::DBGBVR15_EL1 => "DBGBVR15_EL1",
+            SystemRegister::DBGBCR15_EL1 => "DBGBCR15_EL1",
+            SystemRegister::DBGWVR15_EL1 => "DBGWVR15_EL1",
+            SystemRegister::DBGWCR15_EL1 => "DBGWCR15_EL1",
+            SystemRegister::MIDR_EL1 => "MIDR_EL1",
+            SystemRegister::MPIDR_EL1 => "MPIDR_EL1",
+            SystemRegister::ID_AA64PFR0_EL1 => "ID_AA64PFR0_EL1",
+            SystemRegister::ID_AA64PFR1_EL1 => "ID_AA64PFR1_EL1",
+            SystemRegister::ID_AA64DFR0_EL1 => "ID_AA64DFR0_EL1",
+            SystemRegister::ID_AA64DFR1_EL1 => "ID_AA64DFR1_EL1",
+            SystemRegister::ID_AA64ISAR0_EL1 => "ID_AA64ISAR0_EL1",
+            SystemRegister::ID_AA64ISAR1_EL1 => "ID_AA64ISAR1_EL1",
+            SystemRegister::ID_AA64MMFR0_EL1 => "ID_AA64MMFR0_EL1",
+            SystemRegister::ID_AA64MMFR1_EL1 => "ID_AA64MMFR1_EL1",
+            SystemRegister::ID_AA64MMFR2_EL1 => "ID_AA64MMFR2_EL1",
+            SystemRegister::SCTLR_EL1 => "SCTLR_EL1",
+            SystemRegister::CPACR_EL1 => "CPACR_EL1",
+            SystemRegister::TTBR0_EL1 => "TTBR0_EL1",
+            SystemRegister::TTBR1_EL1 => "TTBR1_EL1",
+            SystemRegister::TCR_EL1 => "TCR_EL1",
+            SystemRegister::APIAKEYLO_EL1 => "APIAKEYLO_EL1",
+            SystemRegister::APIAKEYHI_EL1 => "APIAKEYHI_EL1",
+            SystemRegister::APIBKEYLO_EL1 => "APIBKEYLO_EL1",
+            SystemRegister::APIBKEYHI_EL1 => "APIBKEYHI_EL1",
+            SystemRegister::APDAKEYLO_EL1 => "APDAKEYLO_EL1",
+            SystemRegister::APDAKEYHI_EL1 => "APDAKEYHI_EL1",
+            SystemRegister::APDBKEYLO_EL1 => "APDBKEYLO_EL1",
+            SystemRegister::APDBKEYHI_EL1 => "APDBKEYHI_EL1",
+            SystemRegister::APGAKEYLO_EL1 => "APGAKEYLO_EL1",
+            SystemRegister::APGAKEYHI_EL1 => "APGAKEYHI_EL1",
+            SystemRegister::SPSR_EL1 => "SPSR_EL1",
+            SystemRegister::ELR_EL1 => "ELR_EL1",
+            SystemRegister::SP_EL0 => "SP_EL0",
+            SystemRegister::AFSR0_EL1 => "AFSR0_EL1",
+            SystemRegister::AFSR1_EL1 => "AFSR1_EL1",
+            SystemRegister::ESR_EL1 => "ESR_EL1",
+            SystemRegister::FAR_EL1 => "FAR_EL1",
+            SystemRegister::PAR_EL1 => "PAR_EL1",
+            SystemRegister::MAIR_EL1 => "MAIR_EL1",
+            SystemRegister::AMAIR_EL1 => "AMAIR_EL1",
+            SystemRegister::VBAR_EL1 => "VBAR_EL1",
+            SystemRegister::CONTEXTIDR_EL1 => "CONTEXTIDR_EL1",
+            SystemRegister::TPIDR_EL1 => "TPIDR_EL1",
+            SystemRegister::CNTKCTL_EL1 => "CNTKCTL_EL1",
+            SystemRegister::CSSELR_EL1 => "CSSELR_EL1",
+            SystemRegister::TPIDR_EL0 => "TPIDR_EL0",
+            SystemRegister::TPIDRRO_EL0 => "TPIDRRO_EL0",
+            SystemRegister::CNTV_CTL_EL0 => "CNTV_CTL_EL0",
+            SystemRegister::CNTV_CVAL_EL0 => "CNTV_CVAL_EL0",
+            SystemRegister::SP_EL1 => "SP_EL1",
+        }
+    }
+}
+
+/// Error returned when parsing a [SystemRegister] from a string fails.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseSystemRegisterError;
+
+impl core::fmt::Display for ParseSystemRegisterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unknown system register name")
+    }
+}
+
+impl core::str::FromStr for SystemRegister {
+    type Err = ParseSystemRegisterError;
+
+    /// Parse a system register from its name (case insensitive).
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        SystemRegister::ALL
+            .iter()
+            .copied()
+            .find(|register| register.name().eq_ignore_ascii_case(value))
+            .ok_or(ParseSystemRegisterError)
+    }
+}
+
+impl TryFrom<&str> for SystemRegister {
+    type Error = ParseSystemRegisterError;
+
+    fn try_from(value: &str) -> core::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 impl From<SystemRegister> for hv_sys_reg_t {
     fn from(value: SystemRegister) -> hv_sys_reg_t {
         match value {
@@ -1326,6 +3252,26 @@ pub enum VirtualCpuExitReason {
 
     /// Unexpected exit.
     Unknown,
+
+    /// Exit with a reason not known by this crate, carrying the raw reason.
+    UnknownReason(u32),
+}
+
+impl VirtualCpuExitReason {
+    /// Check if the guest can simply be run again after this exit.
+    ///
+    /// - [VirtualCpuExitReason::Cancelled] is an exit requested by the host.
+    /// - [VirtualCpuExitReason::VTimerActivated] only reports the Virtual Timer state.
+    /// - [VirtualCpuExitReason::Exception] is resumable once the exception is handled.
+    /// - [VirtualCpuExitReason::Unknown] and [VirtualCpuExitReason::UnknownReason] likely indicate a terminal problem.
+    pub fn is_resumable(&self) -> bool {
+        match self {
+            VirtualCpuExitReason::Cancelled
+            | VirtualCpuExitReason::Exception { .. }
+            | VirtualCpuExitReason::VTimerActivated => true,
+            VirtualCpuExitReason::Unknown | VirtualCpuExitReason::UnknownReason(_) => false,
+        }
+    }
 }
 
 impl From<hv_vcpu_exit_t> for VirtualCpuExitReason {
@@ -1339,67 +3285,331 @@ impl From<hv_vcpu_exit_t> for VirtualCpuExitReason {
             HV_EXIT_REASON_UNKNOWN => VirtualCpuExitReason::Unknown,
 
             // Unexpected unknown
-            _ => VirtualCpuExitReason::Unknown,
+            reason => VirtualCpuExitReason::UnknownReason(reason),
+        }
+    }
+}
+
+/// Hook called by [VirtualCpu::run] before entering the guest.
+pub type PreRunHook = Box<dyn FnMut(&mut VirtualCpu) -> Result<()>>;
+
+/// Wrapper around a [PreRunHook] to make it printable.
+struct PreRunHookHolder(PreRunHook);
+
+impl core::fmt::Debug for PreRunHookHolder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("PreRunHook")
+    }
+}
+
+/// vCPU for a Virtual Machine.
+#[derive(Debug)]
+pub struct VirtualCpu {
+    /// A vCPU is resident to a specific thread. Therefore, it shouldn't be Send.
+    _not_send_marker: PhantomData<*const u8>,
+
+    /// Handle of the vCPU configuration.
+    handle: hv_vcpu_t,
+
+    /// vCPU exit informations.
+    vcpu_exit: *const hv_vcpu_exit_t,
+
+    /// Hook called before entering the guest.
+    pre_run_hook: Option<PreRunHookHolder>,
+
+    /// Syndrome of the SError pending delivery to the guest.
+    pending_serror: Option<u64>,
+
+    /// Trace of the last exits, None when disabled.
+    trace: Option<VecDeque<TraceEntry>>,
+
+    /// Maximum number of entries kept in the trace.
+    trace_capacity: usize,
+
+    /// The guest PC if it was read or written since the last run, used by [VirtualCpu::advance_pc_cached].
+    cached_pc: Option<u64>,
+
+    /// Watchdog thread of [VirtualCpu::run_bounded], spawned on its first call.
+    #[cfg(feature = "std")]
+    watchdog: Option<vcpu_thread::Watchdog>,
+
+    /// Set once the vCPU is destroyed by [VirtualCpu::try_drop].
+    is_destroyed: bool,
+
+    /// The reservation of the vCPU, released once the vCPU is destroyed.
+    _slot: VcpuSlot,
+}
+
+impl Drop for VirtualCpu {
+    /// Destroy the vCPU.
+    ///
+    /// Errors cannot be reported here and only fail a debug assertion, use [VirtualCpu::try_drop] to observe them.
+    fn drop(&mut self) {
+        if !self.is_destroyed {
+            let result = self.destroy();
+
+            debug_assert!(result.is_ok(), "vCPU destruction failed: {:?}", result);
+        }
+    }
+}
+
+/// Exit of a vCPU alongside the guest state at the time of the exit, returned by [VirtualCpu::run_with_context].
+#[derive(Copy, Clone, Debug)]
+pub struct RunContext {
+    /// The exit reason.
+    pub reason: VirtualCpuExitReason,
+
+    /// The guest PC, which is the faulting instruction for synchronous exceptions.
+    ///
+    /// None if it couldn't be read after the exit.
+    pub pc: Option<u64>,
+
+    /// The guest CPSR.
+    ///
+    /// None if it couldn't be read after the exit.
+    pub cpsr: Option<u64>,
+}
+
+/// Handle allowing to force exit a vCPU from any thread.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VcpuExitHandle(hv_vcpu_t);
+
+impl VcpuExitHandle {
+    /// Gets vCPU handle.
+    pub fn get_handle(&self) -> hv_vcpu_t {
+        self.0
+    }
+
+    /// Forces exit the vCPU.
+    pub fn exit(&self) -> Result<()> {
+        let ret = unsafe { hv_vcpus_exit(&self.0, 1) };
+
+        convert_hv_return(ret)
+    }
+}
+
+impl VirtualCpu {
+    /// Create a new vCPU in the current thread.
+    ///
+    /// Returns [HypervisorError::MaxVcpusReached] if the maximum number of vCPUs supported by the Hypervisor is already alive.
+    fn new(config: hv_vcpu_config_t) -> Result<Self> {
+        let slot = VcpuSlot::reserve()?;
+
+        let mut vcpu_handle: hv_vcpu_t = 0;
+        let mut vcpu_exit: *const hv_vcpu_exit_t = core::ptr::null_mut();
+
+        let ret = unsafe { hv_vcpu_create(&mut vcpu_handle, &mut vcpu_exit, &config) };
+
+        convert_hv_return(ret)?;
+
+        Ok(VirtualCpu {
+            _not_send_marker: PhantomData,
+            handle: vcpu_handle,
+            vcpu_exit,
+            pre_run_hook: None,
+            pending_serror: None,
+            trace: None,
+            trace_capacity: DEFAULT_TRACE_CAPACITY,
+            cached_pc: None,
+            #[cfg(feature = "std")]
+            watchdog: None,
+            is_destroyed: false,
+            _slot: slot,
+        })
+    }
+
+    /// Destroy the vCPU, returning the first error that happened.
+    fn destroy(&mut self) -> Result<()> {
+        // The watchdog must be stopped before the vCPU handle becomes invalid.
+        #[cfg(feature = "std")]
+        drop(self.watchdog.take());
+
+        let exit_result = self.exit();
+        let ret = unsafe { hv_vcpu_destroy(self.handle) };
+
+        self.is_destroyed = true;
+
+        exit_result.and(convert_hv_return(ret))
+    }
+
+    /// Destroy the vCPU, reporting the error that happened during its destruction.
+    ///
+    /// Dropping the vCPU cannot report this error, use this to observe it.
+    /// The vCPU slot is released in all cases.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn try_drop(mut self) -> Result<()> {
+        self.destroy()
+    }
+
+    /// Gets vCPU handle.
+    pub fn get_handle(&self) -> hv_vcpu_t {
+        self.handle
+    }
+
+    /// Gets an handle allowing to force exit the vCPU from any thread.
+    pub fn exit_handle(&self) -> VcpuExitHandle {
+        VcpuExitHandle(self.handle)
+    }
+
+    /// Gets a register value.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_register(&mut self, register: Register) -> Result<u64> {
+        let mut result = 0;
+
+        let ret = unsafe {
+            hv_vcpu_get_reg(
+                self.handle,
+                hv_reg_t::from(register),
+                &mut result as *mut u64,
+            )
+        };
+
+        // Ensure no error got reported
+        convert_hv_return(ret)?;
+
+        if matches!(register, Register::PC) {
+            self.cached_pc = Some(result);
+        }
+
+        Ok(result)
+    }
+
+    /// Sets a register value.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_register(&mut self, register: Register, value: u64) -> Result<()> {
+        let ret = unsafe { hv_vcpu_set_reg(self.handle, hv_reg_t::from(register), value) };
+
+        let result = convert_hv_return(ret);
+
+        if matches!(register, Register::PC) {
+            self.cached_pc = result.as_ref().ok().map(|_| value);
+        }
+
+        result
+    }
+
+    /// Gets a register value, identifying the register in the error.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn get_register_ctx(
+        &mut self,
+        register: Register,
+    ) -> core::result::Result<u64, RegisterError> {
+        self.get_register(register)
+            .map_err(|source| RegisterError { register, source })
+    }
+
+    /// Sets a register value, identifying the register in the error.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_register_ctx(
+        &mut self,
+        register: Register,
+        value: u64,
+    ) -> core::result::Result<(), RegisterError> {
+        self.set_register(register, value)
+            .map_err(|source| RegisterError { register, source })
+    }
+
+    /// Gets the values of all registers.
+    ///
+    /// The result follows the order of [Register::ALL] (aliases such as FP and LR included).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn dump_registers(&mut self) -> Result<Vec<(Register, u64)>> {
+        Register::ALL
+            .iter()
+            .map(|register| Ok((*register, self.get_register(*register)?)))
+            .collect()
+    }
+
+    /// Gets the value of the general purpose register ``X<index>``.
+    ///
+    /// Index 31 stands for XZR and always reads as zero, indexes above return [HypervisorError::BadArgument].
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn gpr(&mut self, index: u8) -> Result<u64> {
+        match Register::from_x_index(index) {
+            Some(register) => self.get_register(register),
+            None if index == 31 => Ok(0),
+            None => Err(HypervisorError::BadArgument),
+        }
+    }
+
+    /// Sets the value of the general purpose register ``X<index>``.
+    ///
+    /// Index 31 stands for XZR and writes to it are discarded, indexes above return [HypervisorError::BadArgument].
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_gpr(&mut self, index: u8, value: u64) -> Result<()> {
+        match Register::from_x_index(index) {
+            Some(register) => self.set_register(register, value),
+            None if index == 31 => Ok(()),
+            None => Err(HypervisorError::BadArgument),
         }
     }
-}
-
-/// vCPU for a Virtual Machine.
-#[derive(Debug)]
-pub struct VirtualCpu {
-    /// A vCPU is resident to a specific thread. Therefore, it shouldn't be Send.
-    _not_send_marker: PhantomData<*const u8>,
-
-    /// Handle of the vCPU configuration.
-    handle: hv_vcpu_t,
 
-    /// vCPU exit informations.
-    vcpu_exit: *const hv_vcpu_exit_t,
-}
+    /// Sets the values of consecutive general purpose registers, from ``X<start_index>`` to ``X<start_index + values.len() - 1>``.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the range doesn't stay within X0 to X30, in which case no register is written.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn set_x_range(&mut self, start_index: u8, values: &[u64]) -> Result<()> {
+        if usize::from(start_index) + values.len() > 31 {
+            return Err(HypervisorError::BadArgument);
+        }
 
-impl Drop for VirtualCpu {
-    fn drop(&mut self) {
-        self.exit().expect("Cannot exit vCPU on drop!");
+        for (index, value) in (start_index..).zip(values) {
+            let register = Register::from_x_index(index).ok_or(HypervisorError::BadArgument)?;
 
-        let ret = unsafe { hv_vcpu_destroy(self.handle) };
+            self.set_register(register, *value)?;
+        }
 
-        convert_hv_return(ret).expect("Cannot destroy vCPU on drop!")
+        Ok(())
     }
-}
 
-impl VirtualCpu {
-    /// Gets vCPU handle.
-    pub fn get_handle(&self) -> hv_vcpu_t {
-        self.handle
+    /// Sample the current guest PC, usually after forcing an exit.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn sample_pc(&mut self) -> Result<u64> {
+        self.get_register(Register::PC)
     }
 
-    /// Gets a register value.
+    /// Sets the values of multiple registers, all or nothing.
+    ///
+    /// The current values of all the targeted registers are saved first. If setting any register fails,
+    /// the registers already set are restored to their saved values and the error is returned.
     ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
-    pub fn get_register(&mut self, register: Register) -> Result<u64> {
-        let mut result = 0;
-
-        let ret = unsafe {
-            hv_vcpu_get_reg(
-                self.handle,
-                hv_reg_t::from(register),
-                &mut result as *mut u64,
-            )
-        };
+    pub fn apply_registers_transactional(&mut self, pairs: &[(Register, u64)]) -> Result<()> {
+        apply_transactional(self, pairs, Self::get_register, Self::set_register)
+    }
 
-        // Ensure no error got reported
-        convert_hv_return(ret)?;
+    /// Advances the PC register by a given amount of bytes (usually 4 to skip the current instruction).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn advance_pc(&mut self, bytes: u64) -> Result<()> {
+        let pc = self.get_register(Register::PC)?;
 
-        Ok(result)
+        self.set_register(Register::PC, pc.wrapping_add(bytes))
     }
 
-    /// Sets a register value.
+    /// Advances the PC register by a given amount of bytes like [VirtualCpu::advance_pc], without reading PC if it's already known.
+    ///
+    /// PC is known once it has been read or written since the last run (for example by [VirtualCpu::run_with_context] or the trap trace),
+    /// this then costs a single register write instead of a read and a write.
     ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
-    pub fn set_register(&mut self, register: Register, value: u64) -> Result<()> {
-        let ret = unsafe { hv_vcpu_set_reg(self.handle, hv_reg_t::from(register), value) };
+    pub fn advance_pc_cached(&mut self, bytes: u64) -> Result<()> {
+        let pc = match self.cached_pc {
+            Some(pc) => pc,
+            None => self.get_register(Register::PC)?,
+        };
 
-        convert_hv_return(ret)
+        self.set_register(Register::PC, pc.wrapping_add(bytes))
     }
 
     // TODO: SIMD APIs
@@ -1426,11 +3636,57 @@ impl VirtualCpu {
 
     /// Sets a system register value.
     ///
+    /// If the Hypervisor rejects a write to a register known to be read-only (see [SystemRegister::is_writable]), [HypervisorError::ReadOnlyRegister] is returned.
+    ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
     pub fn set_system_register(&mut self, register: SystemRegister, value: u64) -> Result<()> {
         let ret = unsafe { hv_vcpu_set_sys_reg(self.handle, hv_sys_reg_t::from(register), value) };
 
-        convert_hv_return(ret)
+        match convert_hv_return(ret) {
+            Err(HypervisorError::BadArgument) if !register.is_writable() => {
+                Err(HypervisorError::ReadOnlyRegister)
+            }
+            result => result,
+        }
+    }
+
+    /// Gets all system registers that can be read on this host.
+    ///
+    /// **This performs one call to the Hypervisor per system register and is intended for setup-time probing.**
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn probe_readable_system_registers(&mut self) -> Vec<SystemRegister> {
+        SystemRegister::ALL
+            .iter()
+            .copied()
+            .filter(|register| self.get_system_register(*register).is_ok())
+            .collect()
+    }
+
+    /// Gets whether the guest uses the stack pointer of its current exception level (PSTATE.SP).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn sp_sel(&mut self) -> Result<bool> {
+        let cpsr = self.get_register(Register::CPSR)?;
+
+        Ok(cpsr & 1 != 0)
+    }
+
+    /// Gets the value of the stack pointer currently in use by the guest.
+    ///
+    /// EL0 always uses SP_EL0, EL1 uses SP_EL1 when PSTATE.SP is set and SP_EL0 otherwise.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn effective_sp(&mut self) -> Result<u64> {
+        let cpsr = self.get_register(Register::CPSR)?;
+        let exception_level = (cpsr >> 2) & 0x3;
+        let sp_sel = cpsr & 1 != 0;
+
+        if exception_level != 0 && sp_sel {
+            self.get_system_register(SystemRegister::SP_EL1)
+        } else {
+            self.get_system_register(SystemRegister::SP_EL0)
+        }
     }
 
     /// Gets pending interrupts.
@@ -1472,6 +3728,41 @@ impl VirtualCpu {
         convert_hv_return(ret)
     }
 
+    /// Injects an interrupt and runs the vCPU until the guest takes it.
+    ///
+    /// As pending interrupts get cleared after every run, the interrupt is re-armed before each run.
+    ///
+    /// After every exit, the guest is considered to have taken the interrupt if its PC is inside the exception vector table (``VBAR_EL1`` to ``VBAR_EL1 + 0x800``),
+    /// the exit is then returned. Otherwise, [VirtualCpuExitReason::Cancelled] and [VirtualCpuExitReason::VTimerActivated] exits re-arm the interrupt and run the vCPU again,
+    /// and any other exit is returned as is (for example an HVC used by the handler as an explicit acknowledgment).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn inject_irq_and_run(
+        &mut self,
+        interrupt_type: InterruptType,
+    ) -> Result<VirtualCpuExitReason> {
+        /// Size of the AArch64 exception vector table.
+        const VECTOR_TABLE_SIZE: u64 = 0x800;
+
+        loop {
+            self.set_pending_interrupt(interrupt_type, true)?;
+
+            let reason = self.run()?;
+
+            let vector_table = self.get_system_register(SystemRegister::VBAR_EL1)?;
+            let pc = self.get_register(Register::PC)?;
+
+            if pc.wrapping_sub(vector_table) < VECTOR_TABLE_SIZE {
+                return Ok(reason);
+            }
+
+            match reason {
+                VirtualCpuExitReason::Cancelled | VirtualCpuExitReason::VTimerActivated => {}
+                _ => return Ok(reason),
+            }
+        }
+    }
+
     /// Gets whether debug exceptions exit the vCPU.
     ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
@@ -1516,15 +3807,87 @@ impl VirtualCpu {
         convert_hv_return(ret)
     }
 
+    /// Sets a hook called by [VirtualCpu::run] before each entry in the guest, replacing any previous one.
+    ///
+    /// This is meant to re-arm state that must be set before every run (pending interrupts, single step...).
+    /// If the hook returns an error, the guest isn't entered and the error is returned by [VirtualCpu::run].
+    ///
+    /// The hook runs on the thread of the vCPU.
+    pub fn set_pre_run_hook(&mut self, hook: PreRunHook) {
+        self.pre_run_hook = Some(PreRunHookHolder(hook));
+    }
+
+    /// Removes the hook previously set with [VirtualCpu::set_pre_run_hook].
+    pub fn clear_pre_run_hook(&mut self) {
+        self.pre_run_hook = None;
+    }
+
     /// Runs the vCPU.
     ///
+    /// The hook set with [VirtualCpu::set_pre_run_hook] is called before entering the guest.
+    ///
     /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
     pub fn run(&mut self) -> Result<VirtualCpuExitReason> {
+        if let Some(mut hook) = self.pre_run_hook.take() {
+            let result = (hook.0)(self);
+
+            // Keep the hook unless it was replaced while running.
+            if self.pre_run_hook.is_none() {
+                self.pre_run_hook = Some(hook);
+            }
+
+            result?;
+        }
+
+        self.deliver_pending_serror()?;
+
+        // The guest changes PC while running.
+        self.cached_pc = None;
+
         let ret = unsafe { hv_vcpu_run(self.handle) };
 
         convert_hv_return(ret)?;
 
-        Ok(VirtualCpuExitReason::from(unsafe { *self.vcpu_exit }))
+        let reason = VirtualCpuExitReason::from(unsafe { read_vcpu_exit(self.vcpu_exit) });
+
+        self.record_trace(&reason);
+
+        Ok(reason)
+    }
+
+    /// Runs the vCPU and captures PC and CPSR at the time of the exit.
+    ///
+    /// Compared to [VirtualCpu::run], this costs two more register reads per run, only use it when the guest state is needed on exit.
+    /// The exit reason is returned even if these reads fail, as the exit has already happened.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_with_context(&mut self) -> Result<RunContext> {
+        let reason = self.run()?;
+
+        Ok(RunContext {
+            reason,
+            pc: self.get_register(Register::PC).ok(),
+            cpsr: self.get_register(Register::CPSR).ok(),
+        })
+    }
+
+    /// Gets the raw informations of the last exit, regardless of its decoded reason.
+    ///
+    /// The informations are owned by the Hypervisor and updated by every run of the vCPU.
+    /// Before the first run, their content is unspecified.
+    pub fn last_raw_exit(&self) -> &hv_vcpu_exit_t {
+        unsafe { &*self.vcpu_exit }
+    }
+
+    /// Runs the vCPU, retrying up to ``max_attempts`` times while the Hypervisor reports a transient error.
+    ///
+    /// Only [HypervisorError::Busy] is retried, any other error is returned immediately.
+    /// The host thread yields between attempts (or spins briefly without the ``std`` feature).
+    /// If all attempts are exhausted, the last error is returned ([HypervisorError::BadArgument] if ``max_attempts`` is zero).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_retrying(&mut self, max_attempts: usize) -> Result<VirtualCpuExitReason> {
+        retry_transient(max_attempts, || self.run())
     }
 
     /// Forces exit the vCPU.
@@ -1547,39 +3910,508 @@ impl VirtualCpu {
         Ok(result)
     }
 
-    /// Gets Virtual Timer mask.
-    pub fn get_vtimer_mask(&mut self) -> Result<bool> {
-        let mut result = false;
+    /// Gets the execution time elapsed since a previous value of [VirtualCpu::get_exec_time].
+    ///
+    /// Returns zero if the previous value is greater than the current one (the counter appears to have been reset).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn exec_time_delta_since(&mut self, previous: u64) -> Result<u64> {
+        self.get_exec_time()
+            .map(|current| current.saturating_sub(previous))
+    }
+}
 
-        let ret = unsafe { hv_vcpu_get_vtimer_mask(self.handle, &mut result) };
+#[cfg(test)]
+mod tests {
+    //! Tests of the types that don't require the Hypervisor.
 
-        convert_hv_return(ret)?;
+    use super::*;
 
-        Ok(result)
+    /// Transient errors are retried until the operation succeeds.
+    #[test]
+    fn retry_transient_retries_busy() {
+        let mut attempts = 0;
+
+        let result = retry_transient(5, || {
+            attempts += 1;
+
+            if attempts < 3 {
+                Err(HypervisorError::Busy)
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result.ok(), Some(3));
+        assert_eq!(attempts, 3);
     }
 
-    /// Sets Virtual Timer mask.
-    pub fn set_vtimer_mask(&mut self, value: bool) -> Result<()> {
-        let ret = unsafe { hv_vcpu_set_vtimer_mask(self.handle, value) };
+    /// Non transient errors are returned right away.
+    #[test]
+    fn retry_transient_stops_on_other_errors() {
+        let mut attempts = 0;
 
-        convert_hv_return(ret)
+        let result: Result<()> = retry_transient(5, || {
+            attempts += 1;
+
+            Err(HypervisorError::Denied)
+        });
+
+        assert!(matches!(result, Err(HypervisorError::Denied)));
+        assert_eq!(attempts, 1);
     }
 
-    /// Gets Virtual Timer offset (CNTVOFF_EL2).
-    pub fn get_vtimer_offset(&mut self) -> Result<u64> {
-        let mut result = 0;
+    /// Memory permissions round-trip through their ``rwx`` format.
+    #[test]
+    fn memory_permission_string_round_trip() {
+        for bits in 0..8 {
+            let permission = MemoryPermission::new(bits & 1 != 0, bits & 2 != 0, bits & 4 != 0);
+            let formatted = alloc::format!("{}", permission);
 
-        let ret = unsafe { hv_vcpu_get_vtimer_offset(self.handle, &mut result) };
+            assert_eq!(formatted.parse::<MemoryPermission>(), Ok(permission));
+        }
 
-        convert_hv_return(ret)?;
+        assert_eq!("r-x".parse(), Ok(MemoryPermission::READ_EXECUTE));
+        assert_eq!("RW-".parse(), Ok(MemoryPermission::READ_WRITE));
+        assert_eq!(alloc::format!("{}", MemoryPermission::WRITE), "-w-");
+    }
 
-        Ok(result)
+    /// Malformed memory permission strings are rejected.
+    #[test]
+    fn memory_permission_string_rejects_invalid() {
+        for value in ["", "rw", "rwxx", "wrx", "r?x", "rw "] {
+            assert_eq!(
+                value.parse::<MemoryPermission>(),
+                Err(ParseMemoryPermissionError)
+            );
+        }
     }
 
-    /// Sets Virtual Timer offset (CNTVOFF_EL2).
-    pub fn set_vtimer_offset(&mut self, value: u64) -> Result<()> {
-        let ret = unsafe { hv_vcpu_set_vtimer_offset(self.handle, value) };
+    /// All valid bit patterns round-trip through the compact representation.
+    #[test]
+    fn memory_permission_bits_round_trip() {
+        for bits in 0..8u8 {
+            let permission = MemoryPermission::from_bits(bits).unwrap();
 
-        convert_hv_return(ret)
+            assert_eq!(permission.bits(), bits);
+            assert_eq!(u8::from(permission), bits);
+        }
+
+        assert_eq!(MemoryPermission::READ_EXECUTE.bits(), 0b101);
+        assert_eq!(
+            MemoryPermission::from_bits(0b011),
+            Some(MemoryPermission::READ_WRITE)
+        );
+    }
+
+    /// Bit patterns with bits above bit 2 set are rejected.
+    #[test]
+    fn memory_permission_bits_rejects_high_bits() {
+        for bits in 8..=u8::MAX {
+            assert_eq!(MemoryPermission::from_bits(bits), None);
+        }
+
+        assert_eq!(MemoryPermission::try_from(0x10), Err(0x10));
+    }
+
+    /// Only one context can be acquired at a time.
+    #[test]
+    fn hypervisor_context_is_exclusive() {
+        let context = HypervisorContext::acquire().unwrap();
+
+        assert!(matches!(
+            HypervisorContext::acquire(),
+            Err(HypervisorError::Busy)
+        ));
+
+        drop(context);
+
+        assert!(HypervisorContext::acquire().is_ok());
+    }
+
+    /// A record keeps the layout of a mapping.
+    #[test]
+    fn mapping_record_from_mapping() {
+        let mapping = VirtualMachineMapping {
+            allocation_handle: AllocationHandle(3),
+            mapping_handle: MappingHandle(7),
+            address: 0x4_0000,
+            size: PAGE_SIZE,
+            permission: MemoryPermission::READ_EXECUTE,
+        };
+
+        let record = MappingRecord::from(&mapping);
+
+        assert_eq!(record.address, 0x4_0000);
+        assert_eq!(record.size, PAGE_SIZE as u64);
+        assert_eq!(record.allocation_handle, 3);
+        assert_eq!(
+            record.memory_permission(),
+            Some(MemoryPermission::READ_EXECUTE)
+        );
+    }
+
+    /// Create a mapping covering ``size`` bytes from ``address``.
+    fn mapping_at(address: hv_ipa_t, size: usize) -> VirtualMachineMapping {
+        VirtualMachineMapping {
+            allocation_handle: AllocationHandle(0),
+            mapping_handle: MappingHandle(0),
+            address,
+            size,
+            permission: MemoryPermission::READ_WRITE,
+        }
+    }
+
+    /// Overlaps are detected between mappings sharing at least one address.
+    #[test]
+    fn mapping_overlaps() {
+        let mapping = mapping_at(0x10000, 0x20000);
+
+        // Adjacent.
+        assert!(!mapping.overlaps(&mapping_at(0x0, 0x10000)));
+        assert!(!mapping.overlaps(&mapping_at(0x30000, 0x10000)));
+
+        // Nested, in both directions.
+        assert!(mapping.overlaps(&mapping_at(0x18000, 0x1000)));
+        assert!(mapping_at(0x18000, 0x1000).overlaps(&mapping));
+        assert!(mapping.overlaps(&mapping));
+
+        // Partially overlapping.
+        assert!(mapping.overlaps(&mapping_at(0x2F000, 0x2000)));
+
+        // Disjoint.
+        assert!(!mapping.overlaps(&mapping_at(0x100000, 0x10000)));
+
+        // Empty mappings never overlap.
+        assert!(!mapping.overlaps(&mapping_at(0x18000, 0)));
+        assert!(!mapping_at(0x18000, 0).overlaps(&mapping));
+    }
+
+    /// Mappings ending at the end of the address space don't wrap around.
+    #[test]
+    fn mapping_overlaps_at_the_end_of_the_address_space() {
+        let last = mapping_at(u64::MAX - 0xFFFF, 0x10000);
+
+        assert!(last.contains_address(u64::MAX));
+        assert!(!last.contains_address(0));
+        assert!(!last.overlaps(&mapping_at(0, 0x10000)));
+        assert!(last.overlaps(&mapping_at(u64::MAX, 1)));
+    }
+
+    /// Containment covers the start of a mapping but not its end.
+    #[test]
+    fn mapping_contains_address() {
+        let mapping = mapping_at(0x10000, 0x10000);
+
+        assert!(mapping.contains_address(0x10000));
+        assert!(mapping.contains_address(0x1FFFF));
+        assert!(!mapping.contains_address(0xFFFF));
+        assert!(!mapping.contains_address(0x20000));
+        assert!(!mapping_at(0x10000, 0).contains_address(0x10000));
+    }
+
+    /// Exit informations are read from a valid pointer.
+    #[test]
+    fn read_vcpu_exit_from_valid_pointer() {
+        let vcpu_exit = hv_vcpu_exit_t {
+            reason: HV_EXIT_REASON_EXCEPTION,
+            exception: hv_vcpu_exit_exception_t {
+                syndrome: 0x5a00_0000,
+                virtual_address: 0x1000,
+                physical_address: 0x2000,
+            },
+        };
+
+        let result = unsafe { read_vcpu_exit(&vcpu_exit) };
+
+        assert_eq!(result.reason, HV_EXIT_REASON_EXCEPTION);
+        assert_eq!(result.exception.syndrome, 0x5a00_0000);
+        assert_eq!(result.exception.physical_address, 0x2000);
+    }
+
+    /// A null exit informations pointer is caught in debug builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "vCPU exit informations pointer is null!")]
+    fn read_vcpu_exit_rejects_null_pointer() {
+        unsafe {
+            read_vcpu_exit(core::ptr::null());
+        }
+    }
+
+    /// A misaligned exit informations pointer is caught in debug builds.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "vCPU exit informations pointer is misaligned!")]
+    fn read_vcpu_exit_rejects_misaligned_pointer() {
+        let buffer = [0u64; 8];
+        let misaligned = (buffer.as_ptr() as *const u8).wrapping_add(1);
+
+        unsafe {
+            read_vcpu_exit(misaligned.cast());
+        }
+    }
+
+    /// Every error maps to a distinct exit code, unknown errors to the catch-all one.
+    #[test]
+    fn error_exit_codes_are_distinct() {
+        let errors = [
+            HypervisorError::Error,
+            HypervisorError::Busy,
+            HypervisorError::BadArgument,
+            HypervisorError::IllegalGuestState,
+            HypervisorError::NoResources,
+            HypervisorError::NoDevice,
+            HypervisorError::Denied,
+            HypervisorError::Unsupported,
+            HypervisorError::InvalidHandle,
+            HypervisorError::AllocationStillMapped,
+            HypervisorError::MisalignedAddress,
+            HypervisorError::UnmappedAddress,
+            HypervisorError::ReadOnlyRegister,
+            HypervisorError::MaxVcpusReached(8),
+            HypervisorError::Unknown(0x1234),
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(HypervisorError::as_exit_code).collect();
+
+        assert!(codes.iter().all(|code| (1..=255).contains(code)));
+
+        codes.sort_unstable();
+        codes.dedup();
+
+        assert_eq!(codes.len(), errors.len());
+
+        assert_eq!(HypervisorError::Unknown(0).as_exit_code(), 255);
+        assert_eq!(HypervisorError::Unknown(u32::MAX).as_exit_code(), 255);
+        assert_eq!(
+            HypervisorError::MaxVcpusReached(1).as_exit_code(),
+            HypervisorError::MaxVcpusReached(64).as_exit_code()
+        );
+    }
+
+    /// Create the exit informations of an exit with a given raw reason.
+    fn exit_with_reason(reason: hv_exit_reason_t) -> hv_vcpu_exit_t {
+        hv_vcpu_exit_t {
+            reason,
+            exception: hv_vcpu_exit_exception_t {
+                syndrome: 0,
+                virtual_address: 0,
+                physical_address: 0,
+            },
+        }
+    }
+
+    /// Reasons not known by the crate keep their raw value, unlike the documented unknown reason.
+    #[test]
+    fn exit_reason_from_unrecognized_reason() {
+        assert!(matches!(
+            VirtualCpuExitReason::from(exit_with_reason(0x1234)),
+            VirtualCpuExitReason::UnknownReason(0x1234)
+        ));
+        assert!(matches!(
+            VirtualCpuExitReason::from(exit_with_reason(u32::MAX)),
+            VirtualCpuExitReason::UnknownReason(u32::MAX)
+        ));
+        assert!(matches!(
+            VirtualCpuExitReason::from(exit_with_reason(HV_EXIT_REASON_UNKNOWN)),
+            VirtualCpuExitReason::Unknown
+        ));
+    }
+
+    /// Only the unknown exits are terminal.
+    #[test]
+    fn exit_reason_is_resumable() {
+        for (reason, resumable) in [
+            (HV_EXIT_REASON_CANCELED, true),
+            (HV_EXIT_REASON_EXCEPTION, true),
+            (HV_EXIT_REASON_VTIMER_ACTIVATED, true),
+            (HV_EXIT_REASON_UNKNOWN, false),
+            (0x1234, false),
+        ] {
+            assert_eq!(
+                VirtualCpuExitReason::from(exit_with_reason(reason)).is_resumable(),
+                resumable,
+                "{:#x}",
+                reason
+            );
+        }
+    }
+
+    /// A record with unknown permission bits is rejected.
+    #[test]
+    fn mapping_record_rejects_invalid_permission() {
+        let record = MappingRecord {
+            address: 0,
+            size: PAGE_SIZE as u64,
+            allocation_handle: 0,
+            permission: 0b1001,
+        };
+
+        assert_eq!(record.memory_permission(), None);
+    }
+
+    /// A failure in the middle of a transactional apply restores the registers already set.
+    #[test]
+    fn apply_transactional_rolls_back_on_failure() {
+        /// Register rejecting any write.
+        const READ_ONLY: usize = 2;
+
+        let mut registers = [1u64, 2, 3, 4];
+
+        let get = |registers: &mut [u64; 4], index: usize| Ok(registers[index]);
+        let set = |registers: &mut [u64; 4], index: usize, value: u64| {
+            if index == READ_ONLY {
+                return Err(HypervisorError::BadArgument);
+            }
+
+            registers[index] = value;
+
+            Ok(())
+        };
+
+        let result = apply_transactional(
+            &mut registers,
+            &[(0, 10), (1, 20), (0, 30), (READ_ONLY, 40), (3, 50)],
+            get,
+            set,
+        );
+
+        assert!(matches!(result, Err(HypervisorError::BadArgument)));
+        assert_eq!(registers, [1, 2, 3, 4]);
+
+        assert!(apply_transactional(&mut registers, &[(0, 10), (3, 50)], get, set).is_ok());
+        assert_eq!(registers, [10, 2, 3, 50]);
+    }
+
+    /// A mapping is undone when its bookkeeping cannot be reserved.
+    #[test]
+    fn map_with_rollback_unmaps_on_reservation_failure() {
+        use core::cell::Cell;
+
+        let mapped = Cell::new(false);
+
+        let map = || {
+            mapped.set(true);
+
+            Ok(())
+        };
+        let unmap = || {
+            mapped.set(false);
+
+            Ok(())
+        };
+
+        let result = map_with_rollback(map, || false, unmap);
+
+        assert!(matches!(result, Err(HypervisorError::NoResources)));
+        assert!(!mapped.get());
+
+        assert!(map_with_rollback(map, || true, unmap).is_ok());
+        assert!(mapped.get());
+
+        // Nothing is reserved if the mapping itself fails.
+        let reserved = Cell::new(false);
+
+        let result = map_with_rollback(
+            || Err(HypervisorError::BadArgument),
+            || {
+                reserved.set(true);
+
+                true
+            },
+            || unreachable!(),
+        );
+
+        assert!(matches!(result, Err(HypervisorError::BadArgument)));
+        assert!(!reserved.get());
+    }
+
+    /// Indexes 0 to 30 map to the X registers.
+    #[test]
+    fn register_from_x_index() {
+        for index in 0..=30u8 {
+            let register = Register::from_x_index(index).unwrap();
+
+            assert_eq!(register.name(), alloc::format!("X{}", index));
+        }
+
+        assert!(Register::from_x_index(31).is_none());
+        assert!(Register::from_x_index(u8::MAX).is_none());
+    }
+
+    /// Registers are parsed from their names, regardless of the case.
+    #[test]
+    fn register_from_name() {
+        assert!(matches!(Register::try_from("x0"), Ok(Register::X0)));
+        assert!(matches!(Register::try_from("X30"), Ok(Register::X30)));
+        assert!(matches!(Register::try_from("pc"), Ok(Register::PC)));
+        assert!(matches!(Register::try_from("Cpsr"), Ok(Register::CPSR)));
+        assert!(matches!(Register::try_from("lr"), Ok(Register::LR)));
+        assert!(matches!(Register::try_from("fp"), Ok(Register::FP)));
+        assert!(matches!("FPSR".parse(), Ok(Register::FPSR)));
+
+        for name in ["", "x31", "sp", "x0 "] {
+            assert_eq!(Register::try_from(name).err(), Some(ParseRegisterError));
+        }
+    }
+
+    /// Register errors name the register that failed.
+    #[test]
+    fn register_error_names_the_register() {
+        for (register, expected) in [
+            (Register::X5, "access to X5 failed: BadArgument"),
+            (Register::PC, "access to PC failed: BadArgument"),
+            (Register::FPSR, "access to FPSR failed: BadArgument"),
+        ] {
+            let error = RegisterError {
+                register,
+                source: HypervisorError::BadArgument,
+            };
+
+            assert_eq!(alloc::format!("{}", error), expected);
+        }
+    }
+
+    /// System registers are parsed from their names, regardless of the case.
+    #[test]
+    fn system_register_from_name() {
+        assert!(matches!(
+            SystemRegister::try_from("sctlr_el1"),
+            Ok(SystemRegister::SCTLR_EL1)
+        ));
+        assert!(matches!(
+            SystemRegister::try_from("MIDR_EL1"),
+            Ok(SystemRegister::MIDR_EL1)
+        ));
+        assert!(matches!("Vbar_El1".parse(), Ok(SystemRegister::VBAR_EL1)));
+
+        for register in SystemRegister::ALL {
+            assert!(matches!(
+                SystemRegister::try_from(register.name()),
+                Ok(parsed) if parsed.name() == register.name()
+            ));
+        }
+
+        assert!(SystemRegister::try_from("sctlr_el2").is_err());
+    }
+
+    /// The last transient error is returned once all attempts are exhausted.
+    #[test]
+    fn retry_transient_gives_up() {
+        let mut attempts = 0;
+
+        let result: Result<()> = retry_transient(4, || {
+            attempts += 1;
+
+            Err(HypervisorError::Busy)
+        });
+
+        assert!(matches!(result, Err(HypervisorError::Busy)));
+        assert_eq!(attempts, 4);
+        assert!(matches!(
+            retry_transient(0, || Ok(())),
+            Err(HypervisorError::BadArgument)
+        ));
     }
 }
@@ -0,0 +1,213 @@
+//! Statistical profiling of guest code by periodically sampling PC.
+
+use super::{Result, VcpuExitHandle, VirtualCpu, VirtualCpuExitReason};
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::time::Duration;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+/// State shared between a [ProfilingRunner] and its sampler thread.
+#[derive(Debug, Default)]
+struct SamplerState {
+    /// Set while [ProfilingRunner::run] is executing, the vCPU is only forced to exit meanwhile.
+    is_running: bool,
+
+    /// Set when the vCPU was forced to exit to take a sample, until the exit is handled.
+    sample_requested: bool,
+
+    /// Set to stop the sampler thread.
+    is_stopping: bool,
+}
+
+/// Lock the state of a sampler, ignoring poisoning as the state stays consistent.
+fn lock_state(state: &Mutex<SamplerState>) -> MutexGuard<'_, SamplerState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Thread forcing a vCPU to exit every sampling interval, reused by every [ProfilingRunner::run] call.
+#[derive(Debug)]
+struct Sampler {
+    /// The state shared with the thread, and the condition variable notified whenever it changes.
+    shared: Arc<(Mutex<SamplerState>, Condvar)>,
+
+    /// The sampler thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sampler {
+    /// Spawn the sampler thread of a vCPU.
+    fn spawn(exit_handle: VcpuExitHandle, interval: Duration) -> Sampler {
+        let shared = Arc::new((Mutex::new(SamplerState::default()), Condvar::new()));
+
+        let thread = {
+            let shared = shared.clone();
+
+            std::thread::spawn(move || {
+                let (state, condvar) = &*shared;
+                let mut state = lock_state(state);
+
+                while !state.is_stopping {
+                    if !state.is_running {
+                        state = condvar
+                            .wait(state)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+                        continue;
+                    }
+
+                    let (guard, result) = condvar
+                        .wait_timeout(state, interval)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+                    state = guard;
+
+                    // The exit is requested with the lock held so it cannot happen once the run returned.
+                    if result.timed_out() && state.is_running && !state.is_stopping {
+                        state.sample_requested = true;
+
+                        let _ = exit_handle.exit();
+                    }
+                }
+            })
+        };
+
+        Sampler {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Start or stop sampling.
+    fn set_running(&self, is_running: bool) {
+        let (state, condvar) = &*self.shared;
+
+        lock_state(state).is_running = is_running;
+        condvar.notify_one();
+    }
+
+    /// Check if an exit was requested to take a sample, acknowledging it.
+    fn take_sample_request(&self) -> bool {
+        let (state, _) = &*self.shared;
+
+        core::mem::take(&mut lock_state(state).sample_requested)
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        let (state, condvar) = &*self.shared;
+
+        lock_state(state).is_stopping = true;
+        condvar.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Run loop of a vCPU building an histogram of the guest PC.
+///
+/// While [ProfilingRunner::run] is executing, a helper thread forces the vCPU to exit every sampling interval.
+/// Each of those exits records the guest PC and re-enters the guest.
+/// The thread is spawned on the first run and reused until the runner is dropped.
+#[derive(Debug)]
+pub struct ProfilingRunner<'a> {
+    /// The vCPU.
+    vcpu: &'a mut VirtualCpu,
+
+    /// Interval between two samples.
+    interval: Duration,
+
+    /// Number of samples per guest address.
+    histogram: BTreeMap<u64, u64>,
+
+    /// The sampler thread, spawned on the first run.
+    sampler: Option<Sampler>,
+}
+
+impl<'a> ProfilingRunner<'a> {
+    /// Create a new profiling runner sampling a vCPU every ``interval``.
+    pub fn new(vcpu: &'a mut VirtualCpu, interval: Duration) -> Self {
+        ProfilingRunner {
+            vcpu,
+            interval,
+            histogram: BTreeMap::new(),
+            sampler: None,
+        }
+    }
+
+    /// Gets the vCPU.
+    pub fn vcpu(&mut self) -> &mut VirtualCpu {
+        self.vcpu
+    }
+
+    /// Gets the number of samples per guest address.
+    pub fn histogram(&self) -> &BTreeMap<u64, u64> {
+        &self.histogram
+    }
+
+    /// Gets the total number of samples.
+    pub fn sample_count(&self) -> u64 {
+        self.histogram.values().sum()
+    }
+
+    /// Gets the ``count`` most sampled guest addresses with their number of samples, from the hottest.
+    pub fn hottest(&self, count: usize) -> Vec<(u64, u64)> {
+        let mut result: Vec<(u64, u64)> = self
+            .histogram
+            .iter()
+            .map(|(address, samples)| (*address, *samples))
+            .collect();
+
+        result.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        result.truncate(count);
+
+        result
+    }
+
+    /// Discard all the samples.
+    pub fn clear(&mut self) {
+        self.histogram.clear();
+    }
+
+    /// Runs the vCPU until an exit that isn't caused by sampling.
+    ///
+    /// A sampling exit requested as a previous run returned is absorbed by the next run, so it never surfaces as [VirtualCpuExitReason::Cancelled].
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run(&mut self) -> Result<VirtualCpuExitReason> {
+        let exit_handle = self.vcpu.exit_handle();
+        let interval = self.interval;
+        let sampler = self
+            .sampler
+            .get_or_insert_with(|| Sampler::spawn(exit_handle, interval));
+
+        sampler.set_running(true);
+
+        let result = loop {
+            let reason = match self.vcpu.run() {
+                Ok(reason) => reason,
+                Err(error) => break Err(error),
+            };
+
+            match reason {
+                VirtualCpuExitReason::Cancelled if sampler.take_sample_request() => {
+                    match self.vcpu.sample_pc() {
+                        Ok(pc) => *self.histogram.entry(pc).or_insert(0) += 1,
+                        Err(error) => break Err(error),
+                    }
+                }
+                reason => break Ok(reason),
+            }
+        };
+
+        sampler.set_running(false);
+
+        result
+    }
+}
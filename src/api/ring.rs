@@ -0,0 +1,254 @@
+//! Single-producer single-consumer byte ring shared between the host and the guest.
+
+use super::{AllocationHandle, HypervisorError, Result, VirtualMachine};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Offset of the head index in the ring region.
+const RING_HEAD_OFFSET: usize = 0;
+
+/// Offset of the tail index in the ring region.
+const RING_TAIL_OFFSET: usize = 4;
+
+/// Offset of the data in the ring region.
+pub const RING_DATA_OFFSET: usize = 8;
+
+/// Byte ring living inside an allocation, used in one direction between the host and the guest.
+///
+/// The ring region has the following layout:
+/// - ``0x0``: head (``u32``), free running index of the next byte written by the producer, only written by the producer.
+/// - ``0x4``: tail (``u32``), free running index of the next byte read by the consumer, only written by the consumer.
+/// - ``0x8``: data, the capacity being the region size minus [RING_DATA_OFFSET]. Byte ``index`` is stored at ``0x8 + index % capacity``.
+///
+/// The ring is empty when head equals tail and full when head minus tail (wrapping) equals the capacity.
+///
+/// A side publishes data by writing it before storing its index with release semantics, and loads the index of the other side with acquire semantics
+/// before accessing the data. On the guest side, this means using ``LDAR``/``STLR`` (or ``DMB ISH`` barriers) for the indices.
+///
+/// The host is either the producer (with [GuestRing::host_write]) or the consumer (with [GuestRing::host_read]) of a given ring,
+/// a bidirectional channel uses two rings.
+#[derive(Debug)]
+pub struct GuestRing {
+    /// Host address of the ring region.
+    base: *mut u8,
+
+    /// Size of the data area, a power of two.
+    capacity: u32,
+}
+
+impl GuestRing {
+    /// Create a ring over ``size`` bytes at ``offset`` inside an allocation.
+    ///
+    /// The indices are not modified, use [GuestRing::reset] to initialize them if the guest doesn't.
+    ///
+    /// Returns [HypervisorError::BadArgument] if ``offset`` isn't aligned on 4 bytes, if the region doesn't fit inside the allocation
+    /// or if the capacity (``size`` minus [RING_DATA_OFFSET]) isn't a power of two between 1 and 2^31.
+    ///
+    /// # Safety
+    ///
+    /// The ring accesses the allocation through its host address (see [VirtualMachine::allocation_host_ptr]):
+    /// the allocation must outlive the ring and the region must not be accessed through slices of the allocation while the ring is alive.
+    pub unsafe fn new(
+        virtual_machine: &VirtualMachine,
+        allocation_handle: AllocationHandle,
+        offset: usize,
+        size: usize,
+    ) -> Result<Self> {
+        let (_, allocation) = virtual_machine.find_allocation_by_handle(allocation_handle)?;
+
+        let capacity = size
+            .checked_sub(RING_DATA_OFFSET)
+            .filter(|capacity| capacity.is_power_of_two() && *capacity <= 1 << 31)
+            .ok_or(HypervisorError::BadArgument)?;
+
+        match offset.checked_add(size) {
+            Some(end) if offset % 4 == 0 && end <= allocation.layout.size() => {}
+            _ => return Err(HypervisorError::BadArgument),
+        }
+
+        Ok(GuestRing::from_raw(
+            allocation.base_address.add(offset),
+            capacity as u32,
+        ))
+    }
+
+    /// Create a ring over a region at a given host address.
+    ///
+    /// # Safety
+    ///
+    /// ``base`` must be aligned on 4 bytes and valid for [RING_DATA_OFFSET] plus ``capacity`` bytes for the lifetime of the ring,
+    /// ``capacity`` must be a power of two between 1 and 2^31.
+    unsafe fn from_raw(base: *mut u8, capacity: u32) -> Self {
+        GuestRing { base, capacity }
+    }
+
+    /// Gets the capacity of the ring in bytes.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Gets the index stored at a given offset of the ring region.
+    fn index(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: The region is valid for the lifetime of the ring and the indices are aligned on 4 bytes.
+        unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+    }
+
+    /// Gets the host address of the data area.
+    fn data(&self) -> *mut u8 {
+        unsafe { self.base.add(RING_DATA_OFFSET) }
+    }
+
+    /// Reset the ring to an empty state.
+    ///
+    /// **This must not be called while the guest uses the ring.**
+    pub fn reset(&mut self) {
+        self.index(RING_HEAD_OFFSET).store(0, Ordering::Release);
+        self.index(RING_TAIL_OFFSET).store(0, Ordering::Release);
+    }
+
+    /// Gets the number of bytes available to read.
+    pub fn len(&self) -> usize {
+        let head = self.index(RING_HEAD_OFFSET).load(Ordering::Acquire);
+        let tail = self.index(RING_TAIL_OFFSET).load(Ordering::Acquire);
+
+        head.wrapping_sub(tail).min(self.capacity) as usize
+    }
+
+    /// Gets whether the ring is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets whether the ring is full.
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Write as much of ``data`` as fits in the ring, the host being the producer.
+    ///
+    /// Returns the number of bytes written, which is 0 if the ring is full.
+    pub fn host_write(&mut self, data: &[u8]) -> usize {
+        let head = self.index(RING_HEAD_OFFSET).load(Ordering::Relaxed);
+        let tail = self.index(RING_TAIL_OFFSET).load(Ordering::Acquire);
+
+        let free = self.capacity - head.wrapping_sub(tail).min(self.capacity);
+        let size = data.len().min(free as usize);
+
+        let start = (head % self.capacity) as usize;
+        let first_size = size.min(self.capacity() - start);
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.data().add(start), first_size);
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr().add(first_size),
+                self.data(),
+                size - first_size,
+            );
+        }
+
+        self.index(RING_HEAD_OFFSET)
+            .store(head.wrapping_add(size as u32), Ordering::Release);
+
+        size
+    }
+
+    /// Read all the bytes available in the ring, the host being the consumer.
+    ///
+    /// Returns an empty vector if the ring is empty.
+    pub fn host_read(&mut self) -> Vec<u8> {
+        let head = self.index(RING_HEAD_OFFSET).load(Ordering::Acquire);
+        let tail = self.index(RING_TAIL_OFFSET).load(Ordering::Relaxed);
+
+        let size = head.wrapping_sub(tail).min(self.capacity) as usize;
+
+        let start = (tail % self.capacity) as usize;
+        let first_size = size.min(self.capacity() - start);
+
+        let mut result = Vec::with_capacity(size);
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data().add(start), result.as_mut_ptr(), first_size);
+            core::ptr::copy_nonoverlapping(
+                self.data(),
+                result.as_mut_ptr().add(first_size),
+                size - first_size,
+            );
+
+            result.set_len(size);
+        }
+
+        self.index(RING_TAIL_OFFSET)
+            .store(tail.wrapping_add(size as u32), Ordering::Release);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the ring over a heap buffer.
+
+    use super::*;
+
+    /// Create a ring of ``capacity`` bytes over a heap buffer, returned alongside the ring to keep it alive.
+    fn heap_ring(capacity: u32) -> (Vec<u64>, GuestRing) {
+        let mut buffer = alloc::vec![0u64; (RING_DATA_OFFSET + capacity as usize) / 8 + 1];
+        let ring = unsafe { GuestRing::from_raw(buffer.as_mut_ptr() as *mut u8, capacity) };
+
+        (buffer, ring)
+    }
+
+    /// The ring reports its empty and full states.
+    #[test]
+    fn empty_and_full() {
+        let (_buffer, mut ring) = heap_ring(8);
+
+        assert!(ring.is_empty());
+        assert!(ring.host_read().is_empty());
+
+        assert_eq!(ring.host_write(b"0123456789"), 8);
+        assert!(ring.is_full());
+        assert_eq!(ring.len(), 8);
+        assert_eq!(ring.host_write(b"a"), 0);
+
+        assert_eq!(ring.host_read(), b"01234567");
+        assert!(ring.is_empty());
+
+        ring.host_write(b"abc");
+        ring.reset();
+
+        assert!(ring.is_empty());
+    }
+
+    /// Data wraps around the end of the data area.
+    #[test]
+    fn data_wraparound() {
+        let (_buffer, mut ring) = heap_ring(8);
+
+        assert_eq!(ring.host_write(b"012345"), 6);
+        assert_eq!(ring.host_read(), b"012345");
+
+        assert_eq!(ring.host_write(b"abcdefgh"), 8);
+        assert!(ring.is_full());
+        assert_eq!(ring.host_read(), b"abcdefgh");
+    }
+
+    /// The free running indices wrap around at 2^32.
+    #[test]
+    fn index_wraparound() {
+        let (_buffer, mut ring) = heap_ring(8);
+
+        ring.index(RING_HEAD_OFFSET)
+            .store(u32::MAX - 2, Ordering::Relaxed);
+        ring.index(RING_TAIL_OFFSET)
+            .store(u32::MAX - 2, Ordering::Relaxed);
+
+        assert!(ring.is_empty());
+        assert_eq!(ring.host_write(b"wrapping"), 8);
+        assert!(ring.is_full());
+        assert_eq!(ring.index(RING_HEAD_OFFSET).load(Ordering::Relaxed), 5);
+        assert_eq!(ring.host_read(), b"wrapping");
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+//! Run loop modeling WFI as an idle state, parking the host thread until the vCPU is woken.
+
+use super::vcpu_thread::ticks_to_duration;
+use super::{
+    ExceptionClass, InterruptType, Result, VcpuExitHandle, VirtualCpu, VirtualCpuExitReason,
+};
+
+use core::time::Duration;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+/// Wake requests shared between a [VcpuRunner] and its [VcpuWaker]s.
+#[derive(Debug, Default)]
+struct WakeFlags {
+    /// Set when the vCPU must leave its idle state.
+    woken: bool,
+
+    /// Set when the vCPU was forced to exit to deliver an interrupt.
+    kicked: bool,
+
+    /// Set when the vCPU was forced to exit with [VcpuWaker::exit].
+    exit_requested: bool,
+
+    /// Set while the IRQ line is asserted.
+    irq: bool,
+
+    /// Set while the FIQ line is asserted.
+    fiq: bool,
+}
+
+/// State shared between a [VcpuRunner] and its [VcpuWaker]s.
+#[derive(Debug, Default)]
+struct WakeState {
+    /// The pending wake requests.
+    flags: Mutex<WakeFlags>,
+
+    /// Notified when a wake request is made.
+    condvar: Condvar,
+}
+
+impl WakeState {
+    /// Lock the wake requests, ignoring poisoning as the flags are always consistent.
+    fn lock(&self) -> std::sync::MutexGuard<'_, WakeFlags> {
+        self.flags
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Handle used from any thread to wake a [VcpuRunner] or deliver interrupts to it.
+///
+/// # Wake protocol
+///
+/// - [VcpuWaker::wake] ends the current idle state, or the next one if the vCPU isn't idle.
+/// - [VcpuWaker::raise] asserts an interrupt line and wakes the vCPU, forcing it to exit if the guest is running.
+///   The line stays asserted until [VcpuWaker::lower] is called, the interrupt is thus made pending before every entry in the guest
+///   and isn't lost while the guest masks it. The vCPU doesn't idle while a line is asserted.
+/// - [VcpuWaker::exit] forces [VcpuRunner::run] to return [VirtualCpuExitReason::Cancelled].
+///
+/// The runner re-enters the guest after the exits it caused itself to deliver interrupts.
+/// As such an exit cannot be told apart from one caused by the exit handle of the vCPU,
+/// [VcpuWaker::exit] must be used instead of the exit handle to stop the runner.
+#[derive(Clone, Debug)]
+pub struct VcpuWaker {
+    /// The state shared with the runner.
+    state: Arc<WakeState>,
+
+    /// The exit handle of the vCPU, used to deliver interrupts while the guest is running.
+    exit_handle: VcpuExitHandle,
+}
+
+impl VcpuWaker {
+    /// Wake the vCPU if it's idle.
+    ///
+    /// If the vCPU isn't idle, its next WFI completes immediately.
+    pub fn wake(&self) {
+        let mut flags = self.state.lock();
+
+        flags.woken = true;
+
+        self.state.condvar.notify_all();
+    }
+
+    /// Assert an interrupt line of the vCPU and wake it.
+    ///
+    /// If the guest is running, it's forced to exit so that the interrupt is delivered right away.
+    /// The line stays asserted until [VcpuWaker::lower] is called.
+    pub fn raise(&self, interrupt_type: InterruptType) -> Result<()> {
+        let mut flags = self.state.lock();
+
+        match interrupt_type {
+            InterruptType::IRQ => flags.irq = true,
+            InterruptType::FIQ => flags.fiq = true,
+        }
+
+        flags.woken = true;
+        flags.kicked = true;
+
+        self.state.condvar.notify_all();
+
+        self.exit_handle.exit()
+    }
+
+    /// Deassert an interrupt line of the vCPU.
+    ///
+    /// The interrupt stops being made pending from the next entry in the guest.
+    pub fn lower(&self, interrupt_type: InterruptType) {
+        let mut flags = self.state.lock();
+
+        match interrupt_type {
+            InterruptType::IRQ => flags.irq = false,
+            InterruptType::FIQ => flags.fiq = false,
+        }
+    }
+
+    /// Force the vCPU to exit, making [VcpuRunner::run] return [VirtualCpuExitReason::Cancelled].
+    ///
+    /// The vCPU is also woken if it's idle.
+    pub fn exit(&self) -> Result<()> {
+        let mut flags = self.state.lock();
+
+        flags.exit_requested = true;
+        flags.woken = true;
+
+        self.state.condvar.notify_all();
+
+        self.exit_handle.exit()
+    }
+}
+
+/// Run loop of a vCPU handling WFI as an idle state.
+///
+/// When the guest executes WFI, the runner parks the host thread until a [VcpuWaker] wakes it or the idle timeout elapses,
+/// then re-enters the guest after the WFI instruction.
+/// Interrupt lines asserted with [VcpuWaker::raise] are made pending on the vCPU before every entry in the guest (see [VcpuWaker] for the wake protocol).
+///
+/// WFE is not treated as an idle state: the guest is re-entered right away after the instruction.
+#[derive(Debug)]
+pub struct VcpuRunner<'a> {
+    /// The vCPU.
+    vcpu: &'a mut VirtualCpu,
+
+    /// The state shared with the wakers.
+    state: Arc<WakeState>,
+
+    /// Maximum time to stay idle, None to wait for a wake request forever.
+    idle_timeout: Option<Duration>,
+}
+
+impl<'a> VcpuRunner<'a> {
+    /// Create a new runner for a vCPU.
+    ///
+    /// The idle timeout should be set if the guest relies on its timer to leave WFI, as the runner doesn't track the timer deadline.
+    pub fn new(vcpu: &'a mut VirtualCpu, idle_timeout: Option<Duration>) -> Self {
+        VcpuRunner {
+            vcpu,
+            state: Arc::new(WakeState::default()),
+            idle_timeout,
+        }
+    }
+
+    /// Gets a new waker for this runner.
+    pub fn waker(&self) -> VcpuWaker {
+        VcpuWaker {
+            state: self.state.clone(),
+            exit_handle: self.vcpu.exit_handle(),
+        }
+    }
+
+    /// Gets the vCPU.
+    pub fn vcpu(&mut self) -> &mut VirtualCpu {
+        self.vcpu
+    }
+
+    /// Make the interrupts asserted by wakers pending on the vCPU.
+    ///
+    /// As the Hypervisor clears pending interrupts after every run, this must be done before every entry in the guest.
+    fn apply_pending_interrupts(&mut self) -> Result<()> {
+        let (irq, fiq) = {
+            let flags = self.state.lock();
+
+            (flags.irq, flags.fiq)
+        };
+
+        if irq {
+            self.vcpu.set_pending_interrupt(InterruptType::IRQ, true)?;
+        }
+
+        if fiq {
+            self.vcpu.set_pending_interrupt(InterruptType::FIQ, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Park the thread until a wake request is made, the idle timeout elapses or the deadline is reached.
+    ///
+    /// The thread isn't parked while an interrupt line is asserted, as the WFI completes right away.
+    fn idle(&self, deadline: Option<Instant>) {
+        let mut flags = self.state.lock();
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                Some(
+                    self.idle_timeout
+                        .map_or(remaining, |timeout| timeout.min(remaining)),
+                )
+            }
+            None => self.idle_timeout,
+        };
+
+        while !flags.woken && !flags.irq && !flags.fiq {
+            match timeout {
+                Some(timeout) => {
+                    let (guard, result) = self
+                        .state
+                        .condvar
+                        .wait_timeout(flags, timeout)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+                    flags = guard;
+
+                    if result.timed_out() {
+                        break;
+                    }
+                }
+                None => {
+                    flags = self
+                        .state
+                        .condvar
+                        .wait(flags)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+
+        flags.woken = false;
+    }
+
+    /// Runs the vCPU until an exit that isn't a WFI, a WFE or caused by [VcpuWaker::raise].
+    ///
+    /// Returns [VirtualCpuExitReason::Cancelled] once [VcpuWaker::exit] is called.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run(&mut self) -> Result<VirtualCpuExitReason> {
+        self.run_until(None)
+    }
+
+    /// Runs the vCPU like [VcpuRunner::run] for at most ``max_exec_ticks`` (in ``mach_absolute_time`` ticks, see [VirtualCpu::run_bounded]).
+    ///
+    /// The time spent idle counts toward the budget: [VirtualCpuExitReason::Cancelled] is returned once it's elapsed, even if the vCPU is idle.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_bounded(&mut self, max_exec_ticks: u64) -> Result<VirtualCpuExitReason> {
+        self.run_until(Some(Instant::now() + ticks_to_duration(max_exec_ticks)))
+    }
+
+    /// Runs the vCPU until an exit that isn't handled by the runner, or until the deadline is reached if any.
+    fn run_until(&mut self, deadline: Option<Instant>) -> Result<VirtualCpuExitReason> {
+        loop {
+            self.apply_pending_interrupts()?;
+
+            let reason = match deadline {
+                Some(deadline) if Instant::now() >= deadline => {
+                    return Ok(VirtualCpuExitReason::Cancelled)
+                }
+                Some(deadline) => self.vcpu.run_until_instant(deadline)?,
+                None => self.vcpu.run()?,
+            };
+
+            match reason {
+                VirtualCpuExitReason::Exception { exception }
+                    if exception.exception_class() == ExceptionClass::WfiWfe =>
+                {
+                    self.vcpu.advance_pc(4)?;
+
+                    // TI is clear for WFI.
+                    if exception.syndrome & 1 == 0 {
+                        self.idle(deadline);
+                    }
+                }
+                VirtualCpuExitReason::Cancelled => {
+                    let mut flags = self.state.lock();
+
+                    // Only absorb the exits caused by the runner itself.
+                    let is_kick = flags.kicked && !flags.exit_requested;
+
+                    flags.kicked = false;
+                    flags.exit_requested = false;
+
+                    if !is_kick {
+                        return Ok(reason);
+                    }
+                }
+                reason => return Ok(reason),
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Round-robin scheduling of vCPUs, each resident in its own thread.
+
+use super::{
+    HypervisorError, Result, VcpuExitHandle, VcpuRunner, VcpuWaker, VirtualCpu,
+    VirtualCpuExitReason, VirtualMachine,
+};
+
+use alloc::boxed::Box;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+/// Work executed on the vCPU thread.
+type GuestCall = Box<dyn FnOnce(&mut VirtualCpu) + Send>;
+
+/// Command sent by a [GuestScheduler] to a vCPU thread.
+enum GuestCommand {
+    /// Run the vCPU for at most the given amount of ticks.
+    Run(u64),
+
+    /// Call a function with the vCPU.
+    Call(GuestCall),
+}
+
+/// Exit of a vCPU reported by [GuestScheduler::run_slice].
+#[derive(Debug)]
+pub struct GuestExit {
+    /// The index of the vCPU.
+    pub index: usize,
+
+    /// The result of the slice.
+    ///
+    /// [VirtualCpuExitReason::Cancelled] is reported when the slice is elapsed.
+    pub reason: Result<VirtualCpuExitReason>,
+}
+
+/// vCPU thread owned by a [GuestScheduler].
+#[derive(Debug)]
+struct GuestSlot {
+    /// Sends commands to the vCPU thread, dropped to stop it.
+    commands: Option<Sender<GuestCommand>>,
+
+    /// The exit handle of the vCPU.
+    exit_handle: VcpuExitHandle,
+
+    /// The waker of the runner of the vCPU, None until the thread reports it.
+    waker: Option<VcpuWaker>,
+
+    /// The vCPU thread.
+    join_handle: Option<JoinHandle<Result<()>>>,
+}
+
+/// Scheduler running multiple vCPUs one at a time, in round-robin time slices.
+///
+/// Every vCPU is resident in its own thread, as required by the Hypervisor, that waits for commands from the scheduler.
+/// The scheduler is used from a single host thread: [GuestScheduler::run_slice] grants a slice to the next vCPU (with [VcpuRunner::run_bounded])
+/// and blocks until its exit arrives in the central exit queue. Only one vCPU runs at a time so every vCPU gets the same share of guest time.
+///
+/// As the vCPUs run with a [VcpuRunner], a vCPU executing WFI parks its thread until it's woken with its [VcpuWaker] or its slice is elapsed.
+///
+/// The host services exits from its own thread by executing code on the vCPU thread with [GuestScheduler::with_vcpu],
+/// and accesses the Virtual Machine borrowed by the scheduler with [GuestScheduler::virtual_machine].
+///
+/// Dropping the scheduler stops and joins all the vCPU threads, destroying the vCPUs.
+#[derive(Debug)]
+pub struct GuestScheduler<'a> {
+    /// The Virtual Machine, borrowed until the vCPU threads are joined.
+    virtual_machine: &'a mut VirtualMachine,
+
+    /// The vCPU threads.
+    slots: Vec<GuestSlot>,
+
+    /// The central exit queue.
+    exits: Receiver<GuestExit>,
+
+    /// Length of a time slice, in ``mach_absolute_time`` ticks.
+    time_slice: u64,
+
+    /// Index of the next vCPU to run.
+    next_index: usize,
+}
+
+impl<'a> GuestScheduler<'a> {
+    /// Create a scheduler for ``count`` new vCPUs, each one resident in its own new thread (see [VirtualMachine::spawn_vcpus]).
+    ///
+    /// On every thread, ``setup`` is called with the vCPU index and the vCPU to set its initial state.
+    /// If ``setup`` fails, the thread stops and the vCPU reports [HypervisorError::Error] afterwards.
+    pub fn new<F>(
+        virtual_machine: &'a mut VirtualMachine,
+        count: usize,
+        time_slice: u64,
+        setup: F,
+    ) -> Result<Self>
+    where
+        F: Fn(usize, &mut VirtualCpu) -> Result<()> + Send + Sync + 'static,
+    {
+        let (exit_sender, exits) = mpsc::channel();
+        let (waker_sender, wakers) = mpsc::channel();
+
+        let mut senders = Vec::with_capacity(count);
+        let mut receivers = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (sender, receiver) = mpsc::channel();
+
+            senders.push(sender);
+            receivers.push(Some(receiver));
+        }
+
+        let receivers = Arc::new(Mutex::new(receivers));
+        let thread_senders = Mutex::new((exit_sender, waker_sender));
+
+        // SAFETY: The threads are joined when the scheduler is dropped, before the borrow of the Virtual Machine ends.
+        let threads = unsafe {
+            virtual_machine.spawn_vcpus(count, move |index, vcpu| {
+                let (exit_sender, waker_sender) = thread_senders
+                    .lock()
+                    .map_err(|_| HypervisorError::Error)?
+                    .clone();
+
+                let mut runner = VcpuRunner::new(vcpu, None);
+
+                // The waker is reported first, the scheduler waits for it.
+                let _ = waker_sender.send((index, runner.waker()));
+
+                let commands: Receiver<GuestCommand> = receivers
+                    .lock()
+                    .map_err(|_| HypervisorError::Error)?
+                    .get_mut(index)
+                    .and_then(Option::take)
+                    .ok_or(HypervisorError::Error)?;
+
+                setup(index, runner.vcpu())?;
+
+                while let Ok(command) = commands.recv() {
+                    match command {
+                        GuestCommand::Run(ticks) => {
+                            let reason = runner.run_bounded(ticks);
+
+                            if exit_sender.send(GuestExit { index, reason }).is_err() {
+                                break;
+                            }
+                        }
+                        GuestCommand::Call(call) => call(runner.vcpu()),
+                    }
+                }
+
+                Ok(())
+            })?
+        };
+
+        let slots = threads
+            .into_iter()
+            .zip(senders)
+            .map(|((exit_handle, join_handle), sender)| GuestSlot {
+                commands: Some(sender),
+                exit_handle,
+                waker: None,
+                join_handle: Some(join_handle),
+            })
+            .collect();
+
+        // Dropping the scheduler on error joins the threads.
+        let mut scheduler = GuestScheduler {
+            virtual_machine,
+            slots,
+            exits,
+            time_slice,
+            next_index: 0,
+        };
+
+        for _ in 0..count {
+            let (index, waker) = wakers.recv().map_err(|_| HypervisorError::Error)?;
+
+            scheduler.slots[index].waker = Some(waker);
+        }
+
+        Ok(scheduler)
+    }
+
+    /// Gets the Virtual Machine.
+    pub fn virtual_machine(&mut self) -> &mut VirtualMachine {
+        self.virtual_machine
+    }
+
+    /// Gets the number of vCPUs.
+    pub fn vcpu_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Gets the exit handle of a vCPU.
+    pub fn exit_handle(&self, index: usize) -> Result<VcpuExitHandle> {
+        self.slots
+            .get(index)
+            .map(|slot| slot.exit_handle)
+            .ok_or(HypervisorError::BadArgument)
+    }
+
+    /// Gets the waker of a vCPU, used to wake it from WFI or deliver interrupts to it.
+    pub fn waker(&self, index: usize) -> Result<VcpuWaker> {
+        self.slots
+            .get(index)
+            .and_then(|slot| slot.waker.clone())
+            .ok_or(HypervisorError::BadArgument)
+    }
+
+    /// Sends a command to a vCPU thread.
+    fn send(&self, index: usize, command: GuestCommand) -> Result<()> {
+        let slot = self.slots.get(index).ok_or(HypervisorError::BadArgument)?;
+
+        slot.commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or(HypervisorError::Error)
+    }
+
+    /// Runs the next vCPU in round-robin order for one time slice and returns its exit.
+    ///
+    /// Returns [HypervisorError::BadArgument] if there is no vCPU and [HypervisorError::Error] if the vCPU thread stopped.
+    pub fn run_slice(&mut self) -> Result<GuestExit> {
+        if self.slots.is_empty() {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let index = self.next_index;
+
+        self.next_index = (index + 1) % self.slots.len();
+
+        self.send(index, GuestCommand::Run(self.time_slice))?;
+
+        self.exits.recv().map_err(|_| HypervisorError::Error)
+    }
+
+    /// Calls a function with a vCPU on its thread and returns its result.
+    ///
+    /// Returns [HypervisorError::BadArgument] if the index is out of range and [HypervisorError::Error] if the vCPU thread stopped.
+    pub fn with_vcpu<F, R>(&mut self, index: usize, function: F) -> Result<R>
+    where
+        F: FnOnce(&mut VirtualCpu) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.send(
+            index,
+            GuestCommand::Call(Box::new(move |vcpu| {
+                let _ = sender.send(function(vcpu));
+            })),
+        )?;
+
+        receiver.recv().map_err(|_| HypervisorError::Error)
+    }
+}
+
+impl<'a> Drop for GuestScheduler<'a> {
+    fn drop(&mut self) {
+        for slot in &mut self.slots {
+            slot.commands = None;
+        }
+
+        for slot in &mut self.slots {
+            if let Some(join_handle) = slot.join_handle.take() {
+                let _ = join_handle.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Minimal ARM semihosting support over HVC, intended for early guest console output.
+//!
+//! The guest issues ``HVC #0xF000`` with the operation number in ``W0`` and its parameter in ``X1``.
+//!
+//! The following operations are supported:
+//!
+//! - ``SYS_WRITEC`` (``0x03``): ``X1`` points to the character to write.
+//! - ``SYS_WRITE0`` (``0x04``): ``X1`` points to the null terminated string to write.
+//!
+//! Guest addresses are assumed to be identity mapped (as with the MMU disabled).
+
+use alloc::vec::Vec;
+
+use super::{
+    hv_vcpu_exit_exception_t, ExceptionClass, HypervisorError, Register, Result, VirtualCpu,
+    VirtualMachine,
+};
+
+/// The HVC immediate used for semihosting calls.
+pub const SEMIHOSTING_HVC_IMMEDIATE: u16 = 0xF000;
+
+/// The ``SYS_WRITEC`` semihosting operation.
+pub const SYS_WRITEC: u32 = 0x03;
+
+/// The ``SYS_WRITE0`` semihosting operation.
+pub const SYS_WRITE0: u32 = 0x04;
+
+/// Size of the chunks in which ``SYS_WRITE0`` strings are read.
+///
+/// Chunks are aligned on their size, so that they never cross a page boundary and thus never read past the mapping holding the end of the string.
+const WRITE0_CHUNK_SIZE: usize = 0x100;
+
+/// Handler of semihosting calls, forwarding the guest console output to a callback.
+pub struct SemihostingHandler<F: FnMut(&[u8])> {
+    /// Callback receiving the bytes written by the guest.
+    output: F,
+}
+
+impl<F: FnMut(&[u8])> core::fmt::Debug for SemihostingHandler<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SemihostingHandler").finish_non_exhaustive()
+    }
+}
+
+impl<F: FnMut(&[u8])> SemihostingHandler<F> {
+    /// Create a new semihosting handler with a callback receiving the bytes written by the guest.
+    pub fn new(output: F) -> Self {
+        SemihostingHandler { output }
+    }
+
+    /// Handle a semihosting call from an exception exit.
+    ///
+    /// Returns ``false`` if the exception isn't a semihosting call.
+    /// The guest resumes after the HVC instruction, no PC adjustment is needed.
+    ///
+    /// Returns [HypervisorError::Unsupported] if the operation isn't supported.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn handle(
+        &mut self,
+        virtual_machine: &VirtualMachine,
+        vcpu: &mut VirtualCpu,
+        exception: &hv_vcpu_exit_exception_t,
+    ) -> Result<bool> {
+        if exception.exception_class() != ExceptionClass::Hvc64
+            || exception.syndrome & 0xFFFF != u64::from(SEMIHOSTING_HVC_IMMEDIATE)
+        {
+            return Ok(false);
+        }
+
+        let operation = vcpu.get_register(Register::X0)? as u32;
+        let parameter = vcpu.get_register(Register::X1)?;
+
+        match operation {
+            SYS_WRITEC => {
+                let mut character = [0; 1];
+
+                virtual_machine.read_guest(parameter, &mut character)?;
+
+                (self.output)(&character);
+            }
+            SYS_WRITE0 => {
+                let mut string = Vec::new();
+                let mut chunk = [0; WRITE0_CHUNK_SIZE];
+
+                loop {
+                    let address = parameter
+                        .checked_add(string.len() as u64)
+                        .ok_or(HypervisorError::UnmappedAddress)?;
+
+                    let chunk_size =
+                        WRITE0_CHUNK_SIZE - (address % WRITE0_CHUNK_SIZE as u64) as usize;
+                    let chunk = &mut chunk[..chunk_size];
+
+                    virtual_machine.read_guest(address, chunk)?;
+
+                    if let Some(length) = chunk.iter().position(|character| *character == 0) {
+                        string.extend_from_slice(&chunk[..length]);
+
+                        break;
+                    }
+
+                    string.extend_from_slice(chunk);
+                }
+
+                (self.output)(&string);
+            }
+            _ => return Err(HypervisorError::Unsupported),
+        }
+
+        Ok(true)
+    }
+}
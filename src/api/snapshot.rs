@@ -0,0 +1,424 @@
+//! Snapshots of the guest state, used to quickly reset a Virtual Machine to a known state.
+
+use alloc::vec::Vec;
+
+use super::{
+    hv_ipa_t, hv_reg_t, HypervisorError, Register, Result, SystemRegister, VirtualCpu,
+    VirtualMachine, PAGE_SIZE,
+};
+
+/// Registers saved by a [VcpuSnapshot].
+const SNAPSHOT_REGISTERS: [Register; 35] = [
+    Register::X0,
+    Register::X1,
+    Register::X2,
+    Register::X3,
+    Register::X4,
+    Register::X5,
+    Register::X6,
+    Register::X7,
+    Register::X8,
+    Register::X9,
+    Register::X10,
+    Register::X11,
+    Register::X12,
+    Register::X13,
+    Register::X14,
+    Register::X15,
+    Register::X16,
+    Register::X17,
+    Register::X18,
+    Register::X19,
+    Register::X20,
+    Register::X21,
+    Register::X22,
+    Register::X23,
+    Register::X24,
+    Register::X25,
+    Register::X26,
+    Register::X27,
+    Register::X28,
+    Register::X29,
+    Register::X30,
+    Register::PC,
+    Register::FPCR,
+    Register::FPSR,
+    Register::CPSR,
+];
+
+/// System registers saved by a [VcpuSnapshot].
+const SNAPSHOT_SYSTEM_REGISTERS: [SystemRegister; 24] = [
+    SystemRegister::SCTLR_EL1,
+    SystemRegister::CPACR_EL1,
+    SystemRegister::TTBR0_EL1,
+    SystemRegister::TTBR1_EL1,
+    SystemRegister::TCR_EL1,
+    SystemRegister::SPSR_EL1,
+    SystemRegister::ELR_EL1,
+    SystemRegister::SP_EL0,
+    SystemRegister::SP_EL1,
+    SystemRegister::AFSR0_EL1,
+    SystemRegister::AFSR1_EL1,
+    SystemRegister::ESR_EL1,
+    SystemRegister::FAR_EL1,
+    SystemRegister::PAR_EL1,
+    SystemRegister::MAIR_EL1,
+    SystemRegister::AMAIR_EL1,
+    SystemRegister::VBAR_EL1,
+    SystemRegister::CONTEXTIDR_EL1,
+    SystemRegister::TPIDR_EL1,
+    SystemRegister::TPIDR_EL0,
+    SystemRegister::TPIDRRO_EL0,
+    SystemRegister::CNTKCTL_EL1,
+    SystemRegister::CNTV_CTL_EL0,
+    SystemRegister::CNTV_CVAL_EL0,
+];
+
+/// Magic of the binary layout of a [VcpuSnapshot] (``AHVS``).
+pub const VCPU_SNAPSHOT_MAGIC: [u8; 4] = *b"AHVS";
+
+/// Version of the binary layout of a [VcpuSnapshot], increased whenever the saved registers change.
+pub const VCPU_SNAPSHOT_VERSION: u32 = 1;
+
+/// Size of the header of the binary layout of a [VcpuSnapshot].
+const VCPU_SNAPSHOT_HEADER_SIZE: usize = 16;
+
+/// Snapshot of the content of every mapped region of the guest.
+#[derive(Clone, Debug)]
+pub struct GuestMemorySnapshot {
+    /// Guest address and content of every mapping.
+    regions: Vec<(hv_ipa_t, Vec<u8>)>,
+}
+
+impl GuestMemorySnapshot {
+    /// Gets the guest address and content of every mapping of the snapshot.
+    pub fn regions(&self) -> &[(hv_ipa_t, Vec<u8>)] {
+        &self.regions
+    }
+}
+
+impl From<Vec<(hv_ipa_t, Vec<u8>)>> for GuestMemorySnapshot {
+    fn from(regions: Vec<(hv_ipa_t, Vec<u8>)>) -> GuestMemorySnapshot {
+        GuestMemorySnapshot { regions }
+    }
+}
+
+/// Snapshot of the general purpose and EL1 context registers of a vCPU.
+#[derive(Clone, Debug)]
+pub struct VcpuSnapshot {
+    /// Values of the general purpose registers.
+    registers: [u64; SNAPSHOT_REGISTERS.len()],
+
+    /// Values of the EL1 context system registers.
+    system_registers: [u64; SNAPSHOT_SYSTEM_REGISTERS.len()],
+}
+
+impl VcpuSnapshot {
+    /// Gets the general purpose registers saved in the snapshot.
+    pub fn registers(&self) -> impl Iterator<Item = (Register, u64)> + '_ {
+        SNAPSHOT_REGISTERS
+            .iter()
+            .copied()
+            .zip(self.registers.iter().copied())
+    }
+
+    /// Gets the system registers saved in the snapshot.
+    pub fn system_registers(&self) -> impl Iterator<Item = (SystemRegister, u64)> + '_ {
+        SNAPSHOT_SYSTEM_REGISTERS
+            .iter()
+            .copied()
+            .zip(self.system_registers.iter().copied())
+    }
+
+    /// Serialize the snapshot to its binary layout.
+    ///
+    /// All values are little endian:
+    /// - ``0x0``: [VCPU_SNAPSHOT_MAGIC].
+    /// - ``0x4``: [VCPU_SNAPSHOT_VERSION] (``u32``).
+    /// - ``0x8``: number of general purpose registers (``u32``).
+    /// - ``0xC``: number of system registers (``u32``).
+    /// - ``0x10``: values of the general purpose registers (``u64`` each), followed by the values of the system registers (``u64`` each).
+    ///
+    /// The registers are stored in the order reported by [VcpuSnapshot::registers] and [VcpuSnapshot::system_registers], which is fixed for a given version.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            VCPU_SNAPSHOT_HEADER_SIZE + (self.registers.len() + self.system_registers.len()) * 8,
+        );
+
+        result.extend_from_slice(&VCPU_SNAPSHOT_MAGIC);
+        result.extend_from_slice(&VCPU_SNAPSHOT_VERSION.to_le_bytes());
+        result.extend_from_slice(&(self.registers.len() as u32).to_le_bytes());
+        result.extend_from_slice(&(self.system_registers.len() as u32).to_le_bytes());
+
+        for value in self.registers.iter().chain(self.system_registers.iter()) {
+            result.extend_from_slice(&value.to_le_bytes());
+        }
+
+        result
+    }
+
+    /// Deserialize a snapshot from its binary layout (see [VcpuSnapshot::to_bytes]).
+    ///
+    /// Returns [HypervisorError::BadArgument] if the magic, the version or the register counts don't match, or if the size is invalid.
+    pub fn from_bytes(bytes: &[u8]) -> Result<VcpuSnapshot> {
+        let read_u32 = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        };
+
+        let header_matches = bytes.get(..4) == Some(&VCPU_SNAPSHOT_MAGIC[..])
+            && read_u32(0x4) == Some(VCPU_SNAPSHOT_VERSION)
+            && read_u32(0x8) == Some(SNAPSHOT_REGISTERS.len() as u32)
+            && read_u32(0xC) == Some(SNAPSHOT_SYSTEM_REGISTERS.len() as u32);
+
+        let expected_size = VCPU_SNAPSHOT_HEADER_SIZE
+            + (SNAPSHOT_REGISTERS.len() + SNAPSHOT_SYSTEM_REGISTERS.len()) * 8;
+
+        if !header_matches || bytes.len() != expected_size {
+            return Err(HypervisorError::BadArgument);
+        }
+
+        let mut snapshot = VcpuSnapshot {
+            registers: [0; SNAPSHOT_REGISTERS.len()],
+            system_registers: [0; SNAPSHOT_SYSTEM_REGISTERS.len()],
+        };
+
+        let values = bytes[VCPU_SNAPSHOT_HEADER_SIZE..]
+            .chunks_exact(8)
+            .map(|chunk| {
+                let mut value = [0; 8];
+
+                value.copy_from_slice(chunk);
+
+                u64::from_le_bytes(value)
+            });
+
+        for (destination, value) in snapshot
+            .registers
+            .iter_mut()
+            .chain(snapshot.system_registers.iter_mut())
+            .zip(values)
+        {
+            *destination = value;
+        }
+
+        Ok(snapshot)
+    }
+}
+
+impl VcpuSnapshot {
+    /// Gets the slot of a register in the snapshot, aliases (FP and LR) share the slot of the aliased register.
+    fn register_slot(register: Register) -> usize {
+        let raw_register = hv_reg_t::from(register);
+
+        SNAPSHOT_REGISTERS
+            .iter()
+            .position(|value| hv_reg_t::from(*value) == raw_register)
+            .expect("Register not saved in snapshot! (BUG)")
+    }
+}
+
+/// Every [Register] is saved in a [VcpuSnapshot], indexing never panics.
+impl core::ops::Index<Register> for VcpuSnapshot {
+    type Output = u64;
+
+    fn index(&self, register: Register) -> &u64 {
+        &self.registers[VcpuSnapshot::register_slot(register)]
+    }
+}
+
+impl core::ops::IndexMut<Register> for VcpuSnapshot {
+    fn index_mut(&mut self, register: Register) -> &mut u64 {
+        &mut self.registers[VcpuSnapshot::register_slot(register)]
+    }
+}
+
+impl VirtualMachine {
+    /// Take a snapshot of the content of every mapped region of the guest.
+    #[must_use]
+    pub fn snapshot_memory(&self) -> GuestMemorySnapshot {
+        GuestMemorySnapshot::from(self.dump_guest_memory())
+    }
+
+    /// Reset the guest memory to the content of a snapshot.
+    ///
+    /// **Every mapping of the snapshot must still be mapped at the same guest address.**
+    pub fn reset_to(&mut self, mem_snapshot: &GuestMemorySnapshot) -> Result<()> {
+        self.restore_guest_memory(&mem_snapshot.regions)
+    }
+
+    /// Reset the guest memory to the content of a snapshot, only restoring the pages written since dirty page tracking was enabled or since the dirty pages were last taken.
+    ///
+    /// The restored pages are write protected again (see [VirtualMachine::take_dirty_pages]).
+    /// When dirty page tracking is disabled, every mapping is restored as with [VirtualMachine::reset_to].
+    ///
+    /// **Every mapping of the snapshot must still be mapped at the same guest address,
+    /// and the snapshot must have been taken while no page was dirty (for example right after [VirtualMachine::enable_dirty_tracking]).**
+    pub fn reset_dirty_to(&mut self, mem_snapshot: &GuestMemorySnapshot) -> Result<()> {
+        if !self.is_dirty_tracking_enabled {
+            return self.reset_to(mem_snapshot);
+        }
+
+        // Pages stay dirty until all of them are restored, so a failed reset can be retried.
+        for page in self.dirty_pages.clone() {
+            let region = mem_snapshot.regions.iter().find(|(address, data)| {
+                page >= *address && page - *address < data.len() as hv_ipa_t
+            });
+
+            if let Some((address, data)) = region {
+                let offset = (page - *address) as usize;
+                let end = data.len().min(offset + PAGE_SIZE);
+
+                self.write_guest(page, &data[offset..end])?;
+            }
+        }
+
+        self.take_dirty_pages();
+
+        Ok(())
+    }
+}
+
+impl VirtualCpu {
+    /// Take a snapshot of the general purpose and EL1 context registers.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn snapshot(&mut self) -> Result<VcpuSnapshot> {
+        let mut snapshot = VcpuSnapshot {
+            registers: [0; SNAPSHOT_REGISTERS.len()],
+            system_registers: [0; SNAPSHOT_SYSTEM_REGISTERS.len()],
+        };
+
+        for (value, register) in snapshot.registers.iter_mut().zip(SNAPSHOT_REGISTERS) {
+            *value = self.get_register(register)?;
+        }
+
+        for (value, register) in snapshot
+            .system_registers
+            .iter_mut()
+            .zip(SNAPSHOT_SYSTEM_REGISTERS)
+        {
+            *value = self.get_system_register(register)?;
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Reset the registers to the values of a snapshot.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn reset_to(&mut self, snap: &VcpuSnapshot) -> Result<()> {
+        for (register, value) in snap.system_registers() {
+            self.set_system_register(register, value)?;
+        }
+
+        for (register, value) in snap.registers() {
+            self.set_register(register, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the vCPU snapshots.
+
+    use super::*;
+
+    /// Create a snapshot where every register holds a distinct value.
+    fn numbered_snapshot() -> VcpuSnapshot {
+        let mut snapshot = VcpuSnapshot {
+            registers: [0; SNAPSHOT_REGISTERS.len()],
+            system_registers: [0; SNAPSHOT_SYSTEM_REGISTERS.len()],
+        };
+
+        for (index, value) in snapshot
+            .registers
+            .iter_mut()
+            .chain(snapshot.system_registers.iter_mut())
+            .enumerate()
+        {
+            *value = 0x1000 + index as u64;
+        }
+
+        snapshot
+    }
+
+    /// Registers are indexed in the order of the snapshot, aliases share the slot of the aliased register.
+    #[test]
+    fn index_by_register() {
+        let mut snapshot = numbered_snapshot();
+
+        assert_eq!(snapshot[Register::X0], 0x1000);
+        assert_eq!(snapshot[Register::X29], 0x1000 + 29);
+        assert_eq!(snapshot[Register::FP], snapshot[Register::X29]);
+        assert_eq!(snapshot[Register::LR], snapshot[Register::X30]);
+        assert_eq!(snapshot[Register::CPSR], 0x1000 + 34);
+
+        snapshot[Register::LR] = 0xdead;
+        snapshot[Register::PC] = 0xbeef;
+
+        assert_eq!(snapshot[Register::X30], 0xdead);
+        assert!(snapshot
+            .registers()
+            .any(|(register, value)| matches!(register, Register::PC) && value == 0xbeef));
+    }
+
+    /// A snapshot survives a round-trip through its binary layout.
+    #[test]
+    fn bytes_round_trip() {
+        let snapshot = numbered_snapshot();
+        let bytes = snapshot.to_bytes();
+
+        assert_eq!(bytes[..4], VCPU_SNAPSHOT_MAGIC);
+        assert_eq!(
+            bytes.len(),
+            VCPU_SNAPSHOT_HEADER_SIZE
+                + (SNAPSHOT_REGISTERS.len() + SNAPSHOT_SYSTEM_REGISTERS.len()) * 8
+        );
+
+        let restored = VcpuSnapshot::from_bytes(&bytes).unwrap();
+
+        assert_eq!(restored.registers, snapshot.registers);
+        assert_eq!(restored.system_registers, snapshot.system_registers);
+    }
+
+    /// Truncated or extended input is rejected.
+    #[test]
+    fn from_bytes_rejects_invalid_size() {
+        let bytes = numbered_snapshot().to_bytes();
+
+        for size in [0, 3, VCPU_SNAPSHOT_HEADER_SIZE, bytes.len() - 1] {
+            assert!(matches!(
+                VcpuSnapshot::from_bytes(&bytes[..size]),
+                Err(HypervisorError::BadArgument)
+            ));
+        }
+
+        let mut extended = bytes;
+        extended.push(0);
+
+        assert!(matches!(
+            VcpuSnapshot::from_bytes(&extended),
+            Err(HypervisorError::BadArgument)
+        ));
+    }
+
+    /// Input with another magic, version or register count is rejected.
+    #[test]
+    fn from_bytes_rejects_invalid_header() {
+        let bytes = numbered_snapshot().to_bytes();
+
+        for offset in [0x0, 0x4, 0x8, 0xC] {
+            let mut corrupted = bytes.clone();
+            corrupted[offset] ^= 1;
+
+            assert!(matches!(
+                VcpuSnapshot::from_bytes(&corrupted),
+                Err(HypervisorError::BadArgument)
+            ));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Declarative mirroring of a subset of the vCPU registers.
+
+/// Declare a structure mirroring a subset of the registers of a vCPU.
+///
+/// Every field is declared as ``name: REGISTER`` where ``REGISTER`` is a [Register](crate::Register) variant, and is stored as a ``u64``.
+/// The structure gets a ``load_from`` method reading all the registers from a [VirtualCpu](crate::VirtualCpu) and a ``store_to`` method writing them back.
+///
+/// # Example:
+///
+/// ```rust,no_run
+/// use ahv::*;
+///
+/// vcpu_state! {
+///     /// Arguments and return address of a call.
+///     #[derive(Default)]
+///     pub struct CallState {
+///         pub argument0: X0,
+///         pub argument1: X1,
+///         pub return_address: LR,
+///     }
+/// }
+///
+/// fn main() -> Result<()> {
+///     let mut virtual_machine = VirtualMachine::new(None)?;
+///     let mut vcpu = virtual_machine.create_vcpu(None)?;
+///
+///     let mut state = CallState::default();
+///     state.load_from(&mut vcpu)?;
+///     state.argument0 += 1;
+///     state.store_to(&mut vcpu)?;
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! vcpu_state {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $register:ident
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: u64,
+            )*
+        }
+
+        impl $name {
+            /// Read the registers of a vCPU into the structure.
+            ///
+            /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+            #[allow(unused_variables)]
+            pub fn load_from(&mut self, vcpu: &mut $crate::VirtualCpu) -> $crate::Result<()> {
+                $(
+                    self.$field = vcpu.get_register($crate::Register::$register)?;
+                )*
+
+                Ok(())
+            }
+
+            /// Write the registers of a vCPU from the structure.
+            ///
+            /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+            #[allow(unused_variables)]
+            pub fn store_to(&self, vcpu: &mut $crate::VirtualCpu) -> $crate::Result<()> {
+                $(
+                    vcpu.set_register($crate::Register::$register, self.$field)?;
+                )*
+
+                Ok(())
+            }
+        }
+    };
+}
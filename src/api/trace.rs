@@ -0,0 +1,95 @@
+//! Trace of the last exits of a vCPU.
+
+use alloc::collections::VecDeque;
+
+use super::{ExceptionClass, Register, VirtualCpu, VirtualCpuExitReason};
+
+/// Default number of entries kept in the trap trace.
+pub const DEFAULT_TRACE_CAPACITY: usize = 64;
+
+/// Entry of the trap trace.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceEntry {
+    /// The guest PC at the time of the exit.
+    pub pc: u64,
+
+    /// The exception class, None if the exit isn't caused by a guest exception.
+    pub exception_class: Option<ExceptionClass>,
+
+    /// The faulting virtual address (FAR_EL2), only set for instruction and data aborts.
+    pub fault_address: Option<u64>,
+}
+
+impl VirtualCpu {
+    /// Enable or disable the trap trace, recording every exit of [VirtualCpu::run].
+    ///
+    /// Recording an exit reads PC, which adds a register access to every run while enabled. Nothing is done when disabled.
+    /// If PC cannot be read, the exit isn't recorded and the result of the run is left untouched.
+    /// Disabling the trace discards all its entries.
+    pub fn set_trace_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.trace = None;
+        } else if self.trace.is_none() {
+            self.trace = Some(VecDeque::new());
+        }
+    }
+
+    /// Sets the maximum number of entries kept in the trap trace (by default [DEFAULT_TRACE_CAPACITY]).
+    ///
+    /// The oldest entries above the new capacity are discarded.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.trace_capacity = capacity;
+
+        if let Some(trace) = &mut self.trace {
+            let excess = trace.len().saturating_sub(capacity);
+
+            trace.drain(..excess);
+        }
+    }
+
+    /// Gets the entries of the trap trace, from the oldest to the newest.
+    pub fn trace(&self) -> impl Iterator<Item = &TraceEntry> + '_ {
+        self.trace.iter().flatten()
+    }
+
+    /// Record an exit in the trap trace if enabled.
+    pub(super) fn record_trace(&mut self, reason: &VirtualCpuExitReason) {
+        if self.trace.is_none() || self.trace_capacity == 0 {
+            return;
+        }
+
+        let pc = match self.get_register(Register::PC) {
+            Ok(pc) => pc,
+            Err(_) => return,
+        };
+
+        let (exception_class, fault_address) = match reason {
+            VirtualCpuExitReason::Exception { exception } => {
+                let exception_class = exception.exception_class();
+
+                let fault_address = match exception_class {
+                    ExceptionClass::InstructionAbortLowerEl
+                    | ExceptionClass::InstructionAbortSameEl
+                    | ExceptionClass::DataAbortLowerEl
+                    | ExceptionClass::DataAbortSameEl => Some(exception.virtual_address),
+                    _ => None,
+                };
+
+                (Some(exception_class), fault_address)
+            }
+            _ => (None, None),
+        };
+
+        if let Some(trace) = &mut self.trace {
+            if trace.len() >= self.trace_capacity {
+                trace.pop_front();
+            }
+
+            trace.push_back(TraceEntry {
+                pc,
+                exception_class,
+                fault_address,
+            });
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Detection of invalid vCPU states before running the guest.
+
+use super::{HypervisorError, Register, SystemRegister, VirtualCpu, VirtualMachine};
+
+/// Mask of the mode field of CPSR (M[4:0]).
+const CPSR_MODE_MASK: u64 = 0x1F;
+
+/// EL0 using SP_EL0.
+const CPSR_MODE_EL0T: u64 = 0b00000;
+
+/// EL1 using SP_EL0.
+const CPSR_MODE_EL1T: u64 = 0b00100;
+
+/// EL1 using SP_EL1.
+const CPSR_MODE_EL1H: u64 = 0b00101;
+
+/// MMU enable bit of SCTLR_EL1.
+const SCTLR_M: u64 = 1 << 0;
+
+/// Likely cause of a vCPU failing to run, as reported by [VirtualCpu::validate_state].
+#[derive(Copy, Clone, Debug)]
+pub enum GuestStateError {
+    /// PC isn't aligned on 4 bytes.
+    MisalignedPc(u64),
+
+    /// PC isn't inside any mapping of the Virtual Machine.
+    UnmappedPc(u64),
+
+    /// PC is inside a mapping of the Virtual Machine without execute permission.
+    NonExecutablePc(u64),
+
+    /// CPSR doesn't encode an AArch64 mode at EL0 or EL1 (the value is the whole CPSR).
+    InvalidMode(u64),
+
+    /// The stack pointer selected by CPSR is zero (the value is the selected stack pointer register).
+    UnsetStackPointer(SystemRegister),
+
+    /// An error was reported by the Hypervisor while reading the vCPU state.
+    Hypervisor(HypervisorError),
+}
+
+impl From<HypervisorError> for GuestStateError {
+    fn from(value: HypervisorError) -> Self {
+        GuestStateError::Hypervisor(value)
+    }
+}
+
+impl VirtualCpu {
+    /// Checks the vCPU state for common mistakes that would make [VirtualCpu::run] fail with [HypervisorError::IllegalGuestState].
+    ///
+    /// The following is checked:
+    /// - PC is aligned on 4 bytes.
+    /// - CPSR encodes EL0t, EL1t or EL1h.
+    /// - The stack pointer selected by CPSR is not zero.
+    /// - If ``virtual_machine`` is provided and the guest MMU is disabled, PC is inside an executable mapping (including host buffers mapped with [VirtualMachine::map_slice]).
+    ///
+    /// Passing the Virtual Machine is optional so the check can be done where it isn't reachable, at the cost of the PC mapping check.
+    /// This only reports likely causes, a state passing the checks can still be rejected by the Hypervisor.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn validate_state(
+        &mut self,
+        virtual_machine: Option<&VirtualMachine>,
+    ) -> core::result::Result<(), GuestStateError> {
+        let pc = self.get_register(Register::PC)?;
+        let cpsr = self.get_register(Register::CPSR)?;
+
+        if pc % 4 != 0 {
+            return Err(GuestStateError::MisalignedPc(pc));
+        }
+
+        let stack_pointer = match cpsr & CPSR_MODE_MASK {
+            CPSR_MODE_EL0T | CPSR_MODE_EL1T => SystemRegister::SP_EL0,
+            CPSR_MODE_EL1H => SystemRegister::SP_EL1,
+            _ => return Err(GuestStateError::InvalidMode(cpsr)),
+        };
+
+        if self.get_system_register(stack_pointer)? == 0 {
+            return Err(GuestStateError::UnsetStackPointer(stack_pointer));
+        }
+
+        if let Some(virtual_machine) = virtual_machine {
+            // With the MMU enabled PC is a virtual address, which cannot be checked against the mappings.
+            if self.get_system_register(SystemRegister::SCTLR_EL1)? & SCTLR_M == 0 {
+                let permission = match virtual_machine.find_mapping_by_address(pc) {
+                    Some(mapping) => Some(mapping.permission),
+                    None => virtual_machine.find_slice_permission(pc),
+                };
+
+                match permission {
+                    None => return Err(GuestStateError::UnmappedPc(pc)),
+                    Some(permission) if !permission.execute => {
+                        return Err(GuestStateError::NonExecutablePc(pc))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
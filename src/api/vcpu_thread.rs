@@ -0,0 +1,228 @@
+//! Helpers running vCPUs on dedicated threads.
+
+use super::{
+    HypervisorError, Result, VcpuExitHandle, VirtualCpu, VirtualCpuExitReason, VirtualMachine,
+};
+
+use crate::ffi::system::{mach_timebase_info, mach_timebase_info_data_t};
+
+use core::time::Duration;
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::vec::Vec;
+
+/// Convert ``mach_absolute_time`` ticks to a duration.
+pub(super) fn ticks_to_duration(ticks: u64) -> Duration {
+    let mut info = mach_timebase_info_data_t::default();
+
+    unsafe {
+        mach_timebase_info(&mut info);
+    }
+
+    // Fallback to a 1:1 ratio if the timebase cannot be queried.
+    if info.denom == 0 {
+        return Duration::from_nanos(ticks);
+    }
+
+    let nanoseconds = u128::from(ticks) * u128::from(info.numer) / u128::from(info.denom);
+
+    Duration::from_nanos(u64::try_from(nanoseconds).unwrap_or(u64::MAX))
+}
+
+/// State shared between a vCPU and its watchdog thread.
+#[derive(Debug, Default)]
+struct WatchdogState {
+    /// Time at which the vCPU must be forced to exit, None when disarmed.
+    deadline: Option<Instant>,
+
+    /// Set to stop the watchdog thread.
+    is_stopping: bool,
+}
+
+/// Thread forcing a vCPU to exit once a deadline is reached, reused by every [VirtualCpu::run_bounded] call of the vCPU.
+#[derive(Debug)]
+pub(super) struct Watchdog {
+    /// The state shared with the thread, and the condition variable notified whenever it changes.
+    shared: Arc<(Mutex<WatchdogState>, Condvar)>,
+
+    /// The watchdog thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Lock the state of a watchdog, ignoring poisoning as the state stays consistent.
+fn lock_state(state: &Mutex<WatchdogState>) -> MutexGuard<'_, WatchdogState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Watchdog {
+    /// Spawn the watchdog thread of a vCPU.
+    fn spawn(exit_handle: VcpuExitHandle) -> Watchdog {
+        let shared = Arc::new((Mutex::new(WatchdogState::default()), Condvar::new()));
+
+        let thread = {
+            let shared = shared.clone();
+
+            std::thread::spawn(move || {
+                let (state, condvar) = &*shared;
+                let mut state = lock_state(state);
+
+                while !state.is_stopping {
+                    state = match state.deadline {
+                        Some(deadline) => {
+                            let now = Instant::now();
+
+                            if now >= deadline {
+                                state.deadline = None;
+
+                                let _ = exit_handle.exit();
+
+                                continue;
+                            }
+
+                            condvar
+                                .wait_timeout(state, deadline - now)
+                                .map(|(state, _)| state)
+                                .unwrap_or_else(|poisoned| poisoned.into_inner().0)
+                        }
+                        None => condvar
+                            .wait(state)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+                    };
+                }
+            })
+        };
+
+        Watchdog {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Arm or disarm the deadline of the watchdog.
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        let (state, condvar) = &*self.shared;
+
+        lock_state(state).deadline = deadline;
+        condvar.notify_one();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (state, condvar) = &*self.shared;
+
+        lock_state(state).is_stopping = true;
+        condvar.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl VirtualMachine {
+    /// Create ``count`` vCPUs, each one resident in its own new thread.
+    ///
+    /// On every thread, the vCPU is created and ``setup`` is called with the vCPU index and the vCPU.
+    /// ``setup`` is in charge of the whole vCPU lifetime (initial state, run loop...) as the vCPU is destroyed once it returns.
+    ///
+    /// Returns the exit handle of every vCPU alongside the join handle of its thread, yielding the result of ``setup``.
+    /// If a vCPU cannot be created, the vCPUs already created are forced to exit, their threads are joined and the error is returned.
+    /// ``setup`` must thus return once its vCPU exits with [VirtualCpuExitReason::Cancelled].
+    ///
+    /// # Safety
+    ///
+    /// The Virtual Machine must outlive all the spawned threads: the caller must join them before the Virtual Machine is dropped.
+    /// Otherwise, the guest memory is freed while the vCPUs still run, and a vCPU can be destroyed once its handle was reused by another Virtual Machine.
+    pub unsafe fn spawn_vcpus<F>(
+        &mut self,
+        count: usize,
+        setup: F,
+    ) -> Result<Vec<(VcpuExitHandle, JoinHandle<Result<()>>)>>
+    where
+        F: Fn(usize, &mut VirtualCpu) -> Result<()> + Send + Sync + 'static,
+    {
+        let setup = Arc::new(setup);
+        let mut result = Vec::with_capacity(count);
+
+        for index in 0..count {
+            let setup = setup.clone();
+            let (sender, receiver) = mpsc::channel();
+
+            let join_handle = std::thread::spawn(move || {
+                let mut vcpu = match VirtualCpu::new(core::ptr::null_mut()) {
+                    Ok(vcpu) => vcpu,
+                    Err(error) => {
+                        let _ = sender.send(Err(error));
+
+                        return Err(error);
+                    }
+                };
+
+                let _ = sender.send(Ok(vcpu.exit_handle()));
+
+                setup(index, &mut vcpu)
+            });
+
+            match receiver.recv().unwrap_or(Err(HypervisorError::Error)) {
+                Ok(exit_handle) => result.push((exit_handle, join_handle)),
+                Err(error) => {
+                    let _ = join_handle.join();
+
+                    for (exit_handle, _) in &result {
+                        let _ = exit_handle.exit();
+                    }
+
+                    for (_, join_handle) in result {
+                        let _ = join_handle.join();
+                    }
+
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl VirtualCpu {
+    /// Runs the vCPU for at most ``max_exec_ticks`` (in ``mach_absolute_time`` ticks).
+    ///
+    /// A watchdog thread forces the vCPU to exit once the budget is elapsed, in which case [VirtualCpuExitReason::Cancelled] is returned.
+    /// The thread is spawned on the first call and reused until the vCPU is destroyed.
+    ///
+    /// As the execution time of a vCPU can only be queried from its own thread, the budget is measured in host wall-clock time since the call.
+    /// The time spent in the guest can thus only be lower than the budget, never higher.
+    /// The preemption happens with the granularity of the host scheduler.
+    ///
+    /// If the guest exits at the same time the budget elapses, the next run may exit immediately with [VirtualCpuExitReason::Cancelled].
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn run_bounded(&mut self, max_exec_ticks: u64) -> Result<VirtualCpuExitReason> {
+        self.run_until_instant(Instant::now() + ticks_to_duration(max_exec_ticks))
+    }
+
+    /// Runs the vCPU until a given instant, in which case [VirtualCpuExitReason::Cancelled] is returned (see [VirtualCpu::run_bounded]).
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub(super) fn run_until_instant(&mut self, deadline: Instant) -> Result<VirtualCpuExitReason> {
+        let exit_handle = self.exit_handle();
+        let watchdog = self
+            .watchdog
+            .get_or_insert_with(|| Watchdog::spawn(exit_handle));
+
+        watchdog.set_deadline(Some(deadline));
+
+        let result = self.run();
+
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.set_deadline(None);
+        }
+
+        result
+    }
+}
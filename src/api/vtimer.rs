@@ -0,0 +1,200 @@
+//! Control of the guest Virtual Timer: its mask, its offset and the guest virtual counter, which can be frozen for deterministic execution.
+
+use super::{convert_hv_return, Result, VirtualCpu};
+use crate::ffi::system::{
+    mach_absolute_time, mach_timebase_info, mach_timebase_info_data_t, sysctlbyname,
+};
+use crate::ffi::{
+    hv_vcpu_get_vtimer_mask, hv_vcpu_get_vtimer_offset, hv_vcpu_set_vtimer_mask,
+    hv_vcpu_set_vtimer_offset,
+};
+
+/// Number of nanoseconds in a second.
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Gets the frequency of the system counter (CNTFRQ_EL0), in Hz.
+fn counter_frequency() -> Option<u64> {
+    let mut frequency = 0u64;
+    let mut size = core::mem::size_of::<u64>();
+
+    let ret = unsafe {
+        sysctlbyname(
+            b"hw.tbfrequency\0".as_ptr().cast(),
+            (&mut frequency as *mut u64).cast(),
+            &mut size,
+            core::ptr::null_mut(),
+            0,
+        )
+    };
+
+    if ret != 0 || frequency == 0 {
+        return None;
+    }
+
+    Some(frequency)
+}
+
+/// Converts ``mach_absolute_time`` ticks to ticks of a counter running at ``frequency`` Hz.
+///
+/// Host ticks are first converted to nanoseconds with the timebase, then to counter ticks: ``ticks * numer / denom * frequency / 10^9``.
+fn host_ticks_to_counter(ticks: u64, timebase: &mach_timebase_info_data_t, frequency: u64) -> u64 {
+    let counter = u128::from(ticks) * u128::from(timebase.numer) * u128::from(frequency)
+        / (u128::from(timebase.denom) * NANOSECONDS_PER_SECOND);
+
+    // The counter wraps like the hardware one.
+    counter as u64
+}
+
+/// Gets the current value of the host counter, in ticks of the system counter frequency (CNTFRQ_EL0).
+///
+/// Falls back to the raw ``mach_absolute_time`` ticks if the timebase or the frequency cannot be queried.
+fn host_counter() -> u64 {
+    let ticks = unsafe { mach_absolute_time() };
+
+    let mut timebase = mach_timebase_info_data_t::default();
+    let ret = unsafe { mach_timebase_info(&mut timebase) };
+
+    match counter_frequency() {
+        Some(frequency) if ret == 0 && timebase.denom != 0 => {
+            host_ticks_to_counter(ticks, &timebase, frequency)
+        }
+        _ => ticks,
+    }
+}
+
+/// State of a frozen guest virtual counter, obtained with [VirtualCpu::freeze_vtimer].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FrozenVtimer {
+    /// The Virtual Timer offset at the time of the freeze.
+    pub offset: u64,
+
+    /// The guest virtual counter value at the time of the freeze.
+    pub count: u64,
+}
+
+impl VirtualCpu {
+    /// Gets Virtual Timer mask.
+    pub fn get_vtimer_mask(&mut self) -> Result<bool> {
+        let mut result = false;
+
+        let ret = unsafe { hv_vcpu_get_vtimer_mask(self.handle, &mut result) };
+
+        convert_hv_return(ret)?;
+
+        Ok(result)
+    }
+
+    /// Sets Virtual Timer mask.
+    pub fn set_vtimer_mask(&mut self, value: bool) -> Result<()> {
+        let ret = unsafe { hv_vcpu_set_vtimer_mask(self.handle, value) };
+
+        convert_hv_return(ret)
+    }
+
+    /// Gets Virtual Timer offset (CNTVOFF_EL2).
+    pub fn get_vtimer_offset(&mut self) -> Result<u64> {
+        let mut result = 0;
+
+        let ret = unsafe { hv_vcpu_get_vtimer_offset(self.handle, &mut result) };
+
+        convert_hv_return(ret)?;
+
+        Ok(result)
+    }
+
+    /// Sets Virtual Timer offset (CNTVOFF_EL2).
+    pub fn set_vtimer_offset(&mut self, value: u64) -> Result<()> {
+        let ret = unsafe { hv_vcpu_set_vtimer_offset(self.handle, value) };
+
+        convert_hv_return(ret)
+    }
+
+    /// Sets Virtual Timer offset (CNTVOFF_EL2) so that the guest virtual counter reads ``epoch`` now.
+    ///
+    /// The guest virtual counter (CNTVCT_EL0) is the physical counter (CNTPCT_EL0) minus CNTVOFF_EL2, the offset is thus set to ``CNTPCT_EL0 - epoch``.
+    /// Both counters tick at the same frequency (CNTFRQ_EL0), so once synced the guest virtual counter is ``epoch`` plus the host time elapsed since this call.
+    /// Use an ``epoch`` of zero for a guest counter starting from zero.
+    pub fn sync_vtimer_to_host(&mut self, epoch: u64) -> Result<()> {
+        let now = host_counter();
+
+        self.set_vtimer_offset(now.wrapping_sub(epoch))
+    }
+
+    /// Gets the value the guest virtual counter (CNTVCT_EL0) has now, to emulate trapped reads of it.
+    ///
+    /// The physical counter (CNTPCT_EL0) is derived from the host time: ``mach_absolute_time()`` ticks are converted to nanoseconds
+    /// with the host timebase, then to ticks of the counter frequency reported to the guest by CNTFRQ_EL0.
+    /// The guest virtual counter is then the physical counter minus the Virtual Timer offset (CNTVOFF_EL2):
+    /// ``CNTVCT_EL0 = mach_absolute_time() * numer / denom * CNTFRQ_EL0 / 10^9 - CNTVOFF_EL2``.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn current_virtual_count(&mut self) -> Result<u64> {
+        let offset = self.get_vtimer_offset()?;
+        let now = host_counter();
+
+        Ok(now.wrapping_sub(offset))
+    }
+
+    /// Record the guest virtual counter (CNTVCT_EL0) so that it can later be resumed from the same value.
+    ///
+    /// The counter keeps following the host time until [VirtualCpu::thaw_vtimer] is called, the guest must thus not run in between.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn freeze_vtimer(&mut self) -> Result<FrozenVtimer> {
+        Ok(FrozenVtimer {
+            offset: self.get_vtimer_offset()?,
+            count: self.current_virtual_count()?,
+        })
+    }
+
+    /// Resume the guest virtual counter from a frozen state, advanced by ``elapsed_ticks``.
+    ///
+    /// The Virtual Timer offset is recomputed with [VirtualCpu::set_vtimer_offset] so that the guest observes the frozen counter value plus
+    /// ``elapsed_ticks`` instead of the host time elapsed since the freeze. Any offset set in between is overridden.
+    ///
+    /// **This should be called in the thread that will run the vCPU as it's resident inside it.**
+    pub fn thaw_vtimer(&mut self, frozen: &FrozenVtimer, elapsed_ticks: u64) -> Result<()> {
+        let now = host_counter();
+
+        self.set_vtimer_offset(now.wrapping_sub(frozen.count.wrapping_add(elapsed_ticks)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the host counter conversion.
+
+    use super::*;
+
+    /// Timebase of Apple Silicon hosts, where a tick lasts 125/3 nanoseconds.
+    const APPLE_SILICON_TIMEBASE: mach_timebase_info_data_t = mach_timebase_info_data_t {
+        numer: 125,
+        denom: 3,
+    };
+
+    /// A 24MHz counter ticks with the Apple Silicon timebase.
+    #[test]
+    fn host_ticks_to_counter_at_the_host_frequency() {
+        assert_eq!(
+            host_ticks_to_counter(24_000_000, &APPLE_SILICON_TIMEBASE, 24_000_000),
+            24_000_000
+        );
+    }
+
+    /// A counter running at another frequency is scaled.
+    #[test]
+    fn host_ticks_to_counter_scales_the_frequency() {
+        assert_eq!(
+            host_ticks_to_counter(24_000_000, &APPLE_SILICON_TIMEBASE, 1_000_000_000),
+            1_000_000_000
+        );
+        assert_eq!(
+            host_ticks_to_counter(
+                1_000,
+                &mach_timebase_info_data_t { numer: 1, denom: 1 },
+                1_000_000
+            ),
+            1
+        );
+    }
+}
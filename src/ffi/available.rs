@@ -0,0 +1,59 @@
+//! Runtime availability checks of the Hypervisor functions introduced after macOS 11.0.
+//!
+//! Cargo features only control which functions are declared at compile time, while the functions
+//! present in the Hypervisor framework depend on the macOS version the binary runs on.
+//! These checks look up the symbols with ``dlsym`` in the already loaded images, so that code built with
+//! a newer feature can decide at runtime whether a function can be called.
+//!
+//! Calling a function reported as unavailable results in a crash at the first call (or at load time if it isn't weakly linked).
+
+use core::ffi::{c_char, c_void};
+
+/// The ``RTLD_DEFAULT`` pseudo handle, searching all the loaded images.
+const RTLD_DEFAULT: *mut c_void = -2isize as *mut c_void;
+
+extern "C" {
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+/// Check if a symbol (null terminated) is available in the loaded images.
+fn is_symbol_available(symbol: &[u8]) -> bool {
+    debug_assert!(symbol.last() == Some(&0), "Symbol isn't null terminated!");
+
+    unsafe { !dlsym(RTLD_DEFAULT, symbol.as_ptr() as *const c_char).is_null() }
+}
+
+/// Check if ``hv_vm_allocate`` is available. (**since macOS 12.1**)
+pub fn hv_vm_allocate_available() -> bool {
+    is_symbol_available(b"hv_vm_allocate\0")
+}
+
+/// Check if ``hv_vm_deallocate`` is available. (**since macOS 12.1**)
+pub fn hv_vm_deallocate_available() -> bool {
+    is_symbol_available(b"hv_vm_deallocate\0")
+}
+
+/// Check if ``hv_vm_config_create`` is available. (**since macOS 13.0**)
+pub fn hv_vm_config_create_available() -> bool {
+    is_symbol_available(b"hv_vm_config_create\0")
+}
+
+/// Check if ``hv_vm_config_get_max_ipa_size`` is available. (**since macOS 13.0**)
+pub fn hv_vm_config_get_max_ipa_size_available() -> bool {
+    is_symbol_available(b"hv_vm_config_get_max_ipa_size\0")
+}
+
+/// Check if ``hv_vm_config_get_default_ipa_size`` is available. (**since macOS 13.0**)
+pub fn hv_vm_config_get_default_ipa_size_available() -> bool {
+    is_symbol_available(b"hv_vm_config_get_default_ipa_size\0")
+}
+
+/// Check if ``hv_vm_config_set_ipa_size`` is available. (**since macOS 13.0**)
+pub fn hv_vm_config_set_ipa_size_available() -> bool {
+    is_symbol_available(b"hv_vm_config_set_ipa_size\0")
+}
+
+/// Check if ``hv_vm_config_get_ipa_size`` is available. (**since macOS 13.0**)
+pub fn hv_vm_config_get_ipa_size_available() -> bool {
+    is_symbol_available(b"hv_vm_config_get_ipa_size\0")
+}
@@ -0,0 +1,105 @@
+//! Bindings to the parts of Grand Central Dispatch used for timers.
+
+#![allow(non_camel_case_types)]
+
+use core::ffi::c_void;
+
+/// Opaque dispatch object.
+pub type dispatch_object_t = *mut c_void;
+
+/// Opaque dispatch queue object.
+pub type dispatch_queue_t = *mut c_void;
+
+/// Opaque dispatch source object.
+pub type dispatch_source_t = *mut c_void;
+
+/// Opaque dispatch semaphore object.
+pub type dispatch_semaphore_t = *mut c_void;
+
+/// Opaque type of a dispatch source.
+pub type dispatch_source_type_t = *const c_void;
+
+/// Dispatch time, as produced by [dispatch_time].
+pub type dispatch_time_t = u64;
+
+/// Function called by dispatch with the context of an object.
+pub type dispatch_function_t = extern "C" fn(*mut c_void);
+
+/// Dispatch time representing now.
+pub const DISPATCH_TIME_NOW: dispatch_time_t = 0;
+
+/// Dispatch time representing infinity.
+pub const DISPATCH_TIME_FOREVER: dispatch_time_t = !0;
+
+/// Priority of the high priority global dispatch queue.
+pub const DISPATCH_QUEUE_PRIORITY_HIGH: isize = 2;
+
+extern "C" {
+    /// Type of the dispatch sources monitoring a timer, use [dispatch_source_type_timer] to refer to it.
+    static _dispatch_source_type_timer: u8;
+
+    /// Creates a dispatch time relative to another one.
+    pub fn dispatch_time(when: dispatch_time_t, delta: i64) -> dispatch_time_t;
+
+    /// Returns a global concurrent queue of a given priority.
+    pub fn dispatch_get_global_queue(identifier: isize, flags: usize) -> dispatch_queue_t;
+
+    /// Sets the context of a dispatch object.
+    pub fn dispatch_set_context(object: dispatch_object_t, context: *mut c_void);
+
+    /// Resumes the invocation of the blocks of a dispatch object.
+    pub fn dispatch_resume(object: dispatch_object_t);
+
+    /// Decrements the reference count of a dispatch object.
+    pub fn dispatch_release(object: dispatch_object_t);
+
+    /// Creates a dispatch source monitoring a low-level system event.
+    pub fn dispatch_source_create(
+        source_type: dispatch_source_type_t,
+        handle: usize,
+        mask: usize,
+        queue: dispatch_queue_t,
+    ) -> dispatch_source_t;
+
+    /// Sets the event handler of a dispatch source, called with its context.
+    pub fn dispatch_source_set_event_handler_f(
+        source: dispatch_source_t,
+        handler: dispatch_function_t,
+    );
+
+    /// Sets the cancellation handler of a dispatch source, called with its context.
+    pub fn dispatch_source_set_cancel_handler_f(
+        source: dispatch_source_t,
+        handler: dispatch_function_t,
+    );
+
+    /// Sets the start time, interval and leeway of a timer dispatch source.
+    pub fn dispatch_source_set_timer(
+        source: dispatch_source_t,
+        start: dispatch_time_t,
+        interval: u64,
+        leeway: u64,
+    );
+
+    /// Asynchronously cancels a dispatch source, preventing any further invocation of its event handler.
+    pub fn dispatch_source_cancel(source: dispatch_source_t);
+
+    /// Creates a counting semaphore.
+    pub fn dispatch_semaphore_create(value: isize) -> dispatch_semaphore_t;
+
+    /// Signals a semaphore.
+    pub fn dispatch_semaphore_signal(semaphore: dispatch_semaphore_t) -> isize;
+
+    /// Waits for a semaphore.
+    pub fn dispatch_semaphore_wait(
+        semaphore: dispatch_semaphore_t,
+        timeout: dispatch_time_t,
+    ) -> isize;
+}
+
+/// Returns the type of the dispatch sources monitoring a timer (``DISPATCH_SOURCE_TYPE_TIMER``).
+// Taking the address of an extern static is only safe since Rust 1.82.
+#[allow(unused_unsafe)]
+pub fn dispatch_source_type_timer() -> dispatch_source_type_t {
+    unsafe { core::ptr::addr_of!(_dispatch_source_type_timer) as dispatch_source_type_t }
+}
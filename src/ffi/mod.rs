@@ -2,8 +2,16 @@
 
 use core::ffi::c_void;
 
+pub mod available;
+#[cfg(feature = "dispatch")]
+pub mod dispatch;
+#[cfg(feature = "std")]
+pub mod security;
+pub mod system;
+mod trampoline;
 pub mod types;
 
+pub use trampoline::*;
 use types::*;
 
 #[link(name = "Hypervisor", kind = "framework")]
@@ -71,9 +79,16 @@ extern "C" {
     /// Sets the value of a vCPU register.
     pub fn hv_vcpu_set_reg(vcpu: hv_vcpu_t, reg: hv_reg_t, value: u64) -> hv_return_t;
 
-    // TODO: SIMD APIs
-    //pub fn hv_vcpu_get_simd_fp_reg(vcpu: hv_vcpu_t, reg: hv_simd_fp_reg_t, value: *mut hv_simd_fp_uchar16_t) -> hv_return_t;
-    //pub fn hv_vcpu_set_simd_fp_reg(vcpu: hv_vcpu_t, reg: hv_simd_fp_reg_t, value: hv_simd_fp_uchar16_t) -> hv_return_t;
+    /// Gets the current value of a vCPU SIMD & FP register.
+    ///
+    /// The value is written as a [hv_simd_fp_uchar16_t], which isn't used directly as it has no stable C interoperability.
+    pub fn hv_vcpu_get_simd_fp_reg(
+        vcpu: hv_vcpu_t,
+        reg: hv_simd_fp_reg_t,
+        value: *mut SimdFpRegisterBuffer,
+    ) -> hv_return_t;
+
+    // hv_vcpu_set_simd_fp_reg takes its value in a vector register, see trampoline.rs.
 
     /// Gets the current value of a vCPU system register.
     pub fn hv_vcpu_get_sys_reg(vcpu: hv_vcpu_t, reg: hv_sys_reg_t, value: *mut u64) -> hv_return_t;
@@ -0,0 +1,43 @@
+//! Bindings to the CoreFoundation and Security frameworks, used to read the entitlements of the running process.
+
+#![allow(non_upper_case_globals)]
+
+use core::ffi::{c_char, c_void};
+
+/// The ``kCFStringEncodingUTF8`` encoding.
+pub const kCFStringEncodingUTF8: u32 = 0x0800_0100;
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    /// Creates an immutable string from a null terminated C string.
+    pub fn CFStringCreateWithCString(
+        allocator: *const c_void,
+        string: *const c_char,
+        encoding: u32,
+    ) -> *const c_void;
+
+    /// Returns the type identifier of a CoreFoundation object.
+    pub fn CFGetTypeID(object: *const c_void) -> usize;
+
+    /// Returns the type identifier of the CoreFoundation boolean type.
+    pub fn CFBooleanGetTypeID() -> usize;
+
+    /// Returns the value of a CoreFoundation boolean.
+    pub fn CFBooleanGetValue(boolean: *const c_void) -> bool;
+
+    /// Releases a CoreFoundation object.
+    pub fn CFRelease(object: *const c_void);
+}
+
+#[link(name = "Security", kind = "framework")]
+extern "C" {
+    /// Creates a task object for the current process.
+    pub fn SecTaskCreateFromSelf(allocator: *const c_void) -> *const c_void;
+
+    /// Returns the value of an entitlement of a task, null if it doesn't have it.
+    pub fn SecTaskCopyValueForEntitlement(
+        task: *const c_void,
+        entitlement: *const c_void,
+        error: *mut *const c_void,
+    ) -> *const c_void;
+}
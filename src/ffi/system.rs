@@ -0,0 +1,39 @@
+//! Bindings to the system libraries used alongside the Hypervisor framework.
+
+#![allow(non_camel_case_types)]
+
+use core::ffi::{c_char, c_int, c_void};
+
+/// The ``sysconf`` name of the page size.
+pub const _SC_PAGESIZE: c_int = 29;
+
+/// Ratio to convert ``mach_absolute_time`` ticks to nanoseconds.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct mach_timebase_info_data_t {
+    /// Numerator of the ratio.
+    pub numer: u32,
+
+    /// Denominator of the ratio.
+    pub denom: u32,
+}
+
+extern "C" {
+    /// Returns the current value of the host counter, in ticks of the timebase.
+    pub fn mach_absolute_time() -> u64;
+
+    /// Gets the ratio to convert ``mach_absolute_time`` ticks to nanoseconds.
+    pub fn mach_timebase_info(info: *mut mach_timebase_info_data_t) -> i32;
+
+    /// Gets the value of a configurable system variable.
+    pub fn sysconf(name: c_int) -> isize;
+
+    /// Reads the value of a system information entry by name.
+    pub fn sysctlbyname(
+        name: *const c_char,
+        oldp: *mut c_void,
+        oldlenp: *mut usize,
+        newp: *mut c_void,
+        newlen: usize,
+    ) -> c_int;
+}
@@ -0,0 +1,38 @@
+//! Calls to Hypervisor functions taking SIMD values, which have no stable C interoperability in Rust.
+
+use super::types::*;
+
+#[link(name = "Hypervisor", kind = "framework")]
+extern "C" {
+    /// Symbol of ``hv_vcpu_set_simd_fp_reg``, only called through [hv_vcpu_set_simd_fp_reg] as its signature cannot be declared.
+    #[link_name = "hv_vcpu_set_simd_fp_reg"]
+    fn hv_vcpu_set_simd_fp_reg_symbol();
+}
+
+/// Sets the value of a vCPU SIMD & FP register.
+///
+/// The [hv_simd_fp_uchar16_t] value is loaded from memory into Q0 before calling the Hypervisor, as required by the AAPCS64.
+///
+/// # Safety
+///
+/// ``value`` must be valid for reads.
+pub unsafe fn hv_vcpu_set_simd_fp_reg(
+    vcpu: hv_vcpu_t,
+    reg: hv_simd_fp_reg_t,
+    value: *const SimdFpRegisterBuffer,
+) -> hv_return_t {
+    let ret: u64;
+
+    core::arch::asm!(
+        "ldr q0, [{value}]",
+        "blr {function}",
+        value = in(reg) value,
+        function = in(reg) hv_vcpu_set_simd_fp_reg_symbol as *const (),
+        inout("x0") vcpu => ret,
+        in("x1") reg,
+        clobber_abi("C"),
+    );
+
+    // The result is returned in w0.
+    ret as hv_return_t
+}
@@ -42,6 +42,11 @@ pub type hv_cache_type_t = u32;
 
 pub type hv_simd_fp_uchar16_t = core::arch::aarch64::uint8x16_t;
 
+/// Memory storage of a [hv_simd_fp_uchar16_t], used to pass SIMD & FP register values by pointer.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SimdFpRegisterBuffer(pub [u8; 16]);
+
 /// Memory region permissions.
 pub type hv_memory_flags_t = u64;
 
@@ -1,5 +1,3 @@
-// TODO: Currently, the SIMD types doesn't seem to support interoperability with C, find a way to bypass that.
-/*#![feature(, stdsimd, simd_ffi))]*/
 #![cfg(all(target_os = "macos", target_arch = "aarch64"))]
 #![deny(clippy::missing_docs_in_private_items)]
 #![no_std]
@@ -70,9 +68,12 @@
 //! **To run this example make sure to give the built binary the ``com.apple.security.hypervisor`` entitlement.**
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 pub mod api;
 pub mod ffi;
+pub mod prelude;
 
 pub use api::*;
 pub use ffi::types::hv_ipa_t;
@@ -0,0 +1,34 @@
+//! Prelude of the safe API.
+//!
+//! This only exports the high-level types, without any of the raw Hypervisor constants.
+//!
+//! # Example:
+//!
+//! ```rust,no_run
+//! use ahv::prelude::*;
+//!
+//! fn main() -> Result<()> {
+//!     let mut virtual_machine = VirtualMachine::new(None)?;
+//!     let allocation_handle = virtual_machine.allocate(PAGE_SIZE)?;
+//!     virtual_machine.map(allocation_handle, 0x10000, MemoryPermission::READ_WRITE_EXECUTE)?;
+//!
+//!     let mut vcpu: VirtualCpu = virtual_machine.create_vcpu(None)?;
+//!     vcpu.set_register(Register::PC, 0x10000)?;
+//!     vcpu.set_system_register(SystemRegister::SP_EL1, 0x20000)?;
+//!
+//!     match vcpu.run() {
+//!         Ok(VirtualCpuExitReason::Exception { exception }) => println!("{:?}", exception),
+//!         Ok(reason) => println!("{:?}", reason),
+//!         Err(HypervisorError::IllegalGuestState) => println!("Illegal guest state"),
+//!         Err(error) => return Err(error),
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+pub use crate::api::{
+    HypervisorContext, HypervisorError, MemoryPermission, Register, Result, SystemRegister,
+    VirtualCpu, VirtualCpuExitReason, VirtualMachine, PAGE_SIZE,
+};
+pub use crate::ffi::types::hv_ipa_t;
@@ -0,0 +1,3004 @@
+#![cfg(all(target_os = "macos", target_arch = "aarch64"))]
+
+//! Tests requiring the Hypervisor.
+//!
+//! The test binary must be signed with the ``com.apple.security.hypervisor`` entitlement.
+
+use ahv::*;
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Serialize the tests as only one Virtual Machine can exist per process.
+static HYPERVISOR_LOCK: Mutex<()> = Mutex::new(());
+
+/// Acquire the exclusive use of the Hypervisor for a test.
+fn lock_hypervisor() -> MutexGuard<'static, ()> {
+    HYPERVISOR_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[test]
+fn try_drop_reports_teardown_errors() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .map(allocation_handle, 0x10000, MemoryPermission::READ_WRITE)
+        .unwrap();
+
+    // Destroy the Virtual Machine behind its back so the teardown fails.
+    unsafe {
+        assert_eq!(ahv::ffi::hv_vm_destroy(), ahv::ffi::types::HV_SUCCESS);
+    }
+
+    let errors = virtual_machine.try_drop().unwrap_err();
+
+    assert!(!errors.is_empty());
+}
+
+#[test]
+fn vcpu_try_drop_releases_the_vcpu() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    virtual_machine
+        .create_vcpu(None)
+        .unwrap()
+        .try_drop()
+        .unwrap();
+
+    // The thread can host a new vCPU once the previous one is destroyed.
+    virtual_machine
+        .create_vcpu(None)
+        .unwrap()
+        .try_drop()
+        .unwrap();
+
+    virtual_machine.try_drop().unwrap();
+}
+
+#[test]
+fn prefault_allocation_keeps_every_page() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(3 * PAGE_SIZE).unwrap();
+
+    for (index, byte) in virtual_machine
+        .get_allocation_slice_mut(allocation_handle)
+        .unwrap()
+        .iter_mut()
+        .enumerate()
+    {
+        *byte = (index % 251) as u8;
+    }
+
+    virtual_machine
+        .prefault_allocation(allocation_handle)
+        .unwrap();
+
+    let slice = virtual_machine
+        .get_allocation_slice(allocation_handle)
+        .unwrap();
+
+    assert_eq!(slice.len(), 3 * PAGE_SIZE);
+    assert!(slice
+        .iter()
+        .enumerate()
+        .all(|(index, byte)| *byte == (index % 251) as u8));
+}
+
+/// A host buffer aligned on [PAGE_SIZE].
+#[repr(C, align(0x10000))]
+struct AlignedPage([u8; PAGE_SIZE]);
+
+#[test]
+fn map_slice_is_tracked_until_dropped() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    let mut buffer = Box::new(AlignedPage([0; PAGE_SIZE]));
+    // SAFETY: the mapping is dropped before the buffer.
+    let mapping = unsafe {
+        virtual_machine
+            .map_slice(&mut buffer.0, 0x20000, MemoryPermission::READ_WRITE)
+            .unwrap()
+    };
+
+    assert_eq!(mapping.address(), 0x20000);
+    assert_eq!(mapping.size(), PAGE_SIZE);
+
+    // The Virtual Machine stays usable but refuses to map over the buffer.
+    assert!(matches!(
+        virtual_machine.map(allocation_handle, 0x20000, MemoryPermission::READ_WRITE),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    drop(mapping);
+
+    virtual_machine
+        .map(allocation_handle, 0x20000, MemoryPermission::READ_WRITE)
+        .unwrap();
+}
+
+#[test]
+fn map_slice_rejects_unaligned_buffers() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let mut buffer = Box::new(AlignedPage([0; PAGE_SIZE]));
+
+    // SAFETY: nothing is mapped as the buffer is rejected.
+    let result = unsafe {
+        virtual_machine.map_slice(&mut buffer.0[1..], 0x20000, MemoryPermission::READ_WRITE)
+    };
+
+    assert!(matches!(result, Err(HypervisorError::BadArgument)));
+}
+
+/// Guest address of the code of the test guests.
+const CODE_ADDRESS: hv_ipa_t = 0x10000;
+
+/// Guest address of the data page of the test guests.
+const DATA_ADDRESS: hv_ipa_t = 0x20000;
+
+/// Create a Virtual Machine with some code mapped at [CODE_ADDRESS] and a zeroed data page mapped at [DATA_ADDRESS].
+fn create_guest_memory(code: &[u8]) -> VirtualMachine {
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let code_handle = virtual_machine.allocate_from(code).unwrap();
+    virtual_machine
+        .map(code_handle, CODE_ADDRESS, MemoryPermission::READ_EXECUTE)
+        .unwrap();
+
+    let data_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    virtual_machine
+        .map(data_handle, DATA_ADDRESS, MemoryPermission::READ_WRITE)
+        .unwrap();
+
+    virtual_machine
+}
+
+/// Map ``count`` zeroed pages contiguously from ``base``, each one backed by its own allocation.
+fn map_contiguous_pages(virtual_machine: &mut VirtualMachine, base: hv_ipa_t, count: usize) {
+    for index in 0..count {
+        let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+        virtual_machine
+            .map(
+                allocation_handle,
+                base + (index * PAGE_SIZE) as u64,
+                MemoryPermission::READ_WRITE,
+            )
+            .unwrap();
+    }
+}
+
+/// Offset of the exception vector table in the code of the test guests.
+const VECTOR_TABLE_OFFSET: usize = 0x800;
+
+/// Offset of the IRQ vector taken from the current exception level with SP_ELx.
+const IRQ_VECTOR: usize = 0x280;
+
+/// Offset of the SError vector taken from the current exception level with SP_ELx.
+const SERROR_VECTOR: usize = 0x380;
+
+/// Build the code of a guest with some handlers in its exception vector table, each made of its vector offset and its code.
+fn with_vectors(code: &[u8], handlers: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut result = vec![0; VECTOR_TABLE_OFFSET + 0x800];
+
+    result[..code.len()].copy_from_slice(code);
+
+    for (vector, handler) in handlers {
+        let start = VECTOR_TABLE_OFFSET + vector;
+
+        result[start..start + handler.len()].copy_from_slice(handler);
+    }
+
+    result
+}
+
+/// Prepare a vCPU to run the code at [CODE_ADDRESS] at EL1h with all interrupts masked, x2 pointing to the data page.
+///
+/// VBAR_EL1 points to the vector table built by [with_vectors].
+fn prepare_vcpu(vcpu: &mut VirtualCpu) -> Result<()> {
+    vcpu.set_register(Register::CPSR, 0x3c5)?;
+    vcpu.set_register(Register::PC, CODE_ADDRESS)?;
+    vcpu.set_register(Register::X2, DATA_ADDRESS)?;
+    vcpu.set_system_register(
+        SystemRegister::VBAR_EL1,
+        CODE_ADDRESS + VECTOR_TABLE_OFFSET as u64,
+    )
+}
+
+/// Create a Virtual Machine running some code (see [create_guest_memory]) on a vCPU of the current thread.
+fn create_guest(code: &[u8]) -> (VirtualMachine, VirtualCpu) {
+    let mut virtual_machine = create_guest_memory(code);
+    let mut vcpu = virtual_machine.create_vcpu(None).unwrap();
+
+    prepare_vcpu(&mut vcpu).unwrap();
+
+    (virtual_machine, vcpu)
+}
+
+/// Check if an exit was caused by ``hvc #0``.
+fn is_hvc(reason: &VirtualCpuExitReason) -> bool {
+    matches!(
+        reason,
+        VirtualCpuExitReason::Exception { exception }
+            if exception.exception_class() == ExceptionClass::Hvc64
+    )
+}
+
+/// Code of a guest incrementing a counter at x2 and reporting it in x0 with an HVC.
+const COUNTER_CODE: [u8; 20] = [
+    0x41, 0x00, 0x40, 0xF9, // ldr x1, [x2]
+    0x21, 0x04, 0x00, 0x91, // add x1, x1, #1
+    0x41, 0x00, 0x00, 0xF9, // str x1, [x2]
+    0xE0, 0x03, 0x01, 0xAA, // mov x0, x1
+    0x02, 0x00, 0x00, 0xD4, // hvc #0
+];
+
+/// Create a Virtual Machine with a guest incrementing a counter in memory and reporting it in x0 with an HVC.
+fn create_counter_guest() -> (VirtualMachine, VirtualCpu) {
+    create_guest(&COUNTER_CODE)
+}
+
+/// Run the counter guest until its HVC, forwarding dirty page tracking faults, and return the reported counter.
+fn run_counter_guest(virtual_machine: &mut VirtualMachine, vcpu: &mut VirtualCpu) -> u64 {
+    loop {
+        match vcpu.run().unwrap() {
+            reason if is_hvc(&reason) => return vcpu.get_register(Register::X0).unwrap(),
+            VirtualCpuExitReason::Exception { exception }
+                if exception.data_abort_info().is_some() =>
+            {
+                assert!(virtual_machine
+                    .handle_write_fault(exception.physical_address)
+                    .unwrap());
+            }
+            reason => panic!("Unexpected exit: {:?}", reason),
+        }
+    }
+}
+
+#[test]
+fn run_with_context_captures_the_faulting_pc() {
+    /// Code of a guest storing to the unmapped address in x3.
+    const FAULTING_CODE: [u8; 4] = [
+        0x61, 0x00, 0x00, 0xF9, // str x1, [x3]
+    ];
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&FAULTING_CODE);
+
+    vcpu.set_register(Register::X3, 0x4000_0000).unwrap();
+
+    let context = vcpu.run_with_context().unwrap();
+
+    assert!(matches!(
+        context.reason,
+        VirtualCpuExitReason::Exception { exception }
+            if exception.exception_class() == ExceptionClass::DataAbortLowerEl
+    ));
+    assert_eq!(context.pc, Some(CODE_ADDRESS));
+    assert_eq!(context.pc, Some(vcpu.get_register(Register::PC).unwrap()));
+    assert_eq!(
+        context.cpsr,
+        Some(vcpu.get_register(Register::CPSR).unwrap())
+    );
+}
+
+#[test]
+fn advance_pc_cached_matches_advance_pc() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_counter_guest();
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+
+    let vcpu_snapshot = vcpu.snapshot().unwrap();
+    let pc = vcpu.get_register(Register::PC).unwrap();
+
+    vcpu.advance_pc(8).unwrap();
+    let expected = vcpu.get_register(Register::PC).unwrap();
+
+    assert_eq!(expected, pc + 8);
+
+    // PC is known after restoring the snapshot.
+    vcpu.reset_to(&vcpu_snapshot).unwrap();
+    vcpu.advance_pc_cached(8).unwrap();
+
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), expected);
+
+    // PC is unknown right after a run.
+    vcpu.reset_to(&vcpu_snapshot).unwrap();
+    vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+    vcpu.set_register(Register::X2, DATA_ADDRESS).unwrap();
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+
+    vcpu.advance_pc_cached(8).unwrap();
+
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), expected);
+}
+
+#[test]
+fn semihosting_writes_the_guest_output() {
+    use std::cell::RefCell;
+
+    /// Code of a guest writing the character then the string at x2 with semihosting calls.
+    const SEMIHOSTING_CODE: [u8; 20] = [
+        0x60, 0x00, 0x80, 0xD2, // mov x0, #3
+        0xE1, 0x03, 0x02, 0xAA, // mov x1, x2
+        0x02, 0x00, 0x1E, 0xD4, // hvc #0xf000
+        0x80, 0x00, 0x80, 0xD2, // mov x0, #4
+        0x02, 0x00, 0x1E, 0xD4, // hvc #0xf000
+    ];
+
+    /// String at the end of the data page, right before unmapped memory.
+    const TAIL: &[u8] = b"tail\0";
+
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&SEMIHOSTING_CODE);
+
+    let tail_address = DATA_ADDRESS + (PAGE_SIZE - TAIL.len()) as u64;
+
+    virtual_machine
+        .write_guest(DATA_ADDRESS, b"Hello, world!\0")
+        .unwrap();
+    virtual_machine.write_guest(tail_address, TAIL).unwrap();
+
+    let output = RefCell::new(Vec::new());
+    let mut handler =
+        SemihostingHandler::new(|bytes: &[u8]| output.borrow_mut().push(bytes.to_vec()));
+
+    for address in [DATA_ADDRESS, tail_address] {
+        vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+        vcpu.set_register(Register::X2, address).unwrap();
+
+        for _ in 0..2 {
+            let exception = match vcpu.run().unwrap() {
+                VirtualCpuExitReason::Exception { exception } => exception,
+                reason => panic!("Unexpected exit: {:?}", reason),
+            };
+
+            assert!(handler
+                .handle(&virtual_machine, &mut vcpu, &exception)
+                .unwrap());
+        }
+    }
+
+    assert_eq!(
+        *output.borrow(),
+        [
+            b"H".to_vec(),
+            b"Hello, world!".to_vec(),
+            b"t".to_vec(),
+            b"tail".to_vec()
+        ]
+    );
+}
+
+#[test]
+fn restore_guest_memory_round_trips_every_region() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&COUNTER_CODE);
+
+    virtual_machine
+        .write_guest(DATA_ADDRESS, b"before")
+        .unwrap();
+
+    let regions = virtual_machine.dump_guest_memory();
+
+    assert_eq!(regions.len(), 2);
+
+    virtual_machine.write_guest(DATA_ADDRESS, b"after").unwrap();
+    virtual_machine.write_guest(CODE_ADDRESS, &[0; 4]).unwrap();
+
+    virtual_machine.restore_guest_memory(&regions).unwrap();
+
+    assert_eq!(virtual_machine.dump_guest_memory(), regions);
+
+    let mut data = [0; 6];
+    virtual_machine.read_guest(DATA_ADDRESS, &mut data).unwrap();
+
+    assert_eq!(&data, b"before");
+
+    // Remove the data mapping, the regions can no longer be restored.
+    let data_mapping = virtual_machine
+        .get_mapping_info_at(DATA_ADDRESS)
+        .unwrap()
+        .mapping_handle;
+
+    virtual_machine.unmap(data_mapping).unwrap();
+    virtual_machine.write_guest(CODE_ADDRESS, &[0; 4]).unwrap();
+
+    assert!(matches!(
+        virtual_machine.restore_guest_memory(&regions),
+        Err(HypervisorError::UnmappedAddress)
+    ));
+
+    // Nothing was restored.
+    let mut code = [0xFF; 4];
+    virtual_machine.read_guest(CODE_ADDRESS, &mut code).unwrap();
+
+    assert_eq!(code, [0; 4]);
+}
+
+#[test]
+fn reset_to_reproduces_behavior() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_counter_guest();
+
+    let memory_snapshot = virtual_machine.snapshot_memory();
+    let vcpu_snapshot = vcpu.snapshot().unwrap();
+
+    assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 1);
+
+    for _ in 0..3 {
+        virtual_machine.reset_to(&memory_snapshot).unwrap();
+        vcpu.reset_to(&vcpu_snapshot).unwrap();
+
+        assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 1);
+    }
+}
+
+#[test]
+fn reset_dirty_to_only_restores_dirty_pages() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_counter_guest();
+
+    virtual_machine.enable_dirty_tracking().unwrap();
+
+    let memory_snapshot = virtual_machine.snapshot_memory();
+    let vcpu_snapshot = vcpu.snapshot().unwrap();
+
+    assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 1);
+
+    for _ in 0..3 {
+        virtual_machine.reset_dirty_to(&memory_snapshot).unwrap();
+        vcpu.reset_to(&vcpu_snapshot).unwrap();
+
+        // Everything was restored and protected again.
+        assert!(virtual_machine.take_dirty_pages().is_empty());
+        assert_eq!(virtual_machine.read_u64(DATA_ADDRESS).unwrap(), 0);
+
+        assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 1);
+    }
+}
+
+vcpu_state! {
+    /// Registers mirrored by [vcpu_state_round_trip].
+    #[derive(Debug, Default, PartialEq)]
+    struct MirroredState {
+        first: X0,
+        second: X17,
+        link: LR,
+        program_counter: PC,
+    }
+}
+
+#[test]
+fn vcpu_state_round_trip() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let mut vcpu = virtual_machine.create_vcpu(None).unwrap();
+
+    let state = MirroredState {
+        first: 0x1122_3344_5566_7788,
+        second: 42,
+        link: 0x8000,
+        program_counter: 0x10000,
+    };
+
+    state.store_to(&mut vcpu).unwrap();
+
+    assert_eq!(vcpu.get_register(Register::X17).unwrap(), 42);
+    assert_eq!(vcpu.get_register(Register::X30).unwrap(), 0x8000);
+
+    let mut loaded = MirroredState::default();
+    loaded.load_from(&mut vcpu).unwrap();
+
+    assert_eq!(loaded, state);
+}
+
+#[test]
+fn permission_audit_keeps_the_newest_entries() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine.set_audit_enabled(true);
+    virtual_machine.set_audit_capacity(2);
+
+    let mapping_handle = virtual_machine
+        .map(allocation_handle, 0x10000, MemoryPermission::READ)
+        .unwrap();
+    virtual_machine
+        .reprotect(mapping_handle, MemoryPermission::READ_WRITE)
+        .unwrap();
+    virtual_machine.unmap(mapping_handle).unwrap();
+
+    let operations: Vec<AuditOperation> = virtual_machine
+        .permission_audit()
+        .iter()
+        .map(|entry| entry.operation)
+        .collect();
+
+    assert_eq!(
+        operations,
+        [AuditOperation::Reprotect, AuditOperation::Unmap]
+    );
+
+    virtual_machine.set_audit_capacity(1);
+
+    assert_eq!(virtual_machine.permission_audit().len(), 1);
+}
+
+#[test]
+fn permission_audit_records_reprotects() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    let mapping_handle = virtual_machine
+        .map(allocation_handle, 0x10000, MemoryPermission::READ)
+        .unwrap();
+
+    // Operations before the audit log is enabled aren't recorded.
+    virtual_machine.set_audit_enabled(true);
+
+    let permissions = [
+        MemoryPermission::READ_WRITE,
+        MemoryPermission::READ_EXECUTE,
+        MemoryPermission::READ,
+    ];
+
+    for permission in permissions {
+        virtual_machine
+            .reprotect(mapping_handle, permission)
+            .unwrap();
+    }
+
+    let audit = virtual_machine.permission_audit();
+
+    assert_eq!(audit.len(), 3);
+
+    let mut old_permission = MemoryPermission::READ;
+
+    for (entry, permission) in audit.iter().zip(permissions) {
+        assert_eq!(entry.operation, AuditOperation::Reprotect);
+        assert_eq!(entry.mapping_handle, mapping_handle);
+        assert_eq!(entry.old_permission, Some(old_permission));
+        assert_eq!(entry.new_permission, Some(permission));
+
+        old_permission = permission;
+    }
+
+    assert!(audit
+        .windows(2)
+        .all(|entries| entries[0].timestamp <= entries[1].timestamp));
+}
+
+/// Code of a guest looping forever.
+const BUSY_LOOP: [u8; 4] = [
+    0x00, 0x00, 0x00, 0x14, // b .
+];
+
+#[cfg(feature = "std")]
+#[test]
+fn run_bounded_preempts_a_busy_guest() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    const BUDGET: u64 = 2_400_000;
+
+    // The watchdog thread is reused across calls.
+    for _ in 0..3 {
+        let start = vcpu.get_exec_time().unwrap();
+
+        assert!(matches!(
+            vcpu.run_bounded(BUDGET).unwrap(),
+            VirtualCpuExitReason::Cancelled
+        ));
+
+        // The guest only runs while the budget elapses, give some room for the host scheduling and the exit latency.
+        let elapsed = vcpu.get_exec_time().unwrap() - start;
+
+        assert!(elapsed >= BUDGET / 2, "exited after {elapsed} ticks");
+        assert!(elapsed <= BUDGET * 2, "exited after {elapsed} ticks");
+    }
+}
+
+/// MMIO device model ignoring every access.
+struct NullDevice;
+
+impl MmioHandler for NullDevice {
+    fn read(&mut self, _offset: u64, _size: usize) -> u64 {
+        0
+    }
+
+    fn write(&mut self, _offset: u64, _size: usize, _value: u64) {}
+}
+
+#[test]
+fn map_rejects_mmio_regions() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .register_mmio(0x30000, 0x1000, Box::new(NullDevice))
+        .unwrap();
+
+    assert!(matches!(
+        virtual_machine.map(allocation_handle, 0x30000, MemoryPermission::READ_WRITE),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    virtual_machine
+        .map(allocation_handle, 0x40000, MemoryPermission::READ_WRITE)
+        .unwrap();
+}
+
+#[test]
+fn simd_registers_round_trip() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let mut vcpu = virtual_machine.create_vcpu(None).unwrap();
+
+    let mut registers = [0; SIMD_FP_REGISTER_COUNT];
+
+    for (index, value) in registers.iter_mut().enumerate() {
+        *value = 0x0011_2233_4455_6677_8899_aabb_ccdd_ee00 | index as u128;
+    }
+
+    vcpu.set_all_simd_registers(&registers).unwrap();
+
+    assert_eq!(vcpu.get_all_simd_registers().unwrap(), registers);
+
+    let state = SimdFpState {
+        registers: [u128::MAX; SIMD_FP_REGISTER_COUNT],
+        fpcr: 0x0300_0000,
+        fpsr: 0x1,
+    };
+
+    vcpu.set_simd_fp_state(&state).unwrap();
+
+    assert_eq!(vcpu.get_simd_fp_state().unwrap(), state);
+    assert!(matches!(
+        vcpu.set_simd_register(SIMD_FP_REGISTER_COUNT as u8, 0),
+        Err(HypervisorError::BadArgument)
+    ));
+}
+
+/// Code of a snippet calling ``hvc #0``.
+const HVC_SNIPPET: [u8; 4] = [0x02, 0x00, 0x00, 0xD4];
+
+#[test]
+fn run_snippet_undoes_its_steps_on_error() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    // Mapping the stack fails once the code is mapped.
+    virtual_machine
+        .register_mmio(SNIPPET_STACK_ADDRESS, PAGE_SIZE, Box::new(NullDevice))
+        .unwrap();
+
+    assert!(matches!(
+        virtual_machine.run_snippet(&HVC_SNIPPET, PAGE_SIZE),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    assert!(virtual_machine.get_all_mapping_infos().is_empty());
+    assert_eq!(virtual_machine.committed_host_bytes(), 0);
+}
+
+#[test]
+fn run_snippet_can_only_run_once() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let (_vcpu, reason) = virtual_machine
+        .run_snippet(&HVC_SNIPPET, PAGE_SIZE)
+        .unwrap();
+
+    assert!(is_hvc(&reason));
+
+    let committed_host_bytes = virtual_machine.committed_host_bytes();
+
+    assert!(matches!(
+        virtual_machine.run_snippet(&HVC_SNIPPET, PAGE_SIZE),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    assert_eq!(virtual_machine.get_all_mapping_infos().len(), 2);
+    assert_eq!(virtual_machine.committed_host_bytes(), committed_host_bytes);
+}
+
+#[test]
+fn trace_keeps_the_newest_exits() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_counter_guest();
+    let vcpu_snapshot = vcpu.snapshot().unwrap();
+
+    vcpu.set_trace_enabled(true);
+    vcpu.set_trace_capacity(2);
+
+    for _ in 0..3 {
+        vcpu.reset_to(&vcpu_snapshot).unwrap();
+        run_counter_guest(&mut virtual_machine, &mut vcpu);
+    }
+
+    let trace: Vec<&TraceEntry> = vcpu.trace().collect();
+
+    assert_eq!(trace.len(), 2);
+
+    for entry in trace {
+        assert_eq!(entry.exception_class, Some(ExceptionClass::Hvc64));
+        assert_eq!(entry.fault_address, None);
+    }
+}
+
+#[test]
+fn trace_records_the_exit_classes_in_order() {
+    /// Code of a guest calling the host, storing to the unmapped address in x3 and calling the host again.
+    const TRAPPING_CODE: [u8; 12] = [
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+        0x61, 0x00, 0x00, 0xF9, // str x1, [x3]
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ];
+
+    /// Unmapped address written by the guest.
+    const UNMAPPED_ADDRESS: u64 = 0x4000_0000;
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&TRAPPING_CODE);
+
+    vcpu.set_register(Register::X3, UNMAPPED_ADDRESS).unwrap();
+    vcpu.set_trace_enabled(true);
+    vcpu.set_trace_capacity(8);
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+    assert!(!is_hvc(&vcpu.run().unwrap()));
+
+    // Skip the faulting store.
+    vcpu.advance_pc(4).unwrap();
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+
+    let trace: Vec<&TraceEntry> = vcpu.trace().collect();
+
+    assert_eq!(trace.len(), 3);
+
+    assert_eq!(trace[0].exception_class, Some(ExceptionClass::Hvc64));
+    assert_eq!(trace[0].fault_address, None);
+
+    assert_eq!(
+        trace[1].exception_class,
+        Some(ExceptionClass::DataAbortLowerEl)
+    );
+    assert_eq!(trace[1].pc, CODE_ADDRESS + 4);
+    assert_eq!(trace[1].fault_address, Some(UNMAPPED_ADDRESS));
+
+    assert_eq!(trace[2].exception_class, Some(ExceptionClass::Hvc64));
+    assert_eq!(trace[2].fault_address, None);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn create_vcpu_fails_one_past_max() {
+    use std::sync::{Arc, Barrier};
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let max_vcpu_count = virtual_machine.max_vcpu_count();
+
+    assert_ne!(max_vcpu_count, 0);
+
+    // Keep every vCPU alive until the limit has been checked.
+    let barrier = Arc::new(Barrier::new(max_vcpu_count as usize + 1));
+    let vcpu_barrier = barrier.clone();
+
+    // SAFETY: The threads are joined before the Virtual Machine is dropped.
+    let vcpus = unsafe {
+        virtual_machine.spawn_vcpus(max_vcpu_count as usize, move |_, _| {
+            vcpu_barrier.wait();
+
+            Ok(())
+        })
+    }
+    .unwrap();
+
+    // SAFETY: No thread is spawned.
+    assert!(matches!(
+        unsafe { virtual_machine.spawn_vcpus(1, |_, _| Ok(())) },
+        Err(HypervisorError::MaxVcpusReached(count)) if count == max_vcpu_count
+    ));
+
+    barrier.wait();
+
+    for (_, join_handle) in vcpus {
+        join_handle.join().unwrap().unwrap();
+    }
+
+    // The slots are released once the vCPUs are destroyed.
+    assert!(virtual_machine.create_vcpu(None).is_ok());
+}
+
+#[test]
+fn guest_compare_exchange_updates_guest_memory() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_counter_guest();
+
+    assert_eq!(
+        virtual_machine
+            .guest_compare_exchange(DATA_ADDRESS, 0, 41, AtomicSize::DoubleWord)
+            .unwrap(),
+        0
+    );
+
+    // The guest observes the exchanged value.
+    assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 42);
+
+    // The value written by the guest is only replaced when it matches.
+    assert_eq!(
+        virtual_machine
+            .guest_compare_exchange(DATA_ADDRESS, 41, 0, AtomicSize::DoubleWord)
+            .unwrap(),
+        42
+    );
+    assert_eq!(
+        virtual_machine
+            .guest_compare_exchange(DATA_ADDRESS, 42, 7, AtomicSize::Word)
+            .unwrap(),
+        42
+    );
+    assert_eq!(virtual_machine.read_u64(DATA_ADDRESS).unwrap(), 7);
+
+    assert!(matches!(
+        virtual_machine.guest_compare_exchange(DATA_ADDRESS + 2, 0, 0, AtomicSize::Word),
+        Err(HypervisorError::MisalignedAddress)
+    ));
+    assert!(matches!(
+        virtual_machine.guest_compare_exchange(0x100_0000, 0, 0, AtomicSize::Word),
+        Err(HypervisorError::UnmappedAddress)
+    ));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn guest_compare_exchange_races_a_running_guest() {
+    use std::time::{Duration, Instant};
+
+    let _guard = lock_hypervisor();
+
+    // The guest publishes 1 and spins until the host exchanges it for 2.
+    let mut virtual_machine = create_guest_memory(&[
+        0x23, 0x00, 0x80, 0x52, // mov w3, #1
+        0x43, 0x00, 0x00, 0xB9, // str w3, [x2]
+        0x43, 0x00, 0x40, 0xB9, // ldr w3, [x2]
+        0x7F, 0x08, 0x00, 0x71, // cmp w3, #2
+        0xC1, 0xFF, 0xFF, 0x54, // b.ne #-8
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ]);
+
+    // SAFETY: The thread is joined before the Virtual Machine is dropped.
+    let vcpus = unsafe {
+        virtual_machine.spawn_vcpus(1, |_, vcpu| {
+            prepare_vcpu(vcpu)?;
+
+            let reason = vcpu.run()?;
+
+            assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+            Ok(())
+        })
+    }
+    .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    loop {
+        match virtual_machine
+            .guest_compare_exchange(DATA_ADDRESS, 1, 2, AtomicSize::Word)
+            .unwrap()
+        {
+            1 => break,
+            previous => assert_eq!(previous, 0),
+        }
+
+        assert!(
+            Instant::now() < deadline,
+            "The guest never published its value"
+        );
+
+        std::thread::yield_now();
+    }
+
+    for (_, join_handle) in vcpus {
+        join_handle.join().unwrap().unwrap();
+    }
+
+    assert_eq!(virtual_machine.read_u32(DATA_ADDRESS).unwrap(), 2);
+}
+
+/// Guest run by a [VcpuRunner] on its own thread, see [spawn_runner].
+#[cfg(feature = "std")]
+struct RunnerThread {
+    /// The waker of the runner.
+    waker: VcpuWaker,
+
+    /// Receives the exit returned by the runner.
+    reasons: std::sync::mpsc::Receiver<VirtualCpuExitReason>,
+
+    /// The vCPU thread.
+    join_handle: std::thread::JoinHandle<Result<()>>,
+}
+
+#[cfg(feature = "std")]
+impl RunnerThread {
+    /// Wait for the exit returned by the runner.
+    fn reason(&self) -> VirtualCpuExitReason {
+        self.reasons
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap()
+    }
+
+    /// Check that the runner doesn't return for a while.
+    fn assert_running(&self) {
+        assert!(self
+            .reasons
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+    }
+
+    /// Join the vCPU thread, stopping the runner if it's still running.
+    fn join(self) {
+        let _ = self.waker.exit();
+
+        self.join_handle.join().unwrap().unwrap();
+    }
+}
+
+/// Run the guest of a Virtual Machine with a [VcpuRunner] on a new thread.
+///
+/// The thread must be joined before the Virtual Machine is dropped.
+#[cfg(feature = "std")]
+fn spawn_runner(virtual_machine: &mut VirtualMachine) -> RunnerThread {
+    use std::sync::{mpsc, Mutex};
+
+    let (waker_sender, waker_receiver) = mpsc::channel();
+    let (reason_sender, reasons) = mpsc::channel();
+    let senders = Mutex::new((waker_sender, reason_sender));
+
+    // SAFETY: The callers join the thread before the Virtual Machine is dropped.
+    let mut threads = unsafe {
+        virtual_machine.spawn_vcpus(1, move |_, vcpu| {
+            prepare_vcpu(vcpu)?;
+
+            let mut runner = VcpuRunner::new(vcpu, None);
+            let senders = senders.lock().unwrap();
+
+            senders.0.send(runner.waker()).unwrap();
+
+            // The runner may be stopped once the test is over.
+            let _ = senders.1.send(runner.run()?);
+
+            Ok(())
+        })
+    }
+    .unwrap();
+
+    RunnerThread {
+        waker: waker_receiver.recv().unwrap(),
+        reasons,
+        join_handle: threads.pop().unwrap().1,
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn vcpu_runner_wakes_an_idle_guest() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&[
+        0x7F, 0x20, 0x03, 0xD5, // wfi
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ]);
+
+    let runner = spawn_runner(&mut virtual_machine);
+
+    // The guest stays idle until it's woken.
+    runner.assert_running();
+    runner.waker.wake();
+
+    let reason = runner.reason();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+    runner.join();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn vcpu_runner_keeps_interrupts_asserted_while_masked() {
+    let _guard = lock_hypervisor();
+
+    // The interrupt is raised while IRQs are masked and is only taken once the guest unmasks them after its WFI.
+    let mut virtual_machine = create_guest_memory(&with_vectors(
+        &[
+            0x7F, 0x20, 0x03, 0xD5, // wfi
+            0xFF, 0x42, 0x03, 0xD5, // msr daifclr, #2
+            0x00, 0x00, 0x00, 0x14, // b .
+        ],
+        &[(
+            IRQ_VECTOR,
+            &[
+                0x22, 0x00, 0x00, 0xD4, // hvc #1
+            ],
+        )],
+    ));
+
+    let runner = spawn_runner(&mut virtual_machine);
+
+    runner.waker.raise(InterruptType::IRQ).unwrap();
+
+    match runner.reason() {
+        VirtualCpuExitReason::Exception { exception } => {
+            assert_eq!(exception.exception_class(), ExceptionClass::Hvc64);
+            assert_eq!(exception.syndrome & 0xffff, 1);
+        }
+        reason => panic!("Unexpected exit: {:?}", reason),
+    }
+
+    runner.waker.lower(InterruptType::IRQ);
+    runner.join();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn vcpu_runner_only_absorbs_its_own_exits() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&BUSY_LOOP);
+
+    let runner = spawn_runner(&mut virtual_machine);
+
+    // The exit delivering the interrupt is absorbed, the guest keeps running with IRQs masked.
+    runner.waker.raise(InterruptType::IRQ).unwrap();
+    runner.assert_running();
+
+    runner.waker.exit().unwrap();
+
+    assert!(matches!(runner.reason(), VirtualCpuExitReason::Cancelled));
+
+    runner.join();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn spawn_vcpus_runs_every_vcpu() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&HVC_SNIPPET);
+
+    let hvc_count = Arc::new(AtomicUsize::new(0));
+    let vcpu_hvc_count = hvc_count.clone();
+
+    // SAFETY: The threads are joined before the Virtual Machine is dropped.
+    let vcpus = unsafe {
+        virtual_machine.spawn_vcpus(2, move |_, vcpu| {
+            prepare_vcpu(vcpu)?;
+
+            if is_hvc(&vcpu.run()?) {
+                vcpu_hvc_count.fetch_add(1, Ordering::SeqCst);
+            }
+
+            Ok(())
+        })
+    }
+    .unwrap();
+
+    assert_eq!(vcpus.len(), 2);
+
+    for (_, join_handle) in vcpus {
+        join_handle.join().unwrap().unwrap();
+    }
+
+    assert_eq!(hvc_count.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(feature = "dispatch")]
+#[test]
+fn run_until_deadline_cancels_its_timer() {
+    use std::time::{Duration, Instant};
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&[
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+        0x00, 0x00, 0x00, 0x14, // b .
+    ]);
+
+    let reason = vcpu
+        .run_until_deadline(Instant::now() + Duration::from_millis(50))
+        .unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+    // A timer surviving the early return would leave an exit pending for the next run.
+    std::thread::sleep(Duration::from_millis(100));
+
+    vcpu.set_register(Register::PC, CODE_ADDRESS + 4).unwrap();
+
+    let start = Instant::now();
+
+    assert!(matches!(
+        vcpu.run_until_deadline(start + Duration::from_millis(50))
+            .unwrap(),
+        VirtualCpuExitReason::Cancelled
+    ));
+    assert!(start.elapsed() >= Duration::from_millis(40));
+}
+
+/// Prepare a vCPU of a guest created by [create_guest] for [VirtualCpu::validate_state], with its stack at the end of the data page.
+fn prepare_validated_vcpu(vcpu: &mut VirtualCpu) {
+    prepare_vcpu(vcpu).unwrap();
+
+    vcpu.set_system_register(SystemRegister::SP_EL1, DATA_ADDRESS + PAGE_SIZE as u64)
+        .unwrap();
+}
+
+#[test]
+fn validate_state_accepts_a_valid_state() {
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    prepare_validated_vcpu(&mut vcpu);
+
+    assert!(vcpu.validate_state(Some(&virtual_machine)).is_ok());
+}
+
+#[test]
+fn validate_state_reports_an_unset_pc() {
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    prepare_validated_vcpu(&mut vcpu);
+    vcpu.set_register(Register::PC, 0).unwrap();
+
+    assert!(matches!(
+        vcpu.validate_state(Some(&virtual_machine)),
+        Err(GuestStateError::UnmappedPc(0))
+    ));
+
+    // The mapping check is skipped without the Virtual Machine.
+    assert!(vcpu.validate_state(None).is_ok());
+}
+
+#[test]
+fn validate_state_reports_an_invalid_cpsr() {
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    prepare_validated_vcpu(&mut vcpu);
+
+    // EL2h isn't available to the guest.
+    vcpu.set_register(Register::CPSR, 0x3c9).unwrap();
+
+    assert!(matches!(
+        vcpu.validate_state(Some(&virtual_machine)),
+        Err(GuestStateError::InvalidMode(0x3c9))
+    ));
+}
+
+#[test]
+fn validate_state_checks_pc_in_slice_mappings() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    prepare_validated_vcpu(&mut vcpu);
+
+    let slice_address = DATA_ADDRESS + PAGE_SIZE as u64;
+    let mut buffer = Box::new(AlignedPage([0; PAGE_SIZE]));
+
+    vcpu.set_register(Register::PC, slice_address).unwrap();
+
+    {
+        // SAFETY: the mapping is dropped before the buffer.
+        let _mapping = unsafe {
+            virtual_machine
+                .map_slice(&mut buffer.0, slice_address, MemoryPermission::READ_EXECUTE)
+                .unwrap()
+        };
+
+        assert!(vcpu.validate_state(Some(&virtual_machine)).is_ok());
+    }
+
+    {
+        // SAFETY: the mapping is dropped before the buffer.
+        let _mapping = unsafe {
+            virtual_machine
+                .map_slice(&mut buffer.0, slice_address, MemoryPermission::READ_WRITE)
+                .unwrap()
+        };
+
+        assert!(matches!(
+            vcpu.validate_state(Some(&virtual_machine)),
+            Err(GuestStateError::NonExecutablePc(address)) if address == slice_address
+        ));
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn guest_scheduler_shares_time_between_vcpus() {
+    let _guard = lock_hypervisor();
+
+    // Every vCPU increments its own counter forever.
+    let mut virtual_machine = create_guest_memory(&[
+        0x41, 0x00, 0x40, 0xF9, // ldr x1, [x2]
+        0x21, 0x04, 0x00, 0x91, // add x1, x1, #1
+        0x41, 0x00, 0x00, 0xF9, // str x1, [x2]
+        0xFD, 0xFF, 0xFF, 0x17, // b #-12
+    ]);
+
+    let mut scheduler = GuestScheduler::new(&mut virtual_machine, 2, 240_000, |index, vcpu| {
+        prepare_vcpu(vcpu)?;
+        vcpu.set_register(Register::X2, DATA_ADDRESS + index as u64 * 8)
+    })
+    .unwrap();
+
+    for slice in 0..6 {
+        let exit = scheduler.run_slice().unwrap();
+
+        assert_eq!(exit.index, slice % 2);
+        assert!(matches!(exit.reason, Ok(VirtualCpuExitReason::Cancelled)));
+    }
+
+    let virtual_machine = scheduler.virtual_machine();
+
+    assert_ne!(virtual_machine.read_u64(DATA_ADDRESS).unwrap(), 0);
+    assert_ne!(virtual_machine.read_u64(DATA_ADDRESS + 8).unwrap(), 0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn profiling_runner_samples_the_hot_loop() {
+    use std::time::Duration;
+
+    let _guard = lock_hypervisor();
+
+    // Count down from about 100 millions before reporting with an HVC, then start over.
+    let (_virtual_machine, mut vcpu) = create_guest(&[
+        0x00, 0xC0, 0xA0, 0xD2, // mov x0, #100663296
+        0x00, 0x04, 0x00, 0xF1, // subs x0, x0, #1
+        0xE1, 0xFF, 0xFF, 0x54, // b.ne #-4
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+        0xFC, 0xFF, 0xFF, 0x17, // b #-16
+    ]);
+
+    let mut runner = ProfilingRunner::new(&mut vcpu, Duration::from_millis(1));
+
+    // The sampler thread is reused, and its exits never surface in a later run.
+    for _ in 0..3 {
+        let reason = runner.run().unwrap();
+
+        assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+    }
+
+    assert_ne!(runner.sample_count(), 0);
+    assert!(runner
+        .histogram()
+        .keys()
+        .all(|address| (CODE_ADDRESS..CODE_ADDRESS + 20).contains(address)));
+
+    let (hottest, _) = runner.hottest(1)[0];
+
+    assert!(hottest == CODE_ADDRESS + 4 || hottest == CODE_ADDRESS + 8);
+}
+
+#[test]
+fn scheduling_account_busy_ticks_only_grow() {
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mut vcpu) = create_counter_guest();
+    let mut account = SchedulingAccount::new();
+    let mut previous_busy_ticks = account.busy_ticks();
+
+    for run_count in 1..=5 {
+        let reason = account.run(&mut vcpu).unwrap();
+
+        assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+        assert!(account.busy_ticks() >= previous_busy_ticks);
+        assert_eq!(account.run_count(), run_count);
+
+        previous_busy_ticks = account.busy_ticks();
+
+        vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+    }
+
+    assert_eq!(
+        virtual_machine.read_u64(DATA_ADDRESS).unwrap(),
+        account.run_count()
+    );
+}
+
+#[test]
+fn inject_irq_and_run_enters_the_irq_handler() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&with_vectors(
+        &[
+            0xFF, 0x42, 0x03, 0xD5, // msr daifclr, #2
+            0x00, 0x00, 0x00, 0x14, // b .
+        ],
+        &[(
+            IRQ_VECTOR,
+            &[
+                0x22, 0x00, 0x00, 0xD4, // hvc #1
+            ],
+        )],
+    ));
+
+    match vcpu.inject_irq_and_run(InterruptType::IRQ).unwrap() {
+        VirtualCpuExitReason::Exception { exception } => {
+            assert_eq!(exception.exception_class(), ExceptionClass::Hvc64);
+            assert_eq!(exception.syndrome & 0xffff, 1);
+        }
+        reason => panic!("Unexpected exit: {:?}", reason),
+    }
+
+    // The interrupt was taken from the main code.
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::ELR_EL1).unwrap(),
+        CODE_ADDRESS + 4
+    );
+}
+
+#[test]
+fn dirty_tracking_reports_the_written_page() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&COUNTER_CODE);
+
+    // Three more data pages after the first one, the counter lives in the middle one.
+    let allocation_handle = virtual_machine.allocate(3 * PAGE_SIZE).unwrap();
+    let mapping_handle = virtual_machine
+        .map(
+            allocation_handle,
+            DATA_ADDRESS + PAGE_SIZE as u64,
+            MemoryPermission::READ_WRITE,
+        )
+        .unwrap();
+
+    let counter_address = DATA_ADDRESS + 2 * PAGE_SIZE as u64;
+
+    vcpu.set_register(Register::X2, counter_address).unwrap();
+
+    virtual_machine.enable_dirty_tracking().unwrap();
+
+    // Reprotecting a tracked mapping keeps it tracked.
+    virtual_machine
+        .reprotect(mapping_handle, MemoryPermission::READ_WRITE)
+        .unwrap();
+
+    assert_eq!(run_counter_guest(&mut virtual_machine, &mut vcpu), 1);
+    assert_eq!(virtual_machine.take_dirty_pages(), vec![counter_address]);
+
+    // Nothing was written since the last call.
+    assert!(virtual_machine.take_dirty_pages().is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_binary_has_the_hypervisor_entitlement() {
+    // The test binary is signed with the entitlement, see the top of this file.
+    assert!(has_hypervisor_entitlement());
+}
+
+#[test]
+fn guest_layout_builds_contiguous_regions() {
+    /// Guest address of the layout.
+    const BASE: hv_ipa_t = 0x100_0000;
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let handles = GuestLayout::new()
+        .region_with_permission("code", PAGE_SIZE, MemoryPermission::READ_EXECUTE)
+        .region("ram", 3 * PAGE_SIZE)
+        .region("mmio", 0x1000)
+        .build(&mut virtual_machine, BASE)
+        .unwrap();
+
+    assert_eq!(handles.len(), 3);
+
+    let expected = [
+        ("code", BASE, PAGE_SIZE, MemoryPermission::READ_EXECUTE),
+        (
+            "ram",
+            BASE + PAGE_SIZE as u64,
+            3 * PAGE_SIZE,
+            MemoryPermission::READ_WRITE,
+        ),
+        (
+            "mmio",
+            BASE + 4 * PAGE_SIZE as u64,
+            PAGE_SIZE,
+            MemoryPermission::READ_WRITE,
+        ),
+    ];
+
+    for (name, address, size, permission) in expected {
+        let mapping = virtual_machine.get_mapping_info(handles[name]).unwrap();
+
+        assert_eq!(mapping.address, address, "{}", name);
+        assert_eq!(mapping.size, size, "{}", name);
+        assert_eq!(mapping.permission, permission, "{}", name);
+    }
+
+    // The regions are contiguous, a new layout over them is rejected.
+    assert!(matches!(
+        GuestLayout::new()
+            .region("overlap", PAGE_SIZE)
+            .build(&mut virtual_machine, BASE + 4 * PAGE_SIZE as u64),
+        Err(HypervisorError::BadArgument)
+    ));
+}
+
+#[test]
+fn host_page_size_is_a_power_of_two() {
+    let page_size = host_page_size();
+
+    assert!(page_size.is_power_of_two());
+    assert!((PAGE_SIZE_4K..=PAGE_SIZE_64K).contains(&page_size));
+}
+
+#[test]
+fn allocate_aligned_respects_the_alignment() {
+    const HUGE_PAGE_SIZE: usize = 0x20_0000;
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let handle = virtual_machine
+        .allocate_aligned(PAGE_SIZE, HUGE_PAGE_SIZE)
+        .unwrap();
+    let slice = virtual_machine.get_allocation_slice(handle).unwrap();
+
+    assert_eq!(slice.as_ptr() as usize % HUGE_PAGE_SIZE, 0);
+    assert_eq!(slice.len(), HUGE_PAGE_SIZE);
+
+    assert!(matches!(
+        virtual_machine.allocate_aligned(PAGE_SIZE, PAGE_SIZE * 3),
+        Err(HypervisorError::BadArgument)
+    ));
+    assert!(matches!(
+        virtual_machine.allocate_aligned(PAGE_SIZE, PAGE_SIZE / 2),
+        Err(HypervisorError::BadArgument)
+    ));
+}
+
+#[test]
+fn allocate_respects_the_memory_limit() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    // Without a limit, huge allocations are still rejected.
+    assert!(matches!(
+        virtual_machine.allocate(usize::MAX),
+        Err(HypervisorError::BadArgument)
+    ));
+    assert!(matches!(
+        virtual_machine.allocate(isize::MAX as usize - 2 * PAGE_SIZE),
+        Err(HypervisorError::NoResources)
+    ));
+
+    virtual_machine.set_memory_limit(Some(2 * PAGE_SIZE));
+
+    virtual_machine.allocate(PAGE_SIZE).unwrap();
+    virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    assert!(matches!(
+        virtual_machine.allocate(1),
+        Err(HypervisorError::NoResources)
+    ));
+    assert!(matches!(
+        virtual_machine.allocate(usize::MAX),
+        Err(HypervisorError::BadArgument)
+    ));
+    assert_eq!(virtual_machine.committed_host_bytes(), 2 * PAGE_SIZE);
+}
+
+/// MMIO device model made of a single 64-bit register, shared with the test.
+struct RegisterDevice(std::rc::Rc<std::cell::Cell<u64>>);
+
+impl MmioHandler for RegisterDevice {
+    fn read(&mut self, _offset: u64, _size: usize) -> u64 {
+        self.0.get()
+    }
+
+    fn write(&mut self, _offset: u64, _size: usize, value: u64) {
+        self.0.set(value);
+    }
+}
+
+#[test]
+fn register_mmio_round_trips_guest_accesses() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&[
+        0x85, 0x00, 0xA0, 0xD2, // mov x5, #0x40000
+        0xA3, 0x04, 0x00, 0xF9, // str x3, [x5, #8]
+        0xA4, 0x04, 0x40, 0xF9, // ldr x4, [x5, #8]
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ]);
+
+    let register = std::rc::Rc::new(std::cell::Cell::new(0));
+
+    virtual_machine
+        .register_mmio(0x40000, 0x1000, Box::new(RegisterDevice(register.clone())))
+        .unwrap();
+
+    vcpu.set_register(Register::X3, 0x1122_3344_5566_7788)
+        .unwrap();
+
+    loop {
+        match vcpu.run().unwrap() {
+            reason if is_hvc(&reason) => break,
+            VirtualCpuExitReason::Exception { exception } => {
+                assert!(virtual_machine
+                    .dispatch_mmio(&mut vcpu, &exception)
+                    .unwrap());
+            }
+            reason => panic!("Unexpected exit: {:?}", reason),
+        }
+    }
+
+    assert_eq!(register.get(), 0x1122_3344_5566_7788);
+    assert_eq!(
+        vcpu.get_register(Register::X4).unwrap(),
+        0x1122_3344_5566_7788
+    );
+}
+
+#[test]
+fn register_mmio_rejects_guard_regions() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .map_with_guard(allocation_handle, 0x100000, MemoryPermission::READ_WRITE, 1)
+        .unwrap();
+
+    // The guard pages are right before and after the mapping.
+    for address in [0x100000 - 0x1000, 0x100000 + PAGE_SIZE as u64] {
+        assert!(matches!(
+            virtual_machine.register_mmio(address, 0x1000, Box::new(NullDevice)),
+            Err(HypervisorError::BadArgument)
+        ));
+    }
+
+    virtual_machine
+        .register_mmio(
+            0x100000 + 2 * PAGE_SIZE as u64,
+            0x1000,
+            Box::new(NullDevice),
+        )
+        .unwrap();
+}
+
+#[test]
+fn map_with_guard_rejects_mmio_regions() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .register_mmio(0x100000 + PAGE_SIZE as u64, 0x1000, Box::new(NullDevice))
+        .unwrap();
+
+    assert!(matches!(
+        virtual_machine.map_with_guard(
+            allocation_handle,
+            0x100000,
+            MemoryPermission::READ_WRITE,
+            1
+        ),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    // Nothing was mapped.
+    assert!(virtual_machine.get_all_mapping_infos().is_empty());
+}
+
+#[test]
+fn map_with_guard_aborts_guest_accesses_to_guards() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&[
+        0x25, 0x02, 0xA0, 0xD2, // mov x5, #0x110000
+        0xA4, 0x00, 0x40, 0xF9, // ldr x4, [x5]
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ]);
+
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .map_with_guard(allocation_handle, 0x100000, MemoryPermission::READ_WRITE, 1)
+        .unwrap();
+
+    match vcpu.run().unwrap() {
+        VirtualCpuExitReason::Exception { exception } => {
+            assert!(exception.data_abort_info().is_some());
+            assert_eq!(exception.physical_address, 0x110000);
+            assert!(virtual_machine.is_guard_address(exception.physical_address));
+        }
+        reason => panic!("Unexpected exit: {:?}", reason),
+    }
+}
+
+#[test]
+fn pending_serror_enters_the_serror_vector() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&with_vectors(
+        &BUSY_LOOP,
+        &[(
+            SERROR_VECTOR,
+            &[
+                0x42, 0x00, 0x00, 0xD4, // hvc #2
+            ],
+        )],
+    ));
+
+    // EL1h with asynchronous aborts unmasked.
+    vcpu.set_register(Register::CPSR, 0x2c5).unwrap();
+    vcpu.set_pending_serror(true, None).unwrap();
+
+    match vcpu.run().unwrap() {
+        VirtualCpuExitReason::Exception { exception } => {
+            assert_eq!(exception.exception_class(), ExceptionClass::Hvc64);
+            assert_eq!(exception.syndrome & 0xffff, 2);
+        }
+        reason => panic!("Unexpected exit: {:?}", reason),
+    }
+
+    assert!(!vcpu.get_pending_serror());
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::ESR_EL1).unwrap(),
+        DEFAULT_SERROR_SYNDROME
+    );
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::ELR_EL1).unwrap(),
+        CODE_ADDRESS
+    );
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::SPSR_EL1).unwrap(),
+        0x2c5
+    );
+}
+
+#[test]
+fn pending_serror_rejects_an_overflowing_vector() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    vcpu.set_register(Register::CPSR, 0x2c5).unwrap();
+    vcpu.set_system_register(SystemRegister::VBAR_EL1, 0xFFFF_FFFF_FFFF_F800)
+        .unwrap();
+    vcpu.set_pending_serror(true, None).unwrap();
+
+    assert!(matches!(
+        vcpu.run(),
+        Err(HypervisorError::IllegalGuestState)
+    ));
+    assert!(vcpu.get_pending_serror());
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), CODE_ADDRESS);
+}
+
+/// Code of a guest reporting its virtual counter in x0 with an HVC.
+const READ_COUNTER_CODE: [u8; 8] = [
+    0x40, 0xE0, 0x3B, 0xD5, // mrs x0, cntvct_el0
+    0x02, 0x00, 0x00, 0xD4, // hvc #0
+];
+
+/// Run the guest of [READ_COUNTER_CODE] from its start and return the virtual counter it observed.
+fn read_guest_counter(vcpu: &mut VirtualCpu) -> u64 {
+    vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+
+    let reason = vcpu.run().unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+    vcpu.get_register(Register::X0).unwrap()
+}
+
+#[test]
+fn sync_vtimer_to_host_starts_from_the_epoch() {
+    /// Guest counter value right after the sync.
+    const EPOCH: u64 = 0x1234_5678_9000;
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&READ_COUNTER_CODE);
+
+    vcpu.sync_vtimer_to_host(EPOCH).unwrap();
+
+    let count = read_guest_counter(&mut vcpu);
+    let elapsed = vcpu.current_virtual_count().unwrap() - EPOCH;
+
+    // The guest counter only advanced by the host time elapsed since the sync.
+    assert!(count >= EPOCH);
+    assert!(count - EPOCH <= elapsed);
+
+    // Less than a second at the 24MHz counter frequency of Apple Silicon.
+    assert!(elapsed < 24_000_000);
+}
+
+#[test]
+fn current_virtual_count_is_monotonic() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&READ_COUNTER_CODE);
+
+    let first = vcpu.current_virtual_count().unwrap();
+    let guest = read_guest_counter(&mut vcpu);
+    let second = vcpu.current_virtual_count().unwrap();
+
+    assert!(first <= guest);
+    assert!(guest <= second);
+}
+
+#[test]
+fn thaw_vtimer_without_elapsed_time_keeps_the_counter() {
+    use std::time::Duration;
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&READ_COUNTER_CODE);
+
+    // Measure how much the counter advances while sleeping.
+    let start = vcpu.current_virtual_count().unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let sleep_ticks = vcpu.current_virtual_count().unwrap() - start;
+
+    let frozen = vcpu.freeze_vtimer().unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    vcpu.thaw_vtimer(&frozen, 0).unwrap();
+
+    let count = read_guest_counter(&mut vcpu);
+
+    assert!(count >= frozen.count);
+    assert!(count - frozen.count < sleep_ticks / 2);
+}
+
+#[test]
+fn probe_readable_system_registers_includes_midr_el1() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    let registers = vcpu.probe_readable_system_registers();
+
+    assert!(registers.len() <= SystemRegister::ALL.len());
+    assert!(registers
+        .iter()
+        .any(|register| matches!(register, SystemRegister::MIDR_EL1)));
+    assert!(registers
+        .iter()
+        .any(|register| matches!(register, SystemRegister::SCTLR_EL1)));
+}
+
+#[test]
+fn allocation_host_ptr_is_page_aligned() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let allocation_handle = virtual_machine.allocate(1).unwrap();
+
+    let pointer = unsafe { virtual_machine.allocation_host_ptr(allocation_handle) }.unwrap();
+
+    assert!(!pointer.is_null());
+    assert_eq!(pointer as usize % PAGE_SIZE, 0);
+    assert_eq!(
+        pointer as *const u8,
+        virtual_machine
+            .get_allocation_slice(allocation_handle)
+            .unwrap()
+            .as_ptr()
+    );
+
+    virtual_machine.deallocate(allocation_handle).unwrap();
+
+    assert!(matches!(
+        unsafe { virtual_machine.allocation_host_ptr(allocation_handle) },
+        Err(HypervisorError::InvalidHandle)
+    ));
+}
+
+#[test]
+fn set_system_register_rejects_read_only_registers() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    assert!(!SystemRegister::ID_AA64MMFR0_EL1.is_writable());
+
+    let value = vcpu
+        .get_system_register(SystemRegister::ID_AA64MMFR0_EL1)
+        .unwrap();
+
+    assert!(matches!(
+        vcpu.set_system_register(SystemRegister::ID_AA64MMFR0_EL1, value),
+        Err(HypervisorError::ReadOnlyRegister)
+    ));
+
+    // Writable registers are still written.
+    assert!(SystemRegister::TPIDR_EL1.is_writable());
+
+    vcpu.set_system_register(SystemRegister::TPIDR_EL1, 0x1234)
+        .unwrap();
+
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::TPIDR_EL1).unwrap(),
+        0x1234
+    );
+}
+
+#[test]
+fn effective_sp_follows_the_pstate() {
+    /// Stack pointer of EL0.
+    const SP_EL0: u64 = 0x1000;
+
+    /// Stack pointer of EL1.
+    const SP_EL1: u64 = 0x2000;
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    vcpu.set_system_register(SystemRegister::SP_EL0, SP_EL0)
+        .unwrap();
+    vcpu.set_system_register(SystemRegister::SP_EL1, SP_EL1)
+        .unwrap();
+
+    // EL1h, EL1t and EL0t, all interrupts masked.
+    for (cpsr, sp_sel, sp) in [
+        (0x3c5, true, SP_EL1),
+        (0x3c4, false, SP_EL0),
+        (0x3c0, false, SP_EL0),
+    ] {
+        vcpu.set_register(Register::CPSR, cpsr).unwrap();
+
+        assert_eq!(vcpu.sp_sel().unwrap(), sp_sel, "CPSR {:#x}", cpsr);
+        assert_eq!(vcpu.effective_sp().unwrap(), sp, "CPSR {:#x}", cpsr);
+    }
+}
+
+#[test]
+fn dump_registers_covers_every_register() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    vcpu.set_register(Register::X7, 0x7777).unwrap();
+
+    let registers = vcpu.dump_registers().unwrap();
+
+    assert_eq!(registers.len(), Register::ALL.len());
+
+    let value_of = |name: &str| {
+        registers
+            .iter()
+            .find(|(register, _)| register.name() == name)
+            .map(|(_, value)| *value)
+    };
+
+    assert_eq!(value_of("PC"), Some(CODE_ADDRESS));
+    assert_eq!(value_of("X7"), Some(0x7777));
+    assert_eq!(value_of("CPSR"), Some(0x3c5));
+}
+
+#[test]
+fn configure_debug_applies_both_toggles() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    for (trap_exceptions, trap_reg_accesses) in [(true, true), (true, false), (false, true)] {
+        vcpu.configure_debug(trap_exceptions, trap_reg_accesses)
+            .unwrap();
+
+        assert_eq!(
+            vcpu.get_debug_config().unwrap(),
+            DebugConfig {
+                trap_exceptions,
+                trap_reg_accesses,
+            }
+        );
+        assert_eq!(vcpu.get_trap_debug_exceptions().unwrap(), trap_exceptions);
+        assert_eq!(
+            vcpu.get_trap_debug_reg_accesses().unwrap(),
+            trap_reg_accesses
+        );
+    }
+}
+
+#[test]
+fn unmap_address_only_matches_the_mapping_start() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&BUSY_LOOP);
+
+    for address in [DATA_ADDRESS + 0x1000, 0x4000_0000] {
+        assert!(matches!(
+            virtual_machine.unmap_address(address),
+            Err(HypervisorError::InvalidHandle)
+        ));
+    }
+
+    assert_eq!(virtual_machine.get_all_mapping_infos().len(), 2);
+
+    virtual_machine.unmap_address(DATA_ADDRESS).unwrap();
+
+    let mappings = virtual_machine.get_all_mapping_infos();
+
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].address, CODE_ADDRESS);
+
+    assert!(matches!(
+        virtual_machine.unmap_address(DATA_ADDRESS),
+        Err(HypervisorError::InvalidHandle)
+    ));
+}
+
+#[test]
+fn get_mapping_info_at_finds_the_faulting_mapping() {
+    /// Code of a guest storing to the address in x3.
+    const STORE_CODE: [u8; 4] = [
+        0x61, 0x00, 0x00, 0xF9, // str x1, [x3]
+    ];
+
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mut vcpu) = create_guest(&STORE_CODE);
+
+    vcpu.set_register(Register::X3, 0x4000_0000).unwrap();
+
+    let exception = match vcpu.run().unwrap() {
+        VirtualCpuExitReason::Exception { exception } => exception,
+        reason => panic!("Unexpected exit: {:?}", reason),
+    };
+
+    assert_eq!(exception.physical_address, 0x4000_0000);
+    assert!(matches!(
+        virtual_machine.get_mapping_info_at(exception.physical_address),
+        Err(HypervisorError::InvalidHandle)
+    ));
+
+    let mapping = virtual_machine
+        .get_mapping_info_at(DATA_ADDRESS + 0x123)
+        .unwrap();
+
+    assert_eq!(mapping.address, DATA_ADDRESS);
+    assert_eq!(mapping.permission, MemoryPermission::READ_WRITE);
+
+    // The end of a mapping is outside of it.
+    assert!(matches!(
+        virtual_machine.get_mapping_info_at(DATA_ADDRESS + PAGE_SIZE as u64),
+        Err(HypervisorError::InvalidHandle)
+    ));
+}
+
+#[test]
+fn pre_run_hook_runs_before_each_entry() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Code of a guest reporting x5 in x0 with an HVC.
+    const REPORT_X5_CODE: [u8; 8] = [
+        0xE0, 0x03, 0x05, 0xAA, // mov x0, x5
+        0x02, 0x00, 0x00, 0xD4, // hvc #0
+    ];
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&REPORT_X5_CODE);
+
+    let calls = Rc::new(Cell::new(0));
+    let hook_calls = calls.clone();
+
+    vcpu.set_pre_run_hook(Box::new(move |vcpu| {
+        hook_calls.set(hook_calls.get() + 1);
+
+        vcpu.set_register(Register::X5, hook_calls.get())
+    }));
+
+    for expected in 1..=3 {
+        vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+
+        assert!(is_hvc(&vcpu.run().unwrap()));
+
+        // The guest observed the value set by the hook for this run.
+        assert_eq!(calls.get(), expected);
+        assert_eq!(vcpu.get_register(Register::X0).unwrap(), expected);
+    }
+
+    // A failing hook prevents entering the guest.
+    vcpu.set_pre_run_hook(Box::new(|_| Err(HypervisorError::Denied)));
+
+    assert!(matches!(vcpu.run(), Err(HypervisorError::Denied)));
+
+    vcpu.clear_pre_run_hook();
+    vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+    assert_eq!(calls.get(), 3);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn guest_memory_cursor_reads_across_mappings() {
+    use std::io::{ErrorKind, Read};
+
+    /// Guest address of the first of the two contiguous mappings.
+    const BASE: hv_ipa_t = 0x10_0000;
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    map_contiguous_pages(&mut virtual_machine, BASE, 2);
+
+    let boundary = BASE + PAGE_SIZE as u64;
+
+    virtual_machine
+        .write_guest(boundary - 4, &[1, 2, 3, 4, 5, 6, 7, 8])
+        .unwrap();
+
+    let mut cursor = GuestMemoryCursor::new(&mut virtual_machine, boundary - 4);
+    let mut buffer = [0; 8];
+
+    cursor.read_exact(&mut buffer).unwrap();
+
+    assert_eq!(buffer, [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(cursor.position(), boundary + 4);
+
+    // Reading past the end of the second mapping fails.
+    let mut cursor = GuestMemoryCursor::new(&mut virtual_machine, boundary + PAGE_SIZE as u64 - 4);
+
+    assert_eq!(
+        cursor.read_exact(&mut buffer).unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn map_with_id_rejects_reuse_and_advances_the_counter() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let first = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    let second = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    let third = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .map_with_id(first, 0x10000, MemoryPermission::READ, MappingHandle(10))
+        .unwrap();
+
+    assert!(matches!(
+        virtual_machine.map_with_id(second, 0x20000, MemoryPermission::READ, MappingHandle(10)),
+        Err(HypervisorError::InvalidHandle)
+    ));
+
+    // Nothing was mapped by the rejected call.
+    assert_eq!(virtual_machine.get_all_mapping_infos().len(), 1);
+
+    // New handles never collide with the chosen one.
+    let mapping_handle = virtual_machine
+        .map(second, 0x20000, MemoryPermission::READ)
+        .unwrap();
+
+    assert!(mapping_handle.0 > 10);
+
+    let next_handle = virtual_machine
+        .map(third, 0x30000, MemoryPermission::READ)
+        .unwrap();
+
+    assert!(next_handle.0 > mapping_handle.0);
+}
+
+#[test]
+fn gpr_covers_every_index() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&BUSY_LOOP);
+
+    for index in 0..=30u8 {
+        vcpu.set_gpr(index, 0x1000 + u64::from(index)).unwrap();
+    }
+
+    for index in 0..=30u8 {
+        let register = Register::from_x_index(index).unwrap();
+
+        assert_eq!(vcpu.gpr(index).unwrap(), 0x1000 + u64::from(index));
+        assert_eq!(
+            vcpu.get_register(register).unwrap(),
+            0x1000 + u64::from(index)
+        );
+    }
+
+    // Index 31 is XZR: reads as zero and ignores writes.
+    vcpu.set_gpr(31, 0x1234).unwrap();
+
+    assert_eq!(vcpu.gpr(31).unwrap(), 0);
+
+    for index in [32, u8::MAX] {
+        assert!(matches!(vcpu.gpr(index), Err(HypervisorError::BadArgument)));
+        assert!(matches!(
+            vcpu.set_gpr(index, 0),
+            Err(HypervisorError::BadArgument)
+        ));
+    }
+}
+
+#[test]
+fn fill_guest_spans_contiguous_mappings() {
+    /// Guest address of the first of the two contiguous mappings.
+    const BASE: hv_ipa_t = 0x10_0000;
+
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    map_contiguous_pages(&mut virtual_machine, BASE, 2);
+
+    let boundary = BASE + PAGE_SIZE as u64;
+    let end = boundary + PAGE_SIZE as u64;
+
+    // Up to the end of the first mapping.
+    virtual_machine.fill_guest(boundary - 16, 0xAA, 16).unwrap();
+
+    // Across both mappings.
+    virtual_machine.fill_guest(boundary - 4, 0x55, 8).unwrap();
+
+    // Up to the end of the second mapping.
+    virtual_machine.fill_guest(end - 4, 0xCC, 4).unwrap();
+
+    let mut buffer = [0; 20];
+    virtual_machine
+        .read_guest(boundary - 16, &mut buffer)
+        .unwrap();
+
+    assert_eq!(buffer[..12], [0xAA; 12]);
+    assert_eq!(buffer[12..], [0x55; 8]);
+    assert_eq!(virtual_machine.read_u8(boundary + 4).unwrap(), 0);
+    assert_eq!(virtual_machine.read_u32(end - 4).unwrap(), 0xCCCC_CCCC);
+
+    // Past the end of the second mapping nothing is written.
+    assert!(matches!(
+        virtual_machine.fill_guest(end - 4, 0x11, 8),
+        Err(HypervisorError::UnmappedAddress)
+    ));
+    assert_eq!(virtual_machine.read_u32(end - 4).unwrap(), 0xCCCC_CCCC);
+}
+
+#[test]
+fn get_esr_el1_decodes_an_injected_syndrome() {
+    /// SError syndrome with an implementation defined syndrome (IDS) set.
+    const ESR: u64 = (0x2F << 26) | (1 << 25) | (1 << 24) | 0x1234;
+
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&with_vectors(
+        &BUSY_LOOP,
+        &[(
+            SERROR_VECTOR,
+            &[
+                0x02, 0x00, 0x00, 0xD4, // hvc #0
+            ],
+        )],
+    ));
+
+    // EL1h with asynchronous aborts unmasked.
+    vcpu.set_register(Register::CPSR, 0x2c5).unwrap();
+    vcpu.set_pending_serror(true, Some(ESR)).unwrap();
+
+    assert!(is_hvc(&vcpu.run().unwrap()));
+
+    assert_eq!(vcpu.get_esr_el1().unwrap(), ESR);
+    assert_eq!(
+        vcpu.get_esr_el1_exception_class().unwrap(),
+        ExceptionClass::SError
+    );
+}
+
+#[test]
+fn typed_accessors_round_trip() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&BUSY_LOOP);
+
+    virtual_machine
+        .write_u64(DATA_ADDRESS, 0x0123_4567_89AB_CDEF)
+        .unwrap();
+
+    assert_eq!(
+        virtual_machine.read_u64(DATA_ADDRESS).unwrap(),
+        0x0123_4567_89AB_CDEF
+    );
+
+    // Values are stored as little-endian.
+    assert_eq!(virtual_machine.read_u8(DATA_ADDRESS).unwrap(), 0xEF);
+
+    // Unaligned reads within the mapping are allowed.
+    assert_eq!(
+        virtual_machine.read_u32(DATA_ADDRESS + 1).unwrap(),
+        0x6789_ABCD
+    );
+    assert_eq!(
+        virtual_machine.read_u64(DATA_ADDRESS + 3).unwrap(),
+        0x0000_0001_2345_6789
+    );
+
+    // Reads crossing the end of the mapping fail.
+    assert!(matches!(
+        virtual_machine.read_u64(DATA_ADDRESS + PAGE_SIZE as u64 - 4),
+        Err(HypervisorError::UnmappedAddress)
+    ));
+}
+
+#[test]
+fn checksum_region_detects_a_single_byte_change() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = create_guest_memory(&BUSY_LOOP);
+
+    let first = DATA_ADDRESS;
+    let second = DATA_ADDRESS + 0x1000;
+
+    virtual_machine
+        .write_guest(first, b"same contents")
+        .unwrap();
+    virtual_machine
+        .write_guest(second, b"same contents")
+        .unwrap();
+
+    let checksum = virtual_machine.checksum_region(first, 0x100).unwrap();
+
+    // Equal contents have equal checksums, regardless of their address.
+    assert_eq!(
+        virtual_machine.checksum_region(second, 0x100).unwrap(),
+        checksum
+    );
+    assert_eq!(
+        virtual_machine.checksum_region(first, 0x100).unwrap(),
+        checksum
+    );
+
+    virtual_machine.write_u8(second + 0xFF, 1).unwrap();
+
+    assert_ne!(
+        virtual_machine.checksum_region(second, 0x100).unwrap(),
+        checksum
+    );
+
+    assert!(matches!(
+        virtual_machine.checksum_region(DATA_ADDRESS + PAGE_SIZE as u64 - 1, 2),
+        Err(HypervisorError::UnmappedAddress)
+    ));
+}
+
+#[test]
+fn last_raw_exit_matches_a_known_exception() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_counter_guest();
+
+    let exception = match vcpu.run().unwrap() {
+        VirtualCpuExitReason::Exception { exception } => exception,
+        reason => panic!("Unexpected exit: {:?}", reason),
+    };
+
+    let raw_exit = vcpu.last_raw_exit();
+
+    assert_eq!(raw_exit.reason, ahv::ffi::types::HV_EXIT_REASON_EXCEPTION);
+    assert_eq!(raw_exit.exception.syndrome, exception.syndrome);
+    assert_eq!(raw_exit.exception.exception_class(), ExceptionClass::Hvc64);
+}
+
+#[test]
+fn vcpu_configuration_clone_has_the_same_feature_registers() {
+    let _guard = lock_hypervisor();
+
+    let virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let configuration = virtual_machine.create_vcpu_configuration();
+    let clone = configuration.try_clone().unwrap();
+
+    for feature_register in [
+        FeatureRegister::ID_AA64DFR0_EL1,
+        FeatureRegister::ID_AA64DFR1_EL1,
+        FeatureRegister::ID_AA64ISAR0_EL1,
+        FeatureRegister::ID_AA64ISAR1_EL1,
+        FeatureRegister::ID_AA64MMFR0_EL1,
+        FeatureRegister::ID_AA64MMFR1_EL1,
+        FeatureRegister::ID_AA64MMFR2_EL1,
+        FeatureRegister::ID_AA64PFR0_EL1,
+        FeatureRegister::ID_AA64PFR1_EL1,
+        FeatureRegister::CTR_EL0,
+        FeatureRegister::CLIDR_EL1,
+        FeatureRegister::DCZID_EL0,
+    ] {
+        assert_eq!(
+            clone.get_feature_register(feature_register).unwrap(),
+            configuration
+                .get_feature_register(feature_register)
+                .unwrap(),
+            "{:?}",
+            feature_register
+        );
+    }
+
+    for cache_type in [CacheType::Data, CacheType::Instruction] {
+        assert_eq!(
+            clone
+                .get_ccsidr_el1_sys_register_values(cache_type)
+                .unwrap(),
+            configuration
+                .get_ccsidr_el1_sys_register_values(cache_type)
+                .unwrap()
+        );
+    }
+}
+
+#[test]
+fn allocation_mapped_size_is_the_padded_size() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    for (requested_size, padded_size) in [(1000, PAGE_SIZE), (PAGE_SIZE + 1, 2 * PAGE_SIZE)] {
+        let allocation_handle = virtual_machine.allocate(requested_size).unwrap();
+
+        assert_eq!(
+            virtual_machine
+                .allocation_mapped_size(allocation_handle)
+                .unwrap(),
+            padded_size
+        );
+        assert_eq!(
+            virtual_machine
+                .get_allocation_slice(allocation_handle)
+                .unwrap()
+                .len(),
+            padded_size
+        );
+
+        let mapping_handle = virtual_machine
+            .map(
+                allocation_handle,
+                DATA_ADDRESS,
+                MemoryPermission::READ_WRITE,
+            )
+            .unwrap();
+
+        assert_eq!(
+            virtual_machine
+                .get_mapping_info(mapping_handle)
+                .unwrap()
+                .size,
+            padded_size
+        );
+
+        virtual_machine.unmap(mapping_handle).unwrap();
+        virtual_machine.deallocate(allocation_handle).unwrap();
+    }
+}
+
+#[test]
+fn zero_size_allocations_and_mappings_are_rejected() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    assert!(matches!(
+        virtual_machine.allocate(0),
+        Err(HypervisorError::BadArgument)
+    ));
+    assert!(matches!(
+        virtual_machine.allocate_from(&[]),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    let mut buffer = Box::new(AlignedPage([0; PAGE_SIZE]));
+
+    // SAFETY: nothing is mapped as the buffer is rejected.
+    let result = unsafe {
+        virtual_machine.map_slice(
+            &mut buffer.0[..0],
+            DATA_ADDRESS,
+            MemoryPermission::READ_WRITE,
+        )
+    };
+
+    assert!(matches!(result, Err(HypervisorError::BadArgument)));
+    assert!(virtual_machine.get_all_mapping_infos().is_empty());
+}
+
+#[test]
+fn exec_time_delta_saturates_when_the_previous_value_is_greater() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_counter_guest();
+
+    vcpu.run().unwrap();
+
+    let current = vcpu.get_exec_time().unwrap();
+
+    assert_eq!(vcpu.exec_time_delta_since(u64::MAX).unwrap(), 0);
+    assert_eq!(vcpu.exec_time_delta_since(current + 1).unwrap(), 0);
+
+    // The vCPU isn't running, so its execution time doesn't advance.
+    assert_eq!(vcpu.exec_time_delta_since(current).unwrap(), 0);
+    assert_eq!(vcpu.exec_time_delta_since(0).unwrap(), current);
+}
+
+#[test]
+fn force_deallocate_unmaps_every_alias() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    let alias_address = DATA_ADDRESS + PAGE_SIZE as u64;
+
+    for address in [DATA_ADDRESS, alias_address] {
+        virtual_machine
+            .map(allocation_handle, address, MemoryPermission::READ_WRITE)
+            .unwrap();
+    }
+
+    assert!(matches!(
+        virtual_machine.deallocate(allocation_handle),
+        Err(HypervisorError::AllocationStillMapped)
+    ));
+
+    virtual_machine.force_deallocate(allocation_handle).unwrap();
+
+    assert!(virtual_machine.get_all_mapping_infos().is_empty());
+
+    for address in [DATA_ADDRESS, alias_address] {
+        assert!(matches!(
+            virtual_machine.get_mapping_info_at(address),
+            Err(HypervisorError::InvalidHandle)
+        ));
+    }
+
+    assert!(matches!(
+        virtual_machine.get_allocation_slice(allocation_handle),
+        Err(HypervisorError::InvalidHandle)
+    ));
+
+    // Both guest addresses can be mapped again.
+    let allocation_handle = virtual_machine.allocate(2 * PAGE_SIZE).unwrap();
+
+    virtual_machine
+        .map(
+            allocation_handle,
+            DATA_ADDRESS,
+            MemoryPermission::READ_WRITE,
+        )
+        .unwrap();
+}
+
+#[test]
+fn host_overhead_accounts_a_slow_host_handler() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    let mut overhead = HostOverhead::new();
+
+    for _ in 0..3 {
+        vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+
+        let reason = overhead.run(&mut vcpu).unwrap();
+
+        assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+        // The host handler takes far longer than the guest.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    // The sleep after the last run isn't accounted until the next one.
+    assert!(overhead.host_ticks() > 0);
+    assert!(
+        overhead.host_ticks() > overhead.guest_ticks(),
+        "{:?}",
+        overhead
+    );
+    assert!(overhead.guest_ratio() < 0.5, "{:?}", overhead);
+}
+
+#[test]
+fn is_range_mapped_spans_contiguous_mappings_only() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    // Two contiguous pages, then a one-page gap before a third page.
+    map_contiguous_pages(&mut virtual_machine, DATA_ADDRESS, 2);
+    map_contiguous_pages(&mut virtual_machine, DATA_ADDRESS + 3 * PAGE_SIZE as u64, 1);
+
+    assert!(virtual_machine.is_range_mapped(DATA_ADDRESS, 2 * PAGE_SIZE));
+    assert!(virtual_machine.is_range_mapped(DATA_ADDRESS + PAGE_SIZE as u64 - 8, 16));
+    assert!(virtual_machine.is_range_mapped(DATA_ADDRESS + 0x4000_0000, 0));
+
+    assert!(!virtual_machine.is_range_mapped(DATA_ADDRESS, 2 * PAGE_SIZE + 1));
+    assert!(!virtual_machine.is_range_mapped(DATA_ADDRESS, 4 * PAGE_SIZE));
+    assert!(!virtual_machine.is_range_mapped(DATA_ADDRESS - 1, 2));
+}
+
+#[test]
+fn setup_linux_boot_sets_the_boot_registers() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&HVC_SNIPPET);
+
+    for register in [Register::X1, Register::X2, Register::X3] {
+        vcpu.set_register(register, 0xdead_beef).unwrap();
+    }
+
+    vcpu.set_register(Register::CPSR, 0x3c4).unwrap();
+
+    vcpu.setup_linux_boot(CODE_ADDRESS, DATA_ADDRESS).unwrap();
+
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), DATA_ADDRESS);
+
+    for register in [Register::X1, Register::X2, Register::X3] {
+        assert_eq!(vcpu.get_register(register).unwrap(), 0, "{:?}", register);
+    }
+
+    assert_eq!(vcpu.get_register(Register::CPSR).unwrap(), 0x3c5);
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), CODE_ADDRESS);
+
+    // The guest starts at the kernel entry.
+    let reason = vcpu.run().unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), DATA_ADDRESS);
+}
+
+#[test]
+fn exception_register_shortcuts_match_the_system_registers() {
+    let _guard = lock_hypervisor();
+
+    /// Offset of the synchronous vector taken from the current exception level with SP_ELx.
+    const SYNC_VECTOR: usize = 0x200;
+
+    /// Code of a guest calling ``svc #0``.
+    const SVC_CODE: [u8; 4] = [0x01, 0x00, 0x00, 0xD4];
+
+    let code = with_vectors(&SVC_CODE, &[(SYNC_VECTOR, &HVC_SNIPPET)]);
+    let (_virtual_machine, mut vcpu) = create_guest(&code);
+
+    let reason = vcpu.run().unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+
+    // The SVC was taken at EL1, returning after it with the state it was called with.
+    assert_eq!(vcpu.elr_el1().unwrap(), CODE_ADDRESS + 4);
+    assert_eq!(vcpu.spsr_el1().unwrap(), 0x3c5);
+
+    for (value, register) in [
+        (vcpu.elr_el1().unwrap(), SystemRegister::ELR_EL1),
+        (vcpu.spsr_el1().unwrap(), SystemRegister::SPSR_EL1),
+        (vcpu.vbar_el1().unwrap(), SystemRegister::VBAR_EL1),
+    ] {
+        assert_eq!(
+            value,
+            vcpu.get_system_register(register).unwrap(),
+            "{:?}",
+            register
+        );
+    }
+
+    vcpu.set_elr_el1(0x1234).unwrap();
+    vcpu.set_spsr_el1(0x3c4).unwrap();
+    vcpu.set_vbar_el1(0x4000).unwrap();
+
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::ELR_EL1).unwrap(),
+        0x1234
+    );
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::SPSR_EL1).unwrap(),
+        0x3c4
+    );
+    assert_eq!(
+        vcpu.get_system_register(SystemRegister::VBAR_EL1).unwrap(),
+        0x4000
+    );
+}
+
+/// Code of a guest adding to x0 twice after setting it to 1, then reporting it with an HVC.
+const INCREMENT_TWICE_CODE: [u8; 16] = [
+    0x20, 0x00, 0x80, 0xD2, // mov x0, #1
+    0x00, 0x04, 0x00, 0x91, // add x0, x0, #1
+    0x00, 0x04, 0x00, 0x91, // add x0, x0, #1
+    0x02, 0x00, 0x00, 0xD4, // hvc #0
+];
+
+/// Gets the exception class of an exit caused by an exception.
+fn exit_exception_class(reason: &VirtualCpuExitReason) -> ExceptionClass {
+    match reason {
+        VirtualCpuExitReason::Exception { exception } => exception.exception_class(),
+        reason => panic!("Unexpected exit: {:?}", reason),
+    }
+}
+
+#[test]
+fn step_over_breakpoint_resumes_past_it() {
+    let _guard = lock_hypervisor();
+
+    let (_virtual_machine, mut vcpu) = create_guest(&INCREMENT_TWICE_CODE);
+
+    let breakpoint_address = CODE_ADDRESS + 4;
+
+    vcpu.set_trap_debug_exceptions(true).unwrap();
+    vcpu.set_hardware_breakpoint(0, breakpoint_address).unwrap();
+
+    let reason = vcpu.run().unwrap();
+
+    assert_eq!(
+        exit_exception_class(&reason),
+        ExceptionClass::BreakpointLowerEl
+    );
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), breakpoint_address);
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), 1);
+
+    // Only the instruction under the breakpoint is executed.
+    let reason = vcpu.step_over_breakpoint(0).unwrap();
+
+    assert_eq!(
+        exit_exception_class(&reason),
+        ExceptionClass::SoftwareStepLowerEl
+    );
+    assert_eq!(
+        vcpu.get_register(Register::PC).unwrap(),
+        breakpoint_address + 4
+    );
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), 2);
+
+    // The guest keeps running normally, with the breakpoint still set.
+    let reason = vcpu.run().unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), 3);
+
+    vcpu.set_register(Register::PC, CODE_ADDRESS).unwrap();
+
+    let reason = vcpu.run().unwrap();
+
+    assert_eq!(
+        exit_exception_class(&reason),
+        ExceptionClass::BreakpointLowerEl
+    );
+}
+
+#[test]
+fn availability_probes_are_consistent_on_the_current_os() {
+    use ahv::ffi::available::*;
+
+    let allocate = hv_vm_allocate_available();
+
+    assert_eq!(hv_vm_deallocate_available(), allocate);
+
+    // Built with the macOS 12.1 functions, the binary only loads if they are present.
+    if cfg!(feature = "macos_12_1_0") {
+        assert!(allocate);
+    }
+
+    let config = hv_vm_config_create_available();
+
+    for available in [
+        hv_vm_config_get_max_ipa_size_available(),
+        hv_vm_config_get_default_ipa_size_available(),
+        hv_vm_config_set_ipa_size_available(),
+        hv_vm_config_get_ipa_size_available(),
+    ] {
+        assert_eq!(available, config);
+    }
+
+    // Every function of macOS 13.0 comes with the ones of macOS 12.1.
+    assert!(!config || allocate);
+}
+
+#[test]
+fn run_to_pc_stops_at_the_target() {
+    let _guard = lock_hypervisor();
+
+    let (mut virtual_machine, mut vcpu) = create_guest(&INCREMENT_TWICE_CODE);
+
+    let target = CODE_ADDRESS + 8;
+    let original = virtual_machine.read_u32(target).unwrap();
+
+    vcpu.set_trap_debug_exceptions(true).unwrap();
+
+    let reason = vcpu.run_to_pc(&mut virtual_machine, target).unwrap();
+
+    assert_eq!(exit_exception_class(&reason), ExceptionClass::Brk64);
+    assert_eq!(vcpu.get_register(Register::PC).unwrap(), target);
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), 2);
+
+    // The original instruction is restored and runs once resumed.
+    assert_eq!(virtual_machine.read_u32(target).unwrap(), original);
+
+    let reason = vcpu.run().unwrap();
+
+    assert!(is_hvc(&reason), "Unexpected exit: {:?}", reason);
+    assert_eq!(vcpu.get_register(Register::X0).unwrap(), 3);
+}
+
+#[test]
+fn allocate_from_segments_places_the_segments() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    for segments in [
+        &[(0x10, &[1u8; 0x10][..]), (0x18, &[2; 4][..])][..],
+        &[(0x18, &[2; 4][..]), (0x10, &[1; 0x10][..])][..],
+        &[(0, &[1; 8][..]), (0, &[2; 8][..])][..],
+    ] {
+        assert!(matches!(
+            virtual_machine.allocate_from_segments(PAGE_SIZE, segments),
+            Err(HypervisorError::BadArgument)
+        ));
+    }
+
+    assert!(matches!(
+        virtual_machine.allocate_from_segments(PAGE_SIZE, &[(PAGE_SIZE - 2, &[1; 4])]),
+        Err(HypervisorError::BadArgument)
+    ));
+
+    // Adjacent segments, given out of order, with an empty one inside another.
+    let allocation_handle = virtual_machine
+        .allocate_from_segments(
+            2 * PAGE_SIZE,
+            &[
+                (PAGE_SIZE, &[3; 4]),
+                (0x10, &[1; 0x10]),
+                (0x20, &[2; 8]),
+                (0x14, &[]),
+            ],
+        )
+        .unwrap();
+
+    let slice = virtual_machine
+        .get_allocation_slice(allocation_handle)
+        .unwrap();
+
+    assert_eq!(slice.len(), 2 * PAGE_SIZE);
+    assert!(slice[..0x10].iter().all(|value| *value == 0));
+    assert!(slice[0x10..0x20].iter().all(|value| *value == 1));
+    assert!(slice[0x20..0x28].iter().all(|value| *value == 2));
+    assert!(slice[0x28..PAGE_SIZE].iter().all(|value| *value == 0));
+    assert!(slice[PAGE_SIZE..PAGE_SIZE + 4]
+        .iter()
+        .all(|value| *value == 3));
+    assert!(slice[PAGE_SIZE + 4..].iter().all(|value| *value == 0));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn exit_vcpus_detailed_reports_destroyed_vcpus() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let vcpu = virtual_machine.create_vcpu(None).unwrap();
+
+    // The vCPU is destroyed as soon as its thread returns.
+    // SAFETY: The thread is joined before the Virtual Machine is dropped.
+    let (exit_handle, join_handle) = unsafe { virtual_machine.spawn_vcpus(1, |_, _| Ok(())) }
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    join_handle.join().unwrap().unwrap();
+
+    let valid = vcpu.get_handle();
+    let destroyed = exit_handle.get_handle();
+
+    let results = virtual_machine.exit_vcpus_detailed(&[valid, destroyed, valid]);
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0, valid);
+    assert!(results[0].1.is_ok());
+    assert_eq!(results[1].0, destroyed);
+    assert!(results[1].1.is_err());
+    assert_eq!(results[2].0, valid);
+    assert!(results[2].1.is_ok());
+}
+
+#[test]
+fn reprotect_allocation_changes_every_alias() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let allocation_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+    let other_handle = virtual_machine.allocate(PAGE_SIZE).unwrap();
+
+    let aliases = [DATA_ADDRESS, DATA_ADDRESS + 2 * PAGE_SIZE as u64].map(|address| {
+        virtual_machine
+            .map(allocation_handle, address, MemoryPermission::READ_WRITE)
+            .unwrap()
+    });
+    let other = virtual_machine
+        .map(
+            other_handle,
+            DATA_ADDRESS + PAGE_SIZE as u64,
+            MemoryPermission::READ_WRITE,
+        )
+        .unwrap();
+
+    virtual_machine
+        .reprotect_allocation(allocation_handle, MemoryPermission::READ)
+        .unwrap();
+
+    for mapping_handle in aliases {
+        assert_eq!(
+            virtual_machine
+                .get_mapping_info(mapping_handle)
+                .unwrap()
+                .permission,
+            MemoryPermission::READ
+        );
+    }
+
+    // The other allocation is left untouched.
+    assert_eq!(
+        virtual_machine.get_mapping_info(other).unwrap().permission,
+        MemoryPermission::READ_WRITE
+    );
+
+    let error = virtual_machine
+        .reprotect_allocation(AllocationHandle(u64::MAX), MemoryPermission::READ)
+        .unwrap_err();
+
+    assert!(error.mapping_handle.is_none());
+    assert!(matches!(error.error, HypervisorError::InvalidHandle));
+}
+
+#[test]
+fn with_flat_ram_maps_the_padded_ram() {
+    let _guard = lock_hypervisor();
+
+    let (virtual_machine, mapping_handle) =
+        VirtualMachine::with_flat_ram(DATA_ADDRESS, 3 * PAGE_SIZE - 1).unwrap();
+
+    let mapping = virtual_machine.get_mapping_info(mapping_handle).unwrap();
+
+    assert_eq!(mapping.address, DATA_ADDRESS);
+    assert_eq!(mapping.size, 3 * PAGE_SIZE);
+    assert_eq!(mapping.permission, MemoryPermission::READ_WRITE_EXECUTE);
+    assert_eq!(virtual_machine.get_all_mapping_infos().len(), 1);
+
+    drop(virtual_machine);
+
+    // A failing step destroys the Virtual Machine, so a new one can be created right away.
+    assert!(matches!(
+        VirtualMachine::with_flat_ram(DATA_ADDRESS + 1, PAGE_SIZE),
+        Err(HypervisorError::MisalignedAddress)
+    ));
+
+    VirtualMachine::new(None).unwrap();
+}
+
+#[test]
+fn mapping_host_slice_covers_the_mapping() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+
+    let allocation_handle = virtual_machine
+        .allocate_from_segments(2 * PAGE_SIZE, &[(0, &[0x5A; 8])])
+        .unwrap();
+
+    let mapping_handles = [DATA_ADDRESS, DATA_ADDRESS + 2 * PAGE_SIZE as u64].map(|address| {
+        virtual_machine
+            .map(allocation_handle, address, MemoryPermission::READ)
+            .unwrap()
+    });
+
+    for mapping_handle in mapping_handles {
+        let mapping = virtual_machine.get_mapping_info(mapping_handle).unwrap();
+        let slice = virtual_machine.mapping_host_slice(mapping_handle).unwrap();
+
+        assert_eq!(slice.len(), mapping.size);
+        assert_eq!(
+            slice.as_ptr(),
+            virtual_machine
+                .get_allocation_slice(allocation_handle)
+                .unwrap()
+                .as_ptr()
+        );
+        assert_eq!(slice[..8], [0x5A; 8]);
+    }
+
+    assert!(matches!(
+        virtual_machine.mapping_host_slice(MappingHandle(u64::MAX)),
+        Err(HypervisorError::InvalidHandle)
+    ));
+}
+
+#[test]
+fn set_x_range_writes_consecutive_registers() {
+    let _guard = lock_hypervisor();
+
+    let mut virtual_machine = VirtualMachine::new(None).unwrap();
+    let mut vcpu = virtual_machine.create_vcpu(None).unwrap();
+
+    let values: Vec<u64> = (0..8).map(|index| 0x1000 + index).collect();
+
+    vcpu.set_register(Register::X8, 0xdead).unwrap();
+    vcpu.set_x_range(0, &values).unwrap();
+
+    for (index, value) in (0u8..).zip(&values) {
+        assert_eq!(vcpu.gpr(index).unwrap(), *value, "X{}", index);
+    }
+
+    assert_eq!(vcpu.get_register(Register::X7).unwrap(), 0x1007);
+    assert_eq!(vcpu.get_register(Register::X8).unwrap(), 0xdead);
+
+    // A range going past X30 writes nothing.
+    vcpu.set_register(Register::X24, 0xbeef).unwrap();
+
+    assert!(matches!(
+        vcpu.set_x_range(24, &[0; 8]),
+        Err(HypervisorError::BadArgument)
+    ));
+    assert_eq!(vcpu.get_register(Register::X24).unwrap(), 0xbeef);
+}